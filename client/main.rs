@@ -140,12 +140,18 @@ fn main() {
     let matches = App::new("rvncclient")
         .about("VNC client")
         .arg(Arg::with_name("HOST")
-                .help("server hostname or IP")
-                .required(true)
+                .help("server hostname or IP (with --listen: address to listen on)")
                 .index(1))
         .arg(Arg::with_name("PORT")
                 .help("server port (default: 5900)")
                 .index(2))
+        .arg(Arg::with_name("LISTEN")
+                .help("wait for a reverse connection from the server on this port \
+                       (default: 5500)")
+                .long("listen")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1))
         .arg(Arg::with_name("USERNAME")
                 .help("server username")
                 .long("username")
@@ -165,8 +171,26 @@ fn main() {
                 .long("heinous-qemu-hacks"))
         .get_matches();
 
-    let host = matches.value_of("HOST").unwrap();
-    let port = value_t!(matches.value_of("PORT"), u16).unwrap_or(5900);
+    let listen = matches.is_present("LISTEN");
+    let host =
+        match matches.value_of("HOST") {
+            Some(host) => host,
+            None if listen => "0.0.0.0",
+            None => {
+                error!("server hostname or IP is required unless listening");
+                std::process::exit(1)
+            }
+        };
+    let port =
+        if listen {
+            if matches.is_present("PORT") {
+                error!("the port to listen on is given to --listen");
+                std::process::exit(1)
+            }
+            value_t!(matches.value_of("LISTEN"), u16).unwrap_or(vnc::client::LISTEN_PORT)
+        } else {
+            value_t!(matches.value_of("PORT"), u16).unwrap_or(5900)
+        };
     let username = matches.value_of("USERNAME");
     let password = matches.value_of("PASSWORD");
     let exclusive = matches.is_present("EXCLUSIVE");
@@ -178,50 +202,92 @@ fn main() {
     let mut sdl_timer = sdl_context.timer().unwrap();
     let mut sdl_events = sdl_context.event_pump().unwrap();
 
-    info!("connecting to {}:{}", host, port);
-    let stream =
-        match std::net::TcpStream::connect((host, port)) {
-            Ok(stream) => stream,
-            Err(error) => {
-                error!("cannot connect to {}:{}: {}", host, port, error);
-                std::process::exit(1)
-            }
-        };
-
-    let mut vnc =
-        match vnc::Client::from_tcp_stream(stream, !exclusive, |methods| {
-            debug!("available authentication methods: {:?}", methods);
-            for method in methods {
-                match method {
-                    &vnc::client::AuthMethod::None =>
-                        return Some(vnc::client::AuthChoice::None),
-                    &vnc::client::AuthMethod::Password => {
-                        return match password {
-                            None => None,
-                            Some(ref password) => {
-                                let mut key = [0; 8];
-                                for (i, byte) in password.bytes().enumerate() {
-                                    if i == 8 { break }
-                                    key[i] = byte
-                                }
-                                Some(vnc::client::AuthChoice::Password(key))
+    let auth = |methods: &[vnc::client::AuthMethod]| {
+        debug!("available authentication methods: {:?}", methods);
+        for method in methods {
+            match method {
+                &vnc::client::AuthMethod::None =>
+                    return Some(vnc::client::AuthChoice::None),
+                &vnc::client::AuthMethod::Password => {
+                    return match password {
+                        None => None,
+                        Some(ref password) => {
+                            let mut key = [0; 8];
+                            for (i, byte) in password.bytes().enumerate() {
+                                if i == 8 { break }
+                                key[i] = byte
                             }
+                            Some(vnc::client::AuthChoice::Password(key))
                         }
+                    }
+                },
+                &vnc::client::AuthMethod::VeNCrypt => {
+                    let mut subtypes = Vec::new();
+                    if username.is_some() && password.is_some() {
+                        subtypes.push(vnc::VeNCryptSubtype::TlsPlain)
+                    }
+                    if password.is_some() {
+                        subtypes.push(vnc::VeNCryptSubtype::TlsVnc)
+                    }
+                    subtypes.push(vnc::VeNCryptSubtype::TlsNone);
+                    return Some(vnc::client::AuthChoice::VeNCrypt(
+                        vnc::client::VeNCryptOptions {
+                            subtypes:    subtypes,
+                            username:    username.unwrap_or("").to_owned(),
+                            password:    password.unwrap_or("").to_owned(),
+                            tls_config:  None,
+                            server_name: host.to_owned(),
+                        }
+                    ))
+                },
+                &vnc::client::AuthMethod::RsaAes(variant) =>
+                    if let Some(password) = password {
+                        return Some(vnc::client::AuthChoice::RsaAes(variant,
+                            vnc::client::RsaAesOptions {
+                                username: username.unwrap_or("").to_owned(),
+                                password: password.to_owned(),
+                                server_key_fingerprint: None,
+                            }
+                        ))
+                    },
+                &vnc::client::AuthMethod::AppleRemoteDesktop =>
+                    match (username, password) {
+                        (Some(username), Some(password)) =>
+                            return Some(vnc::client::AuthChoice::AppleRemoteDesktop(
+                                username.to_owned(), password.to_owned()
+                            )),
+                        _ =>
+                            ()
                     },
-                    &vnc::client::AuthMethod::AppleRemoteDesktop =>
-                        match (username, password) {
-                            (Some(username), Some(password)) =>
-                                return Some(vnc::client::AuthChoice::AppleRemoteDesktop(
-                                    username.to_owned(), password.to_owned()
-                                )),
-                            _ =>
-                                ()
-                        },
-                    _ => ()
+                _ => ()
+            }
+        }
+        None
+    };
+
+    let result =
+        if listen {
+            info!("listening on {}:{}", host, port);
+            match std::net::TcpListener::bind((host, port)) {
+                Ok(listener) => vnc::Client::listen(&listener, !exclusive, auth),
+                Err(error) => {
+                    error!("cannot listen on {}:{}: {}", host, port, error);
+                    std::process::exit(1)
+                }
+            }
+        } else {
+            info!("connecting to {}:{}", host, port);
+            match std::net::TcpStream::connect((host, port)) {
+                Ok(stream) => vnc::Client::from_tcp_stream(stream, !exclusive, auth),
+                Err(error) => {
+                    error!("cannot connect to {}:{}: {}", host, port, error);
+                    std::process::exit(1)
                 }
             }
-            None
-        }) {
+        };
+
+    let mut vnc =
+        match result {
             Ok(vnc) => vnc,
             Err(error) => {
                 error!("cannot initialize VNC session: {}", error);
@@ -254,7 +320,9 @@ fn main() {
     } else {
         vnc.set_encodings(&[
             vnc::Encoding::Zrle, vnc::Encoding::CopyRect, vnc::Encoding::Raw,
-            vnc::Encoding::Cursor, vnc::Encoding::DesktopSize
+            vnc::Encoding::Cursor, vnc::Encoding::DesktopSize,
+            vnc::Encoding::Fence, vnc::Encoding::ContinuousUpdates,
+            vnc::Encoding::ExtendedClipboard
         ]).unwrap()
     }
 
@@ -280,15 +348,22 @@ fn main() {
                        false).unwrap();
 
     let mut incremental = true;
+    let mut continuous_updates = false;
     let mut qemu_network_rtt = 1000;
     let mut qemu_prev_update = sdl_timer.ticks();
     let mut qemu_next_update = sdl_timer.ticks() + qemu_network_rtt / 2;
+    let mut clipboard_replies = Vec::new();
     'running: loop {
         const FRAME_MS: u32 = 1000 / 60;
         let ticks = sdl_timer.ticks();
 
         renderer.present();
 
+        // Replies can only be sent once the events are no longer being polled.
+        for reply in clipboard_replies.drain(..) {
+            vnc.send_extended_clipboard(reply).unwrap()
+        }
+
         for event in vnc.poll_iter() {
             use vnc::client::Event;
 
@@ -305,6 +380,10 @@ fn main() {
                     screen = renderer.create_texture_streaming(
                         sdl_format, (width as u32, height as u32)).unwrap();
                     incremental = false;
+                    continuous_updates = false;
+                },
+                Event::EndOfContinuousUpdates => {
+                    continuous_updates = false;
                 },
                 Event::PutPixels(vnc_rect, ref pixels) => {
                     let sdl_rect = SdlRect::new_unwrap(
@@ -316,6 +395,18 @@ fn main() {
                     incremental |= vnc_rect == vnc::Rect { left: 0, top: 0,
                                                            width: width, height: height };
                 },
+                Event::FillPixels(vnc_rect, ref pixel) => {
+                    let sdl_rect = SdlRect::new_unwrap(
+                        vnc_rect.left as i32, vnc_rect.top as i32,
+                        vnc_rect.width as u32, vnc_rect.height as u32);
+                    let mut pixels = Vec::new();
+                    for _ in 0..(vnc_rect.width as usize * vnc_rect.height as usize) {
+                        pixels.extend_from_slice(pixel)
+                    }
+                    screen.update(Some(sdl_rect), &pixels,
+                        sdl_format.byte_size_of_pixels(vnc_rect.width as usize)).unwrap();
+                    renderer.copy(&screen, Some(sdl_rect), Some(sdl_rect));
+                },
                 Event::CopyPixels { src: vnc_src, dst: vnc_dst } => {
                     let sdl_src = SdlRect::new_unwrap(
                         vnc_src.left as i32, vnc_src.top as i32,
@@ -343,6 +434,33 @@ fn main() {
                     // this returns a Result, but unwrapping it fails with "Invalid renderer",
                     // even though the call to set_clipboard_text actually succeeds.
                 },
+                Event::ExtendedClipboard(ref clipboard) => {
+                    use vnc::{ExtendedClipboard, ClipboardFormat, ClipboardData};
+
+                    match clipboard {
+                        &ExtendedClipboard::Notify(ref formats)
+                                if formats.contains(&ClipboardFormat::Text) =>
+                            clipboard_replies.push(
+                                ExtendedClipboard::Request(vec![ClipboardFormat::Text])),
+                        &ExtendedClipboard::Request(ref formats)
+                                if formats.contains(&ClipboardFormat::Text) => {
+                            let text = sdl_video.clipboard().clipboard_text().unwrap();
+                            clipboard_replies.push(
+                                ExtendedClipboard::Provide(vec![ClipboardData::Text(text)]))
+                        }
+                        &ExtendedClipboard::Peek =>
+                            clipboard_replies.push(
+                                ExtendedClipboard::Notify(vec![ClipboardFormat::Text])),
+                        &ExtendedClipboard::Provide(ref contents) => {
+                            for data in contents {
+                                if let &ClipboardData::Text(ref text) = data {
+                                    let _ = sdl_video.clipboard().set_clipboard_text(text);
+                                }
+                            }
+                        }
+                        _ => ()
+                    }
+                },
                 Event::SetCursor {
                     size:    (width, height),
                     hotspot: (new_hotspot_x, new_hotspot_y),
@@ -500,9 +618,15 @@ fn main() {
             vnc.poke_qemu().unwrap();
             qemu_next_update = sdl_timer.ticks() + qemu_network_rtt / 2;
         } else {
-            vnc.request_update(vnc::Rect { left: 0, top: 0, width: width, height: height},
-                               incremental).unwrap();
-
+            let screen_rect = vnc::Rect { left: 0, top: 0, width: width, height: height };
+            if vnc.supports_continuous_updates() && !continuous_updates {
+                // Let the server push updates as they happen instead of polling it.
+                vnc.enable_continuous_updates(screen_rect).unwrap();
+                continuous_updates = true;
+            }
+            if !continuous_updates || !incremental {
+                vnc.request_update(screen_rect, incremental).unwrap();
+            }
         }
     }
 }
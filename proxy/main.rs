@@ -23,6 +23,11 @@ fn main() {
         .arg(Arg::with_name("LISTEN-PORT")
                 .help("proxy port (default: server port plus one)")
                 .index(4))
+        .arg(Arg::with_name("REPEATER")
+                .help("connect through the UltraVNC repeater at the server address \
+                       to this destination (host:port or ID:nnnn)")
+                .long("repeater")
+                .takes_value(true))
         .get_matches();
 
     let connect_host = matches.value_of("CONNECT-HOST")
@@ -33,6 +38,15 @@ fn main() {
         .unwrap_or("localhost");
     let listen_port = value_t!(matches.value_of("LISTEN-PORT"), u16)
         .unwrap_or(connect_port + 1);
+    let repeater_destination = matches.value_of("REPEATER").map(|destination| {
+        match destination.parse::<vnc::repeater::Destination>() {
+            Ok(destination) => destination,
+            Err(error) => {
+                error!("invalid repeater destination {}: {}", destination, error);
+                std::process::exit(1)
+            }
+        }
+    });
 
     info!("listening at {}:{}", listen_host, listen_port);
     let listener =
@@ -55,7 +69,7 @@ fn main() {
             };
 
         info!("connecting to {}:{}", connect_host, connect_port);
-        let server_stream =
+        let mut server_stream =
             match std::net::TcpStream::connect((connect_host, connect_port)) {
                 Ok(stream) => stream,
                 Err(error) => {
@@ -65,6 +79,16 @@ fn main() {
                 }
             };
 
+        if let Some(ref destination) = repeater_destination {
+            info!("asking repeater for {}", destination);
+            if let Err(error) = vnc::repeater::select_destination(&mut server_stream,
+                                                                  destination) {
+                error!("repeater handshake failed: {}", error);
+                client_stream.shutdown(std::net::Shutdown::Both).unwrap();
+                continue
+            }
+        }
+
         let proxy =
             match vnc::Proxy::from_tcp_streams(server_stream, client_stream) {
                 Ok(proxy) => proxy,
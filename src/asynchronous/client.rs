@@ -0,0 +1,260 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures_core;
+use tokio::io::{AsyncRead, AsyncWrite};
+use ::{protocol, Error, Result};
+use client::{AuthMethod, AuthChoice, Event};
+use connection::{ClientConnection, ClientState};
+use super::{Framed, Flush, Shutdown};
+
+/// A future performing the client side of the handshake; see `Client::connect`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Connect<S, Auth> {
+    framed:     Option<Framed<S>>,
+    connection: Option<ClientConnection>,
+    auth:       Option<Auth>,
+}
+
+impl<S, Auth> Future for Connect<S, Auth>
+        where S: AsyncRead + AsyncWrite + Unpin,
+              Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> + Unpin {
+    type Output = Result<Client<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Client<S>>> {
+        try_ready!(self.poll_handshake(cx));
+        Poll::Ready(Ok(Client {
+            framed:     self.framed.take().unwrap(),
+            connection: self.connection.take().unwrap(),
+            disconnected: None,
+        }))
+    }
+}
+
+impl<S, Auth> Connect<S, Auth>
+        where S: AsyncRead + AsyncWrite + Unpin,
+              Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        let framed = self.framed.as_mut().expect("polled after completion");
+        let connection = self.connection.as_mut().unwrap();
+        loop {
+            framed.output.extend(connection.take_output());
+            try_ready!(framed.poll_flush(cx));
+            let result = match connection.state() {
+                ClientState::Handshaking => {
+                    let mut buffer = [0; 4096];
+                    let count = try_ready!(framed.poll_receive(cx, &mut buffer));
+                    connection.receive(&buffer[..count])
+                }
+                ClientState::ChoosingSecurity => {
+                    let auth = self.auth.take().expect("authentication already chosen");
+                    choose_security(connection, auth)
+                }
+                ClientState::Authenticating(_) =>
+                    Err(Error::AuthenticationUnavailable),
+                ClientState::Connected =>
+                    return Poll::Ready(Ok(()))
+            };
+            if let Err(error) = result {
+                return Poll::Ready(Err(error))
+            }
+        }
+    }
+}
+
+/// Lets `auth` choose among the security types offered by the server, and starts the
+/// chosen one on `connection`.
+fn choose_security<Auth>(connection: &mut ClientConnection, auth: Auth) -> Result<()>
+        where Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+    let auth_methods = connection.security_types().iter().map(|&security_type| {
+        match security_type {
+            protocol::SecurityType::None => AuthMethod::None,
+            protocol::SecurityType::VncAuthentication => AuthMethod::Password,
+            security_type => AuthMethod::Other(security_type)
+        }
+    }).collect::<Vec<_>>();
+    match try!(auth(&auth_methods).ok_or(Error::AuthenticationUnavailable)) {
+        AuthChoice::None =>
+            connection.choose_security(protocol::SecurityType::None),
+        AuthChoice::Password(password) =>
+            connection.choose_vnc_authentication(password),
+        _ => Err(Error::AuthenticationUnavailable)
+    }
+}
+
+/// The asynchronous counterpart of `client::Client`.
+///
+/// Events are received by polling it as a `Stream`, which ends after `Event::Disconnected`.
+/// Every other message is sent by a future that completes once it has been written out.
+pub struct Client<S> {
+    framed:     Framed<S>,
+    connection: ClientConnection,
+    // Once the connection has failed, the `Event::Disconnected` still to be returned
+    // after the events received before.
+    disconnected: Option<Option<Event>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Returns a future performing the handshake with a server connected over `stream`,
+    /// which resolves to the new `Client`.
+    ///
+    /// Only `AuthChoice::None` and `AuthChoice::Password` are supported; choosing any other
+    /// security type fails with `Error::AuthenticationUnavailable`.
+    pub fn connect<Auth>(stream: S, shared: bool, auth: Auth) -> Connect<S, Auth>
+            where Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+        Connect {
+            framed:     Some(Framed::new(stream)),
+            connection: Some(ClientConnection::new(shared)),
+            auth:       Some(auth),
+        }
+    }
+
+    pub fn name(&self) -> &str { self.connection.name() }
+    pub fn size(&self) -> (u16, u16) { self.connection.size() }
+    pub fn format(&self) -> protocol::PixelFormat { self.connection.format() }
+
+    pub fn supports_fence(&self) -> bool { self.connection.supports_fence() }
+    pub fn supports_continuous_updates(&self) -> bool {
+        self.connection.supports_continuous_updates()
+    }
+    pub fn supports_extended_clipboard(&self) -> bool {
+        self.connection.supports_extended_clipboard()
+    }
+
+    fn flush(&mut self, result: Result<()>) -> Flush<'_, S> {
+        self.framed.output.extend(self.connection.take_output());
+        Flush::new(&mut self.framed, result)
+    }
+
+    fn send(&mut self, message: protocol::C2S) -> Flush<'_, S> {
+        let result = self.connection.send(&message);
+        self.flush(result)
+    }
+
+    pub fn set_encodings(&mut self, encodings: &[protocol::Encoding]) -> Flush<'_, S> {
+        self.send(protocol::C2S::SetEncodings(Vec::from(encodings)))
+    }
+
+    pub fn request_update(&mut self, rect: protocol::Rect, incremental: bool) -> Flush<'_, S> {
+        self.send(protocol::C2S::FramebufferUpdateRequest {
+            incremental: incremental,
+            x_position:  rect.left,
+            y_position:  rect.top,
+            width:       rect.width,
+            height:      rect.height
+        })
+    }
+
+    pub fn request_desktop_size(&mut self, width: u16, height: u16,
+                                screens: &[protocol::Screen]) -> Flush<'_, S> {
+        self.send(protocol::C2S::SetDesktopSize {
+            width:   width,
+            height:  height,
+            screens: Vec::from(screens)
+        })
+    }
+
+    pub fn send_fence(&mut self, flags: u32, payload: &[u8]) -> Flush<'_, S> {
+        self.send(protocol::C2S::Fence(protocol::Fence {
+            flags:   flags,
+            payload: Vec::from(payload)
+        }))
+    }
+
+    pub fn enable_continuous_updates(&mut self, rect: protocol::Rect) -> Flush<'_, S> {
+        self.send(protocol::C2S::EnableContinuousUpdates {
+            enable:      true,
+            x_position:  rect.left,
+            y_position:  rect.top,
+            width:       rect.width,
+            height:      rect.height
+        })
+    }
+
+    pub fn disable_continuous_updates(&mut self) -> Flush<'_, S> {
+        let (width, height) = self.connection.size();
+        self.send(protocol::C2S::EnableContinuousUpdates {
+            enable:      false,
+            x_position:  0,
+            y_position:  0,
+            width:       width,
+            height:      height
+        })
+    }
+
+    pub fn send_key_event(&mut self, down: bool, key: u32) -> Flush<'_, S> {
+        self.send(protocol::C2S::KeyEvent {
+            down: down,
+            key:  key
+        })
+    }
+
+    pub fn send_pointer_event(&mut self, buttons: u8, x: u16, y: u16) -> Flush<'_, S> {
+        self.send(protocol::C2S::PointerEvent {
+            button_mask: buttons,
+            x_position:  x,
+            y_position:  y
+        })
+    }
+
+    pub fn update_clipboard(&mut self, text: &str) -> Flush<'_, S> {
+        let result = self.connection.update_clipboard(text);
+        self.flush(result)
+    }
+
+    pub fn send_extended_clipboard(&mut self, clipboard: protocol::ExtendedClipboard)
+            -> Flush<'_, S> {
+        self.send(protocol::C2S::ExtendedCutText(clipboard))
+    }
+
+    /// Sends `SetPixelFormat` message.
+    ///
+    /// Unless the server supports the Fence extension, the new format is used for everything
+    /// received afterwards, so no framebuffer update may be outstanding.
+    pub fn set_format(&mut self, format: protocol::PixelFormat) -> Flush<'_, S> {
+        let result = self.connection.set_format(format);
+        self.flush(result)
+    }
+
+    pub fn disconnect(self) -> Shutdown<S> {
+        Shutdown { framed: self.framed }
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        // Replies queued while receiving events are sent without waiting for
+        // the next `send_*` call.
+        self.framed.output.extend(self.connection.take_output());
+        if let Poll::Ready(Err(error)) = self.framed.poll_flush(cx) {
+            return Poll::Ready(Err(error))
+        }
+
+        let mut buffer = [0; 16384];
+        let count = try_ready!(self.framed.poll_receive(cx, &mut buffer));
+        Poll::Ready(self.connection.receive(&buffer[..count]))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> futures_core::Stream for Client<S> {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Event>> {
+        let this = &mut *self;
+        loop {
+            if let Some(event) = this.connection.next_event() {
+                return Poll::Ready(Some(event))
+            }
+            if let Some(ref mut event) = this.disconnected {
+                return Poll::Ready(event.take())
+            }
+
+            match this.poll_receive(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(Error::Disconnected)) =>
+                    this.disconnected = Some(Some(Event::Disconnected(None))),
+                Poll::Ready(Err(error)) =>
+                    this.disconnected = Some(Some(Event::Disconnected(Some(error)))),
+                Poll::Pending => return Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Versions of `Client`, `Server` and `Proxy` that run on tokio over any `AsyncRead +
+//! AsyncWrite` transport, without a thread per connection. They drive the same
+//! `ClientConnection`, `ServerConnection` and `ProxyConnection` state machines as
+//! the blocking versions.
+//!
+//! Received events are delivered through `futures_core::Stream`, and every message is sent
+//! by a future that completes once it has been written out. Only the `None` and VNC
+//! authentication security types are supported, since the other ones are implemented
+//! on top of blocking streams; the proxy only relays sessions using `None`, whatever
+//! its `SecurityRegistry` holds.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use ::{Error, Result};
+
+macro_rules! try_ready {
+    ($e:expr) => (match $e {
+        ::std::task::Poll::Ready(Ok(value)) => value,
+        ::std::task::Poll::Ready(Err(error)) =>
+            return ::std::task::Poll::Ready(Err(::std::convert::From::from(error))),
+        ::std::task::Poll::Pending => return ::std::task::Poll::Pending
+    })
+}
+
+mod client;
+mod server;
+mod proxy;
+
+pub use self::client::{Client, Connect};
+pub use self::server::{Server, Accept};
+pub use self::proxy::{Proxy, Handshake, Forward};
+
+/// The transport together with the data to be sent but not written yet. Received data is
+/// passed on to a `ClientConnection`, a `ServerConnection` or a `connection::Buffer`.
+struct Framed<S> {
+    stream: S,
+    output: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
+    fn new(stream: S) -> Framed<S> {
+        Framed { stream: stream, output: Vec::new() }
+    }
+
+    /// Receives some data into `buffer`, and returns how much of it was filled.
+    fn poll_receive(&mut self, cx: &mut Context, buffer: &mut [u8]) -> Poll<Result<usize>> {
+        let mut read_buf = ReadBuf::new(buffer);
+        try_ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf));
+        if read_buf.filled().len() == 0 {
+            return Poll::Ready(Err(Error::Disconnected))
+        }
+        Poll::Ready(Ok(read_buf.filled().len()))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        while self.output.len() > 0 {
+            let written = try_ready!(Pin::new(&mut self.stream).poll_write(cx, &self.output));
+            if written == 0 {
+                return Poll::Ready(Err(Error::Disconnected))
+            }
+            self.output.drain(..written);
+        }
+        try_ready!(Pin::new(&mut self.stream).poll_flush(cx));
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        try_ready!(self.poll_flush(cx));
+        try_ready!(Pin::new(&mut self.stream).poll_shutdown(cx));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A future that completes once the messages queued by a `send_*` method have been written
+/// out. If it is dropped early, they are written out together with the next message.
+#[must_use = "futures do nothing unless polled"]
+pub struct Flush<'a, S: 'a> {
+    framed: &'a mut Framed<S>,
+    error:  Option<Error>,
+}
+
+impl<'a, S> Flush<'a, S> {
+    fn new(framed: &'a mut Framed<S>, result: Result<()>) -> Flush<'a, S> {
+        Flush { framed: framed, error: result.err() }
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> ::std::future::Future for Flush<'a, S> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Err(error))
+        }
+        self.framed.poll_flush(cx)
+    }
+}
+
+/// A future that flushes the queued messages and shuts down the transport.
+#[must_use = "futures do nothing unless polled"]
+pub struct Shutdown<S> {
+    framed: Framed<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ::std::future::Future for Shutdown<S> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.framed.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use std::thread;
+    use futures_core::Stream;
+    use tokio::io::duplex;
+    use tokio::runtime::{Builder, Runtime};
+    use ::{protocol, client, server, Error, SecurityRegistry, NoAuthentication};
+    use super::{Client, Server, Proxy};
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().build().unwrap()
+    }
+
+    fn next<S: Stream + Unpin>(runtime: &Runtime, stream: &mut S) -> Option<S::Item> {
+        runtime.block_on(poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)))
+    }
+
+    /// Checks if the asynchronous client and server can authenticate and exchange events
+    /// over an in-memory transport that delivers data in small chunks.
+    #[test]
+    fn check_if_async_client_and_server_talk() {
+        let (client_stream, server_stream) = duplex(7);
+
+        let server = thread::spawn(move || {
+            let runtime = runtime();
+            let authentication = server::VncAuthentication {
+                password: String::from("secret"),
+                view_only_password: None,
+            };
+            let (mut server, shared) = runtime.block_on(Server::accept(
+                server_stream, 4, 2, ::pixel_format::RGB8888, String::from("test"),
+                Some(authentication))).unwrap();
+            assert!(shared);
+
+            match next(&runtime, &mut server) {
+                Some(Ok(server::Event::KeyEvent { down: true, key: 0x61 })) => (),
+                event => panic!("unexpected event {:?}", event)
+            }
+            match next(&runtime, &mut server) {
+                Some(Ok(server::Event::FramebufferUpdateRequest { .. })) => (),
+                event => panic!("unexpected event {:?}", event)
+            }
+
+            let pixels = (0..32).collect::<Vec<u8>>();
+            let update = {
+                let mut builder = server.create_update();
+                builder.add_raw_pixels(protocol::Rect { left: 0, top: 0, width: 4, height: 2 },
+                                       &pixels);
+                builder.done()
+            };
+            runtime.block_on(server.send_update(&update)).unwrap();
+            runtime.block_on(server.send_cut_text("hello")).unwrap();
+
+            match next(&runtime, &mut server) {
+                None => (),
+                event => panic!("unexpected event {:?}", event)
+            }
+        });
+
+        let runtime = runtime();
+        let mut client = runtime.block_on(Client::connect(client_stream, true, |methods| {
+            assert!(methods.iter().any(|method| match method {
+                &client::AuthMethod::Password => true,
+                _ => false
+            }));
+            Some(client::AuthChoice::Password(*b"secret\0\0"))
+        })).unwrap();
+        assert_eq!(client.name(), "test");
+        assert_eq!(client.size(), (4, 2));
+
+        runtime.block_on(client.send_key_event(true, 0x61)).unwrap();
+        runtime.block_on(client.request_update(
+            protocol::Rect { left: 0, top: 0, width: 4, height: 2 }, false)).unwrap();
+
+        match next(&runtime, &mut client) {
+            Some(client::Event::PutPixels(rect, ref pixels))
+                if rect.width == 4 && pixels[..] == (0..32).collect::<Vec<u8>>()[..] => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        match next(&runtime, &mut client) {
+            Some(client::Event::EndOfFrame) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        match next(&runtime, &mut client) {
+            Some(client::Event::Clipboard(ref text)) if text == "hello" => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+
+        runtime.block_on(client.disconnect()).unwrap();
+        server.join().unwrap();
+    }
+
+    /// Checks if the asynchronous proxy leaves out the security types of its registry that
+    /// would have to be relayed by a handler, and tells the client why it cannot connect.
+    #[test]
+    fn check_if_async_proxy_refuses_relayed_security() {
+        let (client_stream, proxy_client_stream) = duplex(7);
+        let (proxy_server_stream, server_stream) = duplex(7);
+
+        let server = thread::spawn(move || {
+            let authentication = server::VncAuthentication {
+                password: String::from("secret"),
+                view_only_password: None,
+            };
+            runtime().block_on(Server::accept(
+                server_stream, 4, 2, ::pixel_format::RGB8888, String::from("test"),
+                Some(authentication))).map(|_| ())
+        });
+        let proxy = thread::spawn(move || {
+            let mut security = SecurityRegistry::new();
+            security.register(NoAuthentication)
+                    .register(client::VncAuthentication(*b"secret\0\0"));
+            runtime().block_on(Proxy::connect_with_security(
+                proxy_server_stream, proxy_client_stream, &security)).map(|_| ())
+        });
+
+        let runtime = runtime();
+        match runtime.block_on(Client::connect(client_stream, true, |_| None)) {
+            Err(Error::Server(_)) => (),
+            result => panic!("unexpected result {:?}", result.map(|_| ()))
+        }
+        match proxy.join().unwrap() {
+            Err(Error::AuthenticationUnavailable) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        assert!(server.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use ::{protocol, Error, Result};
+use connection::{ProxyConnection, ProxyState};
+use security::{SecurityRegistry, NoAuthentication};
+use super::Framed;
+
+/// A future relaying the handshake between a client and a server; see `Proxy::connect`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Handshake<S, C> {
+    proxy: Option<Proxy<S, C>>,
+    error: Option<Error>,
+}
+
+impl<S, C> Future for Handshake<S, C>
+        where S: AsyncRead + AsyncWrite + Unpin, C: AsyncRead + AsyncWrite + Unpin {
+    type Output = Result<Proxy<S, C>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Proxy<S, C>>> {
+        let this = &mut *self;
+        {
+            let proxy = this.proxy.as_mut().expect("polled after completion");
+            if this.error.is_none() {
+                match proxy.poll_handshake(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    Poll::Ready(Err(error)) => {
+                        proxy.client.output.extend(proxy.connection.take_client_output());
+                        this.error = Some(error);
+                    }
+                    Poll::Pending => return Poll::Pending
+                }
+            }
+            // Pass on the reason of the failure to the client before giving up.
+            if this.error.is_some() {
+                if let Poll::Pending = proxy.client.poll_flush(cx) {
+                    return Poll::Pending
+                }
+            }
+        }
+        let proxy = this.proxy.take().unwrap();
+        match this.error.take() {
+            Some(error) => Poll::Ready(Err(error)),
+            None => Poll::Ready(Ok(proxy))
+        }
+    }
+}
+
+/// The asynchronous counterpart of `proxy::Proxy`, driving the same `ProxyConnection`.
+///
+/// Security handlers relay their handshake over blocking streams, so only sessions without
+/// authentication are relayed: every security type other than `SecurityType::None` is
+/// filtered out of the list offered to the client, whatever the registry passed to
+/// `connect_with_security` holds.
+pub struct Proxy<S, C> {
+    server: Framed<S>,
+    client: Framed<C>,
+    connection: ProxyConnection,
+}
+
+impl<S, C> Proxy<S, C>
+        where S: AsyncRead + AsyncWrite + Unpin, C: AsyncRead + AsyncWrite + Unpin {
+    /// Returns a future relaying the handshake between a server connected over
+    /// `server_stream` and a client connected over `client_stream`.
+    pub fn connect(server_stream: S, client_stream: C) -> Handshake<S, C> {
+        let mut security = SecurityRegistry::new();
+        security.register(NoAuthentication);
+        Proxy::connect_with_security(server_stream, client_stream, &security)
+    }
+
+    /// Like `connect`, but offers the security types of `security` that the server
+    /// offers too and that can be relayed without blocking.
+    pub fn connect_with_security(server_stream: S, client_stream: C,
+                                 security: &SecurityRegistry) -> Handshake<S, C> {
+        let security_types = security.security_types().into_iter().filter(|&security_type| {
+            if security_type == protocol::SecurityType::None {
+                true
+            } else {
+                warn!("security type {:?} cannot be relayed asynchronously", security_type);
+                false
+            }
+        }).collect();
+        Handshake {
+            proxy: Some(Proxy {
+                server: Framed::new(server_stream),
+                client: Framed::new(client_stream),
+                connection: ProxyConnection::new(security_types),
+            }),
+            error: None,
+        }
+    }
+
+    /// Returns a future that forwards messages in both directions until either side
+    /// disconnects.
+    pub fn run(self) -> Forward<S, C> {
+        Forward { proxy: self }
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            self.server.output.extend(self.connection.take_server_output());
+            self.client.output.extend(self.connection.take_client_output());
+            try_ready!(self.server.poll_flush(cx));
+            try_ready!(self.client.poll_flush(cx));
+            let mut buffer = [0; 4096];
+            let result = match self.connection.state() {
+                ProxyState::ReceivingFromServer => {
+                    let count = try_ready!(self.server.poll_receive(cx, &mut buffer));
+                    self.connection.receive_from_server(&buffer[..count])
+                }
+                ProxyState::ReceivingFromClient => {
+                    let count = try_ready!(self.client.poll_receive(cx, &mut buffer));
+                    self.connection.receive_from_client(&buffer[..count])
+                }
+                ProxyState::Relaying(_) =>
+                    Err(Error::AuthenticationUnavailable),
+                ProxyState::Connected =>
+                    return Poll::Ready(Ok(()))
+            };
+            if let Err(error) = result {
+                return Poll::Ready(Err(error))
+            }
+        }
+    }
+
+    fn poll_forward(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            // Writes that cannot complete yet are retried once the transport is ready,
+            // while reading carries on in both directions.
+            self.server.output.extend(self.connection.take_server_output());
+            self.client.output.extend(self.connection.take_client_output());
+            if let Poll::Ready(Err(error)) = self.server.poll_flush(cx) {
+                return Poll::Ready(Err(error))
+            }
+            if let Poll::Ready(Err(error)) = self.client.poll_flush(cx) {
+                return Poll::Ready(Err(error))
+            }
+
+            let mut progress = false;
+            let mut buffer = [0; 16384];
+            let result = match self.client.poll_receive(cx, &mut buffer) {
+                Poll::Ready(Ok(count)) => {
+                    progress = true;
+                    self.connection.receive_from_client(&buffer[..count])
+                }
+                Poll::Ready(Err(error)) => Err(error),
+                Poll::Pending => Ok(())
+            };
+            if let Err(error) = result {
+                return Poll::Ready(Err(error))
+            }
+            let result = match self.server.poll_receive(cx, &mut buffer) {
+                Poll::Ready(Ok(count)) => {
+                    progress = true;
+                    self.connection.receive_from_server(&buffer[..count])
+                }
+                Poll::Ready(Err(error)) => Err(error),
+                Poll::Pending => Ok(())
+            };
+            if let Err(error) = result {
+                return Poll::Ready(Err(error))
+            }
+            if !progress {
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+/// A future forwarding messages between the client and the server; see `Proxy::run`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Forward<S, C> {
+    proxy: Proxy<S, C>,
+}
+
+impl<S, C> Future for Forward<S, C>
+        where S: AsyncRead + AsyncWrite + Unpin, C: AsyncRead + AsyncWrite + Unpin {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        match self.proxy.poll_forward(cx) {
+            Poll::Ready(Err(Error::Disconnected)) => Poll::Ready(Ok(())),
+            result => result
+        }
+    }
+}
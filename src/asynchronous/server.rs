@@ -0,0 +1,201 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures_core;
+use tokio::io::{AsyncRead, AsyncWrite};
+use ::{protocol, Error, Result};
+use connection::{ServerConnection, ServerState};
+use server::{Encoder, Event, FramebufferUpdate, FramebufferUpdateBuilder, VncAuthentication};
+use super::{Framed, Flush, Shutdown};
+
+/// A future performing the server side of the handshake; see `Server::accept`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Accept<S> {
+    framed:         Option<Framed<S>>,
+    connection:     Option<ServerConnection>,
+    authentication: Option<VncAuthentication>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Accept<S> {
+    type Output = Result<(Server<S>, bool)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(Server<S>, bool)>> {
+        try_ready!(self.poll_handshake(cx));
+        let connection = self.connection.take().unwrap();
+        let shared = connection.shared();
+        Poll::Ready(Ok((Server {
+            framed: self.framed.take().unwrap(),
+            connection: connection,
+        }, shared)))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Accept<S> {
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        let framed = self.framed.as_mut().expect("polled after completion");
+        let connection = self.connection.as_mut().unwrap();
+        loop {
+            framed.output.extend(connection.take_output());
+            try_ready!(framed.poll_flush(cx));
+            let result = match connection.state() {
+                ServerState::Handshaking => {
+                    let mut buffer = [0; 4096];
+                    let count = try_ready!(framed.poll_receive(cx, &mut buffer));
+                    connection.receive(&buffer[..count])
+                }
+                ServerState::Authenticating(protocol::SecurityType::VncAuthentication) => {
+                    match self.authentication.take() {
+                        Some(authentication) =>
+                            connection.start_vnc_authentication(authentication),
+                        None => Err(Error::AuthenticationUnavailable)
+                    }
+                }
+                ServerState::Authenticating(_) =>
+                    Err(Error::AuthenticationUnavailable),
+                ServerState::Connected =>
+                    return Poll::Ready(Ok(()))
+            };
+            if let Err(error) = result {
+                // Let the client know why it has been rejected.
+                framed.output.extend(connection.take_output());
+                let _ = framed.poll_flush(cx);
+                return Poll::Ready(Err(error))
+            }
+        }
+    }
+}
+
+/// The asynchronous counterpart of `server::Server`.
+///
+/// Events are received by polling it as a `Stream`, which ends once the client disconnects.
+/// Every other message is sent by a future that completes once it has been written out.
+pub struct Server<S> {
+    framed: Framed<S>,
+    connection: ServerConnection,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Server<S> {
+    /// Returns a future performing the handshake with a client connected over `stream`,
+    /// which resolves to the new `Server` and the `shared` flag sent by the client.
+    ///
+    /// The client is required to authenticate if `authentication` is given, and may connect
+    /// without authentication otherwise.
+    pub fn accept(stream: S,
+                  width: u16,
+                  height: u16,
+                  pixel_format: protocol::PixelFormat,
+                  name: String,
+                  authentication: Option<VncAuthentication>)
+                  -> Accept<S> {
+        let security_type = match authentication {
+            Some(_) => protocol::SecurityType::VncAuthentication,
+            None => protocol::SecurityType::None
+        };
+        Accept {
+            framed: Some(Framed::new(stream)),
+            connection: Some(ServerConnection::new(width, height, pixel_format, name,
+                                                   vec![security_type])),
+            authentication: authentication,
+        }
+    }
+
+    /// Constructs new `FramebufferUpdateBuilder` structure used to build `FramebufferUpdate`
+    /// message.
+    pub fn create_update<'a, 'b>(&'b self) -> FramebufferUpdateBuilder<'a, 'b> {
+        self.connection.create_update()
+    }
+
+    /// Returns `true` if the client has authenticated with a view-only password.
+    pub fn is_view_only(&self) -> bool {
+        self.connection.is_view_only()
+    }
+
+    /// Returns the format in which pixels have to be sent, which the client may change
+    /// with `Event::SetPixelFormat`; `pixel_format::Converter` converts pixels into it.
+    pub fn pixel_format(&self) -> protocol::PixelFormat {
+        self.connection.pixel_format()
+    }
+
+    /// Returns the encodings last requested by the client with `Event::SetEncodings`.
+    pub fn encodings(&self) -> &[protocol::Encoding] {
+        self.connection.encodings()
+    }
+
+    /// Adds `encoder` to the encoders pixel data can be sent with; see
+    /// `ServerConnection::register_encoder`.
+    pub fn register_encoder<E: Encoder + 'static>(&mut self, encoder: E) {
+        self.connection.register_encoder(encoder)
+    }
+
+    fn flush(&mut self, result: Result<()>) -> Flush<'_, S> {
+        self.framed.output.extend(self.connection.take_output());
+        Flush::new(&mut self.framed, result)
+    }
+
+    /// Sends `FramebufferUpdate` message.
+    pub fn send_update(&mut self, updates: &FramebufferUpdate) -> Flush<'_, S> {
+        let result = self.connection.send_update(updates);
+        self.flush(result)
+    }
+
+    /// Sends `Fence` message.
+    ///
+    /// Returns `Error::Unexpected` if payload is longer than 64 bytes.
+    pub fn send_fence(&mut self, flags: u32, payload: &[u8]) -> Flush<'_, S> {
+        let result = self.connection.send_fence(flags, payload);
+        self.flush(result)
+    }
+
+    /// Sends `ServerCutText` message.
+    ///
+    /// The text must only contain characters from the Latin-1 character set.
+    pub fn send_cut_text(&mut self, text: &str) -> Flush<'_, S> {
+        let result = self.connection.send_cut_text(text);
+        self.flush(result)
+    }
+
+    /// Sends `ServerCutText` message carrying an Extended Clipboard message.
+    pub fn send_extended_clipboard(&mut self, clipboard: protocol::ExtendedClipboard)
+            -> Flush<'_, S> {
+        let result = self.connection.send_extended_clipboard(clipboard);
+        self.flush(result)
+    }
+
+    /// Sends `EndOfContinuousUpdates` message.
+    pub fn send_end_of_continuous_updates(&mut self) -> Flush<'_, S> {
+        let result = self.connection.send_end_of_continuous_updates();
+        self.flush(result)
+    }
+
+    /// Flushes the queued messages and shuts down the transport.
+    pub fn disconnect(self) -> Shutdown<S> {
+        Shutdown { framed: self.framed }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> futures_core::Stream for Server<S> {
+    type Item = Result<Event>;
+
+    /// Keyboard, pointer, clipboard and desktop size events sent by view-only clients are
+    /// discarded.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Event>>> {
+        let this = &mut *self;
+        loop {
+            if let Some(event) = this.connection.next_event() {
+                return Poll::Ready(Some(Ok(event)))
+            }
+
+            let mut buffer = [0; 4096];
+            let result = match this.framed.poll_receive(cx, &mut buffer) {
+                Poll::Ready(Ok(count)) => this.connection.receive(&buffer[..count]),
+                Poll::Ready(Err(error)) => Err(error),
+                Poll::Pending => return Poll::Pending
+            };
+            match result {
+                Ok(()) => (),
+                Err(Error::Disconnected) => return Poll::Ready(None),
+                Err(error) => return Poll::Ready(Some(Err(error)))
+            }
+        }
+    }
+}
@@ -1,23 +1,35 @@
+use std::cmp;
 use std::io::{Read, Write};
-use std::net::{TcpStream, Shutdown};
+use std::net::{TcpListener, TcpStream, Shutdown};
 use std::thread;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 use byteorder::{BigEndian, ReadBytesExt};
-use ::{zrle, protocol, Colour, Error, Result};
+use rustls;
+use ::{zrle, hextile, rre, tight, protocol, fence, pixel_format, repeater, stream, Colour,
+       Error, Result};
 use protocol::Message;
-use security::des;
+use stream::Stream;
+use transport::Transport;
+use connection::{self, ClientConnection, ClientState};
+
+use security::{des, des_key, rsa_aes, SecurityHandler, SecurityRegistry, NoAuthentication};
 #[cfg(feature = "apple-auth")]
 use security::apple_auth;
 
+pub use framebuffer::{Framebuffer, FramebufferSink};
+
 #[derive(Debug)]
 pub enum AuthMethod {
     None,
     Password,
     AppleRemoteDesktop,
-    /* more to come */
-    #[doc(hidden)]
-    __Nonexhaustive,
+    VeNCrypt,
+    RsaAes(protocol::RsaAesVariant),
+    /// A security type without a built-in implementation, which can be chosen
+    /// with `AuthChoice::Custom`.
+    Other(protocol::SecurityType),
 }
 
 #[derive(Debug)]
@@ -25,27 +37,336 @@ pub enum AuthChoice {
     None,
     Password([u8; 8]),
     AppleRemoteDesktop(String, String),
-    /* more to come */
-    #[doc(hidden)]
-    __Nonexhaustive,
+    VeNCrypt(VeNCryptOptions),
+    RsaAes(protocol::RsaAesVariant, RsaAesOptions),
+    Custom(Box<SecurityHandler>),
+}
+
+impl AuthChoice {
+    fn into_handler(self) -> Box<SecurityHandler> {
+        match self {
+            AuthChoice::None => Box::new(NoAuthentication),
+            AuthChoice::Password(password) => Box::new(VncAuthentication(password)),
+            AuthChoice::AppleRemoteDesktop(username, password) =>
+                Box::new(AppleRemoteDesktop(username, password)),
+            AuthChoice::VeNCrypt(options) => Box::new(options),
+            AuthChoice::RsaAes(variant, options) => Box::new(RsaAes(variant, options)),
+            AuthChoice::Custom(handler) => handler,
+        }
+    }
+}
+
+// The client side of the built-in security types, for use with `SecurityRegistry`;
+// `NoAuthentication` and `VeNCryptOptions` are handlers too.
+#[derive(Debug)]
+pub struct VncAuthentication(pub [u8; 8]);
+
+#[derive(Debug)]
+pub struct AppleRemoteDesktop(pub String, pub String);
+
+#[derive(Debug)]
+pub struct RsaAes(pub protocol::RsaAesVariant, pub RsaAesOptions);
+
+// The server only tells which VeNCrypt sub-types it offers after VeNCrypt has been chosen,
+// so the first sub-type from `subtypes` that the server also offers is used.
+// `username` is only sent by the `*Plain` sub-types, and `password` by those and
+// the `*Vnc` ones, which use its first 8 bytes.
+// `tls_config` is required by the `X509*` sub-types to verify the server certificate against
+// `server_name`; if the `Tls*` sub-types are used without it, any certificate is accepted.
+#[derive(Debug)]
+pub struct VeNCryptOptions {
+    pub subtypes:    Vec<protocol::VeNCryptSubtype>,
+    pub username:    String,
+    pub password:    String,
+    pub tls_config:  Option<Arc<rustls::ClientConfig>>,
+    pub server_name: String,
+}
+
+// `username` is only sent if the server asks for it.
+// `server_key_fingerprint` is the SHA-256 hash of the RSA key the server is expected to present;
+// if it is not set, any key is accepted and the session is not protected against
+// man-in-the-middle attacks. When the server presents a different key, the error contains
+// its fingerprint, so that it can be trusted on first use.
+#[derive(Debug)]
+pub struct RsaAesOptions {
+    pub username: String,
+    pub password: String,
+    pub server_key_fingerprint: Option<Vec<u8>>,
+}
+
+fn vnc_authenticate(stream: &mut Stream, password: [u8; 8]) -> Result<()> {
+    let mut challenge = [0; 16];
+    try!(stream.read_exact(&mut challenge));
+    let response = des(&challenge, &des_key(&password));
+    try!(stream.write_all(&response));
+    Ok(())
+}
+
+fn vencrypt_authenticate(stream: Stream, options: &VeNCryptOptions) -> Result<Stream> {
+    let mut stream = stream;
+
+    let version = try!(protocol::VeNCryptVersion::read_from(&mut stream));
+    debug!("<- {:?}", version);
+    if (version.major, version.minor) < (0, 2) {
+        return Err(Error::Unexpected("VeNCrypt version"))
+    }
+    let version = protocol::VeNCryptVersion { major: 0, minor: 2 };
+    debug!("-> {:?}", version);
+    try!(protocol::VeNCryptVersion::write_to(&version, &mut stream));
+    if try!(stream.read_u8()) != 0 {
+        return Err(Error::Unexpected("VeNCrypt version"))
+    }
+
+    let subtypes = try!(protocol::VeNCryptSubtypes::read_from(&mut stream));
+    debug!("<- {:?}", subtypes);
+    let subtype = try!(options.subtypes.iter().cloned()
+                           .find(|subtype| subtypes.0.contains(subtype))
+                           .ok_or(Error::AuthenticationUnavailable));
+    debug!("-> VeNCryptSubtype::{:?}", subtype);
+    try!(protocol::VeNCryptSubtype::write_to(&subtype, &mut stream));
+
+    if subtype.uses_tls() {
+        if try!(stream.read_u8()) != 1 {
+            return Err(Error::Server(String::from("TLS session refused")))
+        }
+        let config = match options.tls_config {
+            Some(ref config) => config.clone(),
+            None if !subtype.uses_x509() => stream::anonymous_client_config(),
+            None => return Err(Error::AuthenticationUnavailable)
+        };
+        stream = try!(stream.start_tls_client(config, &options.server_name));
+    }
+
+    match subtype {
+        protocol::VeNCryptSubtype::TlsNone |
+        protocol::VeNCryptSubtype::X509None => (),
+        protocol::VeNCryptSubtype::TlsVnc |
+        protocol::VeNCryptSubtype::X509Vnc => {
+            let mut key = [0; 8];
+            for (i, byte) in options.password.bytes().take(8).enumerate() {
+                key[i] = byte
+            }
+            try!(vnc_authenticate(&mut stream, key));
+        }
+        protocol::VeNCryptSubtype::Plain |
+        protocol::VeNCryptSubtype::TlsPlain |
+        protocol::VeNCryptSubtype::X509Plain => {
+            let credentials = protocol::PlainCredentials {
+                username: options.username.clone(),
+                password: options.password.clone()
+            };
+            try!(protocol::PlainCredentials::write_to(&credentials, &mut stream));
+        }
+        protocol::VeNCryptSubtype::Unknown(_) =>
+            return Err(Error::AuthenticationUnavailable)
+    }
+
+    Ok(stream)
+}
+
+// Longest key the client generates for RSA-AES, however long the server key is. The session
+// keys are derived from randoms encrypted with the server key and with the client key, so an
+// eavesdropper has to break both; generating an 8192-bit key, on the other hand, can take
+// minutes.
+const RSA_AES_CLIENT_KEY_MAX_BITS: usize = 2048;
+
+fn rsa_aes_authenticate(stream: Stream, variant: protocol::RsaAesVariant,
+                        options: &RsaAesOptions) -> Result<Stream> {
+    let mut stream = stream;
+
+    let server_key = try!(protocol::RsaAesPublicKey::read_from(&mut stream));
+    debug!("<- {:?}", server_key);
+    let server_key_data = try!(rsa_aes::to_bytes(&server_key));
+    let fingerprint = rsa_aes::fingerprint(&server_key_data);
+    match options.server_key_fingerprint {
+        Some(ref trusted) if *trusted != fingerprint => {
+            let fingerprint = fingerprint.iter().map(|byte| format!("{:02x}", byte))
+                                         .collect::<Vec<_>>().join(":");
+            return Err(Error::AuthenticationFailure(
+                format!("untrusted server key with fingerprint {}", fingerprint)))
+        }
+        _ => ()
+    }
+    let key_bits = cmp::min(server_key.modulus.len() * 8, RSA_AES_CLIENT_KEY_MAX_BITS);
+    let server_key = try!(rsa_aes::from_message(&server_key));
+
+    let client_private_key = try!(rsa_aes::generate_key(key_bits));
+    let client_key = rsa_aes::to_message(&client_private_key.to_public_key());
+    let client_key_data = try!(rsa_aes::to_bytes(&client_key));
+    debug!("-> {:?}", client_key);
+    try!(protocol::RsaAesPublicKey::write_to(&client_key, &mut stream));
+
+    let server_random = try!(protocol::RsaAesRandom::read_from(&mut stream));
+    let server_random = try!(rsa_aes::decrypt(&client_private_key, &server_random.0));
+    if server_random.len() != variant.key_size() {
+        return Err(Error::Unexpected("RSA-AES random length"))
+    }
+    let client_random = rsa_aes::random(variant.key_size());
+    let encrypted_random = try!(rsa_aes::encrypt(&server_key, &client_random));
+    try!(protocol::RsaAesRandom(encrypted_random).write_to(&mut stream));
+
+    let (client_session_key, server_session_key) =
+        rsa_aes::session_keys(variant, &client_random, &server_random);
+    stream = try!(stream.start_eax(&server_session_key, &client_session_key));
+
+    // Prove to each other that both peers have seen the same keys.
+    try!(stream.write_all(&rsa_aes::hash(variant, &[&client_key_data, &server_key_data])));
+    let expected_hash = rsa_aes::hash(variant, &[&server_key_data, &client_key_data]);
+    let mut hash = vec![0; expected_hash.len()];
+    try!(stream.read_exact(&mut hash));
+    if hash != expected_hash {
+        return Err(Error::AuthenticationFailure(String::from("RSA-AES key hash mismatch")))
+    }
+
+    let subtype = try!(protocol::RsaAesSubtype::read_from(&mut stream));
+    debug!("<- RsaAesSubtype::{:?}", subtype);
+    let credentials = protocol::RsaAesCredentials {
+        username: match subtype {
+            protocol::RsaAesSubtype::UsernameAndPassword => options.username.clone(),
+            protocol::RsaAesSubtype::Password => String::new()
+        },
+        password: options.password.clone()
+    };
+    try!(protocol::RsaAesCredentials::write_to(&credentials, &mut stream));
+
+    if !variant.encrypts_session() {
+        stream = try!(stream.stop_eax());
+    }
+    Ok(stream)
+}
+
+impl SecurityHandler for VncAuthentication {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::VncAuthentication
+    }
+
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        let mut stream = stream;
+        try!(vnc_authenticate(&mut stream, self.0));
+        Ok(stream)
+    }
+}
+
+impl SecurityHandler for AppleRemoteDesktop {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::AppleRemoteDesktop
+    }
+
+    #[cfg(feature = "apple-auth")]
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        let mut stream = stream;
+        let handshake = try!(protocol::AppleAuthHandshake::read_from(&mut stream));
+        let response = apple_auth(&self.0, &self.1, &handshake);
+        try!(response.write_to(&mut stream));
+        Ok(stream)
+    }
+}
+
+impl SecurityHandler for VeNCryptOptions {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::VeNCrypt
+    }
+
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        // Everything past the VeNCrypt handshake, including the security result,
+        // is sent over TLS if the sub-type uses it.
+        vencrypt_authenticate(stream, self)
+    }
+}
+
+impl SecurityHandler for RsaAes {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::RsaAes(self.0)
+    }
+
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        rsa_aes_authenticate(stream, self.0, &self.1)
+    }
 }
 
 #[derive(Debug)]
 pub enum Event {
     Disconnected(Option<Error>),
     Resize(u16, u16),
+    DesktopLayout {
+        reason:  protocol::ResizeReason,
+        status:  protocol::ResizeStatus,
+        screens: Vec<protocol::Screen>
+    },
     SetColourMap { first_colour: u16, colours: Vec<Colour> },
+    // The pixels in the events following this one are in the format requested
+    // by `set_format`.
+    SetFormat(protocol::PixelFormat),
     PutPixels(protocol::Rect, Vec<u8>),
+    FillPixels(protocol::Rect, Vec<u8>),
     CopyPixels { src: protocol::Rect, dst: protocol::Rect },
     EndOfFrame,
     SetCursor { size: (u16, u16), hotspot: (u16, u16), pixels: Vec<u8>, mask_bits: Vec<u8> },
     Clipboard(String),
+    ExtendedClipboard(protocol::ExtendedClipboard),
     Bell,
+    Fence { flags: u32, payload: Vec<u8> },
+    EndOfContinuousUpdates,
+}
+
+// Payload tag of the fences used to switch pixel formats; the pixel format itself follows it.
+pub(crate) const FORMAT_FENCE_TAG: &'static [u8] = b"rust-vnc:pf";
+
+// The decoder state carried from one rectangle to the next.
+pub(crate) struct Decoders {
+    zrle:   zrle::Decoder,
+    tight:  tight::Decoder,
+    // Reused for Raw and ZRLE rectangles, so that large updates do not allocate every time.
+    pixels: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl Decoders {
+    pub(crate) fn new() -> Decoders {
+        Decoders {
+            zrle:   zrle::Decoder::new(),
+            tight:  tight::Decoder::new(),
+            pixels: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+}
+
+// Turns the contents of framebuffer updates back into events, for clients without a sink.
+// Resizes are reported by `read_rectangle` itself.
+pub(crate) struct EventSink<F: FnMut(Event)>(pub F);
+
+impl<F: FnMut(Event)> FramebufferSink for EventSink<F> {
+    fn resize(&mut self, _width: u16, _height: u16) {}
+
+    fn put_pixels(&mut self, rect: protocol::Rect, pixels: &[u8]) {
+        (self.0)(Event::PutPixels(rect, pixels.to_vec()))
+    }
+
+    fn fill_pixels(&mut self, rect: protocol::Rect, pixel: &[u8]) {
+        (self.0)(Event::FillPixels(rect, pixel.to_vec()))
+    }
+
+    fn copy_pixels(&mut self, src: protocol::Rect, dst: protocol::Rect) {
+        (self.0)(Event::CopyPixels { src: src, dst: dst })
+    }
+
+    fn set_cursor(&mut self, size: (u16, u16), hotspot: (u16, u16),
+                  pixels: &[u8], mask_bits: &[u8]) {
+        (self.0)(Event::SetCursor {
+            size:      size,
+            hotspot:   hotspot,
+            pixels:    pixels.to_vec(),
+            mask_bits: mask_bits.to_vec()
+        })
+    }
 }
 
+type SharedSink = Arc<Mutex<Option<Box<FramebufferSink + Send>>>>;
+
 impl Event {
-    fn pump(mut stream: TcpStream, format: Arc<Mutex<protocol::PixelFormat>>,
-            tx_events: &mut Sender<Event>) -> Result<()> {
+    fn pump(mut stream: Stream, shared_format: Arc<Mutex<protocol::PixelFormat>>,
+            shared_sink: SharedSink, tx_events: &mut Sender<Event>) -> Result<()> {
         macro_rules! send {
             ($chan:expr, $data:expr) => ({
                 match $chan.send($data) {
@@ -55,7 +376,9 @@ impl Event {
             })
         }
 
-        let mut zrle_decoder = zrle::Decoder::new();
+        let mut decoders = Decoders::new();
+        let mut events = Vec::new();
+        let mut last_format = *shared_format.lock().unwrap();
         loop {
             let packet =
                 match protocol::S2C::read_from(&mut stream) {
@@ -68,131 +391,254 @@ impl Event {
                 };
             debug!("<- {:?}", packet);
 
-            let format = *format.lock().unwrap();
             match packet {
-                protocol::S2C::SetColourMapEntries { first_colour, colours } => {
-                    send!(tx_events, Event::SetColourMap {
-                        first_colour: first_colour, colours: colours
-                    })
-                },
                 protocol::S2C::FramebufferUpdate { count } => {
+                    let format = *shared_format.lock().unwrap();
+                    if format != last_format {
+                        // `Client::set_format` has switched formats without a fence.
+                        last_format = format;
+                        if let Some(ref mut sink) = *shared_sink.lock().unwrap() {
+                            sink.set_format(format)
+                        }
+                        send!(tx_events, Event::SetFormat(format));
+                    }
                     for _ in 0..count {
                         let rectangle = try!(protocol::RectangleHeader::read_from(&mut stream));
                         debug!("<- {:?}", rectangle);
 
-                        let dst = protocol::Rect::new(rectangle.x_position,
-                                                      rectangle.y_position,
-                                                      rectangle.width,
-                                                      rectangle.height);
-                        match rectangle.encoding {
-                            protocol::Encoding::Raw => {
-                                let length = (rectangle.width as usize) *
-                                             (rectangle.height as usize) *
-                                             (format.bits_per_pixel as usize / 8);
-                                let mut pixels = Vec::with_capacity(length);
-                                unsafe { pixels.set_len(length as usize) }
-                                try!(stream.read_exact(&mut pixels));
-                                debug!("<- ...pixels");
-                                send!(tx_events, Event::PutPixels(dst, pixels))
-                            },
-                            protocol::Encoding::CopyRect => {
-                                let copy_rect = try!(protocol::CopyRect::read_from(&mut stream));
-                                let src = protocol::Rect::new(copy_rect.src_x_position,
-                                                              copy_rect.src_y_position,
-                                                              rectangle.width,
-                                                              rectangle.height);
-                                send!(tx_events, Event::CopyPixels { src: src, dst: dst })
-                            },
-                            protocol::Encoding::Zrle => {
-                                let length = try!(stream.read_u32::<BigEndian>());
-                                let mut data = Vec::with_capacity(length as usize);
-                                unsafe { data.set_len(length as usize) }
-                                try!(stream.read_exact(&mut data));
-                                debug!("<- ...compressed pixels");
-                                let result = try!(zrle_decoder.decode(format, dst, &data,
-                                    |tile, pixels| {
-                                        Ok(tx_events.send(Event::PutPixels(tile, pixels)).is_ok())
-                                    }));
-                                if !result { break }
-                            }
-                            protocol::Encoding::Cursor => {
-                                let mut pixels    = vec![0; (rectangle.width as usize) *
-                                                            (rectangle.height as usize) *
-                                                            (format.bits_per_pixel as usize / 8)];
-                                try!(stream.read_exact(&mut pixels));
-                                let mut mask_bits = vec![0; ((rectangle.width as usize + 7) / 8) *
-                                                            (rectangle.height as usize)];
-                                try!(stream.read_exact(&mut mask_bits));
-                                send!(tx_events, Event::SetCursor {
-                                    size:      (rectangle.width, rectangle.height),
-                                    hotspot:   (rectangle.x_position, rectangle.y_position),
-                                    pixels:    pixels,
-                                    mask_bits: mask_bits
-                                })
-                            },
-                            protocol::Encoding::DesktopSize => {
-                                send!(tx_events,
-                                    Event::Resize(rectangle.width, rectangle.height))
-                            }
-                            _ => return Err(Error::Unexpected("encoding"))
-                        };
+                        {
+                            // Only hold on to the sink while reading a single rectangle,
+                            // so that `set_sink` and `set_format` never wait for a whole
+                            // update.
+                            let mut sink = shared_sink.lock().unwrap();
+                            let mut event_sink =
+                                EventSink(|event| { let _ = tx_events.send(event); });
+                            let sink: &mut FramebufferSink = match *sink {
+                                Some(ref mut sink) => &mut **sink,
+                                None => &mut event_sink
+                            };
+                            try!(Event::read_rectangle(&mut stream, &rectangle, format,
+                                                       &mut decoders, sink, &mut events));
+                        }
+                        for event in events.drain(..) {
+                            let _ = tx_events.send(event);
+                        }
                     }
 
                     send!(tx_events, Event::EndOfFrame);
                 },
-                protocol::S2C::Bell =>
-                    send!(tx_events, Event::Bell),
-                protocol::S2C::CutText(text) =>
-                    send!(tx_events, Event::Clipboard(text))
+                packet => {
+                    let mut format = shared_format.lock().unwrap();
+                    let event = try!(Event::from_message(packet, &mut format));
+                    if let Some(ref mut sink) = *shared_sink.lock().unwrap() {
+                        match event {
+                            Some(Event::SetFormat(format)) =>
+                                sink.set_format(format),
+                            Some(Event::SetColourMap { first_colour, ref colours }) =>
+                                sink.set_colour_map(first_colour, colours),
+                            _ => ()
+                        }
+                    }
+                    match event {
+                        Some(Event::SetFormat(format)) => {
+                            last_format = format;
+                            send!(tx_events, Event::SetFormat(format))
+                        }
+                        Some(event) => send!(tx_events, event),
+                        None => ()
+                    }
+                }
             }
         }
 
         Ok(())
     }
+
+    // Reads the pixel data of `rectangle` and writes it to `sink`. Pseudo-rectangles that
+    // resize the framebuffer or change its layout are also appended to `events`.
+    // Every byte of the rectangle is read before any decoder state changes, so that
+    // reading can be retried if it runs out of data.
+    pub(crate) fn read_rectangle<R, S>(reader: &mut R, rectangle: &protocol::RectangleHeader,
+                                       format: protocol::PixelFormat,
+                                       decoders: &mut Decoders,
+                                       sink: &mut S, events: &mut Vec<Event>) -> Result<()>
+            where R: Read, S: FramebufferSink + ?Sized {
+        let dst = protocol::Rect::new(rectangle.x_position,
+                                      rectangle.y_position,
+                                      rectangle.width,
+                                      rectangle.height);
+        match rectangle.encoding {
+            protocol::Encoding::Raw => {
+                let length = (rectangle.width as usize) *
+                             (rectangle.height as usize) *
+                             (format.bits_per_pixel as usize / 8);
+                let pixels = &mut decoders.pixels;
+                pixels.resize(length, 0);
+                try!(reader.read_exact(pixels));
+                debug!("<- ...pixels");
+                sink.put_pixels(dst, pixels);
+                Ok(())
+            },
+            protocol::Encoding::CopyRect => {
+                let copy_rect = try!(protocol::CopyRect::read_from(reader));
+                let src = protocol::Rect::new(copy_rect.src_x_position,
+                                              copy_rect.src_y_position,
+                                              rectangle.width,
+                                              rectangle.height);
+                sink.copy_pixels(src, dst);
+                Ok(())
+            },
+            protocol::Encoding::Zrle => {
+                let length = try!(reader.read_u32::<BigEndian>());
+                let data = &mut decoders.compressed;
+                data.resize(length as usize, 0);
+                try!(reader.read_exact(data));
+                debug!("<- ...compressed pixels");
+                try!(decoders.zrle.decode(format, dst, data, |tile, pixels| {
+                    sink.put_pixels(tile, pixels);
+                    Ok(true)
+                }));
+                Ok(())
+            }
+            protocol::Encoding::Rre |
+            protocol::Encoding::CoRre => {
+                let compact = rectangle.encoding == protocol::Encoding::CoRre;
+                try!(rre::decode(reader, format, dst, compact, |area, pixel| {
+                    sink.fill_pixels(area, pixel);
+                    Ok(true)
+                }));
+                debug!("<- ...subrectangles");
+                Ok(())
+            }
+            protocol::Encoding::Hextile => {
+                try!(hextile::decode(reader, format, dst, |tile, pixels| {
+                    sink.put_pixels(tile, pixels);
+                    Ok(true)
+                }));
+                debug!("<- ...hextile pixels");
+                Ok(())
+            }
+            protocol::Encoding::Tight => {
+                let rectangle = try!(decoders.tight.decode(reader, format, dst));
+                debug!("<- ...tight pixels");
+                match rectangle {
+                    tight::Rectangle::Fill(pixel) => sink.fill_pixels(dst, &pixel),
+                    tight::Rectangle::Pixels(pixels) => sink.put_pixels(dst, &pixels)
+                }
+                Ok(())
+            }
+            protocol::Encoding::Cursor => {
+                let mut pixels    = vec![0; (rectangle.width as usize) *
+                                            (rectangle.height as usize) *
+                                            (format.bits_per_pixel as usize / 8)];
+                try!(reader.read_exact(&mut pixels));
+                let mut mask_bits = vec![0; ((rectangle.width as usize + 7) / 8) *
+                                            (rectangle.height as usize)];
+                try!(reader.read_exact(&mut mask_bits));
+                sink.set_cursor((rectangle.width, rectangle.height),
+                                (rectangle.x_position, rectangle.y_position),
+                                &pixels, &mask_bits);
+                Ok(())
+            },
+            protocol::Encoding::DesktopSize => {
+                sink.resize(rectangle.width, rectangle.height);
+                events.push(Event::Resize(rectangle.width, rectangle.height));
+                Ok(())
+            }
+            protocol::Encoding::ExtendedDesktopSize => {
+                let layout = try!(protocol::ScreenLayout::read_from(reader));
+                debug!("<- {:?}", layout);
+                let reason = protocol::ResizeReason::from(rectangle.x_position);
+                let status = protocol::ResizeStatus::from(rectangle.y_position);
+                if status == protocol::ResizeStatus::Succeeded {
+                    sink.resize(rectangle.width, rectangle.height);
+                    events.push(Event::Resize(rectangle.width, rectangle.height));
+                }
+                events.push(Event::DesktopLayout {
+                    reason:  reason,
+                    status:  status,
+                    screens: layout.0
+                });
+                Ok(())
+            }
+            _ => Err(Error::Unexpected("encoding"))
+        }
+    }
+
+    // Turns a message other than `FramebufferUpdate` into an event. The response to
+    // the fence sent by `Client::set_format` also changes `format`, and turns into
+    // `SetFormat`.
+    pub(crate) fn from_message(packet: protocol::S2C, format: &mut protocol::PixelFormat)
+            -> Result<Option<Event>> {
+        match packet {
+            protocol::S2C::SetColourMapEntries { first_colour, colours } =>
+                Ok(Some(Event::SetColourMap { first_colour: first_colour, colours: colours })),
+            protocol::S2C::FramebufferUpdate { .. } =>
+                Err(Error::Unexpected("framebuffer update")),
+            protocol::S2C::Bell =>
+                Ok(Some(Event::Bell)),
+            protocol::S2C::CutText(text) =>
+                Ok(Some(Event::Clipboard(text))),
+            protocol::S2C::ExtendedCutText(clipboard) =>
+                Ok(Some(Event::ExtendedClipboard(clipboard))),
+            protocol::S2C::EndOfContinuousUpdates =>
+                Ok(Some(Event::EndOfContinuousUpdates)),
+            protocol::S2C::Fence(protocol::Fence { flags, payload }) => {
+                if flags & fence::REQUEST == 0 && payload.starts_with(FORMAT_FENCE_TAG) {
+                    // This is the response to the fence sent by `Client::set_format`;
+                    // everything after it is in the new pixel format.
+                    let mut format_data = &payload[FORMAT_FENCE_TAG.len()..];
+                    let new_format = try!(protocol::PixelFormat::read_from(&mut format_data));
+                    try!(pixel_format::validate(&new_format));
+                    *format = new_format;
+                    Ok(Some(Event::SetFormat(*format)))
+                } else {
+                    Ok(Some(Event::Fence { flags: flags, payload: payload }))
+                }
+            }
+        }
+    }
 }
 
 pub struct Client {
-    stream:  TcpStream,
+    stream:  Stream,
     events:  Receiver<Event>,
-    name:    String,
-    size:    (u16, u16),
-    format:  Arc<Mutex<protocol::PixelFormat>>
+    // Keeps track of what the server supports as events are handed out, and queues
+    // the messages sent to it; events are decoded by the event thread.
+    connection: ClientConnection,
+    format:  Arc<Mutex<protocol::PixelFormat>>,
+    sink:    SharedSink,
+    // Events taken in by `set_format` before the caller has polled them.
+    pending: VecDeque<Event>,
 }
 
+// Port on which viewers conventionally listen for reverse connections.
+pub const LISTEN_PORT: u16 = 5500;
+
 impl Client {
-    pub fn from_tcp_stream<Auth>(mut stream: TcpStream, shared: bool,
+    pub fn from_tcp_stream<Auth>(stream: TcpStream, shared: bool,
                                  auth: Auth) -> Result<Client>
             where Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
-        let version = try!(protocol::Version::read_from(&mut stream));
-        debug!("<- Version::{:?}", version);
-        debug!("-> Version::{:?}", version);
-        try!(protocol::Version::write_to(&version, &mut stream));
-
-        let security_types = match version {
-            protocol::Version::Rfb33 => {
-                let security_type = try!(protocol::SecurityType::read_from(&mut stream));
-                debug!("<- SecurityType::{:?}", security_type);
-                if security_type == protocol::SecurityType::Invalid {
-                    vec![]
-                } else {
-                    vec![security_type]
-                }
-            },
-            _ => {
-                let security_types = try!(protocol::SecurityTypes::read_from(&mut stream));
-                debug!("<- {:?}", security_types);
-                security_types.0
-            }
-        };
+        Client::from_transport(stream, shared, auth)
+    }
 
-        if security_types.len() == 0 {
-            let reason = try!(String::read_from(&mut stream));
-            debug!("<- {:?}", reason);
-            return Err(Error::Server(reason))
-        }
+    // Waits for a server to make a reverse connection to `listener`, as servers behind NAT
+    // do when told to (e.g. with `vncconnect`), and performs the usual handshake with it.
+    // Other servers can still be accepted on `listener` afterwards.
+    pub fn listen<Auth>(listener: &TcpListener, shared: bool, auth: Auth) -> Result<Client>
+            where Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+        let (stream, address) = try!(listener.accept());
+        debug!("accepted reverse connection from {}", address);
+        Client::from_tcp_stream(stream, shared, auth)
+    }
+
+    // Connects over any transport, such as a Unix domain socket or a `Split` pair of pipes.
+    pub fn from_transport<T, Auth>(transport: T, shared: bool, auth: Auth) -> Result<Client>
+            where T: Transport + 'static, Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+        let (stream, connection) = try!(Client::start_handshake(Box::new(transport), shared));
 
         let mut auth_methods = Vec::new();
-        for security_type in security_types {
+        for &security_type in connection.security_types() {
             match security_type {
                 protocol::SecurityType::None =>
                     auth_methods.push(AuthMethod::None),
@@ -200,99 +646,78 @@ impl Client {
                     auth_methods.push(AuthMethod::Password),
                 protocol::SecurityType::AppleRemoteDesktop =>
                     auth_methods.push(AuthMethod::AppleRemoteDesktop),
-                _ => ()
+                protocol::SecurityType::VeNCrypt =>
+                    auth_methods.push(AuthMethod::VeNCrypt),
+                protocol::SecurityType::RsaAes(variant) =>
+                    auth_methods.push(AuthMethod::RsaAes(variant)),
+                security_type =>
+                    auth_methods.push(AuthMethod::Other(security_type))
             }
         }
 
         let auth_choice = try!(auth(&auth_methods).ok_or(Error::AuthenticationUnavailable));
+        let handler = auth_choice.into_handler();
+        Client::finish_handshake(stream, connection, &*handler)
+    }
 
-        match version {
-            protocol::Version::Rfb33 => (),
-            _ => {
-                let used_security_type = match auth_choice {
-                    AuthChoice::None => protocol::SecurityType::None,
-                    AuthChoice::Password(_) => protocol::SecurityType::VncAuthentication,
-                    AuthChoice::AppleRemoteDesktop(_, _) => protocol::SecurityType::AppleRemoteDesktop,
-                    AuthChoice::__Nonexhaustive => unreachable!()
-                };
-                debug!("-> SecurityType::{:?}", used_security_type);
-                try!(protocol::SecurityType::write_to(&used_security_type, &mut stream));
-            }
-        }
-
-        match auth_choice {
-            AuthChoice::Password(mut password) => {
-                // Reverse the bits in every byte of password.
-                // DES is 56-bit and as commonly implemented, it takes a 8-octet key
-                // and ignores LSB of every octet; this of course would be bad for
-                // ASCII passwords.
-                //
-                // I've spent *hours* figuring this out.
-                // I hate every single fucker involved in the chain of decisions that
-                // led to this authentication scheme, and doubly so because it is completely
-                // undocumented in what passes for the specification of the RFB protocol.
-                for i in 0..8 {
-                    let c = password[i];
-                    let mut cs = 0u8;
-                    for j in 0..8 { cs |= ((c >> j) & 1) << (7 - j) }
-                    password[i] = cs;
-                }
+    // Uses the first handler in `security`, in order of preference, whose security type
+    // the server offers.
+    pub fn from_tcp_stream_with_security(stream: TcpStream, shared: bool,
+                                         security: &SecurityRegistry) -> Result<Client> {
+        Client::from_transport_with_security(stream, shared, security)
+    }
 
-                let mut challenge = [0; 16];
-                try!(stream.read_exact(&mut challenge));
-                let response = des(&challenge, &password);
-                try!(stream.write(&response));
-            },
-            #[cfg(feature = "apple-auth")]
-            AuthChoice::AppleRemoteDesktop(ref username, ref password) => {
-                let handshake = try!(protocol::AppleAuthHandshake::read_from(&mut stream));
-                let response = apple_auth(username, password, &handshake);
-                try!(response.write_to(&mut stream));
-            },
-            _ => (),
-        }
+    // Connects to a server through an UltraVNC repeater reached over `transport`, which
+    // relays the connection to `destination`.
+    pub fn from_repeater<T, Auth>(transport: T, destination: &repeater::Destination,
+                                  shared: bool, auth: Auth) -> Result<Client>
+            where T: Transport + 'static, Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
+        let mut transport = transport;
+        try!(repeater::select_destination(&mut transport, destination));
+        Client::from_transport(transport, shared, auth)
+    }
 
-        let mut skip_security_result = false;
-        match &(auth_choice, version) {
-            &(AuthChoice::None, protocol::Version::Rfb33) |
-            &(AuthChoice::None, protocol::Version::Rfb37) => skip_security_result = true,
-            _ => ()
-        }
+    pub fn from_transport_with_security<T>(transport: T, shared: bool,
+                                           security: &SecurityRegistry) -> Result<Client>
+            where T: Transport + 'static {
+        let (stream, connection) = try!(Client::start_handshake(Box::new(transport), shared));
+        let handler = try!(security.choose(connection.security_types())
+                                   .ok_or(Error::AuthenticationUnavailable));
+        Client::finish_handshake(stream, connection, handler)
+    }
 
-        if !skip_security_result {
-            match try!(protocol::SecurityResult::read_from(&mut stream)) {
-                protocol::SecurityResult::Succeeded => (),
-                protocol::SecurityResult::Failed => {
-                    match version {
-                        protocol::Version::Rfb33 |
-                        protocol::Version::Rfb37 =>
-                            return Err(Error::AuthenticationFailure(String::from(""))),
-                        protocol::Version::Rfb38 => {
-                            let reason = try!(String::read_from(&mut stream));
-                            debug!("<- {:?}", reason);
-                            return Err(Error::AuthenticationFailure(reason))
-                        }
-                    }
-                }
-            }
-        }
+    fn start_handshake(transport: Box<Transport>, shared: bool)
+            -> Result<(Stream, ClientConnection)> {
+        let mut stream = Stream::Plain(transport);
+        let mut connection = ClientConnection::new(shared);
+        try!(connection::handshake(&mut stream, &mut connection));
+        Ok((stream, connection))
+    }
 
-        let client_init = protocol::ClientInit { shared: shared };
-        debug!("-> {:?}", client_init);
-        try!(protocol::ClientInit::write_to(&client_init, &mut stream));
+    fn finish_handshake(stream: Stream, connection: ClientConnection,
+                        handler: &SecurityHandler) -> Result<Client> {
+        let (mut stream, mut connection) = (stream, connection);
 
-        let server_init = try!(protocol::ServerInit::read_from(&mut stream));
-        debug!("<- {:?}", server_init);
+        try!(connection.choose_security(handler.security_type()));
+        if let ClientState::Authenticating(_) = connection.state() {
+            try!(stream.write_all(&connection.take_output()));
+            stream = try!(handler.client_handshake(stream));
+            let version = connection.version().unwrap();
+            try!(connection.finish_security(handler.sends_security_result(version)));
+        }
+        try!(connection::handshake(&mut stream, &mut connection));
 
-        let format = Arc::new(Mutex::new(server_init.pixel_format));
+        let format = Arc::new(Mutex::new(connection.format()));
+        let sink: SharedSink = Arc::new(Mutex::new(None));
 
         let (tx_events, rx_events) = channel();
         {
-            let stream = stream.try_clone().unwrap();
+            let stream = try!(stream.try_clone());
             let format = format.clone();
+            let sink = sink.clone();
             thread::spawn(move || {
                 let mut tx_events = tx_events;
-                let error = Event::pump(stream, format, &mut tx_events).err();
+                let error = Event::pump(stream, format, sink, &mut tx_events).err();
                 let _ = tx_events.send(Event::Disconnected(error));
             });
         }
@@ -300,83 +725,183 @@ impl Client {
         Ok(Client {
             stream:  stream,
             events:  rx_events,
-            name:    server_init.name,
-            size:    (server_init.framebuffer_width, server_init.framebuffer_height),
-            format:  format
+            connection: connection,
+            format:  format,
+            sink:    sink,
+            pending: VecDeque::new(),
         })
     }
 
-    pub fn name(&self) -> &str { &self.name }
-    pub fn size(&self) -> (u16, u16) { self.size }
+    pub fn name(&self) -> &str { self.connection.name() }
+    pub fn size(&self) -> (u16, u16) { self.connection.size() }
     pub fn format(&self) -> protocol::PixelFormat { *self.format.lock().unwrap() }
 
-    pub fn set_encodings(&mut self, encodings: &[protocol::Encoding]) -> Result<()> {
-        let set_encodings = protocol::C2S::SetEncodings(Vec::from(encodings));
-        debug!("-> {:?}", set_encodings);
-        try!(protocol::C2S::write_to(&set_encodings, &mut self.stream));
+    // The server announces these extensions by sending a message, so they are only
+    // known to be supported once the corresponding event has been polled.
+    pub fn supports_fence(&self) -> bool { self.connection.supports_fence() }
+    pub fn supports_continuous_updates(&self) -> bool {
+        self.connection.supports_continuous_updates()
+    }
+    pub fn supports_extended_clipboard(&self) -> bool {
+        self.connection.supports_extended_clipboard()
+    }
+
+    // Queues `message` with the connection, and writes out everything it has queued.
+    fn send(&mut self, message: &protocol::C2S) -> Result<()> {
+        try!(self.connection.send(message));
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        try!(self.stream.write_all(&self.connection.take_output()));
         Ok(())
     }
 
+    pub fn set_encodings(&mut self, encodings: &[protocol::Encoding]) -> Result<()> {
+        self.send(&protocol::C2S::SetEncodings(Vec::from(encodings)))
+    }
+
     pub fn request_update(&mut self, rect: protocol::Rect, incremental: bool) -> Result<()> {
-        let update_req = protocol::C2S::FramebufferUpdateRequest {
+        self.send(&protocol::C2S::FramebufferUpdateRequest {
             incremental: incremental,
             x_position:  rect.left,
             y_position:  rect.top,
             width:       rect.width,
             height:      rect.height
-        };
-        trace!("-> {:?}", update_req);
-        try!(protocol::C2S::write_to(&update_req, &mut self.stream));
-        Ok(())
+        })
+    }
+
+    // Note that the server must have announced support for the ExtendedDesktopSize
+    // extension (by sending an `Event::DesktopLayout`) before this request can be used.
+    // The outcome is reported with another `Event::DesktopLayout`. At most 255 screens
+    // can be requested.
+    pub fn request_desktop_size(&mut self, width: u16, height: u16,
+                                screens: &[protocol::Screen]) -> Result<()> {
+        self.send(&protocol::C2S::SetDesktopSize {
+            width:   width,
+            height:  height,
+            screens: Vec::from(screens)
+        })
+    }
+
+    // The payload may be at most 64 bytes long.
+    pub fn send_fence(&mut self, flags: u32, payload: &[u8]) -> Result<()> {
+        if payload.len() > 64 {
+            return Err(Error::Unexpected("fence payload length"))
+        }
+        self.send(&protocol::C2S::Fence(protocol::Fence {
+            flags:   flags,
+            payload: Vec::from(payload)
+        }))
+    }
+
+    // Once enabled, the server sends updates for `rect` as soon as it changes, without
+    // waiting for `request_update`.
+    pub fn enable_continuous_updates(&mut self, rect: protocol::Rect) -> Result<()> {
+        self.send(&protocol::C2S::EnableContinuousUpdates {
+            enable:      true,
+            x_position:  rect.left,
+            y_position:  rect.top,
+            width:       rect.width,
+            height:      rect.height
+        })
+    }
+
+    // The server acknowledges this with an `Event::EndOfContinuousUpdates`.
+    pub fn disable_continuous_updates(&mut self) -> Result<()> {
+        let (width, height) = self.size();
+        self.send(&protocol::C2S::EnableContinuousUpdates {
+            enable:      false,
+            x_position:  0,
+            y_position:  0,
+            width:       width,
+            height:      height
+        })
     }
 
     pub fn send_key_event(&mut self, down: bool, key: u32) -> Result<()> {
-        let key_event = protocol::C2S::KeyEvent {
+        self.send(&protocol::C2S::KeyEvent {
             down: down,
             key:  key
-        };
-        debug!("-> {:?}", key_event);
-        try!(protocol::C2S::write_to(&key_event, &mut self.stream));
-        Ok(())
+        })
     }
 
     pub fn send_pointer_event(&mut self, buttons: u8, x: u16, y: u16) -> Result<()> {
-        let pointer_event = protocol::C2S::PointerEvent {
+        self.send(&protocol::C2S::PointerEvent {
             button_mask: buttons,
             x_position:  x,
             y_position:  y
-        };
-        debug!("-> {:?}", pointer_event);
-        try!(protocol::C2S::write_to(&pointer_event, &mut self.stream));
-        Ok(())
+        })
     }
 
+    // If the server supports the Extended Clipboard extension and accepts text of this size
+    // unprompted, `text` is sent as UTF-8; otherwise it is sent as Latin-1.
     pub fn update_clipboard(&mut self, text: &str) -> Result<()> {
-        let cut_text = protocol::C2S::CutText(String::from(text));
-        debug!("-> {:?}", cut_text);
-        try!(protocol::C2S::write_to(&cut_text, &mut self.stream));
-        Ok(())
+        try!(self.connection.update_clipboard(text));
+        self.flush()
+    }
+
+    // Note that the server must have announced support for the Extended Clipboard
+    // extension (by sending an `ExtendedClipboard::Caps`) before this can be used.
+    pub fn send_extended_clipboard(&mut self, clipboard: protocol::ExtendedClipboard)
+            -> Result<()> {
+        self.send(&protocol::C2S::ExtendedCutText(clipboard))
     }
 
     // Note that due to inherent weaknesses of the VNC protocol, this
-    // function is prone to race conditions that break the connection framing.
+    // function is prone to race conditions that break the connection framing,
+    // unless the server supports the Fence extension.
     // The ZRLE encoding is self-delimiting and if both the client and server
     // support and use it, there can be no race condition, but we currently don't.
     pub fn set_format(&mut self, format: protocol::PixelFormat) -> Result<()> {
-        // Request (and discard) one full update to try and ensure that there
-        // are no FramebufferUpdate's in the buffers somewhere.
+        try!(pixel_format::validate(&format));
+        // Learn about fence support; the events are still handed out by `poll_event`.
+        while let Some(event) = self.receive_event() {
+            self.pending.push_back(event)
+        }
+        if self.supports_fence() {
+            // Let the server tell us exactly where in the stream it switches formats:
+            // the event thread picks up the new format when the fence response arrives.
+            let mut payload = Vec::from(FORMAT_FENCE_TAG);
+            try!(protocol::PixelFormat::write_to(&format, &mut payload));
+            try!(self.send_fence(fence::REQUEST | fence::BLOCK_BEFORE | fence::SYNC_NEXT,
+                                 &payload));
+
+            let set_pixel_format = protocol::C2S::SetPixelFormat(format);
+            debug!("-> {:?}", set_pixel_format);
+            try!(protocol::C2S::write_to(&set_pixel_format, &mut self.stream));
+            return Ok(())
+        }
+
+        // Request one full update to try and ensure that there are no
+        // FramebufferUpdate's in the buffers somewhere.
         // This is not fully robust though (and cannot possibly be).
-        let _ = self.poll_iter().count(); // drain it
-        let framebuffer_rect = protocol::Rect::new(0, 0, self.size.0, self.size.1);
+        // The update has to arrive as an event, so the sink is set aside until then.
+        let mut sink = self.sink.lock().unwrap().take();
+        let (width, height) = self.size();
+        let framebuffer_rect = protocol::Rect::new(0, 0, width, height);
         try!(self.request_update(framebuffer_rect, false));
-        'outer: loop {
-            for event in self.poll_iter() {
-                match event {
-                    Event::PutPixels(rect, _) if rect == framebuffer_rect => break 'outer,
-                    _ => ()
-                }
+        loop {
+            let event = match self.receive_event() {
+                Some(event) => event,
+                None => continue
+            };
+            let done = match event {
+                Event::PutPixels(rect, _) => rect == framebuffer_rect,
+                _ => false
+            };
+            // Pixels still go wherever they would have gone without the wait.
+            match (sink.as_mut(), event) {
+                (Some(sink), Event::PutPixels(rect, pixels)) => sink.put_pixels(rect, &pixels),
+                (Some(sink), Event::FillPixels(rect, pixel)) => sink.fill_pixels(rect, &pixel),
+                (Some(sink), Event::CopyPixels { src, dst }) => sink.copy_pixels(src, dst),
+                (Some(sink), Event::SetCursor { size, hotspot, pixels, mask_bits }) =>
+                    sink.set_cursor(size, hotspot, &pixels, &mask_bits),
+                (_, event) => self.pending.push_back(event)
             }
+            if done { break }
         }
+        *self.sink.lock().unwrap() = sink;
 
         // Since VNC is fully client-driven, by this point the event thread is stuck
         // waiting for the next message and the server is not sending us anything,
@@ -385,10 +910,20 @@ impl Client {
         debug!("-> {:?}", set_pixel_format);
         try!(protocol::C2S::write_to(&set_pixel_format, &mut self.stream));
         *self.format.lock().unwrap() = format;
+        self.pending.push_back(Event::SetFormat(format));
 
         Ok(())
     }
 
+    // Makes the event thread write the contents of framebuffer updates straight to `sink`,
+    // starting with the next rectangle, instead of sending `PutPixels`, `FillPixels`,
+    // `CopyPixels` and `SetCursor` events; the other events, including `EndOfFrame`,
+    // are still sent. `None` switches back to events.
+    // To access the sink from elsewhere, share it as an `Arc<Mutex<_>>`.
+    pub fn set_sink(&mut self, sink: Option<Box<FramebufferSink + Send>>) {
+        *self.sink.lock().unwrap() = sink;
+    }
+
     #[doc(hidden)]
     pub fn poke_qemu(&mut self) -> Result<()> {
         let set_pixel_format = protocol::C2S::SetPixelFormat(*self.format.lock().unwrap());
@@ -398,22 +933,33 @@ impl Client {
     }
 
     pub fn poll_event(&mut self) -> Option<Event> {
-        match self.events.try_recv() {
+        match self.pending.pop_front() {
+            Some(event) => Some(event),
+            None => self.receive_event()
+        }
+    }
+
+    fn receive_event(&mut self) -> Option<Event> {
+        let event = match self.events.try_recv() {
+            Ok(event) => event,
             Err(TryRecvError::Empty) |
-            Err(TryRecvError::Disconnected) => None,
-            Ok(Event::Resize(width, height)) => {
-                self.size = (width, height);
-                Some(Event::Resize(width, height))
-            }
-            Ok(event) => Some(event)
+            Err(TryRecvError::Disconnected) => return None
+        };
+        // Fences are answered here rather than by the event thread: all events preceding
+        // the fence have been handed out already, and the ones following it will not be
+        // until this returns, which satisfies every flag we understand.
+        let result = self.connection.handle_event(&event).and_then(|()| self.flush());
+        if let Err(error) = result {
+            return Some(Event::Disconnected(Some(error)))
         }
+        Some(event)
     }
 
     pub fn poll_iter(&mut self) -> EventPollIterator {
         EventPollIterator { client: self }
     }
 
-    pub fn disconnect(self) -> Result<()> {
+    pub fn disconnect(mut self) -> Result<()> {
         try!(self.stream.shutdown(Shutdown::Both));
         Ok(())
     }
@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::mem;
+use ::{protocol, fence, hextile, pixel_format, rre, Error, Result};
+use protocol::{Message, CLIPBOARD_MAX_SIZE};
+use client::{Event, Decoders, EventSink, FORMAT_FENCE_TAG};
+use security::{des, des_key};
+use super::Buffer;
+
+/// What a `ClientConnection` is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// More data has to be received from the server.
+    Handshaking,
+    /// The server has offered `security_types()`, and one of them has to be chosen with
+    /// `choose_security` or `choose_vnc_authentication`.
+    ChoosingSecurity,
+    /// The handshake of the chosen security type has to be performed over the transport,
+    /// followed by a call to `finish_security`.
+    Authenticating(protocol::SecurityType),
+    /// The handshake is complete; events can be received and messages sent.
+    Connected,
+}
+
+#[derive(Debug)]
+enum State {
+    Version,
+    SecurityTypes,
+    SecurityReason,
+    ChoosingSecurity,
+    Challenge([u8; 8]),
+    Authenticating(protocol::SecurityType),
+    SecurityResult,
+    FailureReason,
+    ServerInit,
+    Connected,
+}
+
+/// The client side of an RFB connection, independent of the transport.
+pub struct ClientConnection {
+    buffer:  Buffer,
+    state:   State,
+    shared:  bool,
+    version: Option<protocol::Version>,
+    security_types: Vec<protocol::SecurityType>,
+    security_type:  Option<protocol::SecurityType>,
+    name:    String,
+    size:    (u16, u16),
+    format:  protocol::PixelFormat,
+    encodings: Vec<protocol::Encoding>,
+    decoders: Decoders,
+    // Rectangles of the framebuffer update being received that have not been read yet.
+    rectangles: u16,
+    // How far the Hextile data of the next rectangle has been scanned.
+    hextile_scan: hextile::Scan,
+    events:  VecDeque<Event>,
+    fence_supported: bool,
+    continuous_updates_supported: bool,
+    clipboard_caps: Option<(Vec<protocol::ClipboardAction>,
+                            Vec<(protocol::ClipboardFormat, u32)>)>,
+}
+
+impl ClientConnection {
+    /// Constructs a connection that waits for the server to announce its version.
+    ///
+    /// `shared` is sent to the server once authenticated, and tells whether other clients
+    /// should stay connected.
+    pub fn new(shared: bool) -> ClientConnection {
+        ClientConnection {
+            buffer:  Buffer::new(),
+            state:   State::Version,
+            shared:  shared,
+            version: None,
+            security_types: Vec::new(),
+            security_type:  None,
+            name:    String::new(),
+            size:    (0, 0),
+            format:  ::pixel_format::RGB8888,
+            encodings: Vec::new(),
+            decoders: Decoders::new(),
+            rectangles: 0,
+            hextile_scan: hextile::Scan::new(),
+            events:  VecDeque::new(),
+            fence_supported: false,
+            continuous_updates_supported: false,
+            clipboard_caps: None,
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        match self.state {
+            State::ChoosingSecurity => ClientState::ChoosingSecurity,
+            State::Authenticating(security_type) => ClientState::Authenticating(security_type),
+            State::Connected => ClientState::Connected,
+            _ => ClientState::Handshaking
+        }
+    }
+
+    /// Returns the negotiated protocol version, once the server has announced it.
+    pub fn version(&self) -> Option<protocol::Version> { self.version }
+
+    /// Returns the security types offered by the server.
+    pub fn security_types(&self) -> &[protocol::SecurityType] { &self.security_types }
+
+    /// Returns the chosen security type.
+    pub fn security_type(&self) -> Option<protocol::SecurityType> { self.security_type }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn size(&self) -> (u16, u16) { self.size }
+    pub fn format(&self) -> protocol::PixelFormat { self.format }
+
+    /// Returns the encodings last sent in a `SetEncodings` message.
+    pub fn encodings(&self) -> &[protocol::Encoding] { &self.encodings }
+
+    pub fn supports_fence(&self) -> bool { self.fence_supported }
+    pub fn supports_continuous_updates(&self) -> bool { self.continuous_updates_supported }
+    pub fn supports_extended_clipboard(&self) -> bool { self.clipboard_caps.is_some() }
+
+    /// Processes `data` received from the server.
+    ///
+    /// An error means that the connection cannot continue, but any failure reason the server
+    /// sent is included in it.
+    pub fn receive(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.receive(data);
+        self.process()
+    }
+
+    /// Returns the number of bytes that have to be received before any progress can be made.
+    pub fn bytes_needed(&self) -> usize {
+        self.buffer.bytes_needed()
+    }
+
+    /// Takes the data to be sent to the server.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.buffer.output, Vec::new())
+    }
+
+    /// Returns the next event received from the server.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Chooses `security_type` among those offered by the server. Unless it is `None`,
+    /// the connection moves to `ClientState::Authenticating`.
+    pub fn choose_security(&mut self, security_type: protocol::SecurityType) -> Result<()> {
+        try!(self.announce_security(security_type));
+        if security_type == protocol::SecurityType::None {
+            let sends_security_result = self.version == Some(protocol::Version::Rfb38);
+            self.finish_security(sends_security_result)
+        } else {
+            self.state = State::Authenticating(security_type);
+            Ok(())
+        }
+    }
+
+    /// Chooses VNC authentication, and answers the challenge of the server with `password`.
+    pub fn choose_vnc_authentication(&mut self, password: [u8; 8]) -> Result<()> {
+        try!(self.announce_security(protocol::SecurityType::VncAuthentication));
+        self.state = State::Challenge(password);
+        self.process()
+    }
+
+    fn announce_security(&mut self, security_type: protocol::SecurityType) -> Result<()> {
+        match self.state {
+            State::ChoosingSecurity => (),
+            _ => return Err(Error::Unexpected("security type chosen out of order"))
+        }
+        if !self.security_types.contains(&security_type) {
+            return Err(Error::AuthenticationUnavailable)
+        }
+        if self.version != Some(protocol::Version::Rfb33) {
+            debug!("-> SecurityType::{:?}", security_type);
+            try!(self.buffer.queue(&security_type));
+        }
+        self.security_type = Some(security_type);
+        Ok(())
+    }
+
+    /// Resumes the handshake once the security type has been handled over the transport.
+    /// `sends_security_result` comes from `SecurityHandler::sends_security_result`.
+    pub fn finish_security(&mut self, sends_security_result: bool) -> Result<()> {
+        match self.state {
+            State::Authenticating(_) | State::ChoosingSecurity => (),
+            _ => return Err(Error::Unexpected("security handshake finished out of order"))
+        }
+        if sends_security_result {
+            self.state = State::SecurityResult;
+        } else {
+            try!(self.send_client_init());
+        }
+        self.process()
+    }
+
+    fn send_client_init(&mut self) -> Result<()> {
+        let client_init = protocol::ClientInit { shared: self.shared };
+        debug!("-> {:?}", client_init);
+        try!(self.buffer.queue(&client_init));
+        self.state = State::ServerInit;
+        Ok(())
+    }
+
+    fn process(&mut self) -> Result<()> {
+        while try!(self.step()) {}
+        Ok(())
+    }
+
+    // Makes one step of progress, and returns whether more steps can be made.
+    fn step(&mut self) -> Result<bool> {
+        match self.state {
+            State::Version => {
+                let version = match try!(self.buffer.parse_message::<protocol::Version>()) {
+                    Some(version) => version,
+                    None => return Ok(false)
+                };
+                debug!("<- Version::{:?}", version);
+                debug!("-> Version::{:?}", version);
+                try!(self.buffer.queue(&version));
+                self.version = Some(version);
+                self.state = State::SecurityTypes;
+            }
+            State::SecurityTypes => {
+                let security_types = match self.version {
+                    Some(protocol::Version::Rfb33) => {
+                        let security_type =
+                            match try!(self.buffer.parse_message::<protocol::SecurityType>()) {
+                                Some(security_type) => security_type,
+                                None => return Ok(false)
+                            };
+                        debug!("<- SecurityType::{:?}", security_type);
+                        if security_type == protocol::SecurityType::Invalid {
+                            vec![]
+                        } else {
+                            vec![security_type]
+                        }
+                    }
+                    _ => {
+                        let security_types =
+                            match try!(self.buffer.parse_message::<protocol::SecurityTypes>()) {
+                                Some(security_types) => security_types,
+                                None => return Ok(false)
+                            };
+                        debug!("<- {:?}", security_types);
+                        security_types.0
+                    }
+                };
+                self.state = if security_types.len() == 0 {
+                    State::SecurityReason
+                } else {
+                    State::ChoosingSecurity
+                };
+                self.security_types = security_types;
+            }
+            State::SecurityReason => {
+                let reason = match try!(self.buffer.parse_message::<String>()) {
+                    Some(reason) => reason,
+                    None => return Ok(false)
+                };
+                debug!("<- {:?}", reason);
+                return Err(Error::Server(reason))
+            }
+            State::ChoosingSecurity | State::Authenticating(_) => return Ok(false),
+            State::Challenge(password) => {
+                let challenge = match try!(self.buffer.parse(|reader| {
+                    let mut challenge = [0; 16];
+                    try!(reader.read_exact(&mut challenge));
+                    Ok(challenge)
+                })) {
+                    Some(challenge) => challenge,
+                    None => return Ok(false)
+                };
+                self.buffer.output.extend_from_slice(&des(&challenge, &des_key(&password)));
+                self.state = State::SecurityResult;
+            }
+            State::SecurityResult => {
+                match try!(self.buffer.parse_message::<protocol::SecurityResult>()) {
+                    Some(protocol::SecurityResult::Succeeded) =>
+                        try!(self.send_client_init()),
+                    Some(protocol::SecurityResult::Failed) => {
+                        if self.version != Some(protocol::Version::Rfb38) {
+                            return Err(Error::AuthenticationFailure(String::from("")))
+                        }
+                        self.state = State::FailureReason;
+                    }
+                    None => return Ok(false)
+                }
+            }
+            State::FailureReason => {
+                let reason = match try!(self.buffer.parse_message::<String>()) {
+                    Some(reason) => reason,
+                    None => return Ok(false)
+                };
+                debug!("<- {:?}", reason);
+                return Err(Error::AuthenticationFailure(reason))
+            }
+            State::ServerInit => {
+                let server_init = match try!(self.buffer.parse_message::<protocol::ServerInit>()) {
+                    Some(server_init) => server_init,
+                    None => return Ok(false)
+                };
+                debug!("<- {:?}", server_init);
+                try!(pixel_format::validate(&server_init.pixel_format));
+                self.name   = server_init.name;
+                self.size   = (server_init.framebuffer_width, server_init.framebuffer_height);
+                self.format = server_init.pixel_format;
+                self.state  = State::Connected;
+            }
+            State::Connected => return self.receive_event()
+        }
+        Ok(true)
+    }
+
+    // Returns how much data has to be received before the next rectangle can be read.
+    // Hextile and RRE data is read in many small pieces, so rather than decoding such
+    // a rectangle again whenever more of it arrives, its end is found first.
+    fn rectangle_length(&mut self) -> usize {
+        let data = self.buffer.received();
+        let mut rest = data;
+        let rectangle = match protocol::RectangleHeader::read_from(&mut rest) {
+            Ok(rectangle) => rectangle,
+            Err(_) => return 0
+        };
+        let header_length = data.len() - rest.len();
+        let bpp = self.format.bits_per_pixel as usize / 8;
+        let rect = protocol::Rect::new(rectangle.x_position, rectangle.y_position,
+                                       rectangle.width, rectangle.height);
+        header_length + match rectangle.encoding {
+            protocol::Encoding::Rre => rre::encoded_length(rest, bpp, false),
+            protocol::Encoding::CoRre => rre::encoded_length(rest, bpp, true),
+            protocol::Encoding::Hextile => self.hextile_scan.advance(rest, bpp, rect),
+            _ => 0
+        }
+    }
+
+    // Reads the next message, or the next rectangle of a framebuffer update, and queues
+    // the events it produces.
+    fn receive_event(&mut self) -> Result<bool> {
+        if self.rectangles > 0 {
+            let length = self.rectangle_length();
+            self.buffer.wait_for(length);
+            let format = self.format;
+            let &mut ClientConnection { ref mut buffer, ref mut decoders, .. } = self;
+            let events = match try!(buffer.parse(|reader| {
+                let rectangle = try!(protocol::RectangleHeader::read_from(reader));
+                // A rectangle either carries pixels or resizes the framebuffer, so its
+                // events can be collected separately without changing their order.
+                let mut events = Vec::new();
+                let mut other_events = Vec::new();
+                try!(Event::read_rectangle(reader, &rectangle, format, decoders,
+                                           &mut EventSink(|event| events.push(event)),
+                                           &mut other_events));
+                events.extend(other_events);
+                Ok(events)
+            })) {
+                Some(events) => events,
+                None => return Ok(false)
+            };
+            self.hextile_scan = hextile::Scan::new();
+            for event in events {
+                try!(self.push_event(event));
+            }
+            self.rectangles -= 1;
+            if self.rectangles == 0 {
+                try!(self.push_event(Event::EndOfFrame));
+            }
+            return Ok(true)
+        }
+
+        let packet = match try!(self.buffer.parse_message::<protocol::S2C>()) {
+            Some(packet) => packet,
+            None => return Ok(false)
+        };
+        debug!("<- {:?}", packet);
+        match packet {
+            protocol::S2C::FramebufferUpdate { count: 0 } =>
+                try!(self.push_event(Event::EndOfFrame)),
+            protocol::S2C::FramebufferUpdate { count } =>
+                self.rectangles = count,
+            packet => {
+                if let Some(event) = try!(Event::from_message(packet, &mut self.format)) {
+                    try!(self.push_event(event));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn push_event(&mut self, event: Event) -> Result<()> {
+        try!(self.handle_event(&event));
+        self.events.push_back(event);
+        Ok(())
+    }
+
+    /// Keeps track of what the server supports, and queues the answers to the messages that
+    /// require one. The blocking `Client` decodes events on a thread of its own, and passes
+    /// them here as they are handed out.
+    pub(crate) fn handle_event(&mut self, event: &Event) -> Result<()> {
+        match event {
+            &Event::Resize(width, height) => {
+                self.size = (width, height);
+            }
+            &Event::Fence { flags, ref payload } => {
+                self.fence_supported = true;
+                if flags & fence::REQUEST != 0 {
+                    try!(self.buffer.queue(&protocol::C2S::Fence(protocol::Fence {
+                        flags:   flags & fence::SUPPORTED,
+                        payload: payload.clone()
+                    })));
+                }
+            }
+            &Event::EndOfContinuousUpdates => {
+                self.continuous_updates_supported = true;
+            }
+            &Event::ExtendedClipboard(protocol::ExtendedClipboard::Caps {
+                ref actions, ref formats
+            }) => {
+                // The server only switches to extended clipboard messages once we
+                // have announced our own capabilities.
+                self.clipboard_caps = Some((actions.clone(), formats.clone()));
+                let caps = protocol::ExtendedClipboard::Caps {
+                    actions: vec![protocol::ClipboardAction::Request,
+                                  protocol::ClipboardAction::Peek,
+                                  protocol::ClipboardAction::Notify,
+                                  protocol::ClipboardAction::Provide],
+                    formats: vec![(protocol::ClipboardFormat::Text, CLIPBOARD_MAX_SIZE),
+                                  (protocol::ClipboardFormat::Rtf,  CLIPBOARD_MAX_SIZE),
+                                  (protocol::ClipboardFormat::Html, CLIPBOARD_MAX_SIZE)]
+                };
+                try!(self.buffer.queue(&protocol::C2S::ExtendedCutText(caps)));
+            }
+            _ => ()
+        }
+        Ok(())
+    }
+
+    /// Queues `message` to be sent to the server.
+    ///
+    /// `SetPixelFormat` messages are sent with `set_format`.
+    pub fn send(&mut self, message: &protocol::C2S) -> Result<()> {
+        match message {
+            &protocol::C2S::SetPixelFormat(format) => return self.set_format(format),
+            &protocol::C2S::SetEncodings(ref encodings) => self.encodings = encodings.clone(),
+            _ => ()
+        }
+        debug!("-> {:?}", message);
+        self.buffer.queue(message)
+    }
+
+    /// Requests the server to send pixels in `format`.
+    ///
+    /// Unless the server supports the Fence extension, the new format is used for everything
+    /// received afterwards, so no framebuffer update may be outstanding. Returns
+    /// `Error::Unexpected` if `format` is not valid; see `pixel_format::validate`.
+    pub fn set_format(&mut self, format: protocol::PixelFormat) -> Result<()> {
+        try!(pixel_format::validate(&format));
+        if self.fence_supported {
+            let mut payload = Vec::from(FORMAT_FENCE_TAG);
+            try!(protocol::PixelFormat::write_to(&format, &mut payload));
+            try!(self.buffer.queue(&protocol::C2S::Fence(protocol::Fence {
+                flags:   fence::REQUEST | fence::BLOCK_BEFORE | fence::SYNC_NEXT,
+                payload: payload
+            })));
+        } else {
+            self.format = format;
+            self.events.push_back(Event::SetFormat(format));
+        }
+        let set_pixel_format = protocol::C2S::SetPixelFormat(format);
+        debug!("-> {:?}", set_pixel_format);
+        self.buffer.queue(&set_pixel_format)
+    }
+
+    /// Sends `text` to the server clipboard, as an Extended Clipboard message if
+    /// the server accepts it.
+    pub fn update_clipboard(&mut self, text: &str) -> Result<()> {
+        let provide_text = match self.clipboard_caps {
+            Some((ref actions, ref formats)) =>
+                actions.contains(&protocol::ClipboardAction::Provide) &&
+                formats.iter().any(|&(format, size)|
+                    format == protocol::ClipboardFormat::Text && text.len() < size as usize),
+            None => false
+        };
+        if provide_text {
+            let provide = protocol::ExtendedClipboard::Provide(
+                vec![protocol::ClipboardData::Text(String::from(text))]);
+            return self.send(&protocol::C2S::ExtendedCutText(provide))
+        }
+        self.send(&protocol::C2S::CutText(String::from(text)))
+    }
+}
@@ -0,0 +1,468 @@
+//! Sans-IO state machines for both ends of an RFB connection.
+//!
+//! `ClientConnection` and `ServerConnection` never touch a transport: data received from
+//! the peer is passed to `receive`, which parses as much of it as possible into events, and
+//! data to be sent to the peer is taken with `take_output`. The blocking `Client` and `Server`
+//! as well as the asynchronous ones are built on top of them. `ProxyConnection` does the same
+//! for a proxy, which has a peer on either side, and underlies both `Proxy`s.
+//!
+//! Security types other than None and VNC authentication wrap the transport, so
+//! the state machines stop in the `Authenticating` state to let the caller perform their
+//! handshake with a `SecurityHandler` before resuming with `finish_security`. In that state
+//! no data beyond what `bytes_needed` asked for may have been passed to `receive`.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use ::{Error, Result};
+use protocol::Message;
+
+mod client;
+mod server;
+mod proxy;
+
+pub use self::client::{ClientConnection, ClientState};
+pub use self::server::{ServerConnection, ServerState};
+pub use self::proxy::{ProxyConnection, ProxyState};
+
+/// Reads from the received data, and remembers how much data would have been needed
+/// when it runs out.
+pub(crate) struct Partial<'a> {
+    data:     &'a [u8],
+    position: usize,
+    needed:   usize,
+}
+
+impl<'a> Read for Partial<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.data.len() - self.position;
+        if available == 0 && buf.len() > 0 {
+            self.needed = self.position + buf.len();
+            return Ok(0)
+        }
+        let count = cmp::min(available, buf.len());
+        buf[..count].copy_from_slice(&self.data[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// The data received but not parsed yet, and the data to be sent but not taken yet.
+///
+/// Messages are parsed with the same blocking parsers as elsewhere, which are simply
+/// restarted once more data arrives if they run out of it. Parsers must therefore only
+/// change their state once they have read everything they need. Values read in many small
+/// pieces should be waited for with `wait_for` once their length is known, so that they
+/// are not parsed over and over again.
+pub(crate) struct Buffer {
+    input:  Vec<u8>,
+    needed: usize,
+    pub(crate) output: Vec<u8>,
+}
+
+impl Buffer {
+    pub(crate) fn new() -> Buffer {
+        Buffer { input: Vec::new(), needed: 1, output: Vec::new() }
+    }
+
+    pub(crate) fn receive(&mut self, data: &[u8]) {
+        self.input.extend_from_slice(data)
+    }
+
+    /// Returns the data received but not parsed yet.
+    pub(crate) fn received(&self) -> &[u8] {
+        &self.input
+    }
+
+    /// Makes `parse` wait until at least `length` bytes have been received.
+    pub(crate) fn wait_for(&mut self, length: usize) {
+        self.needed = cmp::max(self.needed, length)
+    }
+
+    /// Returns the number of bytes that have to be received before parsing can make progress.
+    pub(crate) fn bytes_needed(&self) -> usize {
+        self.needed.saturating_sub(self.input.len())
+    }
+
+    /// Parses a value from the received data with `parse`, or returns `None` if more data
+    /// has to be received first.
+    pub(crate) fn parse<T, F>(&mut self, mut parse: F) -> Result<Option<T>>
+            where F: FnMut(&mut Partial) -> Result<T> {
+        if self.input.len() < self.needed {
+            return Ok(None)
+        }
+        let mut reader = Partial { data: &self.input, position: 0, needed: 0 };
+        match parse(&mut reader) {
+            Ok(value) => {
+                let consumed = reader.position;
+                self.input.drain(..consumed);
+                self.needed = 1;
+                return Ok(Some(value))
+            }
+            Err(Error::Disconnected) => (),
+            Err(Error::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => (),
+            Err(error) => return Err(error)
+        }
+        self.needed = cmp::max(reader.needed, self.input.len() + 1);
+        Ok(None)
+    }
+
+    /// Parses a message from the received data, or returns `None` if more data has to be
+    /// received first.
+    pub(crate) fn parse_message<M: Message>(&mut self) -> Result<Option<M>> {
+        self.parse(|reader| M::read_from(reader))
+    }
+
+    /// Queues `message` to be sent.
+    pub(crate) fn queue<M: Message>(&mut self, message: &M) -> Result<()> {
+        // Leave no partial message behind if it cannot be serialized.
+        let length = self.output.len();
+        let result = message.write_to(&mut self.output);
+        if result.is_err() {
+            self.output.truncate(length);
+        }
+        result
+    }
+}
+
+/// The parts of `ClientConnection` and `ServerConnection` needed to drive their handshake.
+pub(crate) trait Connection {
+    fn receive(&mut self, data: &[u8]) -> Result<()>;
+    fn bytes_needed(&self) -> usize;
+    fn take_output(&mut self) -> Vec<u8>;
+    fn is_handshaking(&self) -> bool;
+}
+
+impl Connection for ClientConnection {
+    fn receive(&mut self, data: &[u8]) -> Result<()> { ClientConnection::receive(self, data) }
+    fn bytes_needed(&self) -> usize { ClientConnection::bytes_needed(self) }
+    fn take_output(&mut self) -> Vec<u8> { ClientConnection::take_output(self) }
+    fn is_handshaking(&self) -> bool { self.state() == ClientState::Handshaking }
+}
+
+impl Connection for ServerConnection {
+    fn receive(&mut self, data: &[u8]) -> Result<()> { ServerConnection::receive(self, data) }
+    fn bytes_needed(&self) -> usize { ServerConnection::bytes_needed(self) }
+    fn take_output(&mut self) -> Vec<u8> { ServerConnection::take_output(self) }
+    fn is_handshaking(&self) -> bool { self.state() == ServerState::Handshaking }
+}
+
+/// Exchanges data with the peer over a blocking `stream` for as long as the connection only
+/// waits for more data.
+///
+/// No more data is read than the connection needs, so that the rest can be left to
+/// a security handler.
+pub(crate) fn handshake<S, C>(stream: &mut S, connection: &mut C) -> Result<()>
+        where S: Read + Write, C: Connection {
+    loop {
+        try!(stream.write_all(&connection.take_output()));
+        try!(stream.flush());
+        if !connection.is_handshaking() {
+            return Ok(())
+        }
+
+        let mut data = vec![0; connection.bytes_needed()];
+        try!(stream.read_exact(&mut data));
+        let result = connection.receive(&data);
+        if result.is_err() {
+            // Let the peer know why the handshake failed.
+            let _ = stream.write_all(&connection.take_output());
+        }
+        try!(result);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::{protocol, client, server, hextile, pixel_format, Access, Error};
+    use super::{ClientConnection, ClientState, ServerConnection, ServerState, ProxyConnection,
+                ProxyState};
+
+    /// Moves the data queued by each side to the other one, one byte at a time, until
+    /// neither has anything left to send.
+    fn exchange(client: &mut ClientConnection, server: &mut ServerConnection)
+            -> Result<(), Error> {
+        loop {
+            let (to_server, to_client) = (client.take_output(), server.take_output());
+            if to_server.is_empty() && to_client.is_empty() {
+                return Ok(())
+            }
+            for byte in to_server {
+                try!(server.receive(&[byte]));
+            }
+            for byte in to_client {
+                try!(client.receive(&[byte]));
+            }
+        }
+    }
+
+    /// Like `exchange`, but with `proxy` in between the client and the server.
+    fn exchange_through(client: &mut ClientConnection, proxy: &mut ProxyConnection,
+                        server: &mut ServerConnection) -> Result<(), Error> {
+        loop {
+            let (to_proxy_from_client, to_proxy_from_server) =
+                (client.take_output(), server.take_output());
+            let (to_server, to_client) = (proxy.take_server_output(), proxy.take_client_output());
+            if to_proxy_from_client.is_empty() && to_proxy_from_server.is_empty() &&
+                    to_server.is_empty() && to_client.is_empty() {
+                return Ok(())
+            }
+            for byte in to_proxy_from_client {
+                try!(proxy.receive_from_client(&[byte]));
+            }
+            for byte in to_proxy_from_server {
+                try!(proxy.receive_from_server(&[byte]));
+            }
+            for byte in to_server {
+                try!(server.receive(&[byte]));
+            }
+            for byte in to_client {
+                try!(client.receive(&[byte]));
+            }
+        }
+    }
+
+    fn handshake(password: &[u8; 8]) -> Result<(ClientConnection, ServerConnection), Error> {
+        let mut client = ClientConnection::new(true);
+        let mut server = ServerConnection::new(
+            2, 1, pixel_format::RGB8888, String::from("test"),
+            vec![protocol::SecurityType::VncAuthentication]);
+        try!(exchange(&mut client, &mut server));
+
+        assert_eq!(client.state(), ClientState::ChoosingSecurity);
+        assert_eq!(client.security_types(), &[protocol::SecurityType::VncAuthentication]);
+        try!(client.choose_vnc_authentication(*password));
+        try!(exchange(&mut client, &mut server));
+
+        assert_eq!(server.state(),
+                   ServerState::Authenticating(protocol::SecurityType::VncAuthentication));
+        try!(server.start_vnc_authentication(server::VncAuthentication {
+            password: String::from("secret"),
+            view_only_password: Some(String::from("viewer")),
+        }));
+        let result = exchange(&mut client, &mut server);
+        // The failure reason has to reach the client even though the server gave up.
+        if let Err(Error::AuthenticationFailure(_)) = result {
+            try!(exchange(&mut client, &mut server));
+        }
+        try!(result);
+        Ok((client, server))
+    }
+
+    /// Checks if both state machines complete the handshake and exchange messages when
+    /// the data trickles in a byte at a time.
+    #[test]
+    fn check_if_connections_talk_to_each_other() {
+        let (mut client, mut server) = handshake(b"secret\0\0").unwrap();
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(server.state(), ServerState::Connected);
+        assert_eq!(client.version(), Some(protocol::Version::Rfb38));
+        assert_eq!(client.name(), "test");
+        assert_eq!(client.size(), (2, 1));
+        assert!(server.shared());
+        assert_eq!(server.access(), Some(Access::Full));
+
+        client.send(&protocol::C2S::SetEncodings(vec![protocol::Encoding::Raw])).unwrap();
+        client.send(&protocol::C2S::KeyEvent { down: true, key: 0x61 }).unwrap();
+        exchange(&mut client, &mut server).unwrap();
+        assert_eq!(server.encodings(), &[protocol::Encoding::Raw]);
+        match server.next_event() {
+            Some(server::Event::SetEncodings(_)) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        match server.next_event() {
+            Some(server::Event::KeyEvent { down: true, key: 0x61 }) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(server.next_event().is_none());
+
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        let update = {
+            let mut builder = server.create_update();
+            builder.add_raw_pixels(protocol::Rect { left: 0, top: 0, width: 2, height: 1 },
+                                   &pixels);
+            builder.done()
+        };
+        server.send_update(&update).unwrap();
+        exchange(&mut client, &mut server).unwrap();
+        match client.next_event() {
+            Some(client::Event::PutPixels(_, ref data)) if data[..] == pixels[..] => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        match client.next_event() {
+            Some(client::Event::EndOfFrame) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+
+    /// Checks if view-only clients are recognized, and wrong passwords rejected.
+    #[test]
+    fn check_if_server_connection_checks_passwords() {
+        let (mut client, mut server) = handshake(b"viewer\0\0").unwrap();
+        assert_eq!(server.access(), Some(Access::ViewOnly));
+        client.send(&protocol::C2S::KeyEvent { down: true, key: 0x61 }).unwrap();
+        exchange(&mut client, &mut server).unwrap();
+        assert!(server.next_event().is_none());
+
+        match handshake(b"wrong\0\0\0") {
+            Err(Error::AuthenticationFailure(ref reason)) if reason == "authentication failed" =>
+                (),
+            result => panic!("unexpected result {:?}", result.map(|_| ()))
+        }
+    }
+
+    /// Checks if a Hextile rectangle received a byte at a time is waited for as a whole
+    /// once all of its tiles have been found, and only decoded then.
+    #[test]
+    fn check_if_hextile_rectangles_are_waited_for() {
+        let (mut client, _) = handshake(b"secret\0\0").unwrap();
+        let rect = protocol::Rect { left: 0, top: 0, width: 32, height: 16 };
+        // No two pixels are alike, so that both tiles are sent raw.
+        let pixels = (0..32 * 16u32).flat_map(|index| vec![index as u8, (index >> 8) as u8, 0, 0])
+                                    .collect::<Vec<_>>();
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 32, 0, 16, 0, 0, 0, 5];
+        hextile::encode(pixel_format::RGB8888, rect, &pixels, &mut data);
+        let second_tile = 16 + 1 + 16 * 16 * 4;
+        assert_eq!(data.len(), second_tile * 2 - 16);
+
+        for &byte in &data[..second_tile + 1] {
+            client.receive(&[byte]).unwrap();
+        }
+        assert_eq!(client.bytes_needed(), data.len() - second_tile - 1);
+        assert!(client.next_event().is_none());
+
+        client.receive(&data[second_tile + 1..]).unwrap();
+        for &left in &[0, 16] {
+            match client.next_event() {
+                Some(client::Event::PutPixels(tile, _)) => assert_eq!(tile.left, left),
+                event => panic!("unexpected event {:?}", event)
+            }
+        }
+        match client.next_event() {
+            Some(client::Event::EndOfFrame) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+
+    /// Checks if updates with rectangles in encodings the client has not requested are
+    /// refused as a whole, leaving nothing of them to be sent.
+    #[test]
+    fn check_if_unrequested_encodings_are_refused() {
+        let (mut client, mut server) = handshake(b"secret\0\0").unwrap();
+        let pixels = [0; 4];
+        let mut send = |encodings: &[protocol::Encoding]| {
+            client.send(&protocol::C2S::SetEncodings(encodings.to_vec())).unwrap();
+            exchange(&mut client, &mut server).unwrap();
+            while server.next_event().is_some() {}
+            let update = {
+                let mut builder = server.create_update();
+                builder.add_raw_pixels(protocol::Rect { left: 0, top: 0, width: 1, height: 1 },
+                                       &pixels)
+                       .add_copy_rect(protocol::Rect { left: 0, top: 0, width: 1, height: 1 },
+                                      1, 0)
+                       .add_compressed_pixels(protocol::Rect { left: 1, top: 0, width: 1,
+                                                               height: 1 }, &[0]);
+                builder.done()
+            };
+            let result = server.send_update(&update);
+            (result, server.take_output())
+        };
+
+        for encodings in &[&[protocol::Encoding::Raw][..],
+                           &[protocol::Encoding::CopyRect][..],
+                           &[protocol::Encoding::Zrle][..]] {
+            match send(encodings) {
+                (Err(Error::Unexpected(_)), ref output) if output.is_empty() => (),
+                result => panic!("{:?}: unexpected result {:?}", encodings, result)
+            }
+        }
+        let (result, output) = send(&[protocol::Encoding::CopyRect, protocol::Encoding::Zrle]);
+        result.unwrap();
+        assert_eq!(&output[..4], &[0, 0, 0, 3]);
+    }
+
+    /// Checks if a pixel format whose components do not fit in its pixels is rejected
+    /// rather than used for encoding.
+    #[test]
+    fn check_if_invalid_pixel_format_is_rejected() {
+        let (_, mut server) = handshake(b"secret\0\0").unwrap();
+        match server.receive(&[0, 0, 0, 0, 32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 0, 8, 40,
+                               0, 0, 0]) {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        assert_eq!(server.pixel_format(), pixel_format::RGB8888);
+    }
+
+    /// Checks if clipboard lengths beyond the limit are rejected before being allocated,
+    /// in both the Latin-1 and the Extended Clipboard form.
+    #[test]
+    fn check_if_oversized_clipboard_is_rejected() {
+        for length in &[[0x7f, 0xff, 0xff, 0xff], [0x80, 0x00, 0x00, 0x00]] {
+            let (_, mut server) = handshake(b"secret\0\0").unwrap();
+            match server.receive(&[6, 0, 0, 0, length[0], length[1], length[2], length[3]]) {
+                Err(Error::Unexpected(_)) => (),
+                result => panic!("unexpected result {:?}", result)
+            }
+        }
+    }
+
+    /// Checks if the proxy only offers the security types it can relay, and forwards
+    /// messages in both directions once the handshake is complete.
+    #[test]
+    fn check_if_proxy_connection_relays_messages() {
+        let mut client = ClientConnection::new(true);
+        let mut proxy = ProxyConnection::new(vec![protocol::SecurityType::None]);
+        let mut server = ServerConnection::new(
+            2, 1, pixel_format::RGB8888, String::from("test"),
+            vec![protocol::SecurityType::VncAuthentication, protocol::SecurityType::None]);
+        exchange_through(&mut client, &mut proxy, &mut server).unwrap();
+        assert_eq!(client.security_types(), &[protocol::SecurityType::None]);
+
+        client.choose_security(protocol::SecurityType::None).unwrap();
+        exchange_through(&mut client, &mut proxy, &mut server).unwrap();
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(proxy.state(), ProxyState::Connected);
+        assert_eq!(server.state(), ServerState::Connected);
+        assert_eq!(proxy.security_type(), Some(protocol::SecurityType::None));
+
+        client.send(&protocol::C2S::KeyEvent { down: true, key: 0x61 }).unwrap();
+        exchange_through(&mut client, &mut proxy, &mut server).unwrap();
+        match server.next_event() {
+            Some(server::Event::KeyEvent { down: true, key: 0x61 }) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+
+        let pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        let update = {
+            let mut builder = server.create_update();
+            builder.add_raw_pixels(protocol::Rect { left: 0, top: 0, width: 2, height: 1 },
+                                   &pixels);
+            builder.done()
+        };
+        server.send_update(&update).unwrap();
+        exchange_through(&mut client, &mut proxy, &mut server).unwrap();
+        match client.next_event() {
+            Some(client::Event::PutPixels(_, ref data)) if data[..] == pixels[..] => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+
+    /// Checks if the client is told why the handshake failed when none of the security
+    /// types of the server can be relayed.
+    #[test]
+    fn check_if_proxy_connection_refuses_unrelayable_security() {
+        let mut client = ClientConnection::new(true);
+        let mut proxy = ProxyConnection::new(vec![protocol::SecurityType::None]);
+        let mut server = ServerConnection::new(
+            2, 1, pixel_format::RGB8888, String::from("test"),
+            vec![protocol::SecurityType::VncAuthentication]);
+        match exchange_through(&mut client, &mut proxy, &mut server) {
+            Err(Error::AuthenticationUnavailable) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        match client.receive(&proxy.take_client_output()) {
+            Err(Error::Server(_)) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+}
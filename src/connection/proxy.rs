@@ -0,0 +1,330 @@
+use std::mem;
+use ::{protocol, pixel_format, Error, Result};
+use proxy::{forward_c2s_message, forward_s2c_message};
+use super::Buffer;
+
+/// What a `ProxyConnection` is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyState {
+    /// More data has to be received from the server.
+    ReceivingFromServer,
+    /// More data has to be received from the client.
+    ReceivingFromClient,
+    /// The client has chosen a security type whose handshake has to be relayed over
+    /// the transports with `SecurityHandler::relay`, followed by a call to `finish_security`.
+    Relaying(protocol::SecurityType),
+    /// The handshake is complete; messages are forwarded in both directions.
+    Connected,
+}
+
+#[derive(Debug)]
+enum State {
+    ServerVersion,
+    ClientVersion,
+    SecurityTypes,
+    SecurityReason,
+    SecurityType,
+    Relaying(protocol::SecurityType),
+    SecurityResult,
+    FailureReason,
+    ClientInit,
+    ServerInit,
+    Connected,
+}
+
+/// A proxy between a client and a server, independent of the transports.
+///
+/// The handshake is relayed as it is, except that only the security types the proxy can
+/// relay are offered to the client. Afterwards, messages are forwarded one at a time, and
+/// encodings the proxy cannot parse are left out of `SetEncodings` messages.
+pub struct ProxyConnection {
+    // The data received from and to be sent to either side.
+    server:  Buffer,
+    client:  Buffer,
+    state:   State,
+    security_types: Vec<protocol::SecurityType>,
+    version: Option<protocol::Version>,
+    security_type: Option<protocol::SecurityType>,
+    format:  protocol::PixelFormat,
+}
+
+impl ProxyConnection {
+    /// Constructs a connection that waits for the server to announce its version, and relays
+    /// the security types among `security_types` that the server offers.
+    pub fn new(security_types: Vec<protocol::SecurityType>) -> ProxyConnection {
+        ProxyConnection {
+            server:  Buffer::new(),
+            client:  Buffer::new(),
+            state:   State::ServerVersion,
+            security_types: security_types,
+            version: None,
+            security_type: None,
+            format:  pixel_format::RGB8888,
+        }
+    }
+
+    pub fn state(&self) -> ProxyState {
+        match self.state {
+            State::ClientVersion | State::SecurityType | State::ClientInit =>
+                ProxyState::ReceivingFromClient,
+            State::Relaying(security_type) => ProxyState::Relaying(security_type),
+            State::Connected => ProxyState::Connected,
+            _ => ProxyState::ReceivingFromServer
+        }
+    }
+
+    /// Returns the protocol version used by the client, once it has announced it.
+    pub fn version(&self) -> Option<protocol::Version> { self.version }
+
+    /// Returns the security type chosen by the client.
+    pub fn security_type(&self) -> Option<protocol::SecurityType> { self.security_type }
+
+    /// Returns the format of the pixels sent by the server.
+    pub fn format(&self) -> protocol::PixelFormat { self.format }
+
+    /// Processes `data` received from the server.
+    ///
+    /// An error means that the connection cannot continue; anything the client should still
+    /// be told is left in the output for it.
+    pub fn receive_from_server(&mut self, data: &[u8]) -> Result<()> {
+        self.server.receive(data);
+        self.process()
+    }
+
+    /// Processes `data` received from the client.
+    pub fn receive_from_client(&mut self, data: &[u8]) -> Result<()> {
+        self.client.receive(data);
+        self.process()
+    }
+
+    /// Returns the number of bytes that have to be received from the server before any
+    /// progress can be made.
+    pub fn server_bytes_needed(&self) -> usize {
+        self.server.bytes_needed()
+    }
+
+    /// Returns the number of bytes that have to be received from the client before any
+    /// progress can be made.
+    pub fn client_bytes_needed(&self) -> usize {
+        self.client.bytes_needed()
+    }
+
+    /// Takes the data to be sent to the server.
+    pub fn take_server_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.server.output, Vec::new())
+    }
+
+    /// Takes the data to be sent to the client.
+    pub fn take_client_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.client.output, Vec::new())
+    }
+
+    /// Resumes the handshake once the security handshake has been relayed.
+    /// `sends_security_result` comes from `SecurityHandler::sends_security_result`.
+    pub fn finish_security(&mut self, sends_security_result: bool) -> Result<()> {
+        match self.state {
+            State::Relaying(_) => (),
+            _ => return Err(Error::Unexpected("security handshake finished out of order"))
+        }
+        self.state = if sends_security_result { State::SecurityResult } else { State::ClientInit };
+        self.process()
+    }
+
+    fn choose_security(&mut self, security_type: protocol::SecurityType) -> Result<()> {
+        self.security_type = Some(security_type);
+        self.state = State::Relaying(security_type);
+        if security_type == protocol::SecurityType::None {
+            let sends_security_result = self.version == Some(protocol::Version::Rfb38);
+            try!(self.finish_security(sends_security_result));
+        }
+        Ok(())
+    }
+
+    // Tells the client that none of the security types of the server can be relayed.
+    fn refuse_security(&mut self) -> Result<bool> {
+        let reason = String::from("no security type offered by the server can be relayed");
+        debug!("c<-! {:?}", reason);
+        try!(self.client.queue(&reason));
+        Err(Error::AuthenticationUnavailable)
+    }
+
+    fn process(&mut self) -> Result<()> {
+        while try!(self.step()) {}
+        Ok(())
+    }
+
+    // Makes one step of progress, and returns whether more steps can be made.
+    fn step(&mut self) -> Result<bool> {
+        match self.state {
+            State::ServerVersion => {
+                let version = match try!(self.server.parse_message::<protocol::Version>()) {
+                    Some(version) => version,
+                    None => return Ok(false)
+                };
+                debug!("c<-s {:?}", version);
+                try!(self.client.queue(&version));
+                self.state = State::ClientVersion;
+            }
+            State::ClientVersion => {
+                let version = match try!(self.client.parse_message::<protocol::Version>()) {
+                    Some(version) => version,
+                    None => return Ok(false)
+                };
+                debug!("c->s {:?}", version);
+                try!(self.server.queue(&version));
+                self.version = Some(version);
+                self.state = State::SecurityTypes;
+            }
+            State::SecurityTypes => {
+                let supported = |security_types: &[protocol::SecurityType],
+                                 security_type: &protocol::SecurityType| {
+                    if security_types.contains(security_type) {
+                        true
+                    } else {
+                        warn!("security type {:?} is not supported", security_type);
+                        false
+                    }
+                };
+
+                if self.version == Some(protocol::Version::Rfb33) {
+                    let security_type =
+                        match try!(self.server.parse_message::<protocol::SecurityType>()) {
+                            Some(security_type) => security_type,
+                            None => return Ok(false)
+                        };
+                    debug!("!<-s SecurityType::{:?}", security_type);
+                    if security_type == protocol::SecurityType::Invalid {
+                        try!(self.client.queue(&security_type));
+                        self.state = State::SecurityReason;
+                    } else if supported(&self.security_types, &security_type) {
+                        try!(self.client.queue(&security_type));
+                        try!(self.choose_security(security_type));
+                    } else {
+                        try!(self.client.queue(&protocol::SecurityType::Invalid));
+                        return self.refuse_security()
+                    }
+                } else {
+                    let mut security_types =
+                        match try!(self.server.parse_message::<protocol::SecurityTypes>()) {
+                            Some(security_types) => security_types,
+                            None => return Ok(false)
+                        };
+                    debug!("!<-s {:?}", security_types);
+                    let offered = security_types.0.len();
+                    {
+                        let relayed = &self.security_types;
+                        security_types.0.retain(|security_type|
+                            supported(relayed, security_type));
+                    }
+                    debug!("c<-! {:?}", security_types);
+                    try!(self.client.queue(&security_types));
+                    if offered == 0 {
+                        self.state = State::SecurityReason;
+                    } else if security_types.0.len() == 0 {
+                        return self.refuse_security()
+                    } else {
+                        self.security_types = security_types.0;
+                        self.state = State::SecurityType;
+                    }
+                }
+            }
+            State::SecurityReason => {
+                let reason = match try!(self.server.parse_message::<String>()) {
+                    Some(reason) => reason,
+                    None => return Ok(false)
+                };
+                debug!("c<-s {:?}", reason);
+                try!(self.client.queue(&reason));
+                return Err(Error::Server(reason))
+            }
+            State::SecurityType => {
+                let security_type =
+                    match try!(self.client.parse_message::<protocol::SecurityType>()) {
+                        Some(security_type) => security_type,
+                        None => return Ok(false)
+                    };
+                debug!("c->s SecurityType::{:?}", security_type);
+                if !self.security_types.contains(&security_type) {
+                    return Err(Error::Unexpected("security type"))
+                }
+                try!(self.server.queue(&security_type));
+                try!(self.choose_security(security_type));
+            }
+            State::Relaying(_) => return Ok(false),
+            State::SecurityResult => {
+                let security_result =
+                    match try!(self.server.parse_message::<protocol::SecurityResult>()) {
+                        Some(security_result) => security_result,
+                        None => return Ok(false)
+                    };
+                debug!("c<-s SecurityResult::{:?}", security_result);
+                try!(self.client.queue(&security_result));
+                match security_result {
+                    protocol::SecurityResult::Succeeded =>
+                        self.state = State::ClientInit,
+                    protocol::SecurityResult::Failed => {
+                        if self.version != Some(protocol::Version::Rfb38) {
+                            return Err(Error::AuthenticationFailure(String::from("")))
+                        }
+                        self.state = State::FailureReason;
+                    }
+                }
+            }
+            State::FailureReason => {
+                let reason = match try!(self.server.parse_message::<String>()) {
+                    Some(reason) => reason,
+                    None => return Ok(false)
+                };
+                debug!("c<-s {:?}", reason);
+                try!(self.client.queue(&reason));
+                return Err(Error::AuthenticationFailure(reason))
+            }
+            State::ClientInit => {
+                let client_init = match try!(self.client.parse_message::<protocol::ClientInit>()) {
+                    Some(client_init) => client_init,
+                    None => return Ok(false)
+                };
+                debug!("c->s {:?}", client_init);
+                try!(self.server.queue(&client_init));
+                self.state = State::ServerInit;
+            }
+            State::ServerInit => {
+                let server_init = match try!(self.server.parse_message::<protocol::ServerInit>()) {
+                    Some(server_init) => server_init,
+                    None => return Ok(false)
+                };
+                debug!("c<-s {:?}", server_init);
+                // The length of raw pixel data depends on the format.
+                try!(pixel_format::validate(&server_init.pixel_format));
+                try!(self.client.queue(&server_init));
+                self.format = server_init.pixel_format;
+                self.state = State::Connected;
+            }
+            State::Connected => return self.forward()
+        }
+        Ok(true)
+    }
+
+    // Forwards the next message in either direction, if there is one.
+    fn forward(&mut self) -> Result<bool> {
+        let mut progress = false;
+        if let Some(message) = try!(self.client.parse(|reader| {
+            let mut message = Vec::new();
+            try!(forward_c2s_message(reader, &mut message));
+            Ok(message)
+        })) {
+            self.server.output.extend_from_slice(&message);
+            progress = true;
+        }
+        let format = self.format;
+        if let Some(message) = try!(self.server.parse(|reader| {
+            let mut message = Vec::new();
+            try!(forward_s2c_message(reader, &mut message, format));
+            Ok(message)
+        })) {
+            self.client.output.extend_from_slice(&message);
+            progress = true;
+        }
+        Ok(progress)
+    }
+}
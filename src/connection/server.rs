@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::mem;
+use ::{protocol, pixel_format, zrle, Error, Result};
+use security::Access;
+use encoder::{Encoder, EncoderRegistry, HextileEncoder, RreEncoder};
+use server::{Event, FramebufferUpdate, FramebufferUpdateBuilder, ValidationData,
+             VncAuthentication, random_challenge};
+use super::Buffer;
+
+/// What a `ServerConnection` is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    /// More data has to be received from the client.
+    Handshaking,
+    /// The client has chosen a security type whose handshake has to be performed, either
+    /// over the transport followed by a call to `finish_security`, or with
+    /// `start_vnc_authentication`.
+    Authenticating(protocol::SecurityType),
+    /// The handshake is complete; events can be received and messages sent.
+    Connected,
+}
+
+enum State {
+    Version,
+    SecurityType,
+    Authenticating(protocol::SecurityType),
+    Response([u8; 16], VncAuthentication),
+    ClientInit,
+    Connected,
+}
+
+/// The server side of an RFB connection, independent of the transport.
+pub struct ServerConnection {
+    buffer:  Buffer,
+    state:   State,
+    version: Option<protocol::Version>,
+    security_types: Vec<protocol::SecurityType>,
+    security_type:  Option<protocol::SecurityType>,
+    access:  Option<Access>,
+    shared:  bool,
+    server_init: protocol::ServerInit,
+    pixel_format: protocol::PixelFormat,
+    validation_data: ValidationData,
+    encodings: Vec<protocol::Encoding>,
+    encoders: EncoderRegistry,
+    events:  VecDeque<Event>,
+}
+
+impl ServerConnection {
+    /// Constructs a connection that announces the server version, and offers
+    /// `security_types` to the client in order of preference.
+    ///
+    /// Clients using RFB 3.3 cannot choose a security type, so the first one supported by that
+    /// version is used for them.
+    pub fn new(width: u16,
+               height: u16,
+               pixel_format: protocol::PixelFormat,
+               name: String,
+               security_types: Vec<protocol::SecurityType>)
+               -> ServerConnection {
+        let mut buffer = Buffer::new();
+        // Send highest supported version. Client may respond with lower version but never
+        // higher. Writing into a vector cannot fail.
+        buffer.queue(&protocol::Version::Rfb38).unwrap();
+        ServerConnection {
+            buffer:  buffer,
+            state:   State::Version,
+            version: None,
+            security_types: security_types,
+            security_type:  None,
+            access:  None,
+            shared:  false,
+            server_init: protocol::ServerInit {
+                framebuffer_width: width,
+                framebuffer_height: height,
+                pixel_format: pixel_format,
+                name: name,
+            },
+            pixel_format: pixel_format,
+            validation_data: ValidationData::new(&pixel_format),
+            encodings: Vec::new(),
+            encoders: {
+                let mut encoders = EncoderRegistry::new();
+                encoders.register(zrle::Encoder::new())
+                        .register(HextileEncoder)
+                        .register(RreEncoder);
+                encoders
+            },
+            events:  VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> ServerState {
+        match self.state {
+            State::Authenticating(security_type) => ServerState::Authenticating(security_type),
+            State::Connected => ServerState::Connected,
+            _ => ServerState::Handshaking
+        }
+    }
+
+    /// Returns the protocol version used by the client, once it has announced it.
+    pub fn version(&self) -> Option<protocol::Version> { self.version }
+
+    /// Returns the security type chosen by the client.
+    pub fn security_type(&self) -> Option<protocol::SecurityType> { self.security_type }
+
+    /// Returns the access granted to the client, once it has been authenticated.
+    pub fn access(&self) -> Option<Access> { self.access }
+
+    /// Returns `true` if the client has authenticated with a view-only password.
+    pub fn is_view_only(&self) -> bool { self.access == Some(Access::ViewOnly) }
+
+    /// Makes the client view-only, or lets it interact with the desktop again, regardless of
+    /// the password it has authenticated with.
+    pub fn set_view_only(&mut self, view_only: bool) {
+        self.access = Some(if view_only { Access::ViewOnly } else { Access::Full });
+    }
+
+    /// Returns the `shared` flag sent by the client.
+    pub fn shared(&self) -> bool { self.shared }
+
+    /// Returns the format in which pixel values are sent to the client.
+    pub fn pixel_format(&self) -> protocol::PixelFormat { self.pixel_format }
+
+    /// Adds `encoder` to the encoders that pixel data can be sent with, replacing any encoder
+    /// for the same encoding. Raw, ZRLE, Hextile and RRE encoders are registered from the start.
+    pub fn register_encoder<E: Encoder + 'static>(&mut self, encoder: E) {
+        self.encoders.register(encoder);
+    }
+
+    /// Returns the encodings last requested by the client.
+    pub fn encodings(&self) -> &[protocol::Encoding] { &self.encodings }
+
+    /// Processes `data` received from the client.
+    ///
+    /// An error means that the connection cannot continue; anything the client should still
+    /// be told is left in the output.
+    pub fn receive(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.receive(data);
+        self.process()
+    }
+
+    /// Returns the number of bytes that have to be received before any progress can be made.
+    pub fn bytes_needed(&self) -> usize {
+        self.buffer.bytes_needed()
+    }
+
+    /// Takes the data to be sent to the client.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.buffer.output, Vec::new())
+    }
+
+    /// Returns the next event received from the client.
+    ///
+    /// Keyboard, pointer, clipboard and desktop size events sent by view-only clients are
+    /// discarded.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Performs VNC authentication, which must be the chosen security type, with
+    /// the passwords in `authentication`.
+    pub fn start_vnc_authentication(&mut self, authentication: VncAuthentication) -> Result<()> {
+        match self.state {
+            State::Authenticating(protocol::SecurityType::VncAuthentication) => (),
+            _ => return Err(Error::Unexpected("VNC authentication started out of order"))
+        }
+        let challenge = try!(random_challenge());
+        self.buffer.output.extend_from_slice(&challenge);
+        self.state = State::Response(challenge, authentication);
+        self.process()
+    }
+
+    /// Resumes the handshake once the security type has been handled over the transport.
+    ///
+    /// `access` is the outcome of the authentication, and `sends_security_result` comes from
+    /// `SecurityHandler::sends_security_result`. If the client could not be authenticated,
+    /// `Error::AuthenticationFailure` is returned after queueing the reason for the client.
+    pub fn finish_security(&mut self, access: Option<Access>,
+                           sends_security_result: bool) -> Result<()> {
+        match self.state {
+            State::Authenticating(_) | State::Response(..) => (),
+            _ => return Err(Error::Unexpected("security handshake finished out of order"))
+        }
+        match access {
+            Some(access) => {
+                if sends_security_result {
+                    try!(self.buffer.queue(&protocol::SecurityResult::Succeeded));
+                }
+                self.access = Some(access);
+                self.state = State::ClientInit;
+                self.process()
+            }
+            None => {
+                let reason = String::from("authentication failed");
+                try!(self.buffer.queue(&protocol::SecurityResult::Failed));
+                if self.version == Some(protocol::Version::Rfb38) {
+                    try!(self.buffer.queue(&reason));
+                }
+                Err(Error::AuthenticationFailure(reason))
+            }
+        }
+    }
+
+    fn choose_security(&mut self, security_type: protocol::SecurityType) -> Result<()> {
+        self.security_type = Some(security_type);
+        self.state = State::Authenticating(security_type);
+        if security_type == protocol::SecurityType::None {
+            let sends_security_result = self.version == Some(protocol::Version::Rfb38);
+            try!(self.finish_security(Some(Access::Full), sends_security_result));
+        }
+        Ok(())
+    }
+
+    fn process(&mut self) -> Result<()> {
+        while try!(self.step()) {}
+        Ok(())
+    }
+
+    // Makes one step of progress, and returns whether more steps can be made.
+    fn step(&mut self) -> Result<bool> {
+        match self.state {
+            State::Version => {
+                let version = match try!(self.buffer.parse_message::<protocol::Version>()) {
+                    Some(version) => version,
+                    None => return Ok(false)
+                };
+                self.version = Some(version);
+
+                match version {
+                    protocol::Version::Rfb33 => {
+                        let security_type = self.security_types.iter().cloned().find(|&t| {
+                            t == protocol::SecurityType::None ||
+                            t == protocol::SecurityType::VncAuthentication
+                        });
+                        match security_type {
+                            Some(security_type) => {
+                                try!(self.buffer.queue(&security_type));
+                                try!(self.choose_security(security_type));
+                            }
+                            None => {
+                                try!(self.buffer.queue(&protocol::SecurityType::Invalid));
+                                try!(self.buffer.queue(&String::from(
+                                    "no security type supported by RFB 3.3 is enabled")));
+                                return Err(Error::AuthenticationUnavailable)
+                            }
+                        }
+                    }
+                    _ => {
+                        let security_types = protocol::SecurityTypes(self.security_types.clone());
+                        try!(self.buffer.queue(&security_types));
+                        self.state = State::SecurityType;
+                    }
+                }
+            }
+            State::SecurityType => {
+                let security_type =
+                    match try!(self.buffer.parse_message::<protocol::SecurityType>()) {
+                        Some(security_type) => security_type,
+                        None => return Ok(false)
+                    };
+                if !self.security_types.contains(&security_type) {
+                    return Err(Error::Unexpected("security type"))
+                }
+                try!(self.choose_security(security_type));
+            }
+            State::Authenticating(_) => return Ok(false),
+            State::Response(challenge, _) => {
+                let response = match try!(self.buffer.parse(|reader| {
+                    let mut response = [0; 16];
+                    try!(reader.read_exact(&mut response));
+                    Ok(response)
+                })) {
+                    Some(response) => response,
+                    None => return Ok(false)
+                };
+                let access = match self.state {
+                    State::Response(_, ref authentication) =>
+                        authentication.access(&challenge, &response),
+                    _ => unreachable!()
+                };
+                try!(self.finish_security(access, true));
+            }
+            State::ClientInit => {
+                let client_init = match try!(self.buffer.parse_message::<protocol::ClientInit>()) {
+                    Some(client_init) => client_init,
+                    None => return Ok(false)
+                };
+                self.shared = client_init.shared;
+                try!(self.buffer.queue(&self.server_init));
+                self.state = State::Connected;
+            }
+            State::Connected => {
+                let message = match try!(self.buffer.parse_message::<protocol::C2S>()) {
+                    Some(message) => message,
+                    None => return Ok(false)
+                };
+                let event = Event::from_message(message);
+                match event {
+                    Event::SetPixelFormat(pixel_format) => {
+                        // Encoders and converters rely on the components fitting in
+                        // the pixels.
+                        try!(pixel_format::validate(&pixel_format));
+                        // Update bytes per pixel number. Server must obey this message and from
+                        // now send data in format requested by client.
+                        self.pixel_format = pixel_format;
+                        self.validation_data.update(&pixel_format);
+                    }
+                    Event::SetEncodings(ref encodings) => {
+                        self.encodings = encodings.clone();
+                    }
+                    _ => ()
+                }
+                if self.is_view_only() && event.changes_state() {
+                    debug!("ignoring {:?} from view-only client", event);
+                } else {
+                    self.events.push_back(event);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Constructs new `FramebufferUpdateBuilder` structure used to build `FramebufferUpdate`
+    /// message.
+    pub fn create_update<'a, 'b>(&'b self) -> FramebufferUpdateBuilder<'a, 'b> {
+        FramebufferUpdateBuilder::new(&self.validation_data)
+    }
+
+    /// Queues `FramebufferUpdate` message.
+    pub fn send_update(&mut self, updates: &FramebufferUpdate) -> Result<()> {
+        updates.write_to(&mut self.buffer.output, &self.pixel_format, &self.encodings,
+                         &mut self.encoders)
+    }
+
+    /// Queues `Fence` message.
+    ///
+    /// Returns `Error::Unexpected` if payload is longer than 64 bytes.
+    pub fn send_fence(&mut self, flags: u32, payload: &[u8]) -> Result<()> {
+        self.buffer.queue(&protocol::S2C::Fence(protocol::Fence {
+            flags: flags,
+            payload: Vec::from(payload),
+        }))
+    }
+
+    /// Queues `ServerCutText` message.
+    ///
+    /// The text must only contain characters from the Latin-1 character set.
+    pub fn send_cut_text(&mut self, text: &str) -> Result<()> {
+        self.buffer.queue(&protocol::S2C::CutText(String::from(text)))
+    }
+
+    /// Queues `ServerCutText` message carrying an Extended Clipboard message.
+    pub fn send_extended_clipboard(&mut self, clipboard: protocol::ExtendedClipboard)
+            -> Result<()> {
+        self.buffer.queue(&protocol::S2C::ExtendedCutText(clipboard))
+    }
+
+    /// Queues `EndOfContinuousUpdates` message.
+    pub fn send_end_of_continuous_updates(&mut self) -> Result<()> {
+        self.buffer.queue(&protocol::S2C::EndOfContinuousUpdates)
+    }
+}
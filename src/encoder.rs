@@ -0,0 +1,187 @@
+use std::fmt;
+use byteorder::{BigEndian, WriteBytesExt};
+use ::{hextile, protocol, rre, zrle};
+
+/// Implementation of an encoding that a server sends rectangles of pixels in.
+///
+/// Encoders may carry state from one rectangle to the next, like the zlib stream of ZRLE, so
+/// every connection has encoders of its own, and rectangles have to be sent in the order
+/// they were encoded in.
+pub trait Encoder: Send {
+    /// Returns the encoding of the rectangles produced by this encoder.
+    fn encoding(&self) -> protocol::Encoding;
+
+    /// Appends the encoded `pixels` of `rect` to `output`, without the rectangle header.
+    /// The pixels are in `format`, row by row and without padding between rows.
+    fn encode(&mut self, format: &protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>);
+}
+
+impl fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Encoder({:?})", self.encoding())
+    }
+}
+
+/// The `Raw` encoding, which every client supports.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawEncoder;
+
+impl Encoder for RawEncoder {
+    fn encoding(&self) -> protocol::Encoding {
+        protocol::Encoding::Raw
+    }
+
+    fn encode(&mut self, _format: &protocol::PixelFormat, _rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+        output.extend_from_slice(pixels);
+    }
+}
+
+impl Encoder for zrle::Encoder {
+    fn encoding(&self) -> protocol::Encoding {
+        protocol::Encoding::Zrle
+    }
+
+    fn encode(&mut self, format: &protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+        let zlib_data = zrle::Encoder::encode(self, *format, rect, pixels);
+        output.write_u32::<BigEndian>(zlib_data.len() as u32).unwrap();
+        output.extend_from_slice(&zlib_data);
+    }
+}
+
+/// The `Hextile` encoding, which sends every 16x16 tile as solid-colour subrectangles or as
+/// raw pixels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HextileEncoder;
+
+impl Encoder for HextileEncoder {
+    fn encoding(&self) -> protocol::Encoding {
+        protocol::Encoding::Hextile
+    }
+
+    fn encode(&mut self, format: &protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+        hextile::encode(*format, rect, pixels, output)
+    }
+}
+
+/// The `RRE` encoding, which sends a rectangle as its background colour and solid-colour
+/// subrectangles. It suits areas of few colours, and is supported by most legacy viewers.
+///
+/// Areas that take more bytes as RRE than as raw pixels are sent as `Raw` instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RreEncoder;
+
+impl Encoder for RreEncoder {
+    fn encoding(&self) -> protocol::Encoding {
+        protocol::Encoding::Rre
+    }
+
+    fn encode(&mut self, format: &protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+        rre::encode(*format, rect, pixels, output)
+    }
+}
+
+/// Encoders available to a connection.
+///
+/// Every rectangle is sent in the encoding the client prefers most among those that have
+/// an encoder, or in `Raw` if there is none, which is why `Raw` is always registered.
+#[derive(Debug)]
+pub struct EncoderRegistry {
+    encoders: Vec<Box<Encoder>>,
+    /// Encodings that rectangles have been encoded in so far.
+    used: Vec<protocol::Encoding>,
+}
+
+impl EncoderRegistry {
+    pub fn new() -> EncoderRegistry {
+        EncoderRegistry { encoders: vec![Box::new(RawEncoder)], used: Vec::new() }
+    }
+
+    /// Adds `encoder`, replacing any encoder registered earlier for the same encoding.
+    pub fn register<E: Encoder + 'static>(&mut self, encoder: E) -> &mut Self {
+        self.register_boxed(Box::new(encoder))
+    }
+
+    pub fn register_boxed(&mut self, encoder: Box<Encoder>) -> &mut Self {
+        let encoding = encoder.encoding();
+        self.encoders.retain(|registered| registered.encoding() != encoding);
+        self.encoders.push(encoder);
+        self
+    }
+
+    /// Removes the encoder for `encoding`, unless it is `Raw`.
+    pub fn unregister(&mut self, encoding: protocol::Encoding) -> &mut Self {
+        if encoding != protocol::Encoding::Raw {
+            self.encoders.retain(|registered| registered.encoding() != encoding);
+        }
+        self
+    }
+
+    /// Returns whether a rectangle has been encoded in `encoding`.
+    pub fn has_encoded(&self, encoding: protocol::Encoding) -> bool {
+        self.used.contains(&encoding)
+    }
+
+    /// Returns the encodings of the registered encoders.
+    pub fn encodings(&self) -> Vec<protocol::Encoding> {
+        self.encoders.iter().map(|encoder| encoder.encoding()).collect()
+    }
+
+    /// Returns the encoder for `preferred` if it is among `encodings`, the encodings requested
+    /// by the client in order of preference, or else the encoder for the first of `encodings`
+    /// that has one, or else the `Raw` encoder.
+    pub fn choose(&mut self, encodings: &[protocol::Encoding],
+                  preferred: Option<protocol::Encoding>) -> &mut Encoder {
+        let index = {
+            let position = |encoding: &protocol::Encoding|
+                self.encoders.iter().position(|encoder| encoder.encoding() == *encoding);
+            preferred.iter().filter(|encoding| encodings.contains(encoding))
+                     .chain(encodings.iter())
+                     .filter_map(&position)
+                     .next()
+                     .or_else(|| position(&protocol::Encoding::Raw))
+                     .expect("Raw encoder")
+        };
+        let encoding = self.encoders[index].encoding();
+        if !self.used.contains(&encoding) {
+            self.used.push(encoding);
+        }
+        &mut *self.encoders[index]
+    }
+}
+
+impl Default for EncoderRegistry {
+    fn default() -> EncoderRegistry {
+        EncoderRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zrle;
+    use protocol::Encoding;
+    use super::EncoderRegistry;
+
+    /// Checks if the encoding preferred by the client is chosen among those that have
+    /// an encoder, and `Raw` if there is none.
+    #[test]
+    fn check_if_encoding_is_chosen_by_client_preference() {
+        let mut encoders = EncoderRegistry::new();
+        encoders.register(zrle::Encoder::new());
+        assert_eq!(encoders.encodings(), vec![Encoding::Raw, Encoding::Zrle]);
+
+        assert_eq!(encoders.choose(&[], Some(Encoding::Zrle)).encoding(), Encoding::Raw);
+        assert_eq!(encoders.choose(&[Encoding::Tight, Encoding::Hextile], None).encoding(),
+                   Encoding::Raw);
+        assert_eq!(encoders.choose(&[Encoding::Tight, Encoding::Zrle, Encoding::Raw], None)
+                           .encoding(), Encoding::Zrle);
+        assert_eq!(encoders.choose(&[Encoding::Raw, Encoding::Zrle], None).encoding(),
+                   Encoding::Raw);
+        assert_eq!(encoders.choose(&[Encoding::Raw, Encoding::Zrle], Some(Encoding::Zrle))
+                           .encoding(), Encoding::Zrle);
+    }
+}
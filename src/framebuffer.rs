@@ -0,0 +1,415 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use protocol::{self, Rect};
+use client::Event;
+use pixel_format::{self, bytes_per_pixel, Converter};
+use ::Colour;
+
+/// Receives the contents of framebuffer updates as they are decoded.
+///
+/// Pixels are in the format negotiated with the server, and are borrowed from the decoder,
+/// so that a sink installed with `Client::set_sink` can copy them straight to their
+/// destination instead of receiving a freshly allocated buffer in every `Event`.
+/// Raw, RRE, Hextile and ZRLE rectangles are decoded into buffers that are reused, but
+/// Tight rectangles are still decoded into buffers of their own. Without a sink, as well
+/// as in `ClientConnection` and the asynchronous client, every tile is copied into the
+/// `Event` it is sent in.
+///
+/// Resizes, format changes and colour map updates are still sent as events as well, since
+/// they also matter to the rest of the application.
+pub trait FramebufferSink {
+    /// Resizes the framebuffer to `width` by `height` pixels; its contents are undefined
+    /// until updated.
+    fn resize(&mut self, width: u16, height: u16);
+
+    /// Switches to `format` for the pixels that follow.
+    fn set_format(&mut self, format: protocol::PixelFormat) {
+        let _ = format;
+    }
+
+    /// Replaces the colour map entries starting at `first_colour`.
+    fn set_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        let _ = (first_colour, colours);
+    }
+
+    /// Replaces the pixels within `rect`, given row by row without padding.
+    fn put_pixels(&mut self, rect: Rect, pixels: &[u8]);
+
+    /// Fills `rect` with the single `pixel`.
+    fn fill_pixels(&mut self, rect: Rect, pixel: &[u8]);
+
+    /// Copies the pixels within `src` to `dst`, which has the same size and may overlap it.
+    fn copy_pixels(&mut self, src: Rect, dst: Rect);
+
+    /// Replaces the cursor shape; `mask_bits` has one bit per pixel, with rows padded
+    /// to whole bytes.
+    fn set_cursor(&mut self, size: (u16, u16), hotspot: (u16, u16),
+                  pixels: &[u8], mask_bits: &[u8]);
+}
+
+/// Lets the sink given to `Client::set_sink` be shared with the rest of the application.
+impl<S: FramebufferSink + ?Sized> FramebufferSink for Arc<Mutex<S>> {
+    fn resize(&mut self, width: u16, height: u16) {
+        self.lock().unwrap().resize(width, height)
+    }
+
+    fn set_format(&mut self, format: protocol::PixelFormat) {
+        self.lock().unwrap().set_format(format)
+    }
+
+    fn set_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        self.lock().unwrap().set_colour_map(first_colour, colours)
+    }
+
+    fn put_pixels(&mut self, rect: Rect, pixels: &[u8]) {
+        self.lock().unwrap().put_pixels(rect, pixels)
+    }
+
+    fn fill_pixels(&mut self, rect: Rect, pixel: &[u8]) {
+        self.lock().unwrap().fill_pixels(rect, pixel)
+    }
+
+    fn copy_pixels(&mut self, src: Rect, dst: Rect) {
+        self.lock().unwrap().copy_pixels(src, dst)
+    }
+
+    fn set_cursor(&mut self, size: (u16, u16), hotspot: (u16, u16),
+                  pixels: &[u8], mask_bits: &[u8]) {
+        self.lock().unwrap().set_cursor(size, hotspot, pixels, mask_bits)
+    }
+}
+
+/// An image of the remote framebuffer, kept up to date by applying the events received
+/// from the server.
+///
+/// Pixels are stored as received, in the format negotiated with the server. Regions that
+/// changed are accumulated until `Event::EndOfFrame`, at which point `handle_event` returns
+/// a rectangle covering all of them.
+///
+/// It can also be installed as the `FramebufferSink` of a `Client`, shared behind
+/// an `Arc<Mutex<_>>`, in which case `take_damage` returns that rectangle.
+pub struct Framebuffer {
+    width:  u16,
+    height: u16,
+    format: protocol::PixelFormat,
+    pixels: Vec<u8>,
+    colour_map: Vec<Colour>,
+    damage: Option<Rect>,
+}
+
+impl Framebuffer {
+    /// Constructs a black framebuffer of `width` by `height` pixels in `format`, usually
+    /// `Client::size()` and `Client::format()` right after connecting.
+    ///
+    /// `format` has to be supported by `pixel_format::Converter`, like any format received
+    /// from the server, or converting pixels panics.
+    pub fn new(width: u16, height: u16, format: protocol::PixelFormat) -> Framebuffer {
+        Framebuffer {
+            width:  width,
+            height: height,
+            format: format,
+            pixels: vec![0; width as usize * height as usize * bytes_per_pixel(&format)],
+            colour_map: Vec::new(),
+            damage: None,
+        }
+    }
+
+    /// Returns the width and height of the framebuffer.
+    pub fn size(&self) -> (u16, u16) { (self.width, self.height) }
+
+    /// Returns the format of the pixels returned by `pixels`.
+    pub fn format(&self) -> protocol::PixelFormat { self.format }
+
+    /// Returns the pixels, row by row and without padding between rows.
+    pub fn pixels(&self) -> &[u8] { &self.pixels }
+
+    /// Updates the framebuffer with `event`, ignoring events that do not affect it.
+    ///
+    /// Returns the region changed since the previous frame when `event` is
+    /// `Event::EndOfFrame`, or `None` if nothing has changed or the frame has not ended yet.
+    pub fn handle_event(&mut self, event: &Event) -> Option<Rect> {
+        match event {
+            &Event::Resize(width, height) => self.resize(width, height),
+            &Event::SetFormat(format) => self.set_format(format),
+            &Event::SetColourMap { first_colour, ref colours } =>
+                self.set_colour_map(first_colour, colours),
+            &Event::PutPixels(rect, ref pixels) => self.put_pixels(rect, pixels),
+            &Event::FillPixels(rect, ref pixel) => self.fill_pixels(rect, pixel),
+            &Event::CopyPixels { src, dst } => self.copy_pixels(src, dst),
+            &Event::EndOfFrame => return self.take_damage(),
+            _ => ()
+        }
+        None
+    }
+
+    /// Returns the region changed since the last call, or `None` if nothing has changed.
+    ///
+    /// This is what `handle_event` returns at the end of a frame, for framebuffers that
+    /// are updated as a `FramebufferSink`.
+    pub fn take_damage(&mut self) -> Option<Rect> {
+        self.damage.take()
+    }
+
+    /// Returns the whole framebuffer as RGBA, with 4 bytes per pixel and an opaque alpha.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.rect_to_rgba(Rect::new(0, 0, self.width, self.height))
+    }
+
+    /// Returns the part of the framebuffer within `rect`, clipped to the framebuffer,
+    /// as RGBA with 4 bytes per pixel and an opaque alpha.
+    pub fn rect_to_rgba(&self, rect: Rect) -> Vec<u8> {
+        let rect = self.clip(rect);
+        let converter = self.converter(RGBA);
+        let mut rgba = Vec::with_capacity(rect.width as usize * rect.height as usize * 4);
+        for row in 0..rect.height {
+            let start = self.offset(rect.left, rect.top + row);
+            let end = start + rect.width as usize * bytes_per_pixel(&self.format);
+            converter.convert(&self.pixels[start..end], &mut rgba);
+        }
+        for pixel in rgba.chunks_mut(4) {
+            pixel[3] = 255;
+        }
+        rgba
+    }
+
+    fn converter(&self, format: protocol::PixelFormat) -> Converter {
+        // Formats received from the server, or set with `Client::set_format`, have been
+        // validated already.
+        let mut converter = Converter::new(self.format, format).expect("invalid pixel format");
+        converter.set_source_colour_map(0, &self.colour_map);
+        converter
+    }
+
+    fn offset(&self, x: u16, y: u16) -> usize {
+        (y as usize * self.width as usize + x as usize) * bytes_per_pixel(&self.format)
+    }
+
+    fn clip(&self, rect: Rect) -> Rect {
+        let left = cmp::min(rect.left, self.width);
+        let top = cmp::min(rect.top, self.height);
+        let right = cmp::min(rect.left as u32 + rect.width as u32, self.width as u32) as u16;
+        let bottom = cmp::min(rect.top as u32 + rect.height as u32, self.height as u32) as u16;
+        Rect::new(left, top, right - left, bottom - top)
+    }
+
+    fn add_damage(&mut self, rect: Rect) {
+        if rect.width == 0 || rect.height == 0 { return }
+        self.damage = Some(match self.damage {
+            None => rect,
+            Some(damage) => {
+                let left = cmp::min(damage.left, rect.left);
+                let top = cmp::min(damage.top, rect.top);
+                let right = cmp::max(damage.left + damage.width, rect.left + rect.width);
+                let bottom = cmp::max(damage.top + damage.height, rect.top + rect.height);
+                Rect::new(left, top, right - left, bottom - top)
+            }
+        })
+    }
+
+    fn damage_all(&mut self) {
+        let (width, height) = (self.width, self.height);
+        self.add_damage(Rect::new(0, 0, width, height));
+    }
+}
+
+impl FramebufferSink for Framebuffer {
+    fn resize(&mut self, width: u16, height: u16) {
+        *self = Framebuffer {
+            colour_map: self.colour_map.split_off(0),
+            ..Framebuffer::new(width, height, self.format)
+        };
+        self.damage_all();
+    }
+
+    fn set_format(&mut self, format: protocol::PixelFormat) {
+        let pixels = self.converter(format).convert_to_vec(&self.pixels);
+        self.format = format;
+        self.pixels = pixels;
+        self.damage_all();
+    }
+
+    fn set_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        let end = first_colour as usize + colours.len();
+        if self.colour_map.len() < end {
+            self.colour_map.resize(end, Colour { red: 0, green: 0, blue: 0 });
+        }
+        self.colour_map[first_colour as usize..end].copy_from_slice(colours);
+        if !self.format.true_colour {
+            self.damage_all();
+        }
+    }
+
+    fn put_pixels(&mut self, rect: Rect, pixels: &[u8]) {
+        let bytes_per_pixel = bytes_per_pixel(&self.format);
+        if pixels.len() < rect.width as usize * rect.height as usize * bytes_per_pixel {
+            warn!("ignoring truncated pixels for {:?}", rect);
+            return
+        }
+        let clipped = self.clip(rect);
+        let source_stride = rect.width as usize * bytes_per_pixel;
+        for row in 0..clipped.height as usize {
+            let source = row * source_stride;
+            let target = self.offset(clipped.left, clipped.top + row as u16);
+            let length = clipped.width as usize * bytes_per_pixel;
+            self.pixels[target..target + length]
+                .copy_from_slice(&pixels[source..source + length]);
+        }
+        self.add_damage(clipped);
+    }
+
+    fn fill_pixels(&mut self, rect: Rect, pixel: &[u8]) {
+        if pixel.len() != bytes_per_pixel(&self.format) {
+            warn!("ignoring fill of {:?} with malformed pixel", rect);
+            return
+        }
+        let clipped = self.clip(rect);
+        for row in 0..clipped.height {
+            let target = self.offset(clipped.left, clipped.top + row);
+            let length = clipped.width as usize * pixel.len();
+            for chunk in self.pixels[target..target + length].chunks_mut(pixel.len()) {
+                chunk.copy_from_slice(pixel);
+            }
+        }
+        self.add_damage(clipped);
+    }
+
+    fn copy_pixels(&mut self, src: Rect, dst: Rect) {
+        let width = cmp::min(self.clip(src).width, self.clip(dst).width);
+        let height = cmp::min(self.clip(src).height, self.clip(dst).height);
+        if width == 0 || height == 0 { return }
+        let length = width as usize * bytes_per_pixel(&self.format);
+        // The regions may overlap, so copy the rows in the direction that reads
+        // every row before it is overwritten.
+        let rows: Vec<u16> = if src.top < dst.top {
+            (0..height).rev().collect()
+        } else {
+            (0..height).collect()
+        };
+        for row in rows {
+            let source = self.offset(src.left, src.top + row);
+            let target = self.offset(dst.left, dst.top + row);
+            self.pixels.copy_within(source..source + length, target);
+        }
+        self.add_damage(Rect::new(dst.left, dst.top, width, height));
+    }
+
+    /// The cursor is not part of the framebuffer, and is ignored.
+    fn set_cursor(&mut self, _size: (u16, u16), _hotspot: (u16, u16),
+                  _pixels: &[u8], _mask_bits: &[u8]) {}
+}
+
+// Red, green, blue and unused bytes, in that order.
+const RGBA: protocol::PixelFormat = protocol::PixelFormat {
+    big_endian: false,
+    ..pixel_format::RGB8888
+};
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use ::{client, pixel_format, protocol, server, Colour};
+    use protocol::Rect;
+    use super::Framebuffer;
+
+    /// Checks if pixels are put, filled and copied within the framebuffer, and if the damage
+    /// of a frame covers all of them.
+    #[test]
+    fn check_if_framebuffer_applies_events() {
+        let mut framebuffer = Framebuffer::new(4, 2, pixel_format::RGB8888);
+        let red = [0, 0, 0, 255];
+        let blue = [0, 255, 0, 0];
+        let mut pixels = Vec::new();
+        pixels.extend_from_slice(&red);
+        pixels.extend_from_slice(&blue);
+        assert_eq!(framebuffer.handle_event(&client::Event::PutPixels(
+            Rect::new(0, 0, 2, 1), pixels)), None);
+        assert_eq!(framebuffer.handle_event(&client::Event::FillPixels(
+            Rect::new(0, 1, 2, 1), blue.to_vec())), None);
+        // Overlapping with the source, and partly outside the framebuffer.
+        assert_eq!(framebuffer.handle_event(&client::Event::CopyPixels {
+            src: Rect::new(0, 0, 2, 2),
+            dst: Rect::new(1, 0, 4, 2),
+        }), None);
+        assert_eq!(framebuffer.handle_event(&client::Event::EndOfFrame),
+                   Some(Rect::new(0, 0, 3, 2)));
+        assert_eq!(framebuffer.handle_event(&client::Event::EndOfFrame), None);
+
+        let (r, b, k) = ([255, 0, 0, 255], [0, 0, 255, 255], [0, 0, 0, 255]);
+        let expected: Vec<u8> = [r, r, b, k, b, b, b, k]
+            .iter().flat_map(|pixel| pixel.iter().cloned()).collect();
+        assert_eq!(framebuffer.to_rgba(), expected);
+        assert_eq!(framebuffer.rect_to_rgba(Rect::new(2, 1, 5, 5)),
+                   vec![0, 0, 255, 255, 0, 0, 0, 255]);
+    }
+
+    /// Checks if pixels in colour map formats are looked up, and kept when switching formats.
+    #[test]
+    fn check_if_framebuffer_follows_colour_map_and_format() {
+        let indexed = protocol::PixelFormat {
+            bits_per_pixel: 8, depth: 8, big_endian: false, true_colour: false,
+            red_max: 0, green_max: 0, blue_max: 0,
+            red_shift: 0, green_shift: 0, blue_shift: 0,
+        };
+        let mut framebuffer = Framebuffer::new(2, 1, indexed);
+        framebuffer.handle_event(&client::Event::PutPixels(Rect::new(0, 0, 2, 1), vec![1, 0]));
+        framebuffer.handle_event(&client::Event::SetColourMap {
+            first_colour: 1,
+            colours: vec![Colour { red: 0xffff, green: 0x8000, blue: 0 }],
+        });
+        assert_eq!(framebuffer.to_rgba(), vec![255, 128, 0, 255, 0, 0, 0, 255]);
+
+        framebuffer.handle_event(&client::Event::SetFormat(pixel_format::BGR8888));
+        assert_eq!(framebuffer.pixels(), &[0, 255, 128, 0, 0, 0, 0, 0]);
+        assert_eq!(framebuffer.to_rgba(), vec![255, 128, 0, 255, 0, 0, 0, 255]);
+    }
+
+    /// Checks if a `Framebuffer` installed as the sink of a `Client` receives the pixels
+    /// of an update in place of the corresponding events.
+    #[test]
+    fn check_if_framebuffer_is_updated_as_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || -> ::Result<server::Event> {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut server, _) = try!(server::Server::from_tcp_stream(
+                stream, 2, 2, pixel_format::RGB8888, String::from("test")));
+            // Wait for the client to ask for CopyRect and install its sink.
+            try!(server.read_event());
+            try!(server.read_event());
+            let pixels = [0, 0, 0, 255, 0, 0, 255, 0];
+            let mut update = server.create_update();
+            update.add_raw_pixels(Rect::new(0, 0, 2, 1), &pixels)
+                  .add_copy_rect(Rect::new(0, 1, 2, 1), 0, 0);
+            try!(server.send_update(&update.done()));
+            server.read_event()
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut client = client::Client::from_tcp_stream(stream, true, |_| {
+            Some(client::AuthChoice::None)
+        }).unwrap();
+        let framebuffer = Arc::new(Mutex::new(Framebuffer::new(2, 2, client.format())));
+        client.set_sink(Some(Box::new(framebuffer.clone())));
+        client.set_encodings(&[protocol::Encoding::Raw, protocol::Encoding::CopyRect]).unwrap();
+        client.request_update(Rect::new(0, 0, 2, 2), false).unwrap();
+        loop {
+            match client.poll_event() {
+                Some(client::Event::EndOfFrame) => break,
+                Some(client::Event::PutPixels(..)) |
+                Some(client::Event::CopyPixels { .. }) => panic!("pixels sent as events"),
+                Some(_) => (),
+                None => thread::sleep(Duration::from_millis(10))
+            }
+        }
+
+        let mut framebuffer = framebuffer.lock().unwrap();
+        assert_eq!(framebuffer.take_damage(), Some(Rect::new(0, 0, 2, 2)));
+        assert_eq!(framebuffer.to_rgba(), vec![255, 0, 0, 255, 0, 255, 0, 255,
+                                               255, 0, 0, 255, 0, 255, 0, 255]);
+        client.disconnect().unwrap();
+        let _ = server.join().unwrap();
+    }
+}
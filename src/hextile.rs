@@ -0,0 +1,342 @@
+use std::cmp;
+use std::io::Read;
+use byteorder::ReadBytesExt;
+use ::{protocol, rre, Error, Result};
+
+const RAW:                  u8 = 1 << 0;
+const BACKGROUND_SPECIFIED: u8 = 1 << 1;
+const FOREGROUND_SPECIFIED: u8 = 1 << 2;
+const ANY_SUBRECTS:         u8 = 1 << 3;
+const SUBRECTS_COLOURED:    u8 = 1 << 4;
+
+/// Decodes a Hextile rectangle from `reader`, calling `callback` for every 16x16 tile.
+///
+/// Unlike ZRLE, Hextile data is not length-prefixed, so it has to be read straight
+/// from the stream. Returns `false` if `callback` asked to stop decoding.
+pub fn decode<R, F>(reader: &mut R, format: protocol::PixelFormat, rect: protocol::Rect,
+                    mut callback: F) -> Result<bool>
+        where R: Read, F: FnMut(protocol::Rect, &[u8]) -> Result<bool> {
+    let bpp = format.bits_per_pixel as usize / 8;
+
+    // The background and foreground colours carry over from tile to tile
+    // within a single rectangle.
+    let mut background = vec![0; bpp];
+    let mut foreground = vec![0; bpp];
+    let mut colour     = vec![0; bpp];
+    let mut pixels     = Vec::with_capacity(16 * 16 * bpp);
+
+    let mut y = 0;
+    while y < rect.height {
+        let height = cmp::min(16, rect.height - y);
+        let mut x = 0;
+        while x < rect.width {
+            let width = cmp::min(16, rect.width - x);
+            let pixel_count = width as usize * height as usize;
+
+            let subencoding = try!(reader.read_u8());
+            pixels.truncate(0);
+            if subencoding & RAW != 0 {
+                pixels.resize(pixel_count * bpp, 0);
+                try!(reader.read_exact(&mut pixels));
+            } else {
+                if subencoding & BACKGROUND_SPECIFIED != 0 {
+                    try!(reader.read_exact(&mut background));
+                }
+                if subencoding & FOREGROUND_SPECIFIED != 0 {
+                    try!(reader.read_exact(&mut foreground));
+                }
+
+                for _ in 0..pixel_count {
+                    pixels.extend_from_slice(&background)
+                }
+
+                if subencoding & ANY_SUBRECTS != 0 {
+                    let count = try!(reader.read_u8());
+                    for _ in 0..count {
+                        if subencoding & SUBRECTS_COLOURED != 0 {
+                            try!(reader.read_exact(&mut colour));
+                        } else {
+                            colour.copy_from_slice(&foreground);
+                        }
+
+                        let xy = try!(reader.read_u8());
+                        let wh = try!(reader.read_u8());
+                        let (sub_x, sub_y) = ((xy >> 4) as usize, (xy & 0xf) as usize);
+                        let (sub_width, sub_height) =
+                            ((wh >> 4) as usize + 1, (wh & 0xf) as usize + 1);
+                        if sub_x + sub_width  > width  as usize ||
+                           sub_y + sub_height > height as usize {
+                            return Err(Error::Unexpected("Hextile subrectangle"))
+                        }
+
+                        for row in sub_y..sub_y + sub_height {
+                            for column in sub_x..sub_x + sub_width {
+                                let start = (row * width as usize + column) * bpp;
+                                pixels[start..start + bpp].copy_from_slice(&colour)
+                            }
+                        }
+                    }
+                }
+            }
+
+            let tile = match (rect.left.checked_add(x), rect.top.checked_add(y)) {
+                (Some(left), Some(top)) => protocol::Rect::new(left, top, width, height),
+                _ => return Err(Error::Unexpected("Hextile rectangle"))
+            };
+            if let false = try!(callback(tile, &pixels)) {
+                return Ok(false)
+            }
+
+            x += width;
+        }
+        y += height;
+    }
+
+    Ok(true)
+}
+
+/// Finds where the Hextile data of a rectangle ends without decoding it, so that
+/// a rectangle received in pieces can be decoded once it is complete instead of every
+/// time more of it arrives. The tiles found so far are remembered between calls.
+pub(crate) struct Scan {
+    tile:   usize,
+    length: usize,
+}
+
+impl Scan {
+    pub(crate) fn new() -> Scan {
+        Scan { tile: 0, length: 0 }
+    }
+
+    /// Scans the tiles of `rect` in `data`, which starts with its Hextile data, as far
+    /// as it goes. Returns the length of the whole rectangle once every tile has been
+    /// found, or how much data is needed to find the next one otherwise.
+    pub(crate) fn advance(&mut self, data: &[u8], bpp: usize, rect: protocol::Rect) -> usize {
+        let columns = (rect.width as usize + 15) / 16;
+        let tiles = columns * ((rect.height as usize + 15) / 16);
+        while self.tile < tiles {
+            let subencoding = match data.get(self.length) {
+                Some(&subencoding) => subencoding,
+                None => return self.length + 1
+            };
+            let width  = cmp::min(16, rect.width  as usize - self.tile % columns * 16);
+            let height = cmp::min(16, rect.height as usize - self.tile / columns * 16);
+
+            let mut length = 1;
+            if subencoding & RAW != 0 {
+                length += width * height * bpp;
+            } else {
+                if subencoding & BACKGROUND_SPECIFIED != 0 {
+                    length += bpp;
+                }
+                if subencoding & FOREGROUND_SPECIFIED != 0 {
+                    length += bpp;
+                }
+                if subencoding & ANY_SUBRECTS != 0 {
+                    let count = match data.get(self.length + length) {
+                        Some(&count) => count as usize,
+                        None => return self.length + length + 1
+                    };
+                    let subrect_length =
+                        if subencoding & SUBRECTS_COLOURED != 0 { bpp + 2 } else { 2 };
+                    length += 1 + count * subrect_length;
+                }
+            }
+
+            self.length += length;
+            self.tile += 1;
+        }
+        self.length
+    }
+}
+
+/// Encodes `pixels` in `format` covering `rect` as a Hextile rectangle, appending it
+/// to `output`.
+///
+/// Every 16x16 tile is sent as a background with subrectangles in a single foreground
+/// colour or in colours of their own, or as raw pixels when that is shorter.
+pub fn encode(format: protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+    let bpp = format.bits_per_pixel as usize / 8;
+
+    // The colours carried over from the previous tile, if they are known to the client.
+    let mut background: Option<Vec<u8>> = None;
+    let mut foreground: Option<Vec<u8>> = None;
+    let mut tile    = Vec::with_capacity(16 * 16 * bpp);
+    let mut encoded = Vec::with_capacity(16 * 16 * bpp);
+
+    let mut y = 0;
+    while y < rect.height {
+        let height = cmp::min(16, rect.height - y);
+        let mut x = 0;
+        while x < rect.width {
+            let width = cmp::min(16, rect.width - x);
+
+            tile.truncate(0);
+            for row in y..y + height {
+                let start = (row as usize * rect.width as usize + x as usize) * bpp;
+                tile.extend_from_slice(&pixels[start..start + width as usize * bpp]);
+            }
+
+            let tile_background = rre::most_common_pixel(&tile, bpp).unwrap().to_vec();
+            let subrects = rre::subrects(&tile, width as usize, height as usize, bpp,
+                                         &tile_background);
+            let coloured = subrects.iter().any(|&(_, colour)| colour != subrects[0].1);
+
+            let mut subencoding = 0;
+            let mut tile_foreground = foreground.clone();
+            encoded.truncate(0);
+            encoded.push(0);
+            if background.as_ref() != Some(&tile_background) {
+                subencoding |= BACKGROUND_SPECIFIED;
+                encoded.extend_from_slice(&tile_background);
+            }
+            if !subrects.is_empty() {
+                subencoding |= ANY_SUBRECTS;
+                if coloured {
+                    subencoding |= SUBRECTS_COLOURED;
+                    // Not every client keeps the foreground across such tiles.
+                    tile_foreground = None;
+                } else if tile_foreground.as_ref().map(|colour| &colour[..]) !=
+                              Some(subrects[0].1) {
+                    subencoding |= FOREGROUND_SPECIFIED;
+                    encoded.extend_from_slice(subrects[0].1);
+                    tile_foreground = Some(subrects[0].1.to_vec());
+                }
+                encoded.push(subrects.len() as u8);
+                for &(subrect, colour) in &subrects {
+                    if coloured {
+                        encoded.extend_from_slice(colour);
+                    }
+                    encoded.push((subrect.left << 4 | subrect.top) as u8);
+                    encoded.push(((subrect.width - 1) << 4 | (subrect.height - 1)) as u8);
+                }
+            }
+            encoded[0] = subencoding;
+
+            if subrects.len() > 255 || encoded.len() > 1 + tile.len() {
+                // The colours become unknown after a raw tile.
+                output.push(RAW);
+                output.extend_from_slice(&tile);
+                background = None;
+                foreground = None;
+            } else {
+                output.extend_from_slice(&encoded);
+                background = Some(tile_background);
+                foreground = tile_foreground;
+            }
+
+            x += width;
+        }
+        y += height;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use ::{protocol, pixel_format, Error, Result};
+    use protocol::Rect;
+    use super::{encode, decode, RAW, BACKGROUND_SPECIFIED, FOREGROUND_SPECIFIED, ANY_SUBRECTS,
+                SUBRECTS_COLOURED};
+
+    // One byte per pixel keeps the tiles readable.
+    const FORMAT: protocol::PixelFormat = protocol::PixelFormat {
+        bits_per_pixel: 8,
+        depth: 8,
+        big_endian: false,
+        true_colour: true,
+        red_max: 7,
+        green_max: 7,
+        blue_max: 3,
+        red_shift: 0,
+        green_shift: 3,
+        blue_shift: 6,
+    };
+
+    fn decode_tiles(data: &[u8], rect: Rect) -> Result<Vec<(Rect, Vec<u8>)>> {
+        let mut tiles = Vec::new();
+        try!(decode(&mut &data[..], FORMAT, rect, |tile, pixels| {
+            tiles.push((tile, pixels.to_vec()));
+            Ok(true)
+        }));
+        Ok(tiles)
+    }
+
+    /// Checks if raw tiles, and tiles with subrectangles in the foreground colour or in
+    /// colours of their own, are decoded, with the background carried over between tiles.
+    #[test]
+    fn check_if_tiles_are_decoded() {
+        let data = [
+            // A 16x2 tile with background 1 and a 2x1 subrectangle of foreground 2 at 1,0.
+            BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED | ANY_SUBRECTS, 1, 2, 1, 0x10, 0x10,
+            // A 2x2 tile with the same background and two 1x1 subrectangles of colours 3 and 4.
+            ANY_SUBRECTS | SUBRECTS_COLOURED, 2, 3, 0x00, 0x00, 4, 0x11, 0x00,
+        ];
+        let mut first = vec![1; 32];
+        first[1] = 2;
+        first[2] = 2;
+        assert_eq!(decode_tiles(&data, Rect::new(10, 20, 18, 2)).unwrap(),
+                   vec![(Rect::new(10, 20, 16, 2), first),
+                        (Rect::new(26, 20, 2, 2), vec![3, 1, 1, 4])]);
+
+        assert_eq!(decode_tiles(&[RAW, 5, 6], Rect::new(0, 0, 2, 1)).unwrap(),
+                   vec![(Rect::new(0, 0, 2, 1), vec![5, 6])]);
+    }
+
+    /// Checks if subrectangles reaching outside of their tile are rejected.
+    #[test]
+    fn check_if_subrect_outside_tile_is_rejected() {
+        // A 2x1 subrectangle at 1,1 in a 2x2 tile.
+        let data = [BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED | ANY_SUBRECTS, 0, 1, 1,
+                    0x11, 0x10];
+        match decode_tiles(&data, Rect::new(0, 0, 2, 2)) {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+
+    /// Checks if tiles reaching past the largest coordinate are rejected.
+    #[test]
+    fn check_if_tile_outside_coordinates_is_rejected() {
+        // The second tile of the row would start at 65535 + 16.
+        let data = [RAW, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, RAW, 0, 0];
+        match decode_tiles(&data, Rect::new(65535, 0, 18, 1)) {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+
+    /// Checks if pixels encoded as Hextile are decoded back unchanged, across tiles of
+    /// every subencoding.
+    #[test]
+    fn check_if_encoded_pixels_are_decoded() {
+        let rect = Rect::new(3, 5, 40, 20);
+        let pixels: Vec<u8> = (0..40 * 20).flat_map(|index| {
+            let (x, y) = (index % 40, index / 40);
+            let colour = match (x / 16, y / 16) {
+                (0, 0) => 7,
+                (1, 0) => if x == y { 1 } else { 0 },
+                (2, 0) => (x + y) as u8 % 3,
+                _ => (x * 31 + y * 17) as u8,
+            };
+            vec![colour, 0, 0, 0]
+        }).collect();
+        let mut output = Vec::new();
+        encode(pixel_format::RGB8888, rect, &pixels, &mut output);
+        assert!(output.len() < pixels.len());
+
+        let mut decoded = vec![0xff; pixels.len()];
+        decode(&mut &output[..], pixel_format::RGB8888, rect, |tile, tile_pixels| {
+            for row in 0..tile.height as usize {
+                let start = ((tile.top - rect.top) as usize + row) * 40 +
+                            (tile.left - rect.left) as usize;
+                let length = tile.width as usize;
+                decoded[start * 4..(start + length) * 4].copy_from_slice(
+                    &tile_pixels[row * length * 4..(row + 1) * length * 4]);
+            }
+            Ok(true)
+        }).unwrap();
+        assert_eq!(decoded, pixels);
+    }
+}
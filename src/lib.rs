@@ -1,6 +1,19 @@
 #[macro_use] extern crate log;
 extern crate byteorder;
 extern crate flate2;
+extern crate jpeg_decoder;
+pub extern crate rustls;
+pub extern crate rsa;
+extern crate aes;
+extern crate eax;
+extern crate sha1;
+extern crate sha2;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(test)]
+extern crate rcgen;
 #[cfg(feature = "apple-auth")]
 extern crate num_bigint;
 #[cfg(feature = "apple-auth")]
@@ -10,47 +23,57 @@ extern crate crypto;
 
 mod protocol;
 mod zrle;
+mod hextile;
+mod rre;
+mod tight;
 mod security;
+mod stream;
+mod transport;
+mod websocket;
+mod connection;
+mod framebuffer;
+mod encoder;
+mod server_framebuffer;
+mod shared_server;
 
 pub mod client;
+pub mod pixel_format;
 pub mod proxy;
+pub mod repeater;
 pub mod server;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
 
-pub use protocol::{PixelFormat, Colour, Encoding};
+pub use protocol::{PixelFormat, Colour, Encoding, Screen, ResizeReason, ResizeStatus,
+                   ClipboardFormat, ClipboardAction, ClipboardData, ExtendedClipboard,
+                   VeNCryptSubtype, RsaAesVariant, SecurityType, Version};
+pub use security::{Access, SecurityHandler, SecurityRegistry, NoAuthentication};
+pub use stream::Stream;
+pub use transport::{Transport, Split};
+pub use websocket::WebSocket;
+pub use connection::{ClientConnection, ClientState, ServerConnection, ServerState,
+                     ProxyConnection, ProxyState};
 pub use client::Client;
 pub use proxy::Proxy;
 pub use server::Server;
 
-pub mod pixel_format {
-    use super::PixelFormat;
+/// Flags of `Fence` messages.
+pub mod fence {
+    /// All messages preceding the fence must have been processed before it is responded to.
+    pub const BLOCK_BEFORE: u32 = 1 << 0;
 
-    /// RGB pixel format with 4 bytes per pixel and 3 bytes of depth.
-    pub const RGB8888: PixelFormat = PixelFormat {
-        bits_per_pixel: 32,
-        depth: 24,
-        big_endian: true,
-        true_colour: true,
-        red_max: 255,
-        green_max: 255,
-        blue_max: 255,
-        red_shift: 0,
-        green_shift: 8,
-        blue_shift: 16,
-    };
+    /// All messages following the fence must not be processed until it is responded to.
+    pub const BLOCK_AFTER: u32 = 1 << 1;
 
-    /// BGR pixel format with 4 bytes per pixel and 3 bytes of depth.
-    pub const BGR8888: PixelFormat = PixelFormat {
-        bits_per_pixel: 32,
-        depth: 24,
-        big_endian: true,
-        true_colour: true,
-        red_max: 255,
-        green_max: 255,
-        blue_max: 255,
-        red_shift: 16,
-        green_shift: 8,
-        blue_shift: 0,
-    };
+    /// The message following the fence must take effect exactly at the point where
+    /// the fence response is sent.
+    pub const SYNC_NEXT: u32 = 1 << 2;
+
+    /// The fence is a request that must be responded to, rather than a response.
+    pub const REQUEST: u32 = 1 << 31;
+
+    /// All flags other than `REQUEST` understood by this crate.
+    pub const SUPPORTED: u32 = BLOCK_BEFORE | BLOCK_AFTER | SYNC_NEXT;
 }
 
 #[derive(Debug)]
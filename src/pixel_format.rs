@@ -0,0 +1,391 @@
+//! Common pixel formats, and conversion of pixels between any two formats.
+
+use super::{PixelFormat, Colour, Error, Result};
+
+/// RGB pixel format with 4 bytes per pixel and 3 bytes of depth.
+pub const RGB8888: PixelFormat = PixelFormat {
+    bits_per_pixel: 32,
+    depth: 24,
+    big_endian: true,
+    true_colour: true,
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    red_shift: 0,
+    green_shift: 8,
+    blue_shift: 16,
+};
+
+/// BGR pixel format with 4 bytes per pixel and 3 bytes of depth.
+pub const BGR8888: PixelFormat = PixelFormat {
+    bits_per_pixel: 32,
+    depth: 24,
+    big_endian: true,
+    true_colour: true,
+    red_max: 255,
+    green_max: 255,
+    blue_max: 255,
+    red_shift: 16,
+    green_shift: 8,
+    blue_shift: 0,
+};
+
+/// Checks if `format` may be used on the wire, as required of the formats in `ServerInit`
+/// and `SetPixelFormat` messages: 8, 16 or 32 bits per pixel with a depth that fits in
+/// them, and either true colour components that fit in them as well, at shifts within
+/// them, or colour map indices of at most 16 bits. Returns `Error::Unexpected` otherwise.
+pub fn validate(format: &PixelFormat) -> Result<()> {
+    match format.bits_per_pixel {
+        8 | 16 | 32 => check_layout(format),
+        _ => Err(Error::Unexpected("bits per pixel"))
+    }
+}
+
+/// Returns the number of bytes taken by a pixel in `format`.
+pub fn bytes_per_pixel(format: &PixelFormat) -> usize {
+    (format.bits_per_pixel as usize + 7) / 8
+}
+
+/// Returns the value of the pixel stored in the first `bytes_per_pixel(format)` bytes
+/// of `bytes`.
+pub fn read_pixel(format: &PixelFormat, bytes: &[u8]) -> u32 {
+    let bytes = &bytes[..bytes_per_pixel(format)];
+    if format.big_endian {
+        bytes.iter().fold(0, |value, &byte| value << 8 | byte as u32)
+    } else {
+        bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+}
+
+/// Appends the pixel with `value` to `output`, in `format`.
+pub fn write_pixel(format: &PixelFormat, value: u32, output: &mut Vec<u8>) {
+    let bytes_per_pixel = bytes_per_pixel(format);
+    for i in 0..bytes_per_pixel {
+        let shift = if format.big_endian { bytes_per_pixel - 1 - i } else { i } * 8;
+        output.push((value >> shift) as u8);
+    }
+}
+
+/// Returns a colour map of 256 entries laid out like a true colour format with 3 bits
+/// of red, 3 bits of green and 2 bits of blue, from the most to the least significant.
+///
+/// Servers can send it to clients that ask for a colour-mapped format, and use it as the
+/// target colour map of a `Converter`.
+pub fn rgb332_colour_map() -> Vec<Colour> {
+    let scale = |value: u16, max: u16| (value as u32 * 0xffff / max as u32) as u16;
+    (0..256).map(|index| Colour {
+        red:   scale(index >> 5, 7),
+        green: scale(index >> 2 & 7, 7),
+        blue:  scale(index & 3, 3),
+    }).collect()
+}
+
+// Colours are handled with 16 bits per component, like in colour maps.
+type Rgb = [u16; 3];
+
+#[derive(Debug, Clone)]
+enum Path {
+    // Both formats are the same.
+    Copy,
+    // Both formats have 4 bytes per pixel and 8 bits per component, each in its own byte;
+    // every output byte is taken from the given input byte, or is zero.
+    Shuffle([Option<usize>; 4]),
+    Generic,
+}
+
+/// Converts pixels from one format to another.
+///
+/// Formats of 8, 16, 24 and 32 bits per pixel are supported, in either byte order, with
+/// any component shifts and maxima that fit in a pixel. Colour-mapped pixels are looked up in the colour map
+/// given to `set_source_colour_map`, and true colour pixels are converted to the closest
+/// entry of the one given to `set_target_colour_map`; pixels without a colour map entry
+/// are treated as black. Pixels are copied as is between identical colour-mapped formats,
+/// on the assumption that both use the same colour map.
+#[derive(Debug, Clone)]
+pub struct Converter {
+    from: PixelFormat,
+    to:   PixelFormat,
+    path: Path,
+    source_map: Vec<Rgb>,
+    target_map: Vec<Rgb>,
+    // The closest target colour map entry to every colour with 5 bits per component.
+    target_lookup: Vec<u16>,
+}
+
+impl Converter {
+    /// Constructs a converter of pixels in `from` into pixels in `to`.
+    ///
+    /// Returns `Error::Unexpected` if either format is not supported; formats accepted by
+    /// `validate` always are.
+    pub fn new(from: PixelFormat, to: PixelFormat) -> Result<Converter> {
+        for format in &[from, to] {
+            match format.bits_per_pixel {
+                8 | 16 | 24 | 32 => try!(check_layout(format)),
+                _ => return Err(Error::Unexpected("bits per pixel"))
+            }
+        }
+
+        let path =
+            if from == to {
+                Path::Copy
+            } else if is_byte_aligned(&from) && is_byte_aligned(&to) {
+                let mut shuffle = [None; 4];
+                for &(from_shift, to_shift) in &[(from.red_shift, to.red_shift),
+                                                 (from.green_shift, to.green_shift),
+                                                 (from.blue_shift, to.blue_shift)] {
+                    shuffle[byte_index(&to, to_shift)] = Some(byte_index(&from, from_shift));
+                }
+                Path::Shuffle(shuffle)
+            } else {
+                Path::Generic
+            };
+        Ok(Converter {
+            from: from,
+            to:   to,
+            path: path,
+            source_map: Vec::new(),
+            target_map: Vec::new(),
+            target_lookup: Vec::new(),
+        })
+    }
+
+    /// Returns the format pixels are converted from.
+    pub fn from(&self) -> PixelFormat { self.from }
+
+    /// Returns the format pixels are converted to.
+    pub fn to(&self) -> PixelFormat { self.to }
+
+    /// Replaces the entries of the colour map of the input, starting at `first_colour`.
+    pub fn set_source_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        update_colour_map(&mut self.source_map, first_colour, colours);
+    }
+
+    /// Replaces the entries of the colour map of the output, starting at `first_colour`.
+    ///
+    /// Every entry is compared with every colour of 15 bits to find the closest ones,
+    /// so this is slow for colour maps larger than 256 entries.
+    pub fn set_target_colour_map(&mut self, first_colour: u16, colours: &[Colour]) {
+        update_colour_map(&mut self.target_map, first_colour, colours);
+
+        let target_map = &self.target_map;
+        self.target_lookup = (0..1u32 << 15).map(|key| {
+            let colour = [expand_5(key >> 10), expand_5(key >> 5), expand_5(key)];
+            let mut closest = (0, u64::max_value());
+            for (index, entry) in target_map.iter().enumerate() {
+                let distance = (0..3).map(|i| {
+                    let difference = entry[i] as i64 - colour[i] as i64;
+                    (difference * difference) as u64
+                }).sum();
+                if distance < closest.1 {
+                    closest = (index as u16, distance);
+                }
+            }
+            closest.0
+        }).collect();
+    }
+
+    /// Appends `pixels`, in the input format, to `output` in the output format. Trailing
+    /// bytes that do not make up a whole pixel are ignored.
+    pub fn convert(&self, pixels: &[u8], output: &mut Vec<u8>) {
+        let from_size = bytes_per_pixel(&self.from);
+        match self.path {
+            Path::Copy => {
+                output.extend_from_slice(&pixels[..pixels.len() / from_size * from_size])
+            }
+            Path::Shuffle(shuffle) => {
+                output.reserve(pixels.len());
+                for pixel in pixels.chunks_exact(4) {
+                    for &index in &shuffle {
+                        output.push(index.map(|index| pixel[index]).unwrap_or(0));
+                    }
+                }
+            }
+            Path::Generic => {
+                output.reserve(pixels.len() / from_size * bytes_per_pixel(&self.to));
+                for pixel in pixels.chunks_exact(from_size) {
+                    let rgb = self.decode(read_pixel(&self.from, pixel));
+                    write_pixel(&self.to, self.encode(rgb), output);
+                }
+            }
+        }
+    }
+
+    /// Returns `pixels`, in the input format, converted to the output format.
+    pub fn convert_to_vec(&self, pixels: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.convert(pixels, &mut output);
+        output
+    }
+
+    fn decode(&self, value: u32) -> Rgb {
+        let format = &self.from;
+        if format.true_colour {
+            [expand(value >> format.red_shift, format.red_max),
+             expand(value >> format.green_shift, format.green_max),
+             expand(value >> format.blue_shift, format.blue_max)]
+        } else {
+            self.source_map.get(value as usize).cloned().unwrap_or([0, 0, 0])
+        }
+    }
+
+    fn encode(&self, rgb: Rgb) -> u32 {
+        let format = &self.to;
+        if format.true_colour {
+            reduce(rgb[0], format.red_max) << format.red_shift |
+            reduce(rgb[1], format.green_max) << format.green_shift |
+            reduce(rgb[2], format.blue_max) << format.blue_shift
+        } else if self.target_lookup.is_empty() {
+            0
+        } else {
+            let key = (rgb[0] as usize >> 11) << 10 | (rgb[1] as usize >> 11) << 5 |
+                      rgb[2] as usize >> 11;
+            self.target_lookup[key] as u32
+        }
+    }
+}
+
+/// Returns `pixels` in `from` converted to `to`; see `Converter` for converting
+/// colour-mapped pixels, or many batches of pixels.
+pub fn convert(from: PixelFormat, to: PixelFormat, pixels: &[u8]) -> Result<Vec<u8>> {
+    Ok(try!(Converter::new(from, to)).convert_to_vec(pixels))
+}
+
+// Checks the depth and the components of `format` against its number of bits per pixel;
+// see `validate`.
+fn check_layout(format: &PixelFormat) -> Result<()> {
+    let bits = format.bits_per_pixel as u32;
+    if format.depth as u32 > bits {
+        return Err(Error::Unexpected("pixel depth"))
+    }
+    if format.true_colour {
+        for &(max, shift) in &[(format.red_max, format.red_shift),
+                               (format.green_max, format.green_shift),
+                               (format.blue_max, format.blue_shift)] {
+            // Pixels are shifted by `shift` even for components with no bits.
+            if shift as u32 >= bits || 16 - max.leading_zeros() + shift as u32 > bits {
+                return Err(Error::Unexpected("pixel component"))
+            }
+        }
+    } else if bits > 16 {
+        return Err(Error::Unexpected("colour-mapped pixel format"))
+    }
+    Ok(())
+}
+
+// Whether every component of `format` takes a whole byte of a 4-byte pixel.
+fn is_byte_aligned(format: &PixelFormat) -> bool {
+    format.true_colour && format.bits_per_pixel == 32 &&
+        [format.red_max, format.green_max, format.blue_max].iter().all(|&max| max == 255) &&
+        [format.red_shift, format.green_shift, format.blue_shift].iter()
+            .all(|&shift| shift % 8 == 0 && shift < 32)
+}
+
+fn byte_index(format: &PixelFormat, shift: u8) -> usize {
+    if format.big_endian { 3 - shift as usize / 8 } else { shift as usize / 8 }
+}
+
+fn update_colour_map(map: &mut Vec<Rgb>, first_colour: u16, colours: &[Colour]) {
+    let end = first_colour as usize + colours.len();
+    if map.len() < end {
+        map.resize(end, [0, 0, 0]);
+    }
+    for (entry, colour) in map[first_colour as usize..end].iter_mut().zip(colours) {
+        *entry = [colour.red, colour.green, colour.blue];
+    }
+}
+
+// Scales a component with the given maximum to 16 bits.
+fn expand(value: u32, max: u16) -> u16 {
+    if max == 0 { return 0 }
+    ((value & max as u32) * 0xffff / max as u32) as u16
+}
+
+fn expand_5(value: u32) -> u16 {
+    expand(value, 0x1f)
+}
+
+// Scales a 16-bit component to the given maximum, rounding to the closest value.
+fn reduce(value: u16, max: u16) -> u32 {
+    (value as u32 * max as u32 + 0x7fff) / 0xffff
+}
+
+#[cfg(test)]
+mod test {
+    use ::{PixelFormat, Colour};
+    use super::{Converter, RGB8888, BGR8888, convert, rgb332_colour_map, validate};
+
+    const RGB565: PixelFormat = PixelFormat {
+        bits_per_pixel: 16, depth: 16, big_endian: false, true_colour: true,
+        red_max: 31, green_max: 63, blue_max: 31,
+        red_shift: 11, green_shift: 5, blue_shift: 0,
+    };
+
+    const INDEXED: PixelFormat = PixelFormat {
+        bits_per_pixel: 8, depth: 8, big_endian: false, true_colour: false,
+        red_max: 0, green_max: 0, blue_max: 0,
+        red_shift: 0, green_shift: 0, blue_shift: 0,
+    };
+
+    /// Checks if pixels are converted between true colour formats of different sizes,
+    /// byte orders and component layouts, through both the fast and the generic paths.
+    #[test]
+    fn check_if_true_colour_pixels_are_converted() {
+        // Orange, then blue.
+        let rgb = [0, 0, 0x80, 0xff, 0, 0xff, 0, 0];
+        let bgr = convert(RGB8888, BGR8888, &rgb).unwrap();
+        assert_eq!(bgr, vec![0, 0xff, 0x80, 0, 0, 0, 0, 0xff]);
+        let little_endian = PixelFormat { big_endian: false, ..RGB8888 };
+        assert_eq!(convert(RGB8888, little_endian, &rgb).unwrap(),
+                   vec![0xff, 0x80, 0, 0, 0, 0, 0xff, 0]);
+
+        let rgb565 = convert(BGR8888, RGB565, &bgr).unwrap();
+        assert_eq!(rgb565, vec![0x00, 0xfc, 0x1f, 0x00]);
+        let big_endian = PixelFormat { big_endian: true, ..RGB565 };
+        assert_eq!(convert(RGB565, big_endian, &rgb565).unwrap(),
+                   vec![0xfc, 0x00, 0x00, 0x1f]);
+        let rgb24 = PixelFormat { bits_per_pixel: 24, ..RGB8888 };
+        let rgb24_pixels = convert(RGB565, rgb24, &rgb565).unwrap();
+        assert_eq!(rgb24_pixels, vec![0, 0x82, 0xff, 0xff, 0, 0]);
+        assert_eq!(convert(rgb24, RGB8888, &rgb24_pixels).unwrap(),
+                   vec![0, 0, 0x82, 0xff, 0, 0xff, 0, 0]);
+    }
+
+    /// Checks if colour-mapped pixels are looked up, and true colour pixels are mapped
+    /// to the closest colour.
+    #[test]
+    fn check_if_colour_mapped_pixels_are_converted() {
+        let mut converter = Converter::new(INDEXED, RGB8888).unwrap();
+        converter.set_source_colour_map(1, &[Colour { red: 0xffff, green: 0x8080, blue: 0 }]);
+        assert_eq!(converter.convert_to_vec(&[1, 0, 2]),
+                   vec![0, 0, 0x80, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut converter = Converter::new(RGB8888, INDEXED).unwrap();
+        converter.set_target_colour_map(0, &rgb332_colour_map());
+        assert_eq!(converter.convert_to_vec(&[0, 0, 0x80, 0xff, 0, 0xff, 0, 0, 0, 0, 0, 0]),
+                   vec![0b1111_0000, 0b0000_0011, 0]);
+    }
+
+    /// Checks if formats whose components do not fit in their pixels are rejected, and
+    /// 24 bits per pixel only accepted for conversions.
+    #[test]
+    fn check_if_invalid_formats_are_rejected() {
+        for format in &[RGB8888, RGB565, INDEXED] {
+            assert!(validate(format).is_ok());
+        }
+        let rgb24 = PixelFormat { bits_per_pixel: 24, ..RGB8888 };
+        assert!(validate(&rgb24).is_err());
+        assert!(Converter::new(rgb24, RGB8888).is_ok());
+
+        for format in &[PixelFormat { bits_per_pixel: 0, ..RGB8888 },
+                        PixelFormat { bits_per_pixel: 8, ..RGB8888 },
+                        PixelFormat { depth: 33, ..RGB8888 },
+                        PixelFormat { blue_shift: 25, ..RGB8888 },
+                        PixelFormat { red_max: 0, red_shift: 32, ..RGB8888 },
+                        PixelFormat { green_shift: 11, ..RGB565 },
+                        PixelFormat { bits_per_pixel: 32, ..INDEXED }] {
+            assert!(validate(format).is_err());
+            assert!(Converter::new(*format, RGB8888).is_err());
+            assert!(Converter::new(RGB8888, *format).is_err());
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::{ErrorKind as IoErrorKind, Read, Write};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use flate2;
 use ::{Error, Result};
 
 pub trait Message {
@@ -80,9 +81,39 @@ pub enum SecurityType {
     None,
     VncAuthentication,
     // extensions
+    RsaAes(RsaAesVariant),
+    VeNCrypt,
     AppleRemoteDesktop,
 }
 
+/// Variants of the RSA-AES security type, which differ in the strength of the cipher
+/// and hash, and in whether the session stays encrypted after authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsaAesVariant {
+    Aes128,
+    Aes128Unencrypted,
+    Aes256,
+    Aes256Unencrypted,
+}
+
+impl RsaAesVariant {
+    /// Size of the session keys and of the random values they are derived from, in bytes.
+    pub fn key_size(&self) -> usize {
+        match self {
+            &RsaAesVariant::Aes128 | &RsaAesVariant::Aes128Unencrypted => 16,
+            &RsaAesVariant::Aes256 | &RsaAesVariant::Aes256Unencrypted => 32,
+        }
+    }
+
+    /// Whether the session stays encrypted once the client has been authenticated.
+    pub fn encrypts_session(&self) -> bool {
+        match self {
+            &RsaAesVariant::Aes128 | &RsaAesVariant::Aes256 => true,
+            &RsaAesVariant::Aes128Unencrypted | &RsaAesVariant::Aes256Unencrypted => false,
+        }
+    }
+}
+
 impl Message for SecurityType {
     fn read_from<R: Read>(reader: &mut R) -> Result<SecurityType> {
         let security_type = try!(reader.read_u8());
@@ -90,7 +121,12 @@ impl Message for SecurityType {
             0  => Ok(SecurityType::Invalid),
             1  => Ok(SecurityType::None),
             2  => Ok(SecurityType::VncAuthentication),
+            5  => Ok(SecurityType::RsaAes(RsaAesVariant::Aes128)),
+            6  => Ok(SecurityType::RsaAes(RsaAesVariant::Aes128Unencrypted)),
+            19 => Ok(SecurityType::VeNCrypt),
             30 => Ok(SecurityType::AppleRemoteDesktop),
+            129 => Ok(SecurityType::RsaAes(RsaAesVariant::Aes256)),
+            130 => Ok(SecurityType::RsaAes(RsaAesVariant::Aes256Unencrypted)),
             n  => Ok(SecurityType::Unknown(n))
         }
     }
@@ -100,6 +136,11 @@ impl Message for SecurityType {
             &SecurityType::Invalid => 0,
             &SecurityType::None => 1,
             &SecurityType::VncAuthentication => 2,
+            &SecurityType::RsaAes(RsaAesVariant::Aes128) => 5,
+            &SecurityType::RsaAes(RsaAesVariant::Aes128Unencrypted) => 6,
+            &SecurityType::RsaAes(RsaAesVariant::Aes256) => 129,
+            &SecurityType::RsaAes(RsaAesVariant::Aes256Unencrypted) => 130,
+            &SecurityType::VeNCrypt => 19,
             &SecurityType::AppleRemoteDesktop => 30,
             &SecurityType::Unknown(n) => n
         };
@@ -157,6 +198,274 @@ impl Message for SecurityResult {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VeNCryptVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl Message for VeNCryptVersion {
+    fn read_from<R: Read>(reader: &mut R) -> Result<VeNCryptVersion> {
+        Ok(VeNCryptVersion {
+            major: try!(reader.read_u8()),
+            minor: try!(reader.read_u8())
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        try!(writer.write_u8(self.major));
+        try!(writer.write_u8(self.minor));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VeNCryptSubtype {
+    Unknown(u32),
+    Plain,
+    TlsNone,
+    TlsVnc,
+    TlsPlain,
+    X509None,
+    X509Vnc,
+    X509Plain,
+}
+
+impl VeNCryptSubtype {
+    /// Whether the sub-type runs over TLS, and so has to be acknowledged before the handshake.
+    pub fn uses_tls(&self) -> bool {
+        match self {
+            &VeNCryptSubtype::Plain |
+            &VeNCryptSubtype::Unknown(_) => false,
+            _ => true
+        }
+    }
+
+    /// Whether the server certificate is verified, rather than used for encryption only.
+    pub fn uses_x509(&self) -> bool {
+        match self {
+            &VeNCryptSubtype::X509None |
+            &VeNCryptSubtype::X509Vnc |
+            &VeNCryptSubtype::X509Plain => true,
+            _ => false
+        }
+    }
+}
+
+impl Message for VeNCryptSubtype {
+    fn read_from<R: Read>(reader: &mut R) -> Result<VeNCryptSubtype> {
+        let subtype = try!(reader.read_u32::<BigEndian>());
+        match subtype {
+            256 => Ok(VeNCryptSubtype::Plain),
+            257 => Ok(VeNCryptSubtype::TlsNone),
+            258 => Ok(VeNCryptSubtype::TlsVnc),
+            259 => Ok(VeNCryptSubtype::TlsPlain),
+            260 => Ok(VeNCryptSubtype::X509None),
+            261 => Ok(VeNCryptSubtype::X509Vnc),
+            262 => Ok(VeNCryptSubtype::X509Plain),
+            n   => Ok(VeNCryptSubtype::Unknown(n))
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let subtype = match self {
+            &VeNCryptSubtype::Plain => 256,
+            &VeNCryptSubtype::TlsNone => 257,
+            &VeNCryptSubtype::TlsVnc => 258,
+            &VeNCryptSubtype::TlsPlain => 259,
+            &VeNCryptSubtype::X509None => 260,
+            &VeNCryptSubtype::X509Vnc => 261,
+            &VeNCryptSubtype::X509Plain => 262,
+            &VeNCryptSubtype::Unknown(n) => n
+        };
+        try!(writer.write_u32::<BigEndian>(subtype));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct VeNCryptSubtypes(pub Vec<VeNCryptSubtype>);
+
+impl Message for VeNCryptSubtypes {
+    fn read_from<R: Read>(reader: &mut R) -> Result<VeNCryptSubtypes> {
+        let count = try!(reader.read_u8());
+        let mut subtypes = Vec::new();
+        for _ in 0..count {
+            subtypes.push(try!(VeNCryptSubtype::read_from(reader)))
+        }
+        Ok(VeNCryptSubtypes(subtypes))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.0.len() > u8::max_value() as usize {
+            return Err(Error::Unexpected("number of VeNCrypt sub-types"))
+        }
+        try!(writer.write_u8(self.0.len() as u8));
+        for subtype in &self.0 {
+            try!(subtype.write_to(writer));
+        }
+        Ok(())
+    }
+}
+
+/// Credentials sent by the VeNCrypt `Plain` sub-authentication, in UTF-8. Usernames and
+/// passwords longer than 1024 bytes are refused.
+#[derive(Debug)]
+pub struct PlainCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+const PLAIN_CREDENTIALS_MAX_LENGTH: usize = 1024;
+
+impl Message for PlainCredentials {
+    fn read_from<R: Read>(reader: &mut R) -> Result<PlainCredentials> {
+        let username_length = try!(reader.read_u32::<BigEndian>());
+        let password_length = try!(reader.read_u32::<BigEndian>());
+        if username_length as usize > PLAIN_CREDENTIALS_MAX_LENGTH ||
+                password_length as usize > PLAIN_CREDENTIALS_MAX_LENGTH {
+            return Err(Error::Unexpected("credentials length"))
+        }
+        let mut username = vec![0; username_length as usize];
+        try!(reader.read_exact(&mut username));
+        let mut password = vec![0; password_length as usize];
+        try!(reader.read_exact(&mut password));
+        Ok(PlainCredentials {
+            username: try!(String::from_utf8(username)
+                               .map_err(|_| Error::Unexpected("username encoding"))),
+            password: try!(String::from_utf8(password)
+                               .map_err(|_| Error::Unexpected("password encoding"))),
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.username.len() > PLAIN_CREDENTIALS_MAX_LENGTH ||
+                self.password.len() > PLAIN_CREDENTIALS_MAX_LENGTH {
+            return Err(Error::Unexpected("credentials length"))
+        }
+        try!(writer.write_u32::<BigEndian>(self.username.len() as u32));
+        try!(writer.write_u32::<BigEndian>(self.password.len() as u32));
+        try!(writer.write_all(self.username.as_bytes()));
+        try!(writer.write_all(self.password.as_bytes()));
+        Ok(())
+    }
+}
+
+/// An RSA public key as sent during the RSA-AES handshake: the key length in bits, followed by
+/// the modulus and the public exponent, both big-endian and padded to the key length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaAesPublicKey {
+    pub modulus: Vec<u8>,
+    pub exponent: Vec<u8>,
+}
+
+impl Message for RsaAesPublicKey {
+    fn read_from<R: Read>(reader: &mut R) -> Result<RsaAesPublicKey> {
+        let bits = try!(reader.read_u32::<BigEndian>());
+        if bits < 1024 || bits > 8192 {
+            return Err(Error::Unexpected("RSA key length"))
+        }
+        let length = (bits as usize + 7) / 8;
+        let mut modulus = vec![0; length];
+        try!(reader.read_exact(&mut modulus));
+        let mut exponent = vec![0; length];
+        try!(reader.read_exact(&mut exponent));
+        Ok(RsaAesPublicKey { modulus: modulus, exponent: exponent })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let length = self.modulus.len();
+        if self.exponent.len() > length {
+            return Err(Error::Unexpected("RSA exponent length"))
+        }
+        let leading_zeros = self.modulus.first().map(|byte| byte.leading_zeros()).unwrap_or(8);
+        try!(writer.write_u32::<BigEndian>(length as u32 * 8 - leading_zeros));
+        try!(writer.write_all(&self.modulus));
+        try!(writer.write_all(&vec![0; length - self.exponent.len()]));
+        try!(writer.write_all(&self.exponent));
+        Ok(())
+    }
+}
+
+/// A random value encrypted with the peer's public key during the RSA-AES handshake.
+#[derive(Debug)]
+pub struct RsaAesRandom(pub Vec<u8>);
+
+impl Message for RsaAesRandom {
+    fn read_from<R: Read>(reader: &mut R) -> Result<RsaAesRandom> {
+        let length = try!(reader.read_u16::<BigEndian>());
+        let mut data = vec![0; length as usize];
+        try!(reader.read_exact(&mut data));
+        Ok(RsaAesRandom(data))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        try!(writer.write_u16::<BigEndian>(self.0.len() as u16));
+        try!(writer.write_all(&self.0));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RsaAesSubtype {
+    UsernameAndPassword,
+    Password,
+}
+
+impl Message for RsaAesSubtype {
+    fn read_from<R: Read>(reader: &mut R) -> Result<RsaAesSubtype> {
+        match try!(reader.read_u8()) {
+            1 => Ok(RsaAesSubtype::UsernameAndPassword),
+            2 => Ok(RsaAesSubtype::Password),
+            _ => Err(Error::Unexpected("RSA-AES sub-type"))
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let subtype = match self {
+            &RsaAesSubtype::UsernameAndPassword => 1,
+            &RsaAesSubtype::Password => 2,
+        };
+        try!(writer.write_u8(subtype));
+        Ok(())
+    }
+}
+
+/// Credentials sent at the end of the RSA-AES handshake, in UTF-8. The username is empty
+/// with `RsaAesSubtype::Password`.
+#[derive(Debug)]
+pub struct RsaAesCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Message for RsaAesCredentials {
+    fn read_from<R: Read>(reader: &mut R) -> Result<RsaAesCredentials> {
+        let mut fields = Vec::new();
+        for _ in 0..2 {
+            let length = try!(reader.read_u8());
+            let mut field = vec![0; length as usize];
+            try!(reader.read_exact(&mut field));
+            fields.push(try!(String::from_utf8(field)
+                                 .map_err(|_| Error::Unexpected("credentials encoding"))));
+        }
+        let password = fields.pop().unwrap();
+        let username = fields.pop().unwrap();
+        Ok(RsaAesCredentials { username: username, password: password })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for field in &[&self.username, &self.password] {
+            if field.len() > 255 {
+                return Err(Error::Unexpected("credentials length"))
+            }
+            try!(writer.write_u8(field.len() as u8));
+            try!(writer.write_all(field.as_bytes()));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct AppleAuthHandshake {
     pub generator: u16,
@@ -326,13 +635,24 @@ pub enum Encoding {
     Raw,
     CopyRect,
     Rre,
+    CoRre,
     Hextile,
     Zrle,
     Cursor,
     DesktopSize,
+    // Tight encoding and its tuning pseudo-encodings
+    Tight,
+    /// Compression level from 0 to 9.
+    CompressionLevel(u8),
+    /// JPEG quality level from 0 to 9.
+    JpegQuality(u8),
 
     // extensions
     ExtendedKeyEvent,
+    ExtendedDesktopSize,
+    Fence,
+    ContinuousUpdates,
+    ExtendedClipboard,
 }
 
 impl Message for Encoding {
@@ -342,11 +662,19 @@ impl Message for Encoding {
             0    => Ok(Encoding::Raw),
             1    => Ok(Encoding::CopyRect),
             2    => Ok(Encoding::Rre),
+            4    => Ok(Encoding::CoRre),
             5    => Ok(Encoding::Hextile),
+            7    => Ok(Encoding::Tight),
             16   => Ok(Encoding::Zrle),
             -239 => Ok(Encoding::Cursor),
             -223 => Ok(Encoding::DesktopSize),
             -258 => Ok(Encoding::ExtendedKeyEvent),
+            -308 => Ok(Encoding::ExtendedDesktopSize),
+            -312 => Ok(Encoding::Fence),
+            -313 => Ok(Encoding::ContinuousUpdates),
+            -1063131698 => Ok(Encoding::ExtendedClipboard),
+            n @ -256...-247 => Ok(Encoding::CompressionLevel((n + 256) as u8)),
+            n @ -32...-23 => Ok(Encoding::JpegQuality((n + 32) as u8)),
             n    => Ok(Encoding::Unknown(n))
         }
     }
@@ -356,11 +684,21 @@ impl Message for Encoding {
             &Encoding::Raw => 0,
             &Encoding::CopyRect => 1,
             &Encoding::Rre => 2,
+            &Encoding::CoRre => 4,
             &Encoding::Hextile => 5,
+            &Encoding::Tight => 7,
             &Encoding::Zrle => 16,
             &Encoding::Cursor => -239,
             &Encoding::DesktopSize => -223,
             &Encoding::ExtendedKeyEvent => -258,
+            &Encoding::ExtendedDesktopSize => -308,
+            &Encoding::Fence => -312,
+            &Encoding::ContinuousUpdates => -313,
+            &Encoding::ExtendedClipboard => -1063131698,
+            &Encoding::CompressionLevel(level) if level <= 9 => -256 + level as i32,
+            &Encoding::CompressionLevel(_) => return Err(Error::Unexpected("compression level")),
+            &Encoding::JpegQuality(quality) if quality <= 9 => -32 + quality as i32,
+            &Encoding::JpegQuality(_) => return Err(Error::Unexpected("JPEG quality")),
             &Encoding::Unknown(n) => n
         };
         try!(writer.write_i32::<BigEndian>(encoding));
@@ -392,11 +730,25 @@ pub enum C2S {
     CutText(String),
 
     // extensions
+    ExtendedCutText(ExtendedClipboard),
     ExtendedKeyEvent {
         down:        bool,
         keysym:      u32,
         keycode:     u32,
     },
+    SetDesktopSize {
+        width:       u16,
+        height:      u16,
+        screens:     Vec<Screen>,
+    },
+    Fence(Fence),
+    EnableContinuousUpdates {
+        enable:      bool,
+        x_position:  u16,
+        y_position:  u16,
+        width:       u16,
+        height:      u16,
+    },
 }
 
 impl Message for C2S {
@@ -445,7 +797,34 @@ impl Message for C2S {
             },
             6 => {
                 try!(reader.read_exact(&mut [0u8; 3]));
-                Ok(C2S::CutText(try!(String::read_from(reader))))
+                match try!(read_cut_text(reader)) {
+                    CutText::Latin1(text) => Ok(C2S::CutText(text)),
+                    CutText::Extended(clipboard) => Ok(C2S::ExtendedCutText(clipboard))
+                }
+            },
+            150 => {
+                Ok(C2S::EnableContinuousUpdates {
+                    enable:      try!(reader.read_u8()) != 0,
+                    x_position:  try!(reader.read_u16::<BigEndian>()),
+                    y_position:  try!(reader.read_u16::<BigEndian>()),
+                    width:       try!(reader.read_u16::<BigEndian>()),
+                    height:      try!(reader.read_u16::<BigEndian>())
+                })
+            },
+            248 => {
+                Ok(C2S::Fence(try!(Fence::read_from(reader))))
+            },
+            251 => {
+                try!(reader.read_exact(&mut [0u8; 1]));
+                let width = try!(reader.read_u16::<BigEndian>());
+                let height = try!(reader.read_u16::<BigEndian>());
+                let count = try!(reader.read_u8());
+                try!(reader.read_exact(&mut [0u8; 1]));
+                let mut screens = Vec::new();
+                for _ in 0..count {
+                    screens.push(try!(Screen::read_from(reader)));
+                }
+                Ok(C2S::SetDesktopSize { width: width, height: height, screens: screens })
             },
             255 => {
                 let submessage_type = try!(reader.read_u8());
@@ -498,8 +877,15 @@ impl Message for C2S {
                 try!(writer.write_u16::<BigEndian>(y_position));
             },
             &C2S::CutText(ref text) => {
+                try!(writer.write_u8(6));
+                try!(writer.write_all(&[0u8; 3]));
                 try!(String::write_to(text, writer));
             }
+            &C2S::ExtendedCutText(ref clipboard) => {
+                try!(writer.write_u8(6));
+                try!(writer.write_all(&[0u8; 3]));
+                try!(write_extended_cut_text(clipboard, writer));
+            }
             &C2S::ExtendedKeyEvent { down, keysym, keycode } => {
                 try!(writer.write_u8(255));
                 try!(writer.write_u8(0));
@@ -507,11 +893,449 @@ impl Message for C2S {
                 try!(writer.write_u32::<BigEndian>(keysym));
                 try!(writer.write_u32::<BigEndian>(keycode));
             }
+            &C2S::SetDesktopSize { width, height, ref screens } => {
+                if screens.len() > u8::max_value() as usize {
+                    return Err(Error::Unexpected("number of screens"))
+                }
+                try!(writer.write_u8(251));
+                try!(writer.write_all(&[0u8; 1]));
+                try!(writer.write_u16::<BigEndian>(width));
+                try!(writer.write_u16::<BigEndian>(height));
+                try!(writer.write_u8(screens.len() as u8));
+                try!(writer.write_all(&[0u8; 1]));
+                for screen in screens {
+                    try!(Screen::write_to(screen, writer));
+                }
+            }
+            &C2S::Fence(ref fence) => {
+                try!(writer.write_u8(248));
+                try!(Fence::write_to(fence, writer));
+            }
+            &C2S::EnableContinuousUpdates { enable, x_position, y_position, width, height } => {
+                try!(writer.write_u8(150));
+                try!(writer.write_u8(if enable { 1 } else { 0 }));
+                try!(writer.write_u16::<BigEndian>(x_position));
+                try!(writer.write_u16::<BigEndian>(y_position));
+                try!(writer.write_u16::<BigEndian>(width));
+                try!(writer.write_u16::<BigEndian>(height));
+            }
         }
         Ok(())
     }
 }
 
+/// Clipboard data formats of the Extended Clipboard extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardFormat {
+    Text,
+    Rtf,
+    Html,
+    Dib,
+    Files,
+}
+
+// Formats in the order of their flag bits, which is also the order their data is sent in.
+const CLIPBOARD_FORMATS: [ClipboardFormat; 5] = [
+    ClipboardFormat::Text,
+    ClipboardFormat::Rtf,
+    ClipboardFormat::Html,
+    ClipboardFormat::Dib,
+    ClipboardFormat::Files,
+];
+
+impl ClipboardFormat {
+    fn flag(&self) -> u32 {
+        match self {
+            &ClipboardFormat::Text => 1 << 0,
+            &ClipboardFormat::Rtf => 1 << 1,
+            &ClipboardFormat::Html => 1 << 2,
+            &ClipboardFormat::Dib => 1 << 3,
+            &ClipboardFormat::Files => 1 << 4,
+        }
+    }
+}
+
+fn clipboard_formats_from_flags(flags: u32) -> Vec<ClipboardFormat> {
+    CLIPBOARD_FORMATS.iter().cloned().filter(|format| flags & format.flag() != 0).collect()
+}
+
+fn clipboard_formats_to_flags(formats: &[ClipboardFormat]) -> u32 {
+    formats.iter().fold(0, |flags, format| flags | format.flag())
+}
+
+/// Actions of the Extended Clipboard extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardAction {
+    Caps,
+    Request,
+    Peek,
+    Notify,
+    Provide,
+}
+
+/// Largest clipboard contents accepted from a peer, which is also announced as the largest
+/// size of every format accepted without a `Request`.
+pub(crate) const CLIPBOARD_MAX_SIZE: u32 = 20 << 20;
+
+const CLIPBOARD_ACTIONS: [ClipboardAction; 5] = [
+    ClipboardAction::Caps,
+    ClipboardAction::Request,
+    ClipboardAction::Peek,
+    ClipboardAction::Notify,
+    ClipboardAction::Provide,
+];
+
+impl ClipboardAction {
+    fn flag(&self) -> u32 {
+        match self {
+            &ClipboardAction::Caps => 1 << 24,
+            &ClipboardAction::Request => 1 << 25,
+            &ClipboardAction::Peek => 1 << 26,
+            &ClipboardAction::Notify => 1 << 27,
+            &ClipboardAction::Provide => 1 << 28,
+        }
+    }
+}
+
+/// Clipboard contents in one of the formats that can be represented as text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardData {
+    Text(String),
+    Rtf(String),
+    Html(String),
+}
+
+impl ClipboardData {
+    pub fn format(&self) -> ClipboardFormat {
+        match self {
+            &ClipboardData::Text(_) => ClipboardFormat::Text,
+            &ClipboardData::Rtf(_) => ClipboardFormat::Rtf,
+            &ClipboardData::Html(_) => ClipboardFormat::Html,
+        }
+    }
+}
+
+/// An Extended Clipboard message, sent in place of `CutText` in both directions once
+/// both peers have announced support for the extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtendedClipboard {
+    /// Actions and formats the sender supports, together with the largest size of every
+    /// format it accepts without asking for it with `Request` first.
+    Caps {
+        actions: Vec<ClipboardAction>,
+        formats: Vec<(ClipboardFormat, u32)>,
+    },
+    /// Asks the peer to `Provide` its clipboard contents in these formats.
+    Request(Vec<ClipboardFormat>),
+    /// Asks the peer to `Notify` which formats its clipboard currently holds.
+    Peek,
+    /// Tells the peer that the clipboard has changed and now holds these formats.
+    Notify(Vec<ClipboardFormat>),
+    /// Clipboard contents. Formats that cannot be represented as text are dropped.
+    Provide(Vec<ClipboardData>),
+}
+
+impl Message for ExtendedClipboard {
+    fn read_from<R: Read>(reader: &mut R) -> Result<ExtendedClipboard> {
+        let flags = try!(reader.read_u32::<BigEndian>());
+        let formats = clipboard_formats_from_flags(flags);
+        if flags & ClipboardAction::Caps.flag() != 0 {
+            let actions = CLIPBOARD_ACTIONS.iter().cloned()
+                .filter(|&action| action != ClipboardAction::Caps && flags & action.flag() != 0)
+                .collect();
+            // Every format bit set, known or not, is followed by a size.
+            let mut sizes = Vec::new();
+            for bit in 0..16 {
+                if flags & (1 << bit) != 0 {
+                    sizes.push((bit, try!(reader.read_u32::<BigEndian>())))
+                }
+            }
+            let formats = sizes.into_iter()
+                .filter(|&(bit, _)| bit < CLIPBOARD_FORMATS.len())
+                .map(|(bit, size)| (CLIPBOARD_FORMATS[bit], size))
+                .collect();
+            Ok(ExtendedClipboard::Caps { actions: actions, formats: formats })
+        } else if flags & ClipboardAction::Request.flag() != 0 {
+            Ok(ExtendedClipboard::Request(formats))
+        } else if flags & ClipboardAction::Peek.flag() != 0 {
+            Ok(ExtendedClipboard::Peek)
+        } else if flags & ClipboardAction::Notify.flag() != 0 {
+            Ok(ExtendedClipboard::Notify(formats))
+        } else if flags & ClipboardAction::Provide.flag() != 0 {
+            let mut decoder = flate2::read::ZlibDecoder::new(reader);
+            let mut contents = Vec::new();
+            for format in formats {
+                let length = try!(decoder.read_u32::<BigEndian>());
+                if length > CLIPBOARD_MAX_SIZE {
+                    return Err(Error::Unexpected("clipboard length"))
+                }
+                let mut data = vec![0; length as usize];
+                try!(decoder.read_exact(&mut data));
+
+                // Text formats are sent null-terminated, and plain text has CRLF line endings.
+                if data.last() == Some(&0) { data.pop(); }
+                let text = String::from_utf8_lossy(&data).into_owned();
+                match format {
+                    ClipboardFormat::Text =>
+                        contents.push(ClipboardData::Text(text.replace("\r\n", "\n"))),
+                    ClipboardFormat::Rtf =>
+                        contents.push(ClipboardData::Rtf(text)),
+                    ClipboardFormat::Html =>
+                        contents.push(ClipboardData::Html(text)),
+                    _ => ()
+                }
+            }
+            Ok(ExtendedClipboard::Provide(contents))
+        } else {
+            Err(Error::Unexpected("extended clipboard action"))
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            &ExtendedClipboard::Caps { ref actions, ref formats } => {
+                let mut formats = formats.clone();
+                formats.sort_by_key(|&(format, _)| format.flag());
+                let flags = actions.iter().fold(ClipboardAction::Caps.flag(),
+                                                |flags, action| flags | action.flag());
+                let flags = formats.iter().fold(flags, |flags, &(format, _)| flags | format.flag());
+                try!(writer.write_u32::<BigEndian>(flags));
+                for &(_, size) in &formats {
+                    try!(writer.write_u32::<BigEndian>(size));
+                }
+            }
+            &ExtendedClipboard::Request(ref formats) => {
+                try!(writer.write_u32::<BigEndian>(ClipboardAction::Request.flag() |
+                                                   clipboard_formats_to_flags(formats)));
+            }
+            &ExtendedClipboard::Peek => {
+                try!(writer.write_u32::<BigEndian>(ClipboardAction::Peek.flag()));
+            }
+            &ExtendedClipboard::Notify(ref formats) => {
+                try!(writer.write_u32::<BigEndian>(ClipboardAction::Notify.flag() |
+                                                   clipboard_formats_to_flags(formats)));
+            }
+            &ExtendedClipboard::Provide(ref contents) => {
+                let mut contents = contents.iter().collect::<Vec<_>>();
+                contents.sort_by_key(|data| data.format().flag());
+                let flags = contents.iter()
+                    .fold(ClipboardAction::Provide.flag(), |flags, data| flags | data.format().flag());
+                try!(writer.write_u32::<BigEndian>(flags));
+
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(),
+                                                                  flate2::Compression::Default);
+                for data in contents {
+                    let mut bytes = match data {
+                        &ClipboardData::Text(ref text) =>
+                            text.replace("\r\n", "\n").replace("\n", "\r\n").into_bytes(),
+                        &ClipboardData::Rtf(ref text) |
+                        &ClipboardData::Html(ref text) =>
+                            text.clone().into_bytes(),
+                    };
+                    bytes.push(0);
+                    try!(encoder.write_u32::<BigEndian>(bytes.len() as u32));
+                    try!(encoder.write_all(&bytes));
+                }
+                try!(writer.write_all(&try!(encoder.finish())));
+            }
+        }
+        Ok(())
+    }
+}
+
+enum CutText {
+    Latin1(String),
+    Extended(ExtendedClipboard),
+}
+
+/// Reads the body of a `CutText` message in either direction; a negative length means
+/// that it carries an Extended Clipboard message.
+fn read_cut_text<R: Read>(reader: &mut R) -> Result<CutText> {
+    let length = try!(reader.read_i32::<BigEndian>());
+    if (length as i64).abs() > CLIPBOARD_MAX_SIZE as i64 {
+        return Err(Error::Unexpected("clipboard length"))
+    }
+    if length >= 0 {
+        let mut string = vec![0; length as usize];
+        try!(reader.read_exact(&mut string));
+        Ok(CutText::Latin1(string.iter().map(|c| *c as char).collect()))
+    } else {
+        let mut payload = vec![0; -length as usize];
+        try!(reader.read_exact(&mut payload));
+        Ok(CutText::Extended(try!(ExtendedClipboard::read_from(&mut &payload[..]))))
+    }
+}
+
+fn write_extended_cut_text<W: Write>(clipboard: &ExtendedClipboard, writer: &mut W) -> Result<()> {
+    let mut payload = Vec::new();
+    try!(clipboard.write_to(&mut payload));
+    try!(writer.write_i32::<BigEndian>(-(payload.len() as i32)));
+    try!(writer.write_all(&payload));
+    Ok(())
+}
+
+/// Body of a `Fence` message, which is the same in both directions. See `::fence` for
+/// the meaning of `flags`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fence {
+    pub flags:   u32,
+    pub payload: Vec<u8>,
+}
+
+impl Message for Fence {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Fence> {
+        try!(reader.read_exact(&mut [0u8; 3]));
+        let flags = try!(reader.read_u32::<BigEndian>());
+        let length = try!(reader.read_u8());
+        if length > 64 {
+            return Err(Error::Unexpected("fence payload length"))
+        }
+        let mut payload = vec![0; length as usize];
+        try!(reader.read_exact(&mut payload));
+        Ok(Fence { flags: flags, payload: payload })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.payload.len() > 64 {
+            return Err(Error::Unexpected("fence payload length"))
+        }
+        try!(writer.write_all(&[0u8; 3]));
+        try!(writer.write_u32::<BigEndian>(self.flags));
+        try!(writer.write_u8(self.payload.len() as u8));
+        try!(writer.write_all(&self.payload));
+        Ok(())
+    }
+}
+
+/// A single screen of a multi-head framebuffer, as used by the `ExtendedDesktopSize`
+/// extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Screen {
+    pub id:         u32,
+    pub x_position: u16,
+    pub y_position: u16,
+    pub width:      u16,
+    pub height:     u16,
+    pub flags:      u32,
+}
+
+impl Message for Screen {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Screen> {
+        Ok(Screen {
+            id:         try!(reader.read_u32::<BigEndian>()),
+            x_position: try!(reader.read_u16::<BigEndian>()),
+            y_position: try!(reader.read_u16::<BigEndian>()),
+            width:      try!(reader.read_u16::<BigEndian>()),
+            height:     try!(reader.read_u16::<BigEndian>()),
+            flags:      try!(reader.read_u32::<BigEndian>()),
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        try!(writer.write_u32::<BigEndian>(self.id));
+        try!(writer.write_u16::<BigEndian>(self.x_position));
+        try!(writer.write_u16::<BigEndian>(self.y_position));
+        try!(writer.write_u16::<BigEndian>(self.width));
+        try!(writer.write_u16::<BigEndian>(self.height));
+        try!(writer.write_u32::<BigEndian>(self.flags));
+        Ok(())
+    }
+}
+
+/// Screen layout carried by an `ExtendedDesktopSize` rectangle; the rectangle header
+/// itself holds the reason, status and framebuffer size.
+#[derive(Debug)]
+pub struct ScreenLayout(pub Vec<Screen>);
+
+impl Message for ScreenLayout {
+    fn read_from<R: Read>(reader: &mut R) -> Result<ScreenLayout> {
+        let count = try!(reader.read_u8());
+        try!(reader.read_exact(&mut [0u8; 3]));
+        let mut screens = Vec::new();
+        for _ in 0..count {
+            screens.push(try!(Screen::read_from(reader)));
+        }
+        Ok(ScreenLayout(screens))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.0.len() > u8::max_value() as usize {
+            return Err(Error::Unexpected("number of screens"))
+        }
+        try!(writer.write_u8(self.0.len() as u8));
+        try!(writer.write_all(&[0u8; 3]));
+        for screen in &self.0 {
+            try!(screen.write_to(writer));
+        }
+        Ok(())
+    }
+}
+
+/// Why the framebuffer size or screen layout has changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeReason {
+    Unknown(u16),
+    Server,
+    Client,
+    OtherClient,
+}
+
+impl From<u16> for ResizeReason {
+    fn from(reason: u16) -> ResizeReason {
+        match reason {
+            0 => ResizeReason::Server,
+            1 => ResizeReason::Client,
+            2 => ResizeReason::OtherClient,
+            n => ResizeReason::Unknown(n)
+        }
+    }
+}
+
+impl From<ResizeReason> for u16 {
+    fn from(reason: ResizeReason) -> u16 {
+        match reason {
+            ResizeReason::Server => 0,
+            ResizeReason::Client => 1,
+            ResizeReason::OtherClient => 2,
+            ResizeReason::Unknown(n) => n
+        }
+    }
+}
+
+/// Outcome of a `SetDesktopSize` request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeStatus {
+    Unknown(u16),
+    Succeeded,
+    Prohibited,
+    OutOfResources,
+    InvalidLayout,
+}
+
+impl From<u16> for ResizeStatus {
+    fn from(status: u16) -> ResizeStatus {
+        match status {
+            0 => ResizeStatus::Succeeded,
+            1 => ResizeStatus::Prohibited,
+            2 => ResizeStatus::OutOfResources,
+            3 => ResizeStatus::InvalidLayout,
+            n => ResizeStatus::Unknown(n)
+        }
+    }
+}
+
+impl From<ResizeStatus> for u16 {
+    fn from(status: ResizeStatus) -> u16 {
+        match status {
+            ResizeStatus::Succeeded => 0,
+            ResizeStatus::Prohibited => 1,
+            ResizeStatus::OutOfResources => 2,
+            ResizeStatus::InvalidLayout => 3,
+            ResizeStatus::Unknown(n) => n
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Rect {
     pub left:   u16,
@@ -581,7 +1405,7 @@ impl Message for RectangleHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Colour {
     pub red:   u16,
     pub green: u16,
@@ -619,6 +1443,9 @@ pub enum S2C {
     Bell,
     CutText(String),
     // extensions
+    ExtendedCutText(ExtendedClipboard),
+    EndOfContinuousUpdates,
+    Fence(Fence),
 }
 
 impl Message for S2C {
@@ -651,7 +1478,16 @@ impl Message for S2C {
             },
             3 => {
                 try!(reader.read_exact(&mut [0u8; 3]));
-                Ok(S2C::CutText(try!(String::read_from(reader))))
+                match try!(read_cut_text(reader)) {
+                    CutText::Latin1(text) => Ok(S2C::CutText(text)),
+                    CutText::Extended(clipboard) => Ok(S2C::ExtendedCutText(clipboard))
+                }
+            },
+            150 => {
+                Ok(S2C::EndOfContinuousUpdates)
+            },
+            248 => {
+                Ok(S2C::Fence(try!(Fence::read_from(reader))))
             },
             _ => Err(Error::Unexpected("server to client message type"))
         }
@@ -680,6 +1516,18 @@ impl Message for S2C {
                 try!(writer.write_all(&[0u8; 3]));
                 try!(String::write_to(text, writer));
             }
+            &S2C::ExtendedCutText(ref clipboard) => {
+                try!(writer.write_u8(3));
+                try!(writer.write_all(&[0u8; 3]));
+                try!(write_extended_cut_text(clipboard, writer));
+            }
+            &S2C::EndOfContinuousUpdates => {
+                try!(writer.write_u8(150));
+            }
+            &S2C::Fence(ref fence) => {
+                try!(writer.write_u8(248));
+                try!(Fence::write_to(fence, writer));
+            }
         }
         Ok(())
     }
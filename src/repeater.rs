@@ -0,0 +1,153 @@
+//! The preamble of the UltraVNC repeater protocol.
+//!
+//! A repeater relays connections to machines that cannot be reached directly. In mode 1,
+//! the viewer connects to the repeater and names the server to be connected to by its
+//! address; in mode 2, both the viewer and the server connect to the repeater, and are
+//! paired by a numeric ID. Either way, the preamble is 250 bytes long, zero-padded, and is
+//! followed by the usual RFB handshake between the viewer and the server.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use ::{Error, Result};
+
+/// The length of the preamble, in bytes.
+pub const PREAMBLE_LENGTH: usize = 250;
+
+// The protocol version sent by the repeater in place of the server's, to ask the viewer
+// for the destination.
+const REPEATER_VERSION: &'static [u8] = b"RFB 000.000\n";
+
+/// The server a viewer asks the repeater to connect it to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// Mode 1: the repeater connects to a server at `host:port`.
+    Address(String),
+    /// Mode 2: the repeater pairs the viewer with a server that announced the same ID.
+    Id(u32),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Destination::Address(ref address) => f.write_str(address),
+            &Destination::Id(id) => write!(f, "ID:{}", id),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = Error;
+
+    /// Parses `ID:nnnn` as an ID, and anything else as an address.
+    fn from_str(destination: &str) -> Result<Destination> {
+        if destination.starts_with("ID:") {
+            destination[3..].parse().map(Destination::Id)
+                            .map_err(|_| Error::Unexpected("repeater ID"))
+        } else {
+            Ok(Destination::Address(String::from(destination)))
+        }
+    }
+}
+
+fn write_preamble<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    // Leave room for the terminating zero expected by the repeater.
+    if text.len() >= PREAMBLE_LENGTH {
+        return Err(Error::Unexpected("repeater preamble length"))
+    }
+    let mut preamble = [0; PREAMBLE_LENGTH];
+    preamble[..text.len()].copy_from_slice(text.as_bytes());
+    try!(writer.write_all(&preamble));
+    try!(writer.flush());
+    Ok(())
+}
+
+/// Performs the viewer side of the preamble over `stream`, a connection to a repeater,
+/// after which the RFB handshake with the server can start.
+pub fn select_destination<S: Read + Write>(stream: &mut S, destination: &Destination)
+        -> Result<()> {
+    let mut version = [0; 12];
+    try!(stream.read_exact(&mut version));
+    if &version[..] != REPEATER_VERSION {
+        return Err(Error::Unexpected("repeater protocol version"))
+    }
+    debug!("-> repeater {}", destination);
+    write_preamble(stream, &destination.to_string())
+}
+
+/// Performs the server side of the preamble in mode 2 over `stream`, a connection to
+/// a repeater, after which the RFB handshake with the viewer that asked for `id` can start.
+pub fn announce_id<W: Write>(stream: &mut W, id: u32) -> Result<()> {
+    debug!("-> repeater ID:{}", id);
+    write_preamble(stream, &Destination::Id(id).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use ::{client, pixel_format, server, SecurityRegistry, NoAuthentication, Server};
+    use super::{Destination, PREAMBLE_LENGTH};
+
+    fn read_preamble(stream: &mut TcpStream) -> String {
+        let mut preamble = [0; PREAMBLE_LENGTH];
+        stream.read_exact(&mut preamble).unwrap();
+        let length = preamble.iter().position(|&byte| byte == 0).unwrap();
+        String::from_utf8(preamble[..length].to_vec()).unwrap()
+    }
+
+    fn relay(mut from: TcpStream, mut to: TcpStream) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let _ = io::copy(&mut from, &mut to);
+            let _ = to.shutdown(Shutdown::Write);
+        })
+    }
+
+    /// Checks if a `Client` and a `Server` that both announce the same ID to a repeater
+    /// establish a session through it.
+    #[test]
+    fn check_if_session_is_established_through_repeater() {
+        assert_eq!("ID:1234".parse::<Destination>().unwrap(), Destination::Id(1234));
+        assert_eq!("host:5900".parse::<Destination>().unwrap(),
+                   Destination::Address(String::from("host:5900")));
+
+        // Like actual repeaters, accept servers and viewers on different ports.
+        let server_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let viewer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (address, viewer_address) = (server_listener.local_addr().unwrap(),
+                                         viewer_listener.local_addr().unwrap());
+        let server = thread::spawn(move || -> ::Result<server::Event> {
+            let mut security = SecurityRegistry::new();
+            security.register(NoAuthentication);
+            let (mut server, _) = try!(Server::connect_reverse_via_repeater(
+                address, 1234, 64, 64, pixel_format::RGB8888, String::from("test"), &security));
+            server.read_event()
+        });
+
+        let repeater = thread::spawn(move || {
+            let (mut server_stream, _) = server_listener.accept().unwrap();
+            assert_eq!(read_preamble(&mut server_stream), "ID:1234");
+            let (mut viewer_stream, _) = viewer_listener.accept().unwrap();
+            viewer_stream.write_all(b"RFB 000.000\n").unwrap();
+            assert_eq!(read_preamble(&mut viewer_stream), "ID:1234");
+            let relay = relay(viewer_stream.try_clone().unwrap(),
+                              server_stream.try_clone().unwrap());
+            let _ = io::copy(&mut server_stream, &mut viewer_stream);
+            let _ = viewer_stream.shutdown(Shutdown::Write);
+            relay.join().unwrap();
+        });
+
+        let stream = TcpStream::connect(viewer_address).unwrap();
+        let mut client = client::Client::from_repeater(stream, &Destination::Id(1234), true,
+                                                       |_| Some(client::AuthChoice::None))
+                                                       .unwrap();
+        client.send_key_event(true, 0x61).unwrap();
+        match server.join().unwrap() {
+            Ok(server::Event::KeyEvent { down: true, key: 0x61 }) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        client.disconnect().unwrap();
+        repeater.join().unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io::Read;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use ::{protocol, Error, Result};
+use protocol::Message;
+
+/// Decodes an RRE rectangle from `reader`, or a CoRRE one if `compact` is set, calling
+/// `callback` with every solid-colour area and the single pixel it should be filled with.
+///
+/// The first area passed to `callback` is always the background, covering all of `rect`.
+/// Returns `false` if `callback` asked to stop decoding.
+pub fn decode<R, F>(reader: &mut R, format: protocol::PixelFormat, rect: protocol::Rect,
+                    compact: bool, mut callback: F) -> Result<bool>
+        where R: Read, F: FnMut(protocol::Rect, &[u8]) -> Result<bool> {
+    let bpp = format.bits_per_pixel as usize / 8;
+
+    let count = try!(reader.read_u32::<BigEndian>());
+    let mut background = vec![0; bpp];
+    try!(reader.read_exact(&mut background));
+    if let false = try!(callback(rect, &background)) {
+        return Ok(false)
+    }
+
+    let mut colour = vec![0; bpp];
+    for _ in 0..count {
+        try!(reader.read_exact(&mut colour));
+        let subrect =
+            if compact {
+                protocol::Rect::new(try!(reader.read_u8()) as u16,
+                                    try!(reader.read_u8()) as u16,
+                                    try!(reader.read_u8()) as u16,
+                                    try!(reader.read_u8()) as u16)
+            } else {
+                try!(protocol::Rect::read_from(reader))
+            };
+        if subrect.left as u32 + subrect.width  as u32 > rect.width  as u32 ||
+           subrect.top  as u32 + subrect.height as u32 > rect.height as u32 {
+            return Err(Error::Unexpected("RRE subrectangle"))
+        }
+
+        let area = match (rect.left.checked_add(subrect.left),
+                          rect.top.checked_add(subrect.top)) {
+            (Some(left), Some(top)) => protocol::Rect::new(left, top, subrect.width,
+                                                           subrect.height),
+            _ => return Err(Error::Unexpected("RRE subrectangle"))
+        };
+        if let false = try!(callback(area, &colour)) {
+            return Ok(false)
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the length of the RRE data at the start of `data`, or of the CoRRE data if
+/// `compact` is set, once its header is there, or the length of the header otherwise.
+pub(crate) fn encoded_length(data: &[u8], bpp: usize, compact: bool) -> usize {
+    let header_length = 4 + bpp;
+    if data.len() < 4 {
+        return header_length
+    }
+    let count = BigEndian::read_u32(&data[..4]) as usize;
+    let subrect_length = bpp + if compact { 4 } else { 8 };
+    header_length.saturating_add(count.saturating_mul(subrect_length))
+}
+
+/// Returns the pixel that occurs most often in `pixels`, each `bpp` bytes long.
+pub fn most_common_pixel(pixels: &[u8], bpp: usize) -> Option<&[u8]> {
+    let mut counts = HashMap::new();
+    for pixel in pixels.chunks(bpp) {
+        *counts.entry(pixel).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|&(pixel, count)| (count, pixel)).map(|(pixel, _)| pixel)
+}
+
+/// Splits the pixels of a `width` by `height` area that differ from `background` into
+/// solid-colour subrectangles, relative to the area, and returns them with their colour.
+///
+/// Every subrectangle is grown to the right first and then downwards, as far as it goes.
+pub fn subrects<'a>(pixels: &'a [u8], width: usize, height: usize, bpp: usize,
+                    background: &[u8]) -> Vec<(protocol::Rect, &'a [u8])> {
+    let pixel = |x: usize, y: usize| {
+        let start = (y * width + x) * bpp;
+        &pixels[start..start + bpp]
+    };
+    let mut covered = vec![false; width * height];
+    let mut subrects = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let colour = pixel(x, y);
+            if covered[y * width + x] || colour == background { continue }
+
+            let mut right = x + 1;
+            while right < width && !covered[y * width + right] && pixel(right, y) == colour {
+                right += 1;
+            }
+            let mut bottom = y + 1;
+            while bottom < height && (x..right).all(|column| {
+                !covered[bottom * width + column] && pixel(column, bottom) == colour
+            }) {
+                bottom += 1;
+            }
+
+            for row in y..bottom {
+                for column in x..right {
+                    covered[row * width + column] = true;
+                }
+            }
+            subrects.push((protocol::Rect::new(x as u16, y as u16, (right - x) as u16,
+                                               (bottom - y) as u16), colour));
+        }
+    }
+    subrects
+}
+
+/// Encodes `pixels` in `format` covering `rect` as an RRE rectangle, appending it
+/// to `output`.
+///
+/// The most common pixel becomes the background, and the others are sent
+/// as subrectangles.
+pub fn encode(format: protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8],
+              output: &mut Vec<u8>) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    let zero = vec![0; bpp];
+    let background = most_common_pixel(pixels, bpp).unwrap_or(&zero);
+    let subrects = subrects(pixels, rect.width as usize, rect.height as usize, bpp,
+                            background);
+
+    // Writing into a vector cannot fail.
+    output.write_u32::<BigEndian>(subrects.len() as u32).unwrap();
+    output.extend_from_slice(background);
+    for (subrect, colour) in subrects {
+        output.extend_from_slice(colour);
+        subrect.write_to(output).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::{pixel_format, Error, Result};
+    use protocol::Rect;
+
+    /// Decodes `data` in RGB8888, returning every area with the first byte of its colour,
+    /// and stopping once `limit` areas have been returned.
+    fn decode_areas(data: &[u8], rect: Rect, compact: bool, limit: usize)
+                    -> Result<(bool, Vec<(Rect, u8)>)> {
+        let mut areas = Vec::new();
+        let done = try!(super::decode(&mut &data[..], pixel_format::RGB8888, rect, compact,
+                                      |area, pixel| {
+            areas.push((area, pixel[0]));
+            Ok(areas.len() < limit)
+        }));
+        Ok((done, areas))
+    }
+
+    /// Checks if CoRRE subrectangles, with coordinates of a byte each, are decoded.
+    #[test]
+    fn check_if_compact_subrects_are_decoded() {
+        let data = [0, 0, 0, 2, 1, 0, 0, 0,
+                    2, 0, 0, 0, 1, 0, 3, 2,
+                    3, 0, 0, 0, 0, 1, 1, 1];
+        assert_eq!(decode_areas(&data, Rect::new(10, 20, 4, 2), true, 10).unwrap(),
+                   (true, vec![(Rect::new(10, 20, 4, 2), 1),
+                               (Rect::new(11, 20, 3, 2), 2),
+                               (Rect::new(10, 21, 1, 1), 3)]));
+    }
+
+    /// Checks if subrectangles reaching outside of the rectangle, or past the largest
+    /// coordinate, are rejected.
+    #[test]
+    fn check_if_subrect_outside_rect_is_rejected() {
+        let data = [0, 0, 0, 1, 1, 0, 0, 0,
+                    2, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 1];
+        for &rect in &[Rect::new(0, 0, 2, 1), Rect::new(65535, 0, 3, 1)] {
+            match decode_areas(&data, rect, false, 10) {
+                Err(Error::Unexpected(_)) => (),
+                result => panic!("{:?}: unexpected result {:?}", rect, result)
+            }
+        }
+    }
+
+    /// Checks if decoding stops as soon as the callback asks it to.
+    #[test]
+    fn check_if_decoding_stops_when_asked() {
+        let data = [0, 0, 0, 2, 1, 0, 0, 0,
+                    2, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1];
+        assert_eq!(decode_areas(&data, Rect::new(0, 0, 2, 2), false, 1).unwrap(),
+                   (false, vec![(Rect::new(0, 0, 2, 2), 1)]));
+        assert_eq!(decode_areas(&data, Rect::new(0, 0, 2, 2), false, 2).unwrap(),
+                   (false, vec![(Rect::new(0, 0, 2, 2), 1), (Rect::new(0, 0, 1, 1), 2)]));
+    }
+
+    /// Checks if pixels encoded as RRE are decoded back unchanged.
+    #[test]
+    fn check_if_encoded_pixels_are_decoded() {
+        let rect = Rect::new(10, 20, 5, 3);
+        let pixels: Vec<u8> = [0, 0, 1, 1, 0,
+                               0, 1, 1, 2, 0,
+                               0, 0, 0, 2, 0].iter().flat_map(|&colour| vec![colour, 0, 0, 0])
+                                             .collect();
+        let mut output = Vec::new();
+        super::encode(pixel_format::RGB8888, rect, &pixels, &mut output);
+        // Three subrectangles of 4 + 8 bytes after the count and the background.
+        assert_eq!(output.len(), 4 + 4 + 3 * 12);
+
+        let mut decoded = vec![0xff; pixels.len()];
+        super::decode(&mut &output[..], pixel_format::RGB8888, rect, false, |area, pixel| {
+            for y in area.top - rect.top..area.top - rect.top + area.height {
+                for x in area.left - rect.left..area.left - rect.left + area.width {
+                    let start = (y as usize * 5 + x as usize) * 4;
+                    decoded[start..start + 4].copy_from_slice(pixel);
+                }
+            }
+            Ok(true)
+        }).unwrap();
+        assert_eq!(decoded, pixels);
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use ::{protocol, Error, Result};
+use stream::Stream;
+
+/// Access granted to a client that has been authenticated by a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The client may interact with the desktop.
+    Full,
+    /// The client may only watch the desktop.
+    ViewOnly,
+}
+
+/// Implementation of a security type, which performs its handshake after the security type
+/// has been chosen, and may wrap the stream carrying the rest of the session.
+///
+/// A handler implements one or more of the client side, the server side, and relaying
+/// the handshake between a client and a server; the sides it does not implement fail with
+/// `Error::AuthenticationUnavailable`.
+pub trait SecurityHandler: Send + Sync {
+    /// Returns the security type implemented by this handler.
+    fn security_type(&self) -> protocol::SecurityType;
+
+    /// Returns whether the server sends a `SecurityResult` after the handshake with clients
+    /// using `version`. Prior to RFB 3.8, it is not sent when there is no authentication.
+    fn sends_security_result(&self, version: protocol::Version) -> bool {
+        let _ = version;
+        true
+    }
+
+    /// Performs the client side of the handshake, and returns the stream carrying the rest
+    /// of the session.
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        let _ = stream;
+        Err(Error::AuthenticationUnavailable)
+    }
+
+    /// Performs the server side of the handshake, and returns the stream carrying the rest
+    /// of the session together with the access granted to the client, or `None` if it could
+    /// not be authenticated.
+    fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+        let _ = stream;
+        Err(Error::AuthenticationUnavailable)
+    }
+
+    /// Forwards the handshake between a client and a server without taking part in it.
+    /// This is only possible if neither stream has to be wrapped afterwards.
+    fn relay(&self, client_stream: &mut Stream, server_stream: &mut Stream) -> Result<()> {
+        let _ = (client_stream, server_stream);
+        Err(Error::AuthenticationUnavailable)
+    }
+}
+
+impl fmt::Debug for SecurityHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecurityHandler({:?})", self.security_type())
+    }
+}
+
+/// Security handlers available to an endpoint, in order of preference.
+#[derive(Debug, Default)]
+pub struct SecurityRegistry {
+    handlers: Vec<Box<SecurityHandler>>,
+}
+
+impl SecurityRegistry {
+    pub fn new() -> SecurityRegistry {
+        SecurityRegistry { handlers: Vec::new() }
+    }
+
+    /// Adds `handler` with the lowest preference, replacing any handler registered earlier
+    /// for the same security type.
+    pub fn register<H: SecurityHandler + 'static>(&mut self, handler: H) -> &mut Self {
+        self.register_boxed(Box::new(handler))
+    }
+
+    pub fn register_boxed(&mut self, handler: Box<SecurityHandler>) -> &mut Self {
+        let security_type = handler.security_type();
+        self.handlers.retain(|registered| registered.security_type() != security_type);
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Returns the security types of the registered handlers, in order of preference.
+    pub fn security_types(&self) -> Vec<protocol::SecurityType> {
+        self.handlers.iter().map(|handler| handler.security_type()).collect()
+    }
+
+    /// Returns the handler registered for `security_type`.
+    pub fn get(&self, security_type: protocol::SecurityType) -> Option<&SecurityHandler> {
+        self.handlers.iter().find(|handler| handler.security_type() == security_type)
+                     .map(|handler| &**handler)
+    }
+
+    /// Returns the first handler, in order of preference, for one of `security_types`.
+    pub fn choose(&self, security_types: &[protocol::SecurityType])
+                  -> Option<&SecurityHandler> {
+        self.handlers.iter().find(|handler| security_types.contains(&handler.security_type()))
+                     .map(|handler| &**handler)
+    }
+}
+
+/// The `None` security type, which neither authenticates the client nor encrypts the session.
+/// It can be used by clients, servers and proxies alike.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuthentication;
+
+impl SecurityHandler for NoAuthentication {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::None
+    }
+
+    fn sends_security_result(&self, version: protocol::Version) -> bool {
+        version == protocol::Version::Rfb38
+    }
+
+    fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+        Ok(stream)
+    }
+
+    fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+        Ok((stream, Some(Access::Full)))
+    }
+
+    fn relay(&self, _client_stream: &mut Stream, _server_stream: &mut Stream) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use ::{protocol, client, Client, Server, Error, Result};
+    use stream::Stream;
+    use super::{Access, SecurityHandler, SecurityRegistry};
+
+    /// A security type where the client proves that it knows a token by sending it.
+    struct Token(&'static [u8; 4]);
+
+    impl SecurityHandler for Token {
+        fn security_type(&self) -> protocol::SecurityType {
+            protocol::SecurityType::Unknown(200)
+        }
+
+        fn client_handshake(&self, stream: Stream) -> Result<Stream> {
+            let mut stream = stream;
+            try!(stream.write_all(self.0));
+            Ok(stream)
+        }
+
+        fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+            let mut stream = stream;
+            let mut token = [0; 4];
+            try!(stream.read_exact(&mut token));
+            Ok((stream, if &token == self.0 { Some(Access::ViewOnly) } else { None }))
+        }
+    }
+
+    fn connect_with_token(token: &'static [u8; 4]) -> Result<bool> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut security = SecurityRegistry::new();
+            security.register(Token(b"open"));
+            let (server, _) = try!(Server::from_tcp_stream_with_security(
+                stream, 64, 64, ::pixel_format::RGB8888, String::from("test"), &security));
+            Ok(server.is_view_only())
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let client = Client::from_tcp_stream(stream, true, |methods| {
+            assert!(methods.iter().any(|method| match method {
+                &client::AuthMethod::Other(protocol::SecurityType::Unknown(200)) => true,
+                _ => false
+            }));
+            Some(client::AuthChoice::Custom(Box::new(Token(token))))
+        });
+        let result = server.join().unwrap();
+        match client {
+            Ok(client) => {
+                let _ = client.disconnect();
+                result
+            }
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Checks if handlers defined outside of the crate can authenticate both peers.
+    #[test]
+    fn check_if_custom_security_handler_is_used() {
+        match connect_with_token(b"open") {
+            Ok(true) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        match connect_with_token(b"shut") {
+            Err(Error::AuthenticationFailure(_)) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+}
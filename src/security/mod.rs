@@ -1,6 +1,32 @@
 mod des;
 pub use self::des::encrypt as des;
 
+pub mod rsa_aes;
+
+mod handler;
+pub use self::handler::{Access, SecurityHandler, SecurityRegistry, NoAuthentication};
+
+/// Turns a VNC password into the DES key used to encrypt the authentication challenge.
+pub fn des_key(password: &[u8; 8]) -> [u8; 8] {
+    // Reverse the bits in every byte of password.
+    // DES is 56-bit and as commonly implemented, it takes a 8-octet key
+    // and ignores LSB of every octet; this of course would be bad for
+    // ASCII passwords.
+    //
+    // I've spent *hours* figuring this out.
+    // I hate every single fucker involved in the chain of decisions that
+    // led to this authentication scheme, and doubly so because it is completely
+    // undocumented in what passes for the specification of the RFB protocol.
+    let mut key = [0; 8];
+    for i in 0..8 {
+        let c = password[i];
+        let mut cs = 0u8;
+        for j in 0..8 { cs |= ((c >> j) & 1) << (7 - j) }
+        key[i] = cs;
+    }
+    key
+}
+
 #[cfg(feature = "apple-auth")]
 mod md5;
 #[cfg(feature = "apple-auth")]
@@ -0,0 +1,79 @@
+use rsa::{self, BigUint, RsaPrivateKey, RsaPublicKey, Pkcs1v15Encrypt};
+use rsa::traits::PublicKeyParts;
+use rsa::rand_core::{OsRng, RngCore};
+use sha1::{Sha1, Digest};
+use sha2::Sha256;
+use ::{protocol, Error, Result};
+use protocol::Message;
+
+fn rsa_error(_error: rsa::Error) -> Error {
+    Error::Unexpected("RSA operation failure")
+}
+
+/// Generates a key pair of `bits` bits.
+pub fn generate_key(bits: usize) -> Result<RsaPrivateKey> {
+    RsaPrivateKey::new(&mut OsRng, bits).map_err(rsa_error)
+}
+
+pub fn to_message(key: &RsaPublicKey) -> protocol::RsaAesPublicKey {
+    protocol::RsaAesPublicKey {
+        modulus: key.n().to_bytes_be(),
+        exponent: key.e().to_bytes_be()
+    }
+}
+
+pub fn from_message(key: &protocol::RsaAesPublicKey) -> Result<RsaPublicKey> {
+    RsaPublicKey::new(BigUint::from_bytes_be(&key.modulus),
+                      BigUint::from_bytes_be(&key.exponent)).map_err(rsa_error)
+}
+
+/// Returns the key as sent on the wire, which is what the handshake hashes are computed over.
+pub fn to_bytes(key: &protocol::RsaAesPublicKey) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(key.write_to(&mut data));
+    Ok(data)
+}
+
+/// Returns the SHA-256 hash of the key as sent on the wire, which identifies a server.
+pub fn fingerprint(key_data: &[u8]) -> Vec<u8> {
+    Sha256::digest(key_data).to_vec()
+}
+
+pub fn random(length: usize) -> Vec<u8> {
+    let mut data = vec![0; length];
+    OsRng.fill_bytes(&mut data);
+    data
+}
+
+pub fn encrypt(key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
+    key.encrypt(&mut OsRng, Pkcs1v15Encrypt, data).map_err(rsa_error)
+}
+
+pub fn decrypt(key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    key.decrypt(Pkcs1v15Encrypt, data).map_err(rsa_error)
+}
+
+/// Hashes the concatenation of `parts` with SHA-1, or SHA-256 for the 256-bit variants.
+pub fn hash(variant: protocol::RsaAesVariant, parts: &[&[u8]]) -> Vec<u8> {
+    if variant.key_size() == 16 {
+        let mut hasher = Sha1::new();
+        for part in parts { hasher.update(part) }
+        hasher.finalize().to_vec()
+    } else {
+        let mut hasher = Sha256::new();
+        for part in parts { hasher.update(part) }
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Derives the keys encrypting the client-to-server and server-to-client directions
+/// from the random values the peers have exchanged.
+pub fn session_keys(variant: protocol::RsaAesVariant, client_random: &[u8],
+                    server_random: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let key_size = variant.key_size();
+    let mut client_key = hash(variant, &[server_random, client_random]);
+    client_key.truncate(key_size);
+    let mut server_key = hash(variant, &[client_random, server_random]);
+    server_key.truncate(key_size);
+    (client_key, server_key)
+}
@@ -1,8 +1,255 @@
-use std::io::Write;
-use std::net::{TcpStream, Shutdown};
+use std::io::{Read, Write};
+use std::net::{TcpStream, Shutdown, ToSocketAddrs};
+use std::sync::Arc;
 use byteorder::{BigEndian, WriteBytesExt};
-use ::{protocol, Result};
+use rustls;
+use ::{protocol, repeater, Error, Result};
 use protocol::Message;
+use rsa;
+use security::{des, des_key, rsa_aes, Access, SecurityHandler, SecurityRegistry,
+               NoAuthentication};
+use stream::Stream;
+use transport::Transport;
+use connection::{self, ServerConnection, ServerState};
+
+pub use encoder::{Encoder, EncoderRegistry, RawEncoder, HextileEncoder, RreEncoder};
+pub use server_framebuffer::Framebuffer;
+pub use shared_server::{SharedServer, ClientId, ClientInfo, ExclusivePolicy};
+
+/// Server-side configuration of the VNC authentication security type.
+///
+/// Only the first 8 bytes of the passwords are significant. A client that knows
+/// `view_only_password` but not `password` is allowed to watch the session, but not to
+/// interact with it.
+pub struct VncAuthentication {
+    /// Password granting full access.
+    pub password: String,
+
+    /// Password granting view-only access.
+    pub view_only_password: Option<String>,
+}
+
+impl SecurityHandler for VncAuthentication {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::VncAuthentication
+    }
+
+    fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+        let mut stream = stream;
+        let (challenge, response) = try!(vnc_challenge(&mut stream));
+        Ok((stream, self.access(&challenge, &response)))
+    }
+}
+
+impl VncAuthentication {
+    /// Returns the access granted by `response` to `challenge`, if any.
+    pub(crate) fn access(&self, challenge: &[u8; 16], response: &[u8; 16]) -> Option<Access> {
+        if vnc_response_matches(challenge, response, &self.password) {
+            Some(Access::Full)
+        } else {
+            match self.view_only_password {
+                Some(ref password) if vnc_response_matches(challenge, response, password) =>
+                    Some(Access::ViewOnly),
+                _ => None
+            }
+        }
+    }
+}
+
+/// Server-side configuration of the VeNCrypt security type.
+///
+/// All sub-types except `Plain` run the rest of the session over TLS, and require
+/// `tls_config`. The `Tls*` sub-types are meant for servers without a certificate the client
+/// can verify, but rustls cannot run TLS without one, so a self-signed certificate should be
+/// used for them.
+pub struct VeNCrypt {
+    /// Offered sub-types, in order of preference.
+    pub subtypes: Vec<protocol::VeNCryptSubtype>,
+
+    /// TLS configuration containing the server certificate.
+    pub tls_config: Option<Arc<rustls::ServerConfig>>,
+
+    /// Password checked by the `*Vnc` sub-types. Only its first 8 bytes are significant.
+    pub password: Option<String>,
+
+    /// Checks the username and password sent with the `*Plain` sub-types.
+    pub check_credentials: Option<Box<Fn(&str, &str) -> bool + Send + Sync>>,
+}
+
+impl SecurityHandler for VeNCrypt {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::VeNCrypt
+    }
+
+    fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+        let (stream, authenticated) = try!(vencrypt_authenticate(stream, self));
+        Ok((stream, if authenticated { Some(Access::Full) } else { None }))
+    }
+}
+
+/// Performs the VeNCrypt handshake and returns the stream carrying the rest of the session,
+/// and whether the client has been authenticated.
+fn vencrypt_authenticate(stream: Stream, vencrypt: &VeNCrypt) -> Result<(Stream, bool)> {
+    let mut stream = stream;
+
+    let version = protocol::VeNCryptVersion { major: 0, minor: 2 };
+    try!(version.write_to(&mut stream));
+    if try!(protocol::VeNCryptVersion::read_from(&mut stream)) != version {
+        try!(stream.write_u8(1));
+        return Err(Error::Unexpected("VeNCrypt version"))
+    }
+    try!(stream.write_u8(0));
+
+    try!(protocol::VeNCryptSubtypes(vencrypt.subtypes.clone()).write_to(&mut stream));
+    let subtype = try!(protocol::VeNCryptSubtype::read_from(&mut stream));
+    if !vencrypt.subtypes.contains(&subtype) {
+        return Err(Error::Unexpected("VeNCrypt sub-type"))
+    }
+
+    if subtype.uses_tls() {
+        match vencrypt.tls_config {
+            Some(ref config) => {
+                try!(stream.write_u8(1));
+                stream = try!(stream.start_tls_server(config.clone()));
+            }
+            None => {
+                try!(stream.write_u8(0));
+                return Err(Error::AuthenticationUnavailable)
+            }
+        }
+    }
+
+    let authenticated = match subtype {
+        protocol::VeNCryptSubtype::TlsNone |
+        protocol::VeNCryptSubtype::X509None => true,
+        protocol::VeNCryptSubtype::TlsVnc |
+        protocol::VeNCryptSubtype::X509Vnc => {
+            let password = try!(vencrypt.password.as_ref().ok_or(Error::AuthenticationUnavailable));
+            let (challenge, response) = try!(vnc_challenge(&mut stream));
+            vnc_response_matches(&challenge, &response, password)
+        }
+        protocol::VeNCryptSubtype::Plain |
+        protocol::VeNCryptSubtype::TlsPlain |
+        protocol::VeNCryptSubtype::X509Plain => {
+            let check_credentials = try!(vencrypt.check_credentials.as_ref()
+                                             .ok_or(Error::AuthenticationUnavailable));
+            let credentials = try!(protocol::PlainCredentials::read_from(&mut stream));
+            check_credentials(&credentials.username, &credentials.password)
+        }
+        protocol::VeNCryptSubtype::Unknown(_) =>
+            return Err(Error::AuthenticationUnavailable)
+    };
+
+    Ok((stream, authenticated))
+}
+
+/// Server-side configuration of the RSA-AES security type.
+///
+/// The server proves its identity with `key`; clients can only detect man-in-the-middle
+/// attacks if they know its fingerprint in advance, or have remembered it from an earlier
+/// connection. Keys must be between 1024 and 8192 bits long.
+pub struct RsaAes {
+    /// Offered variant.
+    pub variant: protocol::RsaAesVariant,
+
+    /// Key pair of the server.
+    pub key: rsa::RsaPrivateKey,
+
+    /// Whether the client has to send a username together with the password.
+    pub username_required: bool,
+
+    /// Checks the username and password sent by the client. The username is empty unless
+    /// `username_required` is set.
+    pub check_credentials: Box<Fn(&str, &str) -> bool + Send + Sync>,
+}
+
+impl SecurityHandler for RsaAes {
+    fn security_type(&self) -> protocol::SecurityType {
+        protocol::SecurityType::RsaAes(self.variant)
+    }
+
+    fn server_handshake(&self, stream: Stream) -> Result<(Stream, Option<Access>)> {
+        let (stream, authenticated) = try!(rsa_aes_authenticate(stream, self));
+        Ok((stream, if authenticated { Some(Access::Full) } else { None }))
+    }
+}
+
+/// Performs the RSA-AES handshake and returns the stream carrying the rest of the session,
+/// and whether the client has been authenticated.
+fn rsa_aes_authenticate(stream: Stream, config: &RsaAes) -> Result<(Stream, bool)> {
+    let mut stream = stream;
+    let variant = config.variant;
+
+    let server_key = rsa_aes::to_message(&config.key.to_public_key());
+    let server_key_data = try!(rsa_aes::to_bytes(&server_key));
+    try!(server_key.write_to(&mut stream));
+
+    let client_key = try!(protocol::RsaAesPublicKey::read_from(&mut stream));
+    let client_key_data = try!(rsa_aes::to_bytes(&client_key));
+    let client_key = try!(rsa_aes::from_message(&client_key));
+
+    let server_random = rsa_aes::random(variant.key_size());
+    let encrypted_random = try!(rsa_aes::encrypt(&client_key, &server_random));
+    try!(protocol::RsaAesRandom(encrypted_random).write_to(&mut stream));
+    let client_random = try!(protocol::RsaAesRandom::read_from(&mut stream));
+    let client_random = try!(rsa_aes::decrypt(&config.key, &client_random.0));
+    if client_random.len() != variant.key_size() {
+        return Err(Error::Unexpected("RSA-AES random length"))
+    }
+
+    let (client_session_key, server_session_key) =
+        rsa_aes::session_keys(variant, &client_random, &server_random);
+    stream = try!(stream.start_eax(&client_session_key, &server_session_key));
+
+    try!(stream.write_all(&rsa_aes::hash(variant, &[&server_key_data, &client_key_data])));
+    let expected_hash = rsa_aes::hash(variant, &[&client_key_data, &server_key_data]);
+    let mut hash = vec![0; expected_hash.len()];
+    try!(stream.read_exact(&mut hash));
+    if hash != expected_hash {
+        return Err(Error::Unexpected("RSA-AES key hash"))
+    }
+
+    let subtype = if config.username_required {
+        protocol::RsaAesSubtype::UsernameAndPassword
+    } else {
+        protocol::RsaAesSubtype::Password
+    };
+    try!(subtype.write_to(&mut stream));
+    let credentials = try!(protocol::RsaAesCredentials::read_from(&mut stream));
+    let authenticated = (config.check_credentials)(&credentials.username, &credentials.password);
+
+    if !variant.encrypts_session() {
+        stream = try!(stream.stop_eax());
+    }
+    Ok((stream, authenticated))
+}
+
+/// Returns a random VNC authentication challenge.
+pub(crate) fn random_challenge() -> Result<[u8; 16]> {
+    let mut challenge = [0; 16];
+    try!(rustls::crypto::ring::default_provider().secure_random.fill(&mut challenge)
+             .map_err(|_| Error::Unexpected("random number generator failure")));
+    Ok(challenge)
+}
+
+/// Sends a random challenge and returns it together with the client's response.
+fn vnc_challenge(stream: &mut Stream) -> Result<([u8; 16], [u8; 16])> {
+    let challenge = try!(random_challenge());
+    try!(stream.write_all(&challenge));
+
+    let mut response = [0; 16];
+    try!(stream.read_exact(&mut response));
+    Ok((challenge, response))
+}
+
+/// Returns whether `response` to `challenge` proves that the client knows `password`.
+fn vnc_response_matches(challenge: &[u8; 16], response: &[u8; 16], password: &str) -> bool {
+    let mut key = [0; 8];
+    for (i, byte) in password.bytes().take(8).enumerate() {
+        key[i] = byte
+    }
+    des(challenge, &des_key(&key)) == &response[..]
+}
 
 /// Definitions of events received by server from client.
 #[derive(Debug)]
@@ -81,6 +328,16 @@ pub enum Event {
     /// outside the Latin-1 character set.
     CutText(String),
 
+    /// An Extended Clipboard message replaces `CutText` for clients that announced support for
+    /// the `ExtendedClipboard` pseudo-encoding, once the server has sent its own
+    /// `ExtendedClipboard::Caps`. It carries UTF-8 text, RTF or HTML instead of Latin-1 text.
+    ///
+    /// The client answers the server's capabilities with its own. Afterwards either side can
+    /// announce clipboard changes with `Notify`, ask for the contents with `Request` (to be
+    /// answered with `Provide`), ask for a `Notify` with `Peek`, or send the contents directly
+    /// with `Provide` if they fit into the size the peer accepts for their format.
+    ExtendedCutText(protocol::ExtendedClipboard),
+
     /// This encoding allows the client to send an extended key event containing a keycode, in
     /// addition to a keysym. The advantage of providing the keycode is that it enables the server
     /// to interpret the key event independently of the clients’ locale specific keymap. This can
@@ -97,6 +354,120 @@ pub enum Event {
         keysym: u32,
         keycode: u32,
     },
+
+    /// A `SetDesktopSize` message requests a change of desktop size and screen layout. It may
+    /// only be sent by clients that announced support for the `ExtendedDesktopSize`
+    /// pseudo-encoding, and after the server has confirmed it.
+    ///
+    /// The server should reply with an `ExtendedDesktopSize` update with `ResizeReason::Client`
+    /// and a status telling whether the request was granted; on success every other client
+    /// should receive the new layout with `ResizeReason::OtherClient`.
+    SetDesktopSize {
+        width: u16,
+        height: u16,
+        screens: Vec<protocol::Screen>,
+    },
+
+    /// A `Fence` message is used to synchronize the message streams of the client and the server.
+    /// It may only be sent by clients that announced support for the `Fence` pseudo-encoding,
+    /// after the server has sent a fence of its own.
+    ///
+    /// If `flags` contain `fence::REQUEST`, the server must respond with a fence carrying the
+    /// same `payload` and the subset of the other flags it understands, after satisfying them:
+    /// `fence::BLOCK_BEFORE` requires all preceding messages to have taken effect,
+    /// `fence::BLOCK_AFTER` requires no following message to be processed before the response,
+    /// and `fence::SYNC_NEXT` requires the following message to take effect exactly at the point
+    /// in the server-to-client stream where the response is sent.
+    Fence {
+        flags: u32,
+        payload: Vec<u8>,
+    },
+
+    /// An `EnableContinuousUpdates` message asks the server to send updates for `rect` whenever
+    /// it changes, without waiting for `FramebufferUpdateRequest` messages. It may only be sent by
+    /// clients that announced support for the `ContinuousUpdates` pseudo-encoding, after the server
+    /// has sent an `EndOfContinuousUpdates` message.
+    ///
+    /// When `enable` is `false`, the server must stop sending unsolicited updates and reply with
+    /// an `EndOfContinuousUpdates` message.
+    EnableContinuousUpdates {
+        enable: bool,
+        rect: protocol::Rect,
+    },
+}
+
+impl Event {
+    /// Converts a message received from the client into an event.
+    pub(crate) fn from_message(message: protocol::C2S) -> Event {
+        match message {
+            protocol::C2S::SetPixelFormat(pixel_format) => {
+                Event::SetPixelFormat(pixel_format)
+            }
+            protocol::C2S::SetEncodings(encodings) => {
+                Event::SetEncodings(encodings)
+            }
+            protocol::C2S::FramebufferUpdateRequest {
+                incremental,
+                x_position,
+                y_position,
+                width,
+                height,
+            } => {
+                Event::FramebufferUpdateRequest {
+                    incremental,
+                    rect: protocol::Rect::new(x_position, y_position, width, height),
+                }
+            }
+            protocol::C2S::KeyEvent { down, key } => {
+                Event::KeyEvent { down, key }
+            }
+            protocol::C2S::PointerEvent { button_mask, x_position, y_position } => {
+                Event::PointerEvent { button_mask, x_position, y_position }
+            }
+            protocol::C2S::CutText(clipboard) => {
+                Event::CutText(clipboard)
+            }
+            protocol::C2S::ExtendedCutText(clipboard) => {
+                Event::ExtendedCutText(clipboard)
+            }
+            protocol::C2S::ExtendedKeyEvent { down, keysym, keycode } => {
+                Event::ExtendedKeyEvent { down, keysym, keycode }
+            }
+            protocol::C2S::SetDesktopSize { width, height, screens } => {
+                Event::SetDesktopSize { width, height, screens }
+            }
+            protocol::C2S::Fence(protocol::Fence { flags, payload }) => {
+                Event::Fence { flags, payload }
+            }
+            protocol::C2S::EnableContinuousUpdates {
+                enable,
+                x_position,
+                y_position,
+                width,
+                height,
+            } => {
+                Event::EnableContinuousUpdates {
+                    enable,
+                    rect: protocol::Rect::new(x_position, y_position, width, height),
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the event interacts with the desktop rather than only affecting what
+    /// is sent to the client.
+    pub(crate) fn changes_state(&self) -> bool {
+        match self {
+            &Event::KeyEvent { .. } |
+            &Event::ExtendedKeyEvent { .. } |
+            &Event::PointerEvent { .. } |
+            &Event::CutText(_) |
+            &Event::ExtendedCutText(protocol::ExtendedClipboard::Notify(_)) |
+            &Event::ExtendedCutText(protocol::ExtendedClipboard::Provide(_)) |
+            &Event::SetDesktopSize { .. } => true,
+            _ => false
+        }
+    }
 }
 
 /// Helper data structure containing data to be sent by server in messages containing rectangles.
@@ -115,6 +486,11 @@ enum Update<'a> {
         rect: protocol::Rect,
         zlib_data: &'a [u8],
     },
+    Pixels {
+        rect: protocol::Rect,
+        pixel_data: &'a [u8],
+        preferred: Option<protocol::Encoding>,
+    },
     SetCursor {
         size: (u16, u16),
         hotspot: (u16, u16),
@@ -125,6 +501,13 @@ enum Update<'a> {
         width: u16,
         height: u16,
     },
+    ExtendedDesktopSize {
+        reason: protocol::ResizeReason,
+        status: protocol::ResizeStatus,
+        width: u16,
+        height: u16,
+        screens: &'a [protocol::Screen],
+    },
     Encoding { encoding: protocol::Encoding },
 }
 
@@ -132,7 +515,8 @@ impl<'a> Update<'a> {
     /// Checks validity of given `Update`. Panics if it is not valid.
     fn check(&self, validation_data: &ValidationData) {
         match *self {
-            Update::Raw { ref rect, pixel_data } => {
+            Update::Raw { ref rect, pixel_data } |
+            Update::Pixels { ref rect, pixel_data, .. } => {
                 let expected_num_bytes = rect.width as usize *
                                          rect.height as usize *
                                          validation_data.bytes_per_pixel as usize;
@@ -173,14 +557,40 @@ impl<'a> Update<'a> {
             Update::DesktopSize { width: _, height: _ } => {
                 // No check is needed
             }
+            Update::ExtendedDesktopSize { screens, .. } => {
+                if screens.len() > u8::max_value() as usize {
+                    panic!("Maximal number of screens is {}", u8::max_value());
+                }
+            }
             Update::Encoding { encoding: _ } => {
                 // No check is needed
             }
         }
     }
 
-    /// Serializes `Update` to given stream.
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+    /// Returns the encoding or pseudo-encoding the client has to have requested for `Update`
+    /// to be sent, unless it can always be sent.
+    fn required_encoding(&self) -> Option<protocol::Encoding> {
+        match *self {
+            Update::Raw { .. } | Update::Pixels { .. } => None,
+            Update::CopyRect { .. } => Some(protocol::Encoding::CopyRect),
+            Update::Zrle { .. } => Some(protocol::Encoding::Zrle),
+            Update::SetCursor { .. } => Some(protocol::Encoding::Cursor),
+            Update::DesktopSize { .. } => Some(protocol::Encoding::DesktopSize),
+            Update::ExtendedDesktopSize { .. } => Some(protocol::Encoding::ExtendedDesktopSize),
+            Update::Encoding { encoding } => Some(encoding),
+        }
+    }
+
+    /// Serializes `Update` to given stream, compressing pixel data if needed.
+    ///
+    /// The client has to have requested the encoding `Update` needs, if any.
+    fn write_to<W: Write>(&self,
+                          writer: &mut W,
+                          pixel_format: &protocol::PixelFormat,
+                          encodings: &[protocol::Encoding],
+                          encoders: &mut EncoderRegistry)
+                          -> Result<()> {
         match *self {
             Update::Raw { ref rect, pixel_data } => {
                 try!(rect.write_to(writer));
@@ -199,6 +609,25 @@ impl<'a> Update<'a> {
                 try!(writer.write_u32::<BigEndian>(zlib_data.len() as u32));
                 try!(writer.write_all(zlib_data));
             }
+            Update::Pixels { ref rect, pixel_data, preferred } => {
+                let encoder = encoders.choose(encodings, preferred);
+                let mut data = Vec::new();
+                encoder.encode(pixel_format, *rect, pixel_data, &mut data);
+                // RRE has no way to send pixels as they are, and takes more than that for
+                // areas of many colours; such areas are sent as `Raw` instead.
+                let encoding =
+                    if encoder.encoding() == protocol::Encoding::Rre &&
+                            data.len() > pixel_data.len() {
+                        data.clear();
+                        data.extend_from_slice(pixel_data);
+                        protocol::Encoding::Raw
+                    } else {
+                        encoder.encoding()
+                    };
+                try!(rect.write_to(writer));
+                try!(encoding.write_to(writer));
+                try!(writer.write_all(&data));
+            }
             Update::SetCursor { size, hotspot, pixels, mask_bits } => {
                 try!(writer.write_u16::<BigEndian>(hotspot.0));
                 try!(writer.write_u16::<BigEndian>(hotspot.1));
@@ -215,6 +644,18 @@ impl<'a> Update<'a> {
                 try!(writer.write_u16::<BigEndian>(height));
                 try!(protocol::Encoding::DesktopSize.write_to(writer));
             }
+            Update::ExtendedDesktopSize { reason, status, width, height, screens } => {
+                try!(writer.write_u16::<BigEndian>(reason.into()));
+                try!(writer.write_u16::<BigEndian>(status.into()));
+                try!(writer.write_u16::<BigEndian>(width));
+                try!(writer.write_u16::<BigEndian>(height));
+                try!(protocol::Encoding::ExtendedDesktopSize.write_to(writer));
+                try!(writer.write_u8(screens.len() as u8));
+                try!(writer.write_all(&[0u8; 3]));
+                for screen in screens {
+                    try!(screen.write_to(writer));
+                }
+            }
             Update::Encoding { encoding } => {
                 try!(protocol::Rect::new(0, 0, 0, 0).write_to(writer));
                 try!(encoding.write_to(writer));
@@ -227,6 +668,10 @@ impl<'a> Update<'a> {
 /// Builder of `FramebufferUpdate` message.
 ///
 /// This structure can be constructed with `Server::create_update`.
+///
+/// Pixels added with `add_raw_pixels`, `add_pixels` and `add_zrle_pixels` can be sent to any
+/// client, but every other rectangle requires the client to have requested its encoding or
+/// pseudo-encoding; otherwise sending the update fails with `Error::Unexpected`.
 pub struct FramebufferUpdateBuilder<'a, 'b> {
     updates: Vec<Update<'a>>,
     validation_data: &'b ValidationData,
@@ -234,7 +679,7 @@ pub struct FramebufferUpdateBuilder<'a, 'b> {
 
 impl<'a, 'b> FramebufferUpdateBuilder<'a, 'b> {
     /// Constructs new `FramebufferUpdateBuilder`.
-    fn new(validation_data: &'b ValidationData) -> Self {
+    pub(crate) fn new(validation_data: &'b ValidationData) -> Self {
         FramebufferUpdateBuilder {
             updates: Vec::new(),
             validation_data: validation_data,
@@ -272,11 +717,30 @@ impl<'a, 'b> FramebufferUpdateBuilder<'a, 'b> {
         self
     }
 
+    /// Adds pixel data which will be encoded when the update is sent, using the encoding
+    /// the client prefers most among those that the connection has an encoder for.
+    ///
+    /// Panics if length of pixel data does not match rectangle size.
+    pub fn add_pixels(&mut self, rect: protocol::Rect, pixel_data: &'a [u8]) -> &mut Self {
+        let update = Update::Pixels {
+            rect: rect,
+            pixel_data: pixel_data,
+            preferred: None,
+        };
+
+        update.check(self.validation_data);
+        self.updates.push(update);
+        self
+    }
+
     /// Adds compressed pixel data.
     ///
-    /// Panics if length of compressed data is bigger than `u32::MAX`.
+    /// The client inflates all ZRLE rectangles of the connection with a single zlib stream, so
+    /// `zlib_data` has to continue the stream of the data added earlier. Once compressed data
+    /// has been sent, pixel data is no longer ZRLE-encoded by the connection itself; sending
+    /// it after pixel data has been ZRLE-encoded fails with `Error::Unexpected`.
     ///
-    /// TODO: add method taking uncompressed data and compressing them.
+    /// Panics if length of compressed data is bigger than `u32::MAX`.
     pub fn add_compressed_pixels(&mut self, rect: protocol::Rect, zlib_data: &'a [u8]) -> &mut Self {
         let update = Update::Zrle {
             rect: rect,
@@ -288,6 +752,23 @@ impl<'a, 'b> FramebufferUpdateBuilder<'a, 'b> {
         self
     }
 
+    /// Adds raw pixel data which will be ZRLE-encoded when the update is sent, or encoded
+    /// like by `add_pixels` if the client has not requested the ZRLE encoding, or if
+    /// compressed pixel data has been sent with `add_compressed_pixels`.
+    ///
+    /// Panics if length of pixel data does not match rectangle size.
+    pub fn add_zrle_pixels(&mut self, rect: protocol::Rect, pixel_data: &'a [u8]) -> &mut Self {
+        let update = Update::Pixels {
+            rect: rect,
+            pixel_data: pixel_data,
+            preferred: Some(protocol::Encoding::Zrle),
+        };
+
+        update.check(self.validation_data);
+        self.updates.push(update);
+        self
+    }
+
     /// Adds data for drawing cursor.
     ///
     /// Panics if pixel data or mask bits length does not match size of the cursor.
@@ -323,6 +804,30 @@ impl<'a, 'b> FramebufferUpdateBuilder<'a, 'b> {
         self
     }
 
+    /// Adds notification about framebuffer resize or screen layout change, or a reply to
+    /// `Event::SetDesktopSize`.
+    ///
+    /// Panics if there are more than 255 screens.
+    pub fn add_extended_desktop_size(&mut self,
+                                     reason: protocol::ResizeReason,
+                                     status: protocol::ResizeStatus,
+                                     width: u16,
+                                     height: u16,
+                                     screens: &'a [protocol::Screen])
+                                     -> &mut Self {
+        let update = Update::ExtendedDesktopSize {
+            reason: reason,
+            status: status,
+            width: width,
+            height: height,
+            screens: screens,
+        };
+
+        update.check(self.validation_data);
+        self.updates.push(update);
+        self
+    }
+
     /// Adds confirmation of support of pseudo-encoding.
     pub fn add_pseudo_encoding(&mut self, encoding: protocol::Encoding) -> &mut Self {
         let update = Update::Encoding { encoding: encoding };
@@ -347,12 +852,42 @@ pub struct FramebufferUpdate<'a> {
 
 impl<'a> FramebufferUpdate<'a> {
     /// Serializes this structure and sends it using given `writer`.
-    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+    ///
+    /// Returns `Error::Unexpected`, having written nothing, if the client has not requested
+    /// the encoding of one of the rectangles, or if it has compressed pixels and the ZRLE
+    /// encoder has been used already.
+    pub(crate) fn write_to<W: Write>(&self,
+                                     writer: &mut W,
+                                     pixel_format: &protocol::PixelFormat,
+                                     encodings: &[protocol::Encoding],
+                                     encoders: &mut EncoderRegistry)
+                          -> Result<()> {
+        // Nothing is written unless every rectangle can be sent, so that a refused update
+        // leaves no partial message behind to be sent with the next one.
+        for update in &self.updates {
+            if let Some(encoding) = update.required_encoding() {
+                if !encodings.contains(&encoding) {
+                    return Err(Error::Unexpected("encoding not requested by client"))
+                }
+            }
+        }
+        // The client inflates all ZRLE rectangles with a single zlib stream, which cannot be
+        // continued both by the caller and by the ZRLE encoder.
+        let compressed = self.updates.iter().any(|update| match *update {
+            Update::Zrle { .. } => true,
+            _ => false
+        });
+        if compressed {
+            if encoders.has_encoded(protocol::Encoding::Zrle) {
+                return Err(Error::Unexpected("compressed pixels after ZRLE-encoded pixels"))
+            }
+            encoders.unregister(protocol::Encoding::Zrle);
+        }
         for chunk in self.updates.chunks(u16::max_value() as usize) {
             let count = chunk.len() as u16;
             try!(protocol::S2C::FramebufferUpdate{count}.write_to(writer));
             for update in chunk {
-                try!(update.write_to(writer));
+                try!(update.write_to(writer, pixel_format, encodings, encoders));
             }
         }
         Ok(())
@@ -360,142 +895,280 @@ impl<'a> FramebufferUpdate<'a> {
 }
 
 /// Gathers all data needed to validate framebuffer updates.
-struct ValidationData {
+pub(crate) struct ValidationData {
     /// Number of bytes per pixel used to check validity of sent data extracted from `PixelFormat`.
     bytes_per_pixel: u16,
 }
 
 impl ValidationData {
     /// Constructs new `ValidationData`.
-    fn new(pixel_format: &protocol::PixelFormat) -> Self {
+    pub(crate) fn new(pixel_format: &protocol::PixelFormat) -> Self {
         let mut mine = ValidationData { bytes_per_pixel: 0 };
         mine.update(pixel_format);
         mine
     }
 
     /// Updates bytes per pixel from `PixelFormat`.
-    fn update(&mut self, pixel_format: &protocol::PixelFormat) {
+    pub(crate) fn update(&mut self, pixel_format: &protocol::PixelFormat) {
         self.bytes_per_pixel = (pixel_format.bits_per_pixel as u16 + 7) / 8;
     }
 }
 
 /// This structure provides basic server-side functionality of RDP protocol.
 pub struct Server {
-    stream: TcpStream,
-    validation_data: ValidationData
+    stream: Stream,
+    connection: ServerConnection,
 }
 
 impl Server {
-    /// Constructs new `Server`.
+    /// Constructs new `Server` that does not require authentication.
     ///
     /// Returns new `Server` instance and `shared` flag.
     ///
     /// `shared` flag is `true` if the server should try to share the desktop by leaving other
     /// clients connected, and `false` if it should give exclusive access to this client by
     /// disconnecting all other clients.
-    pub fn from_tcp_stream(mut stream: TcpStream,
+    pub fn from_tcp_stream(stream: TcpStream,
                            width: u16,
                            height: u16,
                            pixel_format: protocol::PixelFormat,
                            name: String)
                            -> Result<(Server, bool)> {
-        // Start version handshake - send highest supported version. Client may respond with lower
-        // version but never higher.
-        try!(protocol::Version::Rfb38.write_to(&mut stream));
-        let version = try!(protocol::Version::read_from(&mut stream));
+        Server::from_transport(stream, width, height, pixel_format, name)
+    }
 
-        // Start security handshake.
-        // TODO: Add support for more security types and handle errors if negotiations fail.
-        match version {
-            protocol::Version::Rfb33 => {
-                try!(protocol::SecurityType::None.write_to(&mut stream));
-            }
-            _ => {
-                let security_types = vec![protocol::SecurityType::None];
-                try!(protocol::SecurityTypes(security_types).write_to(&mut stream));
-            }
-        }
+    /// Constructs new `Server` that offers the security types of the handlers in `security`
+    /// to a client connected over a TCP socket.
+    ///
+    /// See `from_transport_with_security`.
+    pub fn from_tcp_stream_with_security(stream: TcpStream,
+                                         width: u16,
+                                         height: u16,
+                                         pixel_format: protocol::PixelFormat,
+                                         name: String,
+                                         security: &SecurityRegistry)
+                                         -> Result<(Server, bool)> {
+        Server::from_transport_with_security(stream, width, height, pixel_format, name,
+                                             security)
+    }
 
-        let _security_type = try!(protocol::SecurityType::read_from(&mut stream));
-        try!(protocol::SecurityResult::Succeeded.write_to(&mut stream));
+    /// Constructs new `Server` that does not require authentication by making a reverse
+    /// connection to a client listening at `address`.
+    ///
+    /// Viewers usually listen on port 5500 (`client::LISTEN_PORT`). Apart from the server
+    /// initiating the TCP connection, the session is no different from one initiated by
+    /// the client, which is useful when the server cannot accept connections, e.g. behind NAT.
+    pub fn connect_reverse<A: ToSocketAddrs>(address: A,
+                                             width: u16,
+                                             height: u16,
+                                             pixel_format: protocol::PixelFormat,
+                                             name: String)
+                                             -> Result<(Server, bool)> {
+        let stream = try!(TcpStream::connect(address));
+        Server::from_tcp_stream(stream, width, height, pixel_format, name)
+    }
 
-        // Wait for client init message
-        let client_init = try!(protocol::ClientInit::read_from(&mut stream));
+    /// Constructs new `Server` that offers the security types of the handlers in `security`
+    /// to a client listening at `address`, by making a reverse connection to it.
+    ///
+    /// See `connect_reverse` and `from_transport_with_security`.
+    pub fn connect_reverse_with_security<A: ToSocketAddrs>(address: A,
+                                                           width: u16,
+                                                           height: u16,
+                                                           pixel_format: protocol::PixelFormat,
+                                                           name: String,
+                                                           security: &SecurityRegistry)
+                                                           -> Result<(Server, bool)> {
+        let stream = try!(TcpStream::connect(address));
+        Server::from_tcp_stream_with_security(stream, width, height, pixel_format, name,
+                                              security)
+    }
 
-        // Send server init message
-        let server_init = protocol::ServerInit {
-            framebuffer_width: width,
-            framebuffer_height: height,
-            pixel_format: pixel_format,
-            name: name,
-        };
+    /// Constructs new `Server` that offers the security types of the handlers in `security`
+    /// to a client reaching it through an UltraVNC repeater at `address`.
+    ///
+    /// The server connects to the repeater and announces `id`, then waits for the repeater to
+    /// pair it with a client that asked for the same ID (repeater mode 2).
+    pub fn connect_reverse_via_repeater<A: ToSocketAddrs>(address: A,
+                                                          id: u32,
+                                                          width: u16,
+                                                          height: u16,
+                                                          pixel_format: protocol::PixelFormat,
+                                                          name: String,
+                                                          security: &SecurityRegistry)
+                                                          -> Result<(Server, bool)> {
+        let mut stream = try!(TcpStream::connect(address));
+        try!(repeater::announce_id(&mut stream, id));
+        Server::from_tcp_stream_with_security(stream, width, height, pixel_format, name,
+                                              security)
+    }
 
-        try!(server_init.write_to(&mut stream));
+    /// Constructs new `Server` that does not require authentication, communicating with
+    /// the client over `transport`.
+    ///
+    /// This allows serving clients over Unix domain sockets, or over the standard input and
+    /// output when started by inetd by wrapping them in a `Split`.
+    pub fn from_transport<T>(transport: T,
+                             width: u16,
+                             height: u16,
+                             pixel_format: protocol::PixelFormat,
+                             name: String)
+                             -> Result<(Server, bool)>
+            where T: Transport + 'static {
+        let mut security = SecurityRegistry::new();
+        security.register(NoAuthentication);
+        Server::from_transport_with_security(transport, width, height, pixel_format, name,
+                                             &security)
+    }
 
+    /// Constructs new `Server` that offers the security types of the handlers in `security`
+    /// to the client, in order of preference.
+    ///
+    /// Returns new `Server` instance and `shared` flag, or `Error::AuthenticationFailure` if the
+    /// client could not be authenticated.
+    ///
+    /// Clients using RFB 3.3 cannot choose a security type, so the first one supported by that
+    /// version is used for them.
+    pub fn from_transport_with_security<T>(transport: T,
+                                           width: u16,
+                                           height: u16,
+                                           pixel_format: protocol::PixelFormat,
+                                           name: String,
+                                           security: &SecurityRegistry)
+                                           -> Result<(Server, bool)>
+            where T: Transport + 'static {
+        let mut stream = Stream::Plain(Box::new(transport));
+        let mut connection = ServerConnection::new(width, height, pixel_format, name,
+                                                   security.security_types());
+        try!(connection::handshake(&mut stream, &mut connection));
+
+        if let ServerState::Authenticating(security_type) = connection.state() {
+            let handler = try!(security.get(security_type)
+                                       .ok_or(Error::Unexpected("security type")));
+            let (handler_stream, access) = try!(handler.server_handshake(stream));
+            stream = handler_stream;
+            let version = connection.version().unwrap();
+            let result = connection.finish_security(access,
+                                                    handler.sends_security_result(version));
+            if result.is_err() {
+                // Let the client know why it has been rejected.
+                try!(stream.write_all(&connection.take_output()));
+            }
+            try!(result);
+            try!(connection::handshake(&mut stream, &mut connection));
+        }
+
+        let shared = connection.shared();
         Ok((Server {
             stream: stream,
-            validation_data: ValidationData::new(&pixel_format),
-        }, client_init.shared))
+            connection: connection,
+        }, shared))
     }
 
     /// Constructs new `FramebufferUpdateBuilder` structure used to build `FramebufferUpdate`
     /// message.
     pub fn create_update<'a, 'b>(&'b self) -> FramebufferUpdateBuilder<'a, 'b> {
-        FramebufferUpdateBuilder::new(&self.validation_data)
+        self.connection.create_update()
+    }
+
+    /// Returns `true` if the client has authenticated with a view-only password.
+    pub fn is_view_only(&self) -> bool {
+        self.connection.is_view_only()
+    }
+
+    /// Returns the format in which pixels have to be sent, which the client may change
+    /// with `Event::SetPixelFormat`; `pixel_format::Converter` converts pixels into it.
+    pub fn pixel_format(&self) -> protocol::PixelFormat {
+        self.connection.pixel_format()
+    }
+
+    /// Returns the encodings last requested by the client with `Event::SetEncodings`.
+    pub fn encodings(&self) -> &[protocol::Encoding] {
+        self.connection.encodings()
+    }
+
+    /// Adds `encoder` to the encoders pixel data can be sent with; see
+    /// `ServerConnection::register_encoder`.
+    pub fn register_encoder<E: Encoder + 'static>(&mut self, encoder: E) {
+        self.connection.register_encoder(encoder)
     }
 
     /// Reads the socket and returns received event.
+    ///
+    /// Keyboard, pointer, clipboard and desktop size events sent by view-only clients are
+    /// discarded.
     pub fn read_event(&mut self) -> Result<Event> {
-        match protocol::C2S::read_from(&mut self.stream) {
-            Ok(package) => {
-                match package {
-                    protocol::C2S::SetPixelFormat(pixel_format) => {
-                        // Update bytes per pixel number. Server must obey this message and from
-                        // now send data in format requested by client.
-                        self.validation_data.update(&pixel_format);
-                        Ok(Event::SetPixelFormat(pixel_format))
-                    }
-                    protocol::C2S::SetEncodings(encodings) => {
-                        Ok(Event::SetEncodings(encodings))
-                    }
-                    protocol::C2S::FramebufferUpdateRequest {
-                        incremental,
-                        x_position,
-                        y_position,
-                        width,
-                        height,
-                    } => {
-                        Ok(Event::FramebufferUpdateRequest {
-                            incremental,
-                            rect: protocol::Rect::new(x_position, y_position, width, height),
-                        })
-                    }
-                    protocol::C2S::KeyEvent { down, key } => {
-                        Ok(Event::KeyEvent { down, key })
-                    }
-                    protocol::C2S::PointerEvent { button_mask, x_position, y_position } => {
-                        Ok(Event::PointerEvent { button_mask, x_position, y_position })
-                    }
-                    protocol::C2S::CutText(clipboard) => {
-                        Ok(Event::CutText(clipboard))
-                    }
-                    protocol::C2S::ExtendedKeyEvent { down, keysym, keycode } => {
-                        Ok(Event::ExtendedKeyEvent { down, keysym, keycode })
-                    }
-                }
+        loop {
+            if let Some(event) = self.connection.next_event() {
+                return Ok(event)
+            }
+
+            let mut buffer = [0; 4096];
+            let count = try!(self.stream.read(&mut buffer));
+            if count == 0 {
+                return Err(Error::Disconnected)
             }
-            Err(error) => Err(error)
+            try!(self.connection.receive(&buffer[..count]));
         }
     }
 
+    fn flush(&mut self) -> Result<()> {
+        try!(self.stream.write_all(&self.connection.take_output()));
+        Ok(())
+    }
+
     /// Sends `FramebufferUpdate` message.
     pub fn send_update(&mut self, updates: &FramebufferUpdate) -> Result<()> {
-        try!(updates.write_to(&mut self.stream));
-        Ok(())
+        try!(self.connection.send_update(updates));
+        self.flush()
     }
 
-    /// Shuts down communication over TCP stream in both directions.
-    pub fn disconnect(self) -> Result<()> {
+    /// Sends `Fence` message.
+    ///
+    /// A server announces support for fences by sending a fence with `fence::REQUEST` flag to
+    /// a client that has sent `Encoding::Fence` in its `SetEncodings` message.
+    ///
+    /// Returns `Error::Unexpected` if payload is longer than 64 bytes.
+    pub fn send_fence(&mut self, flags: u32, payload: &[u8]) -> Result<()> {
+        try!(self.connection.send_fence(flags, payload));
+        self.flush()
+    }
+
+    /// Sends `ServerCutText` message.
+    ///
+    /// The text must only contain characters from the Latin-1 character set.
+    pub fn send_cut_text(&mut self, text: &str) -> Result<()> {
+        try!(self.connection.send_cut_text(text));
+        self.flush()
+    }
+
+    /// Sends `ServerCutText` message carrying an Extended Clipboard message.
+    ///
+    /// A server announces support for the extension by sending `ExtendedClipboard::Caps` to
+    /// a client that has sent `Encoding::ExtendedClipboard` in its `SetEncodings` message;
+    /// other actions may only be used once the client has replied with its own capabilities.
+    pub fn send_extended_clipboard(&mut self, clipboard: protocol::ExtendedClipboard)
+            -> Result<()> {
+        try!(self.connection.send_extended_clipboard(clipboard));
+        self.flush()
+    }
+
+    /// Sends `EndOfContinuousUpdates` message.
+    ///
+    /// This message is sent when continuous updates get disabled, and also once to announce
+    /// support for them to a client that has sent `Encoding::ContinuousUpdates` in its
+    /// `SetEncodings` message.
+    pub fn send_end_of_continuous_updates(&mut self) -> Result<()> {
+        try!(self.connection.send_end_of_continuous_updates());
+        self.flush()
+    }
+
+    pub(crate) fn into_parts(self) -> (Stream, ServerConnection) {
+        (self.stream, self.connection)
+    }
+
+    /// Shuts down communication with the client in both directions.
+    pub fn disconnect(mut self) -> Result<()> {
         try!(self.stream.shutdown(Shutdown::Both));
         Ok(())
     }
@@ -503,7 +1176,351 @@ impl Server {
 
 #[cfg(test)]
 mod test {
-    use super::{protocol, Update, ValidationData};
+    use std::net::{TcpListener, TcpStream};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+    use rcgen;
+    use rustls;
+    use ::client::{self, AuthChoice, VeNCryptOptions, RsaAesOptions};
+    use ::{Error, Split};
+    use super::{protocol, Update, ValidationData, Event, Server, VeNCrypt, RsaAes,
+                VncAuthentication, FramebufferUpdateBuilder, EncoderRegistry, RreEncoder};
+    use ::zrle;
+    use ::security::SecurityRegistry;
+
+    /// Connects a `Client` to a `Server` offering VNC authentication over loopback, sends
+    /// a key event followed by an update request, and returns whether the session is view-only
+    /// and the first event received by the server if authentication succeeded.
+    fn connect_with_vnc_authentication(password: &str) -> ::Result<(bool, Event)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let config = VncAuthentication {
+                password: String::from("secret"),
+                view_only_password: Some(String::from("viewer")),
+            };
+            let mut security = SecurityRegistry::new();
+            security.register(config);
+            let (mut server, _) = try!(Server::from_tcp_stream_with_security(
+                stream, 64, 64, ::pixel_format::RGB8888, String::from("test"), &security));
+            let event = try!(server.read_event());
+            Ok((server.is_view_only(), event))
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let client = client::Client::from_tcp_stream(stream, true, |_| {
+            let mut key = [0; 8];
+            for (i, byte) in password.bytes().take(8).enumerate() {
+                key[i] = byte
+            }
+            Some(AuthChoice::Password(key))
+        });
+        match client {
+            Ok(mut client) => {
+                client.send_key_event(true, 0x61).unwrap();
+                client.request_update(protocol::Rect::new(0, 0, 64, 64), false).unwrap();
+                client.disconnect().unwrap();
+                server.join().unwrap()
+            }
+            Err(error) => {
+                let _ = server.join().unwrap();
+                Err(error)
+            }
+        }
+    }
+
+    /// Checks if VNC authentication grants full or view-only access depending on the password,
+    /// and rejects wrong passwords with a reason.
+    #[test]
+    fn check_if_vnc_authentication_checks_passwords() {
+        match connect_with_vnc_authentication("secret") {
+            Ok((false, Event::KeyEvent { down: true, key: 0x61 })) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        match connect_with_vnc_authentication("viewer") {
+            Ok((true, Event::FramebufferUpdateRequest { incremental: false, .. })) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        match connect_with_vnc_authentication("wrong") {
+            Err(Error::AuthenticationFailure(ref reason)) if reason.len() > 0 => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
+
+    /// Checks if a session can be established without TCP, with the server using separate
+    /// reading and writing halves like an inetd service would.
+    #[cfg(unix)]
+    #[test]
+    fn check_if_session_is_established_over_other_transports() {
+        let (client_socket, server_socket) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || -> ::Result<Event> {
+            let transport = Split::new(server_socket.try_clone().unwrap(), server_socket);
+            let (mut server, _) = try!(Server::from_transport(
+                transport, 64, 64, ::pixel_format::RGB8888, String::from("test")));
+            let event = try!(server.read_event());
+            try!(server.disconnect());
+            Ok(event)
+        });
+
+        let mut client = client::Client::from_transport(client_socket, true, |_| {
+            Some(AuthChoice::None)
+        }).unwrap();
+        client.send_key_event(true, 0x61).unwrap();
+        match server.join().unwrap() {
+            Ok(Event::KeyEvent { down: true, key: 0x61 }) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        client.disconnect().unwrap();
+    }
+
+    /// Checks if a `Server` can make a reverse connection to a listening `Client`.
+    #[test]
+    fn check_if_reverse_connection_is_established() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || -> ::Result<Event> {
+            let (mut server, _) = try!(Server::connect_reverse(
+                address, 64, 64, ::pixel_format::RGB8888, String::from("test")));
+            server.read_event()
+        });
+
+        let mut client = client::Client::listen(&listener, true, |_| {
+            Some(AuthChoice::None)
+        }).unwrap();
+        assert_eq!(client.name(), "test");
+        client.send_key_event(true, 0x61).unwrap();
+        match server.join().unwrap() {
+            Ok(Event::KeyEvent { down: true, key: 0x61 }) => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        client.disconnect().unwrap();
+    }
+
+    /// Checks if events received while a `Client` switches pixel formats without fences are
+    /// still handed out, followed by the new format.
+    #[test]
+    fn check_if_events_are_kept_while_switching_formats() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || -> ::Result<Event> {
+            let (stream, _) = listener.accept().unwrap();
+            let (mut server, _) = try!(Server::from_tcp_stream(
+                stream, 4, 4, ::pixel_format::RGB8888, String::from("test")));
+            try!(server.send_cut_text("hello"));
+            loop {
+                match try!(server.read_event()) {
+                    Event::FramebufferUpdateRequest { .. } => {
+                        let pixels = vec![0; 4 * 4 * 4];
+                        let update = {
+                            let mut builder = server.create_update();
+                            builder.add_raw_pixels(protocol::Rect::new(0, 0, 4, 4), &pixels);
+                            builder.done()
+                        };
+                        try!(server.send_update(&update));
+                    }
+                    event => return Ok(event)
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut client = client::Client::from_tcp_stream(stream, true, |_| {
+            Some(AuthChoice::None)
+        }).unwrap();
+        client.set_format(::pixel_format::BGR8888).unwrap();
+        match server.join().unwrap() {
+            Ok(Event::SetPixelFormat(format)) => assert_eq!(format, ::pixel_format::BGR8888),
+            result => panic!("unexpected result {:?}", result)
+        }
+        let events: Vec<client::Event> = client.poll_iter().collect();
+        assert!(events.iter().any(|event| match event {
+            &client::Event::Clipboard(ref text) => text == "hello",
+            _ => false
+        }), "{:?}", events);
+        let update = events.iter().position(|event| match event {
+            &client::Event::PutPixels(..) => true,
+            _ => false
+        });
+        let format = events.iter().position(|event| match event {
+            &client::Event::SetFormat(format) => format == ::pixel_format::BGR8888,
+            _ => false
+        });
+        assert!(update.is_some() && update < format, "{:?}", events);
+        client.disconnect().unwrap();
+    }
+
+    /// Connects a `Client` to a `Server` offering VeNCrypt with `subtype` over loopback,
+    /// and returns the first event received by the server if authentication succeeded.
+    fn connect_with_vencrypt(subtype: protocol::VeNCryptSubtype, password: &str)
+            -> ::Result<Event> {
+        let certified_key = rcgen::generate_simple_self_signed(vec![String::from("localhost")])
+            .unwrap();
+        let certificate = certified_key.cert.der().clone();
+        let private_key = rustls::pki_types::PrivatePkcs8KeyDer::from(
+            certified_key.key_pair.serialize_der());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions().unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], private_key.into())
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certificate).unwrap();
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let vencrypt = VeNCrypt {
+                subtypes: vec![subtype],
+                tls_config: Some(Arc::new(server_config)),
+                password: Some(String::from("secret")),
+                check_credentials: Some(Box::new(|username, password|
+                    username == "user" && password == "secret")),
+            };
+            let mut security = SecurityRegistry::new();
+            security.register(vencrypt);
+            let (mut server, _) = try!(Server::from_tcp_stream_with_security(
+                stream, 64, 64, ::pixel_format::RGB8888, String::from("test"), &security));
+            server.read_event()
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let client = client::Client::from_tcp_stream(stream, true, |methods| {
+            assert!(methods.iter().any(|method| match method {
+                &client::AuthMethod::VeNCrypt => true,
+                _ => false
+            }));
+            Some(AuthChoice::VeNCrypt(VeNCryptOptions {
+                subtypes:    vec![subtype],
+                username:    String::from("user"),
+                password:    String::from(password),
+                tls_config:  Some(Arc::new(client_config)),
+                server_name: String::from("localhost"),
+            }))
+        });
+        match client {
+            Ok(mut client) => {
+                assert_eq!(client.size(), (64, 64));
+                client.send_key_event(true, 0x61).unwrap();
+                client.disconnect().unwrap();
+                server.join().unwrap()
+            }
+            Err(error) => {
+                let _ = server.join().unwrap();
+                Err(error)
+            }
+        }
+    }
+
+    /// Checks if the session runs over TLS after the VeNCrypt handshake, for both kinds of
+    /// sub-authentication.
+    #[test]
+    fn check_if_vencrypt_session_is_established() {
+        for &subtype in &[protocol::VeNCryptSubtype::X509Plain,
+                          protocol::VeNCryptSubtype::X509Vnc] {
+            match connect_with_vencrypt(subtype, "secret") {
+                Ok(Event::KeyEvent { down: true, key: 0x61 }) => (),
+                result => panic!("{:?}: unexpected result {:?}", subtype, result)
+            }
+        }
+    }
+
+    /// Checks if clients sending a wrong password are rejected with a reason.
+    #[test]
+    fn check_if_vencrypt_rejects_wrong_password() {
+        for &subtype in &[protocol::VeNCryptSubtype::TlsPlain,
+                          protocol::VeNCryptSubtype::TlsVnc] {
+            match connect_with_vencrypt(subtype, "wrong") {
+                Err(Error::AuthenticationFailure(ref reason)) if reason.len() > 0 => (),
+                result => panic!("{:?}: unexpected result {:?}", subtype, result)
+            }
+        }
+    }
+
+    /// Connects a `Client` to a `Server` offering RSA-AES with `variant` over loopback,
+    /// and returns the first event received by the server if authentication succeeded.
+    fn connect_with_rsa_aes(variant: protocol::RsaAesVariant, password: &str,
+                            server_key_fingerprint: Option<Vec<u8>>) -> ::Result<Event> {
+        let key = ::security::rsa_aes::generate_key(1024).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let rsa_aes = RsaAes {
+                variant: variant,
+                key: key,
+                username_required: true,
+                check_credentials: Box::new(|username, password|
+                    username == "user" && password == "secret"),
+            };
+            let mut security = SecurityRegistry::new();
+            security.register(rsa_aes);
+            let (mut server, _) = try!(Server::from_tcp_stream_with_security(
+                stream, 64, 64, ::pixel_format::RGB8888, String::from("test"), &security));
+            server.read_event()
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let client = client::Client::from_tcp_stream(stream, true, |methods| {
+            assert!(methods.iter().any(|method| match method {
+                &client::AuthMethod::RsaAes(method_variant) => method_variant == variant,
+                _ => false
+            }));
+            Some(AuthChoice::RsaAes(variant, RsaAesOptions {
+                username: String::from("user"),
+                password: String::from(password),
+                server_key_fingerprint: server_key_fingerprint,
+            }))
+        });
+        match client {
+            Ok(mut client) => {
+                assert_eq!(client.size(), (64, 64));
+                client.send_key_event(true, 0x61).unwrap();
+                client.disconnect().unwrap();
+                server.join().unwrap()
+            }
+            Err(error) => {
+                let _ = server.join().unwrap();
+                Err(error)
+            }
+        }
+    }
+
+    /// Checks if the session is established over RSA-AES, whether it stays encrypted or not.
+    #[test]
+    fn check_if_rsa_aes_session_is_established() {
+        for &variant in &[protocol::RsaAesVariant::Aes128,
+                          protocol::RsaAesVariant::Aes256Unencrypted] {
+            match connect_with_rsa_aes(variant, "secret", None) {
+                Ok(Event::KeyEvent { down: true, key: 0x61 }) => (),
+                result => panic!("{:?}: unexpected result {:?}", variant, result)
+            }
+        }
+    }
+
+    /// Checks if clients reject servers with unexpected keys, and servers reject clients
+    /// with a wrong password.
+    #[test]
+    fn check_if_rsa_aes_rejects_wrong_credentials() {
+        match connect_with_rsa_aes(protocol::RsaAesVariant::Aes256, "wrong", None) {
+            Err(Error::AuthenticationFailure(ref reason)) if reason.len() > 0 => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+        match connect_with_rsa_aes(protocol::RsaAesVariant::Aes128, "secret", Some(vec![0; 32])) {
+            Err(Error::AuthenticationFailure(ref reason)) if reason.contains("fingerprint") => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+    }
 
     /// Checks if `ValidationData` correctly converts bits per pixel from `PixelFormat` to bytes
     /// per pixel.
@@ -609,4 +1626,66 @@ mod test {
             mask_bits: &data[0 .. 7],
         }.check(&validation_data);
     }
+
+    /// Checks if pixels that would take more as RRE than as they are are sent as `Raw`.
+    #[test]
+    fn check_if_rre_falls_back_to_raw() {
+        let format = ::pixel_format::BGR8888;
+        let validation_data = ValidationData::new(&format);
+        let mut encoders = EncoderRegistry::new();
+        encoders.register(RreEncoder);
+        let send = |encoders: &mut EncoderRegistry, pixels: &[u8]| {
+            let mut builder = FramebufferUpdateBuilder::new(&validation_data);
+            builder.add_pixels(protocol::Rect::new(0, 0, 4, 1), pixels);
+            let mut output = Vec::new();
+            builder.done().write_to(&mut output, &format, &[protocol::Encoding::Rre],
+                                    encoders).unwrap();
+            output
+        };
+
+        let solid = send(&mut encoders, &[7; 16]);
+        assert_eq!(&solid[12..16], &[0, 0, 0, 2]);
+        assert_eq!(solid.len(), 16 + 4 + 4);
+
+        let pixels: Vec<u8> = (0..16).collect();
+        let mixed = send(&mut encoders, &pixels);
+        assert_eq!(&mixed[12..16], &[0, 0, 0, 0]);
+        assert_eq!(&mixed[16..], &pixels[..]);
+    }
+
+    /// Checks if compressed pixels are refused, without anything being written, once the
+    /// ZRLE encoder has continued the zlib stream of the client, and if the encoder is no
+    /// longer used once they are sent, even for pixels before them in the same update.
+    #[test]
+    fn check_if_compressed_pixels_do_not_share_zlib_stream_with_encoder() {
+        let pixels = vec![0; 4 * 8 * 8];
+        let rect = protocol::Rect::new(0, 0, 8, 8);
+        let format = ::pixel_format::BGR8888;
+        let validation_data = ValidationData::new(&format);
+        let send = |encoders: &mut EncoderRegistry, compressed: bool| {
+            let mut builder = FramebufferUpdateBuilder::new(&validation_data);
+            builder.add_zrle_pixels(rect, &pixels);
+            if compressed {
+                builder.add_compressed_pixels(rect, &pixels[0 .. 4]);
+            }
+            let mut output = Vec::new();
+            let result = builder.done().write_to(&mut output, &format,
+                                                 &[protocol::Encoding::Zrle], encoders);
+            (result, output)
+        };
+
+        let mut encoders = EncoderRegistry::new();
+        encoders.register(zrle::Encoder::new());
+        send(&mut encoders, false).0.unwrap();
+        match send(&mut encoders, true) {
+            (Err(Error::Unexpected(_)), ref output) if output.is_empty() => (),
+            result => panic!("unexpected result {:?}", result)
+        }
+
+        let mut encoders = EncoderRegistry::new();
+        encoders.register(zrle::Encoder::new());
+        send(&mut encoders, true).0.unwrap();
+        send(&mut encoders, false).0.unwrap();
+        assert!(!encoders.has_encoded(protocol::Encoding::Zrle));
+    }
 }
@@ -0,0 +1,552 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use ::{protocol, Result};
+use protocol::Rect;
+use pixel_format::{bytes_per_pixel, Converter};
+use server::{Event, FramebufferUpdate, FramebufferUpdateBuilder, Server};
+
+// Beyond this many separate damaged regions, they are merged into one.
+const MAX_DAMAGE_RECTS: usize = 32;
+// Beyond this many moves waiting for an update, further ones are sent as pixels.
+const MAX_MOVES: usize = 16;
+// Scrolls by fewer rows than this are sent as pixels.
+const MIN_SCROLL_ROWS: usize = 4;
+
+/// The framebuffer of a server, which keeps track of the regions changed by the application
+/// and of the updates requested by the client, and turns them into `FramebufferUpdate`s.
+///
+/// Every event received from the client is passed to `handle_event`, and `send_update` is
+/// called whenever either pixels or requests change. Incremental requests are then only
+/// answered once a requested region has changed, with just the changed regions;
+/// non-incremental ones are answered with the whole requested area right away. Requests
+/// received before an update is sent are answered together by that update.
+pub struct Framebuffer {
+    canvas:  Canvas,
+    updates: Updates,
+}
+
+impl Framebuffer {
+    /// Constructs a black framebuffer of `width` by `height` pixels in `format`, in which
+    /// the application draws regardless of the format requested by the client.
+    pub fn new(width: u16, height: u16, format: protocol::PixelFormat) -> Framebuffer {
+        Framebuffer {
+            canvas:  Canvas::new(width, height, format),
+            updates: Updates::new(),
+        }
+    }
+
+    /// Returns the width and height of the framebuffer.
+    pub fn size(&self) -> (u16, u16) { (self.canvas.width, self.canvas.height) }
+
+    /// Returns the format of the pixels of the framebuffer.
+    pub fn format(&self) -> protocol::PixelFormat { self.canvas.format }
+
+    /// Returns the pixels, row by row and without padding between rows.
+    pub fn pixels(&self) -> &[u8] { &self.canvas.pixels }
+
+    /// Returns the pixels for drawing into them directly; the regions changed this way have
+    /// to be passed to `add_damage`.
+    pub fn pixels_mut(&mut self) -> &mut [u8] { &mut self.canvas.pixels }
+
+    /// Records that the pixels within `rect` have changed.
+    pub fn add_damage(&mut self, rect: Rect) {
+        let rect = self.canvas.clip(rect);
+        self.updates.add_damage(rect);
+    }
+
+    /// Replaces the pixels within `rect`, given row by row without padding.
+    ///
+    /// If the new pixels are mostly the old ones moved up or down, as when scrolling, clients
+    /// that support the `CopyRect` encoding are told to move the pixels they already have,
+    /// and are only sent the rows that are actually new. Only whole rows of `rect` moving
+    /// up or down are detected; pixels moved sideways, or from outside `rect`, are sent
+    /// as they are. Several scrolls between two updates are sent as several moves.
+    ///
+    /// Panics if length of pixel data does not match rectangle size.
+    pub fn put_pixels(&mut self, rect: Rect, pixels: &[u8]) {
+        let (rect, scroll) = self.canvas.put_pixels(rect, pixels);
+        match scroll {
+            Some(scroll) => self.updates.add_move(scroll, rect),
+            None => self.updates.add_damage(rect)
+        }
+    }
+
+    /// Fills `rect` with the single `pixel`.
+    ///
+    /// Panics if `pixel` is not exactly one pixel long.
+    pub fn fill_pixels(&mut self, rect: Rect, pixel: &[u8]) {
+        let rect = self.canvas.fill_pixels(rect, pixel);
+        self.updates.add_damage(rect);
+    }
+
+    /// Resizes the framebuffer to `width` by `height` black pixels, which is announced to
+    /// clients that support the `DesktopSize` pseudo-encoding with the next update.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.canvas = Canvas::new(width, height, self.canvas.format);
+        self.updates.resize(width, height);
+    }
+
+    /// Records the update requests of the client; other events are ignored.
+    pub fn handle_event(&mut self, event: &Event) {
+        self.updates.handle_event(event)
+    }
+
+    /// Returns `true` if a request is waiting for an update.
+    pub fn is_update_requested(&self) -> bool {
+        self.updates.is_update_requested()
+    }
+
+    /// Builds the update answering the pending requests using `builder`, with pixels in
+    /// `format` to be sent in one of `encodings` when possible, which are usually
+    /// the `pixel_format()` and `encodings()` of the server. Returns `None` if there is
+    /// nothing to send yet, or `Error::Unexpected` if pixels cannot be converted into
+    /// `format`.
+    ///
+    /// Clients asking for a colour-mapped format are sent black pixels.
+    pub fn take_update<'a, 'b>(&'a mut self, builder: FramebufferUpdateBuilder<'a, 'b>,
+                               format: protocol::PixelFormat,
+                               encodings: &[protocol::Encoding])
+                               -> Result<Option<FramebufferUpdate<'a>>> {
+        self.updates.take_update(&self.canvas, builder, format, encodings)
+    }
+
+    /// Sends the update answering the pending requests to `server`, if there is anything
+    /// to send yet, and returns whether it did.
+    pub fn send_update(&mut self, server: &mut Server) -> Result<bool> {
+        let (format, encodings) = (server.pixel_format(), server.encodings().to_vec());
+        match try!(self.take_update(server.create_update(), format, &encodings)) {
+            Some(update) => {
+                try!(server.send_update(&update));
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+}
+
+// The pixels at `src_x, src_y` moved to `dst`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Move {
+    pub dst:   Rect,
+    pub src_x: u16,
+    pub src_y: u16,
+}
+
+// The pixels of a framebuffer.
+pub(crate) struct Canvas {
+    pub width:  u16,
+    pub height: u16,
+    pub format: protocol::PixelFormat,
+    pub pixels: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: u16, height: u16, format: protocol::PixelFormat) -> Canvas {
+        Canvas {
+            width:  width,
+            height: height,
+            format: format,
+            pixels: vec![0; width as usize * height as usize * bytes_per_pixel(&format)],
+        }
+    }
+
+    // Returns the part of `rect` that was changed, like `fill_pixels`, and the rows of it
+    // that were scrolled into place, if any.
+    pub fn put_pixels(&mut self, rect: Rect, pixels: &[u8]) -> (Rect, Option<Move>) {
+        let bytes_per_pixel = bytes_per_pixel(&self.format);
+        assert_eq!(pixels.len(), rect.width as usize * rect.height as usize * bytes_per_pixel,
+                   "pixel data length for rectangle {:?}", rect);
+        let clipped = self.clip(rect);
+        let scroll = if clipped == rect { self.find_scroll(rect, pixels) } else { None };
+        let length = clipped.width as usize * bytes_per_pixel;
+        for row in 0..clipped.height as usize {
+            let source = row * rect.width as usize * bytes_per_pixel;
+            let target = self.offset(clipped.left, clipped.top + row as u16);
+            self.pixels[target..target + length]
+                .copy_from_slice(&pixels[source..source + length]);
+        }
+        (clipped, scroll)
+    }
+
+    // Finds the longest run of rows of `rect` in which `pixels` are the current pixels
+    // shifted vertically by the same number of rows. The shift is guessed from the rows that
+    // occur just once in the current pixels, and then verified.
+    fn find_scroll(&self, rect: Rect, pixels: &[u8]) -> Option<Move> {
+        let length = rect.width as usize * bytes_per_pixel(&self.format);
+        let height = rect.height as usize;
+        if length == 0 || height < MIN_SCROLL_ROWS { return None }
+
+        let old_row = |row: usize| {
+            let start = self.offset(rect.left, rect.top + row as u16);
+            &self.pixels[start..start + length]
+        };
+        let new_row = |row: usize| &pixels[row * length..(row + 1) * length];
+        let hash = |row: &[u8]| {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let mut old_rows: HashMap<u64, Option<usize>> = HashMap::new();
+        for row in 0..height {
+            old_rows.entry(hash(old_row(row))).and_modify(|found| *found = None)
+                                              .or_insert(Some(row));
+        }
+        let mut shifts = BTreeMap::new();
+        for row in 0..height {
+            if let Some(&Some(old)) = old_rows.get(&hash(new_row(row))) {
+                if old != row {
+                    *shifts.entry(old as isize - row as isize).or_insert(0) += 1;
+                }
+            }
+        }
+        let shift = match shifts.iter().max_by_key(|&(shift, &count)| (count, -shift.abs())) {
+            Some((&shift, _)) => shift,
+            None => return None
+        };
+
+        let (mut best, mut run) = ((0, 0), None);
+        for row in 0..height + 1 {
+            let old = row as isize + shift;
+            let matches = row < height && old >= 0 && (old as usize) < height &&
+                          new_row(row) == old_row(old as usize);
+            match (matches, run) {
+                (true, None) => run = Some(row),
+                (false, Some(start)) => {
+                    if row - start > best.1 - best.0 { best = (start, row) }
+                    run = None;
+                }
+                _ => ()
+            }
+        }
+        if best.1 - best.0 < MIN_SCROLL_ROWS { return None }
+
+        Some(Move {
+            dst: Rect::new(rect.left, rect.top + best.0 as u16, rect.width,
+                           (best.1 - best.0) as u16),
+            src_x: rect.left,
+            src_y: (rect.top as isize + best.0 as isize + shift) as u16,
+        })
+    }
+
+    pub fn fill_pixels(&mut self, rect: Rect, pixel: &[u8]) -> Rect {
+        assert_eq!(pixel.len(), bytes_per_pixel(&self.format), "pixel length");
+        let clipped = self.clip(rect);
+        for row in 0..clipped.height {
+            let target = self.offset(clipped.left, clipped.top + row);
+            let length = clipped.width as usize * pixel.len();
+            for chunk in self.pixels[target..target + length].chunks_mut(pixel.len()) {
+                chunk.copy_from_slice(pixel);
+            }
+        }
+        clipped
+    }
+
+    pub fn clip(&self, rect: Rect) -> Rect {
+        intersection(rect, Rect::new(0, 0, self.width, self.height))
+    }
+
+    fn offset(&self, x: u16, y: u16) -> usize {
+        (y as usize * self.width as usize + x as usize) * bytes_per_pixel(&self.format)
+    }
+}
+
+// What has to be sent to one client: the regions changed since its last update, and
+// the updates it requested.
+pub(crate) struct Updates {
+    damage: Vec<Rect>,
+    // Sent before the damage, in order.
+    moves: Vec<Move>,
+    resized: bool,
+    // The area covered by the pending requests, and whether all of them are incremental.
+    request: Option<(Rect, bool)>,
+    // The pixels of the last update, converted to the format of the client.
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Updates {
+    pub fn new() -> Updates {
+        Updates {
+            damage: Vec::new(),
+            moves: Vec::new(),
+            resized: false,
+            request: None,
+            buffers: Vec::new(),
+        }
+    }
+
+    // `rect` has to be within the canvas.
+    pub fn add_damage(&mut self, rect: Rect) {
+        if rect.width == 0 || rect.height == 0 { return }
+
+        // Keep the regions disjoint, so that no pixel is sent twice.
+        let mut rect = rect;
+        while let Some(index) = self.damage.iter().position(|&damage| intersects(damage, rect)) {
+            rect = union(self.damage.swap_remove(index), rect);
+        }
+        self.damage.push(rect);
+        if self.damage.len() > MAX_DAMAGE_RECTS {
+            let all = self.damage.drain(..).fold(rect, union);
+            self.damage.push(all);
+        }
+    }
+
+    // Records that `rect` has changed, with `scroll.dst` within it having moved.
+    pub fn add_move(&mut self, scroll: Move, rect: Rect) {
+        if self.moves.len() >= MAX_MOVES {
+            return self.add_damage(rect)
+        }
+        // The client has stale pixels wherever damage is still to be sent, and moving them
+        // makes the places they move to stale as well.
+        let src = Rect::new(scroll.src_x, scroll.src_y, scroll.dst.width, scroll.dst.height);
+        let stale: Vec<Rect> =
+            self.damage.iter().map(|&damage| intersection(damage, src))
+                              .filter(|part| part.width > 0 && part.height > 0)
+                              .map(|part| Rect::new(part.left - src.left + scroll.dst.left,
+                                                    part.top - src.top + scroll.dst.top,
+                                                    part.width, part.height))
+                              .collect();
+        self.moves.push(scroll);
+        for part in stale {
+            self.add_damage(part);
+        }
+        // What is left of `rect` above and below the rows that moved.
+        self.add_damage(Rect::new(rect.left, rect.top, rect.width,
+                                  scroll.dst.top - rect.top));
+        self.add_damage(Rect::new(rect.left, bottom(scroll.dst) as u16, rect.width,
+                                  (bottom(rect) - bottom(scroll.dst)) as u16));
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.damage.clear();
+        self.moves.clear();
+        self.resized = true;
+        self.add_damage(Rect::new(0, 0, width, height));
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        if let &Event::FramebufferUpdateRequest { incremental, rect } = event {
+            self.request = Some(match self.request {
+                None => (rect, incremental),
+                Some((area, all_incremental)) =>
+                    (union(area, rect), all_incremental && incremental)
+            });
+        }
+    }
+
+    pub fn is_update_requested(&self) -> bool {
+        self.request.is_some()
+    }
+
+    pub fn take_update<'a, 'b>(&'a mut self, canvas: &Canvas,
+                               builder: FramebufferUpdateBuilder<'a, 'b>,
+                               format: protocol::PixelFormat,
+                               encodings: &[protocol::Encoding])
+                               -> Result<Option<FramebufferUpdate<'a>>> {
+        let (area, incremental) = match self.request {
+            Some(request) => request,
+            None => return Ok(None)
+        };
+
+        let resized = self.resized && encodings.contains(&protocol::Encoding::DesktopSize);
+        // Moves are sent as `CopyRect`s to clients that support them, as long as the damage
+        // they come with is sent too; otherwise the regions they moved to are sent as pixels.
+        let copy_rect = incremental && !resized &&
+                        encodings.contains(&protocol::Encoding::CopyRect) &&
+                        self.moves.iter().all(|scroll| contains(area, scroll.dst));
+        if !copy_rect {
+            let moves: Vec<Move> = self.moves.drain(..).collect();
+            for scroll in moves {
+                self.add_damage(scroll.dst);
+            }
+        }
+        let rects: Vec<Rect> =
+            if resized {
+                vec![Rect::new(0, 0, canvas.width, canvas.height)]
+            } else if !incremental {
+                vec![canvas.clip(area)]
+            } else {
+                self.damage.iter().map(|&damage| intersection(damage, area))
+                                  .filter(|rect| rect.width > 0 && rect.height > 0)
+                                  .collect()
+            };
+        if incremental && !resized && rects.is_empty() && self.moves.is_empty() {
+            return Ok(None)
+        }
+
+        let converter = try!(Converter::new(canvas.format, format));
+        self.request = None;
+        self.resized = false;
+        self.damage.retain(|&damage| !rects.iter().any(|&rect| contains(rect, damage)));
+
+        let bytes_per_pixel = bytes_per_pixel(&canvas.format);
+        self.buffers.resize(rects.len(), Vec::new());
+        for (&rect, buffer) in rects.iter().zip(self.buffers.iter_mut()) {
+            buffer.clear();
+            for row in 0..rect.height {
+                let start = canvas.offset(rect.left, rect.top + row);
+                let end = start + rect.width as usize * bytes_per_pixel;
+                converter.convert(&canvas.pixels[start..end], buffer);
+            }
+        }
+
+        let mut builder = builder;
+        if resized {
+            builder.add_desktop_size(canvas.width, canvas.height);
+        }
+        for scroll in self.moves.drain(..) {
+            builder.add_copy_rect(scroll.dst, scroll.src_x, scroll.src_y);
+        }
+        for (&rect, buffer) in rects.iter().zip(self.buffers.iter()) {
+            if rect.width == 0 || rect.height == 0 { continue }
+            builder.add_pixels(rect, buffer);
+        }
+        Ok(Some(builder.done()))
+    }
+}
+
+fn right(rect: Rect) -> u32 { rect.left as u32 + rect.width as u32 }
+fn bottom(rect: Rect) -> u32 { rect.top as u32 + rect.height as u32 }
+
+fn intersection(a: Rect, b: Rect) -> Rect {
+    let left = cmp::max(a.left, b.left);
+    let top = cmp::max(a.top, b.top);
+    let right = cmp::min(right(a), right(b));
+    let bottom = cmp::min(bottom(a), bottom(b));
+    if right <= left as u32 || bottom <= top as u32 {
+        return Rect::new(left, top, 0, 0)
+    }
+    Rect::new(left, top, (right - left as u32) as u16, (bottom - top as u32) as u16)
+}
+
+fn intersects(a: Rect, b: Rect) -> bool {
+    let rect = intersection(a, b);
+    rect.width > 0 && rect.height > 0
+}
+
+fn contains(outer: Rect, inner: Rect) -> bool {
+    inner.left >= outer.left && inner.top >= outer.top &&
+        right(inner) <= right(outer) && bottom(inner) <= bottom(outer)
+}
+
+fn union(a: Rect, b: Rect) -> Rect {
+    let left = cmp::min(a.left, b.left);
+    let top = cmp::min(a.top, b.top);
+    let right = cmp::max(right(a), right(b));
+    let bottom = cmp::max(bottom(a), bottom(b));
+    Rect::new(left, top, (right - left as u32) as u16, (bottom - top as u32) as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use ::{pixel_format, protocol};
+    use protocol::Rect;
+    use server::{Event, EncoderRegistry, FramebufferUpdate, ValidationData,
+                 FramebufferUpdateBuilder};
+    use super::Framebuffer;
+
+    fn take_update(framebuffer: &mut Framebuffer, encodings: &[protocol::Encoding])
+            -> Option<Vec<u8>> {
+        let validation_data = ValidationData::new(&pixel_format::BGR8888);
+        let update: Option<FramebufferUpdate> = framebuffer.take_update(
+            FramebufferUpdateBuilder::new(&validation_data), pixel_format::BGR8888, encodings)
+            .unwrap();
+        update.map(|update| {
+            let mut encoders = EncoderRegistry::new();
+            encoders.register(::zrle::Encoder::new());
+            let mut output = Vec::new();
+            update.write_to(&mut output, &pixel_format::BGR8888, encodings,
+                            &mut encoders).unwrap();
+            output
+        })
+    }
+
+    fn request(incremental: bool, rect: Rect) -> Event {
+        Event::FramebufferUpdateRequest { incremental: incremental, rect: rect }
+    }
+
+    /// Checks if incremental requests are only answered with the damaged regions once there
+    /// are some, and non-incremental ones with the whole area at once.
+    #[test]
+    fn check_if_requests_are_answered_with_damage() {
+        let mut framebuffer = Framebuffer::new(2, 2, pixel_format::RGB8888);
+        assert_eq!(take_update(&mut framebuffer, &[]), None);
+
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 2)));
+        assert_eq!(take_update(&mut framebuffer, &[]), None);
+        framebuffer.fill_pixels(Rect::new(1, 1, 2, 2), &[0, 0, 0, 255]);
+        // Answered together with the pending request.
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 1, 1)));
+        assert_eq!(take_update(&mut framebuffer, &[]),
+                   Some(vec![0, 0, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 0, 0, 0,
+                             0, 255, 0, 0]));
+        assert!(!framebuffer.is_update_requested());
+
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 2)));
+        assert_eq!(take_update(&mut framebuffer, &[]), None);
+        framebuffer.handle_event(&request(false, Rect::new(0, 0, 1, 1)));
+        assert_eq!(take_update(&mut framebuffer, &[]),
+                   Some(vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 0,
+                             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 0]));
+
+        framebuffer.resize(3, 1);
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 2)));
+        assert_eq!(take_update(&mut framebuffer, &[protocol::Encoding::DesktopSize]),
+                   Some(vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 3, 0, 1, 255, 255, 255, 0x21,
+                             0, 0, 0, 0, 0, 3, 0, 1, 0, 0, 0, 0,
+                             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    /// Checks if scrolled rows are sent as `CopyRect` to clients that support it, and as
+    /// pixels to others.
+    #[test]
+    fn check_if_scroll_is_sent_as_copy_rect() {
+        // Rows of two pixels, each row different.
+        let rows = |first: u8| -> Vec<u8> {
+            (first..first + 8).flat_map(|row| vec![row, 0, 0, 0, row, 0, 0, 0]).collect()
+        };
+        let mut framebuffer = Framebuffer::new(2, 8, pixel_format::BGR8888);
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(1));
+        framebuffer.handle_event(&request(false, Rect::new(0, 0, 2, 8)));
+        assert!(take_update(&mut framebuffer, &[]).is_some());
+
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 8)));
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(3));
+        assert_eq!(take_update(&mut framebuffer, &[protocol::Encoding::CopyRect]),
+                   Some(vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 2, 0, 6, 0, 0, 0, 1, 0, 0, 0, 2,
+                             0, 0, 0, 6, 0, 2, 0, 2, 0, 0, 0, 0,
+                             9, 0, 0, 0, 9, 0, 0, 0, 10, 0, 0, 0, 10, 0, 0, 0]));
+
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 8)));
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(5));
+        let update = take_update(&mut framebuffer, &[]).unwrap();
+        // Two Raw rectangles covering all rows.
+        assert_eq!(update.len(), 4 + 2 * 12 + 2 * 8 * 4);
+        assert_eq!(&update[12..16], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn check_if_consecutive_scrolls_are_sent_as_copy_rects() {
+        let rows = |first: u8| -> Vec<u8> {
+            (first..first + 8).flat_map(|row| vec![row, 0, 0, 0, row, 0, 0, 0]).collect()
+        };
+        let mut framebuffer = Framebuffer::new(2, 8, pixel_format::BGR8888);
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(1));
+        framebuffer.handle_event(&request(false, Rect::new(0, 0, 2, 8)));
+        assert!(take_update(&mut framebuffer, &[]).is_some());
+
+        // The rows that were new after the first scroll move up with the second one, and
+        // are sent along with the row that is new after it.
+        framebuffer.handle_event(&request(true, Rect::new(0, 0, 2, 8)));
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(3));
+        framebuffer.put_pixels(Rect::new(0, 0, 2, 8), &rows(4));
+        assert_eq!(take_update(&mut framebuffer, &[protocol::Encoding::CopyRect]),
+                   Some(vec![0, 0, 0, 3, 0, 0, 0, 0, 0, 2, 0, 6, 0, 0, 0, 1, 0, 0, 0, 2,
+                             0, 0, 0, 0, 0, 2, 0, 7, 0, 0, 0, 1, 0, 0, 0, 1,
+                             0, 0, 0, 5, 0, 2, 0, 3, 0, 0, 0, 0,
+                             9, 0, 0, 0, 9, 0, 0, 0, 10, 0, 0, 0, 10, 0, 0, 0,
+                             11, 0, 0, 0, 11, 0, 0, 0]));
+    }
+}
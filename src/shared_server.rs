@@ -0,0 +1,493 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use ::{protocol, Error, Result};
+use protocol::Rect;
+use security::SecurityRegistry;
+use stream::Stream;
+use transport::Transport;
+use connection::ServerConnection;
+use server::{Event, Server};
+use server_framebuffer::{Canvas, Updates};
+
+// Beyond this many batches of messages waiting to be written to a client, it is disconnected.
+const WRITE_QUEUE_LENGTH: usize = 64;
+
+/// Identifies a client of a `SharedServer` for as long as the server exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientId(u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// What a `SharedServer` does when a client asks for exclusive access to the desktop, by
+/// clearing the `shared` flag of its `ClientInit` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExclusivePolicy {
+    /// Disconnect all other clients, as suggested by the protocol.
+    DisconnectOthers,
+    /// Refuse the client if others are connected, and refuse further clients while it is.
+    RefuseNew,
+    /// Treat the client as if it had asked to share the desktop.
+    Ignore,
+}
+
+/// A client connected to a `SharedServer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    /// The `shared` flag sent by the client.
+    pub shared: bool,
+    /// Whether keyboard, pointer, clipboard and desktop size events of the client are
+    /// discarded.
+    pub view_only: bool,
+    pub pixel_format: protocol::PixelFormat,
+    pub encodings: Vec<protocol::Encoding>,
+}
+
+struct SharedClient {
+    // The messages to be written by the writing thread of the client; the reading thread
+    // owns the other half of the stream.
+    queue: mpsc::SyncSender<Vec<u8>>,
+    // Shuts the connection down even while the writing thread is stuck.
+    transport: Box<Transport>,
+    connection: ServerConnection,
+    updates: Updates,
+}
+
+impl SharedClient {
+    fn send_update(&mut self, canvas: &Canvas) -> Result<()> {
+        let (format, encodings) = (self.connection.pixel_format(),
+                                   self.connection.encodings().to_vec());
+        let update = try!(self.updates.take_update(canvas, self.connection.create_update(),
+                                                   format, &encodings));
+        if let Some(update) = update {
+            try!(self.connection.send_update(&update));
+        }
+        self.flush()
+    }
+
+    // Hands the queued messages over to the writing thread, without waiting for them to be
+    // written.
+    fn flush(&mut self) -> Result<()> {
+        let output = self.connection.take_output();
+        if output.is_empty() {
+            return Ok(())
+        }
+        match self.queue.try_send(output) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => Err(Error::Unexpected("client not reading")),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(Error::Disconnected)
+        }
+    }
+}
+
+struct State {
+    canvas: Canvas,
+    name: String,
+    policy: ExclusivePolicy,
+    clients: BTreeMap<ClientId, SharedClient>,
+    next_id: u64,
+    // Taken by `SharedServer::close`, so that the event channel closes once the threads
+    // reading from the clients have finished.
+    events: Option<mpsc::Sender<(ClientId, Event)>>,
+}
+
+impl State {
+    fn remove(&mut self, id: ClientId) -> bool {
+        match self.clients.remove(&id) {
+            Some(client) => {
+                debug!("disconnecting client {}", id);
+                // Also stops the threads reading from and writing to the client.
+                let _ = client.transport.shutdown(Shutdown::Both);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn is_exclusive(&self) -> bool {
+        self.clients.values().any(|client| !client.connection.shared())
+    }
+
+    // Sends the updates that became due, and drops the clients that could not be sent them.
+    fn send_updates(&mut self) {
+        let canvas = &self.canvas;
+        let failed: Vec<ClientId> =
+            self.clients.iter_mut()
+                        .filter_map(|(&id, client)| client.send_update(canvas).err().map(|_| id))
+                        .collect();
+        for id in failed {
+            self.remove(id);
+        }
+    }
+}
+
+/// A server that serves one framebuffer to any number of clients at once.
+///
+/// Each client is read by a thread of its own, which answers its update requests with pixels
+/// in the format and encoding it asked for, and passes its other events on to `read_event`.
+/// Messages are written by another thread of the client, so that a client that stops reading
+/// holds up no one else; it is disconnected once too many messages are waiting for it.
+/// Clients asking for exclusive access are dealt with according to an `ExclusivePolicy`.
+///
+/// `SharedServer` is a handle that can be cloned and sent to other threads, so that one thread
+/// can accept connections while another draws and a third handles input.
+#[derive(Clone)]
+pub struct SharedServer {
+    state: Arc<Mutex<State>>,
+    events: Arc<Mutex<mpsc::Receiver<(ClientId, Event)>>>,
+}
+
+impl SharedServer {
+    /// Constructs a server with a black framebuffer of `width` by `height` pixels in `format`,
+    /// announced to clients as `name`.
+    pub fn new(width: u16, height: u16, format: protocol::PixelFormat, name: String,
+               policy: ExclusivePolicy) -> SharedServer {
+        let (sender, receiver) = mpsc::channel();
+        SharedServer {
+            state: Arc::new(Mutex::new(State {
+                canvas: Canvas::new(width, height, format),
+                name: name,
+                policy: policy,
+                clients: BTreeMap::new(),
+                next_id: 0,
+                events: Some(sender),
+            })),
+            events: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Performs the handshake with a client connected over `transport`, offering the security
+    /// types of the handlers in `security`, and starts serving it.
+    ///
+    /// Returns `Error::Unexpected` if the client is refused because of an exclusive client,
+    /// or `Error::Disconnected` if the server has been closed. The handshake is performed on
+    /// the calling thread.
+    pub fn add_client<T>(&self, transport: T, security: &SecurityRegistry) -> Result<ClientId>
+            where T: Transport + 'static {
+        let (width, height, format, name) = {
+            let state = self.state.lock().unwrap();
+            (state.canvas.width, state.canvas.height, state.canvas.format, state.name.clone())
+        };
+        let (server, shared) = try!(Server::from_transport_with_security(
+            transport, width, height, format, name, security));
+        let (mut stream, connection) = server.into_parts();
+        let reader = try!(stream.try_clone());
+        let transport = try!(stream.try_clone_transport());
+
+        let mut state = self.state.lock().unwrap();
+        let events = match state.events {
+            Some(ref events) => events.clone(),
+            None => {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(Error::Disconnected)
+            }
+        };
+        let policy = if shared { ExclusivePolicy::Ignore } else { state.policy };
+        if state.policy == ExclusivePolicy::RefuseNew && state.is_exclusive() ||
+                policy == ExclusivePolicy::RefuseNew && !state.clients.is_empty() {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(Error::Unexpected("exclusive access to shared desktop"))
+        }
+        if policy == ExclusivePolicy::DisconnectOthers {
+            let others: Vec<ClientId> = state.clients.keys().cloned().collect();
+            for id in others {
+                state.remove(id);
+            }
+        }
+
+        let id = ClientId(state.next_id);
+        state.next_id += 1;
+        let mut updates = Updates::new();
+        if (state.canvas.width, state.canvas.height) != (width, height) {
+            // Resized during the handshake.
+            updates.resize(state.canvas.width, state.canvas.height);
+        }
+        let (queue, queued) = mpsc::sync_channel(WRITE_QUEUE_LENGTH);
+        state.clients.insert(id, SharedClient {
+            queue: queue,
+            transport: transport,
+            connection: connection,
+            updates: updates,
+        });
+        debug!("client {} connected, shared: {}", id, shared);
+
+        let shared_state = self.state.clone();
+        thread::spawn(move || write_client(id, stream, queued));
+        thread::spawn(move || read_client(shared_state, id, reader, events));
+        Ok(id)
+    }
+
+    /// Waits for the next keyboard, pointer, clipboard or other event sent by any client.
+    ///
+    /// Update requests, pixel formats and encodings are taken care of by the server itself.
+    /// Returns `Error::Disconnected` once the server has been closed with `close` and every
+    /// event received before has been returned.
+    pub fn read_event(&self) -> Result<(ClientId, Event)> {
+        self.events.lock().unwrap().recv().map_err(|_| Error::Disconnected)
+    }
+
+    /// Disconnects all clients and refuses new ones, which makes `read_event` return
+    /// `Error::Disconnected` once the clients' threads have finished.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.events = None;
+        let ids: Vec<ClientId> = state.clients.keys().cloned().collect();
+        for id in ids {
+            state.remove(id);
+        }
+    }
+
+    /// Returns the width and height of the framebuffer.
+    pub fn size(&self) -> (u16, u16) {
+        let state = self.state.lock().unwrap();
+        (state.canvas.width, state.canvas.height)
+    }
+
+    /// Returns the format of the pixels of the framebuffer.
+    pub fn format(&self) -> protocol::PixelFormat {
+        self.state.lock().unwrap().canvas.format
+    }
+
+    /// Replaces the pixels within `rect`, given row by row without padding, and sends them to
+    /// the clients waiting for them.
+    ///
+    /// Scrolls are detected and sent like by `Framebuffer::put_pixels`.
+    ///
+    /// Panics if length of pixel data does not match rectangle size.
+    pub fn put_pixels(&self, rect: Rect, pixels: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let (rect, scroll) = state.canvas.put_pixels(rect, pixels);
+        for client in state.clients.values_mut() {
+            match scroll {
+                Some(scroll) => client.updates.add_move(scroll, rect),
+                None => client.updates.add_damage(rect)
+            }
+        }
+        state.send_updates();
+    }
+
+    /// Fills `rect` with the single `pixel`, and sends it to the clients waiting for it.
+    ///
+    /// Panics if `pixel` is not exactly one pixel long.
+    pub fn fill_pixels(&self, rect: Rect, pixel: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let rect = state.canvas.fill_pixels(rect, pixel);
+        for client in state.clients.values_mut() {
+            client.updates.add_damage(rect);
+        }
+        state.send_updates();
+    }
+
+    /// Resizes the framebuffer to `width` by `height` black pixels, which is announced to
+    /// clients that support the `DesktopSize` pseudo-encoding with their next update.
+    pub fn resize(&self, width: u16, height: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.canvas = Canvas::new(width, height, state.canvas.format);
+        for client in state.clients.values_mut() {
+            client.updates.resize(width, height);
+        }
+        state.send_updates();
+    }
+
+    /// Returns the clients currently connected.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let state = self.state.lock().unwrap();
+        state.clients.iter().map(|(&id, client)| ClientInfo {
+            id: id,
+            shared: client.connection.shared(),
+            view_only: client.connection.is_view_only(),
+            pixel_format: client.connection.pixel_format(),
+            encodings: client.connection.encodings().to_vec(),
+        }).collect()
+    }
+
+    /// Disconnects the client `id`. Returns `false` if it is not connected.
+    pub fn kick(&self, id: ClientId) -> bool {
+        self.state.lock().unwrap().remove(id)
+    }
+
+    /// Makes the client `id` view-only, or lets it interact with the desktop again.
+    /// Returns `false` if it is not connected.
+    pub fn set_view_only(&self, id: ClientId, view_only: bool) -> bool {
+        match self.state.lock().unwrap().clients.get_mut(&id) {
+            Some(client) => {
+                client.connection.set_view_only(view_only);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Sends `ServerCutText` message to all clients.
+    ///
+    /// The text must only contain characters from the Latin-1 character set.
+    pub fn send_cut_text(&self, text: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut failed = Vec::new();
+        for (&id, client) in state.clients.iter_mut() {
+            try!(client.connection.send_cut_text(text));
+            if client.flush().is_err() {
+                failed.push(id);
+            }
+        }
+        for id in failed {
+            state.remove(id);
+        }
+        Ok(())
+    }
+}
+
+// Writes the messages queued for the client `id` until it is removed, or writing fails.
+fn write_client(id: ClientId, mut writer: Stream, queued: mpsc::Receiver<Vec<u8>>) {
+    for output in queued {
+        if let Err(error) = writer.write_all(&output).and_then(|()| writer.flush()) {
+            debug!("writing to client {} failed: {:?}", id, error);
+            // Also stops the thread reading from the client, which removes it.
+            let _ = writer.shutdown(Shutdown::Both);
+            return
+        }
+    }
+}
+
+fn read_client(state: Arc<Mutex<State>>, id: ClientId, mut reader: Stream,
+               events: mpsc::Sender<(ClientId, Event)>) {
+    let mut buffer = [0; 4096];
+    loop {
+        let count = reader.read(&mut buffer).unwrap_or(0);
+
+        let mut state = state.lock().unwrap();
+        let state = &mut *state;
+        let canvas = &state.canvas;
+        let result = match state.clients.get_mut(&id) {
+            // Kicked.
+            None => return,
+            Some(_) if count == 0 => Err(Error::Disconnected),
+            Some(client) => {
+                let result = client.connection.receive(&buffer[..count]);
+                while let Some(event) = client.connection.next_event() {
+                    client.updates.handle_event(&event);
+                    match event {
+                        Event::FramebufferUpdateRequest { .. } |
+                        Event::SetPixelFormat(_) | Event::SetEncodings(_) => (),
+                        event => { let _ = events.send((id, event)); }
+                    }
+                }
+                result.and_then(|()| client.send_update(canvas))
+            }
+        };
+        if let Err(error) = result {
+            debug!("client {} failed: {:?}", id, error);
+            state.remove(id);
+            return
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use ::{client, pixel_format, NoAuthentication, SecurityRegistry};
+    use protocol::Rect;
+    use super::{ExclusivePolicy, SharedServer};
+
+    fn connect(server: &SharedServer, listener: &TcpListener, shared: bool)
+            -> (::Result<super::ClientId>, ::Result<client::Client>) {
+        let address = listener.local_addr().unwrap();
+        let viewer = thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            client::Client::from_tcp_stream(stream, shared, |_| Some(client::AuthChoice::None))
+        });
+        let mut security = SecurityRegistry::new();
+        security.register(NoAuthentication);
+        let id = server.add_client(listener.accept().unwrap().0, &security);
+        (id, viewer.join().unwrap())
+    }
+
+    /// Checks if clients share the framebuffer, can be made view-only, are disconnected
+    /// by an exclusive client, and if closing the server ends its events.
+    #[test]
+    fn check_if_clients_share_framebuffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = SharedServer::new(4, 4, pixel_format::RGB8888, String::from("test"),
+                                       ExclusivePolicy::DisconnectOthers);
+        let (first, first_client) = connect(&server, &listener, true);
+        let (second, mut second_client) = connect(&server, &listener, true);
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(server.clients().iter().map(|client| client.id).collect::<Vec<_>>(),
+                   vec![first, second]);
+
+        assert!(server.set_view_only(first, true));
+        let mut first_client = first_client.unwrap();
+        first_client.send_key_event(true, 0x61).unwrap();
+        second_client.as_mut().unwrap().send_key_event(true, 0x62).unwrap();
+        match server.read_event().unwrap() {
+            (id, ::server::Event::KeyEvent { down: true, key: 0x62 }) if id == second => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+
+        first_client.request_update(Rect::new(0, 0, 4, 4), true).unwrap();
+        server.fill_pixels(Rect::new(0, 0, 2, 2), &[0, 0, 255, 0]);
+        loop {
+            match first_client.poll_event() {
+                Some(client::Event::PutPixels(rect, _)) => {
+                    assert_eq!(rect, Rect::new(0, 0, 2, 2));
+                    break
+                }
+                Some(client::Event::Disconnected(error)) => panic!("disconnected: {:?}", error),
+                _ => thread::sleep(::std::time::Duration::from_millis(10))
+            }
+        }
+
+        let (third, _third_client) = connect(&server, &listener, false);
+        let third = third.unwrap();
+        assert_eq!(server.clients().iter().map(|client| client.id).collect::<Vec<_>>(),
+                   vec![third]);
+        assert!(server.kick(third));
+        assert!(server.clients().is_empty());
+
+        server.close();
+        match server.read_event() {
+            Err(::Error::Disconnected) => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+        assert!(connect(&server, &listener, true).0.is_err());
+    }
+
+    /// Checks if a client that stops reading is disconnected, while the framebuffer can
+    /// still be drawn in and other clients kicked.
+    #[test]
+    fn check_if_stalled_client_holds_up_no_one() {
+        use std::io::Write;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = SharedServer::new(256, 256, pixel_format::RGB8888, String::from("test"),
+                                       ExclusivePolicy::Ignore);
+        let mut stalled = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // Version, security type None, and ClientInit, without reading anything back.
+        stalled.write_all(b"RFB 003.008\n\x01\x01").unwrap();
+        let mut security = SecurityRegistry::new();
+        security.register(NoAuthentication);
+        let stalled_id = server.add_client(listener.accept().unwrap().0, &security).unwrap();
+        let (other, _other_client) = connect(&server, &listener, true);
+
+        // Ask for the whole framebuffer over and over again.
+        let request = [3, 0, 0, 0, 0, 0, 1, 0, 1, 0];
+        for _ in 0..1000 {
+            if stalled.write_all(&request).is_err() { break }
+            server.fill_pixels(Rect::new(0, 0, 1, 1), &[0, 0, 255, 0]);
+            if server.clients().iter().all(|client| client.id != stalled_id) { break }
+            thread::sleep(::std::time::Duration::from_millis(1));
+        }
+        assert!(server.clients().iter().all(|client| client.id != stalled_id));
+        assert!(server.kick(other.unwrap()));
+    }
+}
@@ -0,0 +1,423 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use aes::{Aes128, Aes256};
+use eax::Eax;
+use eax::aead::{AeadInPlace, KeyInit};
+use eax::aead::generic_array::GenericArray;
+use rustls;
+use rustls::pki_types::{ServerName, CertificateDer, UnixTime};
+use rustls::client::danger::{ServerCertVerifier, ServerCertVerified, HandshakeSignatureValid};
+use ::{Error, Result};
+use transport::Transport;
+
+/// The connection to a peer, which is either a plain transport, or a TLS session or
+/// AES-EAX messages over one.
+///
+/// Like `TcpStream`, it can be cloned to read and write from different threads at once.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(Box<Transport>),
+    Tls(TlsStream),
+    Eax(EaxStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> Result<Stream> {
+        match self {
+            &Stream::Plain(ref socket) =>
+                Ok(Stream::Plain(try!(socket.try_clone()))),
+            &Stream::Tls(ref stream) =>
+                Ok(Stream::Tls(TlsStream {
+                    socket:     try!(stream.socket.try_clone()),
+                    connection: stream.connection.clone()
+                })),
+            &Stream::Eax(ref stream) =>
+                Ok(Stream::Eax(EaxStream {
+                    socket: try!(stream.socket.try_clone()),
+                    reader: stream.reader.clone(),
+                    writer: stream.writer.clone()
+                }))
+        }
+    }
+
+    /// Returns another handle to the transport underneath, which can be shut down without
+    /// waiting for a thread that is blocked writing to the stream.
+    pub(crate) fn try_clone_transport(&self) -> Result<Box<Transport>> {
+        match self {
+            &Stream::Plain(ref socket) => Ok(try!(socket.try_clone())),
+            &Stream::Tls(ref stream) => Ok(try!(stream.socket.try_clone())),
+            &Stream::Eax(ref stream) => Ok(try!(stream.socket.try_clone()))
+        }
+    }
+
+    /// Performs a client TLS handshake over the transport, which must not carry TLS yet.
+    pub fn start_tls_client(self, config: Arc<rustls::ClientConfig>,
+                            server_name: &str) -> Result<Stream> {
+        let server_name = try!(ServerName::try_from(server_name.to_owned())
+                                   .map_err(|_| Error::Unexpected("TLS server name")));
+        let connection = try!(rustls::ClientConnection::new(config, server_name)
+                                  .map_err(tls_error));
+        self.start_tls(rustls::Connection::Client(connection))
+    }
+
+    /// Performs a server TLS handshake over the transport, which must not carry TLS yet.
+    pub fn start_tls_server(self, config: Arc<rustls::ServerConfig>) -> Result<Stream> {
+        let connection = try!(rustls::ServerConnection::new(config).map_err(tls_error));
+        self.start_tls(rustls::Connection::Server(connection))
+    }
+
+    fn start_tls(self, mut connection: rustls::Connection) -> Result<Stream> {
+        let mut socket = match self {
+            Stream::Plain(socket) => socket,
+            _ => return Err(Error::Unexpected("nested security layer"))
+        };
+        while connection.is_handshaking() {
+            try!(connection.complete_io(&mut socket));
+        }
+        Ok(Stream::Tls(TlsStream {
+            socket:     socket,
+            connection: Arc::new(Mutex::new(connection))
+        }))
+    }
+
+    /// Starts sending messages encrypted with AES-EAX over the transport, which must not
+    /// carry another security layer yet. `read_key` and `write_key` are 16 bytes long
+    /// for AES-128, or 32 bytes long for AES-256.
+    pub fn start_eax(self, read_key: &[u8], write_key: &[u8]) -> Result<Stream> {
+        let socket = match self {
+            Stream::Plain(socket) => socket,
+            _ => return Err(Error::Unexpected("nested security layer"))
+        };
+        Ok(Stream::Eax(EaxStream {
+            socket: socket,
+            reader: Arc::new(Mutex::new(EaxReader {
+                state:     EaxState::new(read_key),
+                plaintext: Vec::new(),
+                position:  0
+            })),
+            writer: Arc::new(Mutex::new(EaxState::new(write_key)))
+        }))
+    }
+
+    /// Goes back to using the transport underneath the AES-EAX messages as is.
+    pub fn stop_eax(self) -> Result<Stream> {
+        match self {
+            Stream::Eax(stream) => {
+                if stream.reader.lock().unwrap().buffered() > 0 {
+                    return Err(Error::Unexpected("data past the end of encrypted stream"))
+                }
+                Ok(Stream::Plain(stream.socket))
+            }
+            _ => Err(Error::Unexpected("stream without AES-EAX layer"))
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        match self {
+            &mut Stream::Plain(ref socket) => try!(socket.shutdown(how)),
+            &mut Stream::Eax(ref stream) => try!(stream.socket.shutdown(how)),
+            &mut Stream::Tls(ref mut stream) => {
+                let mut connection = stream.connection.lock().unwrap();
+                connection.send_close_notify();
+                while connection.wants_write() {
+                    // The peer may well be gone already.
+                    if connection.write_tls(&mut stream.socket).is_err() { break }
+                }
+                // If it is, the close notification makes it reset the connection.
+                match stream.socket.shutdown(how) {
+                    Err(ref error) if error.kind() == io::ErrorKind::NotConnected => (),
+                    result => try!(result)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Plain(ref mut socket) => socket.read(buf),
+            &mut Stream::Tls(ref mut stream) => stream.read(buf),
+            &mut Stream::Eax(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Plain(ref mut socket) => socket.write(buf),
+            &mut Stream::Tls(ref mut stream) => stream.write(buf),
+            &mut Stream::Eax(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut Stream::Plain(ref mut socket) => socket.flush(),
+            &mut Stream::Tls(ref mut stream) => stream.flush(),
+            &mut Stream::Eax(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+/// A TLS session shared between clones of a `Stream`.
+///
+/// The session is only locked while records are being processed, never while waiting
+/// for the socket to become readable, so that one thread can block reading while another
+/// keeps writing.
+#[derive(Debug)]
+pub struct TlsStream {
+    socket:     Box<Transport>,
+    connection: Arc<Mutex<rustls::Connection>>,
+}
+
+impl TlsStream {
+    fn send_records(connection: &mut rustls::Connection, socket: &mut Transport)
+            -> io::Result<()> {
+        while connection.wants_write() {
+            try!(connection.write_tls(socket));
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut connection = self.connection.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result
+                }
+            }
+
+            // No plaintext is buffered, so the records read here can always be accepted.
+            let mut records = [0; 16384];
+            let length = try!(self.socket.read(&mut records));
+            if length == 0 { return Ok(0) }
+
+            let mut connection = self.connection.lock().unwrap();
+            let mut records = &records[..length];
+            while records.len() > 0 {
+                try!(connection.read_tls(&mut records));
+                try!(connection.process_new_packets().map_err(|error|
+                    io::Error::new(io::ErrorKind::InvalidData, error)));
+            }
+            try!(TlsStream::send_records(&mut connection, &mut *self.socket));
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let length = try!(connection.writer().write(buf));
+        try!(TlsStream::send_records(&mut connection, &mut *self.socket));
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        try!(connection.writer().flush());
+        TlsStream::send_records(&mut connection, &mut *self.socket)
+    }
+}
+
+// Longest plaintext sent in a single AES-EAX message.
+const EAX_MAX_MESSAGE: usize = 8192;
+
+enum EaxCipher {
+    Aes128(Eax<Aes128>),
+    Aes256(Eax<Aes256>),
+}
+
+/// The cipher and nonce of one direction of an AES-EAX stream.
+///
+/// The nonce is a 128-bit little-endian counter, starting at zero and incremented
+/// after every message.
+struct EaxState {
+    cipher: EaxCipher,
+    nonce:  [u8; 16],
+}
+
+impl EaxState {
+    fn new(key: &[u8]) -> EaxState {
+        let cipher = match key.len() {
+            16 => EaxCipher::Aes128(Eax::new(GenericArray::from_slice(key))),
+            32 => EaxCipher::Aes256(Eax::new(GenericArray::from_slice(key))),
+            _  => panic!("AES key must be 16 or 32 bytes long, not {}", key.len())
+        };
+        EaxState { cipher: cipher, nonce: [0; 16] }
+    }
+
+    fn advance_nonce(&mut self) {
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 { break }
+        }
+    }
+
+    // Every message is authenticated together with its length, which precedes it in clear.
+    fn seal(&mut self, length: &[u8], data: &mut [u8]) -> Vec<u8> {
+        let nonce = GenericArray::from_slice(&self.nonce);
+        let tag = match self.cipher {
+            EaxCipher::Aes128(ref cipher) => cipher.encrypt_in_place_detached(nonce, length, data),
+            EaxCipher::Aes256(ref cipher) => cipher.encrypt_in_place_detached(nonce, length, data),
+        }.expect("AES-EAX message is not too long");
+        self.advance_nonce();
+        tag.to_vec()
+    }
+
+    fn open(&mut self, length: &[u8], data: &mut [u8], tag: &[u8]) -> io::Result<()> {
+        let result = {
+            let nonce = GenericArray::from_slice(&self.nonce);
+            let tag = GenericArray::from_slice(tag);
+            match self.cipher {
+                EaxCipher::Aes128(ref cipher) =>
+                    cipher.decrypt_in_place_detached(nonce, length, data, tag),
+                EaxCipher::Aes256(ref cipher) =>
+                    cipher.decrypt_in_place_detached(nonce, length, data, tag),
+            }
+        };
+        self.advance_nonce();
+        result.map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                                          "AES-EAX message authentication failed"))
+    }
+}
+
+struct EaxReader {
+    state:     EaxState,
+    plaintext: Vec<u8>,
+    position:  usize,
+}
+
+impl EaxReader {
+    fn buffered(&self) -> usize { self.plaintext.len() - self.position }
+}
+
+/// Messages encrypted with AES-EAX, as used by the RSA-AES security type.
+///
+/// Every message consists of its length, the ciphertext and a 16-byte tag.
+/// The two directions are locked separately, so that one thread can block reading
+/// while another keeps writing.
+pub struct EaxStream {
+    socket: Box<Transport>,
+    reader: Arc<Mutex<EaxReader>>,
+    writer: Arc<Mutex<EaxState>>,
+}
+
+impl fmt::Debug for EaxStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EaxStream").field("socket", &self.socket).finish()
+    }
+}
+
+impl Read for EaxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        if reader.buffered() == 0 {
+            let length = match self.socket.read_u16::<BigEndian>() {
+                Ok(length) => length,
+                Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(error) => return Err(error)
+            };
+            let mut data = vec![0; length as usize];
+            try!(self.socket.read_exact(&mut data));
+            let mut tag = [0; 16];
+            try!(self.socket.read_exact(&mut tag));
+
+            let mut length_data = [0; 2];
+            try!((&mut length_data[..]).write_u16::<BigEndian>(length));
+            try!(reader.state.open(&length_data, &mut data, &tag));
+            reader.plaintext = data;
+            reader.position = 0;
+        }
+
+        let length = ::std::cmp::min(buf.len(), reader.buffered());
+        let position = reader.position;
+        buf[..length].copy_from_slice(&reader.plaintext[position..position + length]);
+        reader.position += length;
+        Ok(length)
+    }
+}
+
+impl Write for EaxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() == 0 { return Ok(0) }
+
+        let length = ::std::cmp::min(buf.len(), EAX_MAX_MESSAGE);
+        let mut message = Vec::with_capacity(2 + length + 16);
+        try!(message.write_u16::<BigEndian>(length as u16));
+        message.extend_from_slice(&buf[..length]);
+
+        let mut writer = self.writer.lock().unwrap();
+        let (length_data, data) = message.split_at_mut(2);
+        let tag = writer.seal(length_data, data);
+        message.extend_from_slice(&tag);
+        try!(self.socket.write_all(&message));
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+fn tls_error(error: rustls::Error) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Returns a client configuration that accepts any server certificate.
+///
+/// VeNCrypt's anonymous `Tls*` sub-types were designed around anonymous Diffie-Hellman
+/// cipher suites, which rustls does not implement; instead, servers present a certificate
+/// that is only used for encryption. Such sessions are not protected against
+/// man-in-the-middle attacks.
+pub fn anonymous_client_config() -> Arc<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+#[derive(Debug)]
+struct AnyCertificate(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer, _intermediates: &[CertificateDer],
+                          _server_name: &ServerName, _ocsp_response: &[u8], _now: UnixTime)
+            -> ::std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // The handshake signatures are still checked, so that the session is at least
+    // bound to the key in the certificate.
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer,
+                              dss: &rustls::DigitallySignedStruct)
+            -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer,
+                              dss: &rustls::DigitallySignedStruct)
+            -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
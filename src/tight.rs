@@ -0,0 +1,366 @@
+use std;
+use std::io::Read;
+use flate2;
+use jpeg_decoder;
+use byteorder::ReadBytesExt;
+use ::{protocol, Error, Result};
+
+const FILL_COMPRESSION:  u8 = 0x08;
+const JPEG_COMPRESSION:  u8 = 0x09;
+const EXPLICIT_FILTER:   u8 = 0x04;
+
+const COPY_FILTER:       u8 = 0;
+const PALETTE_FILTER:    u8 = 1;
+const GRADIENT_FILTER:   u8 = 2;
+
+// Data shorter than this is always sent uncompressed.
+const MIN_TO_COMPRESS: usize = 12;
+
+/// Pixel data of a single Tight rectangle, in the negotiated pixel format.
+pub enum Rectangle {
+    /// The whole rectangle is filled with this pixel.
+    Fill(Vec<u8>),
+    /// Pixels of the whole rectangle.
+    Pixels(Vec<u8>),
+}
+
+pub struct Decoder {
+    decompressors: Vec<flate2::Decompress>
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            decompressors: (0..4).map(|_| flate2::Decompress::new(/*zlib_header*/true)).collect()
+        }
+    }
+
+    /// Decodes a Tight rectangle from `reader`.
+    ///
+    /// Like Hextile, Tight data is not length-prefixed as a whole and has to be read
+    /// straight from the stream.
+    pub fn decode<R: Read>(&mut self, reader: &mut R, format: protocol::PixelFormat,
+                           rect: protocol::Rect) -> Result<Rectangle> {
+        let control = try!(reader.read_u8());
+        for stream_id in 0..4 {
+            if control & (1 << stream_id) != 0 {
+                self.decompressors[stream_id] = flate2::Decompress::new(/*zlib_header*/true)
+            }
+        }
+
+        let tpixel_size = if is_tpixel(&format) { 3 } else { format.bits_per_pixel as usize / 8 };
+        let pixel_count = rect.width as usize * rect.height as usize;
+
+        match control >> 4 {
+            FILL_COMPRESSION => {
+                let mut colour = vec![0; tpixel_size];
+                try!(reader.read_exact(&mut colour));
+                Ok(Rectangle::Fill(from_tpixels(&format, &colour)))
+            },
+            JPEG_COMPRESSION => {
+                let length = try!(read_compact_length(reader));
+                let mut data = vec![0; length];
+                try!(reader.read_exact(&mut data));
+                Ok(Rectangle::Pixels(try!(decode_jpeg(&format, rect, &data))))
+            },
+            subencoding if subencoding & 0x08 == 0 => { // Basic compression
+                let stream_id = (subencoding & 0x03) as usize;
+                let filter =
+                    if subencoding & EXPLICIT_FILTER != 0 {
+                        try!(reader.read_u8())
+                    } else {
+                        COPY_FILTER
+                    };
+
+                match filter {
+                    COPY_FILTER => {
+                        let data = try!(self.read_data(reader, stream_id,
+                                                       pixel_count * tpixel_size));
+                        Ok(Rectangle::Pixels(from_tpixels(&format, &data)))
+                    },
+                    PALETTE_FILTER => {
+                        let palette_size = try!(reader.read_u8()) as usize + 1;
+                        let mut palette = vec![0; palette_size * tpixel_size];
+                        try!(reader.read_exact(&mut palette));
+                        let palette = from_tpixels(&format, &palette);
+                        let bpp = format.bits_per_pixel as usize / 8;
+
+                        let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                        if palette_size == 2 {
+                            let stride = (rect.width as usize + 7) / 8;
+                            let data = try!(self.read_data(reader, stream_id,
+                                                           stride * rect.height as usize));
+                            for y in 0..rect.height as usize {
+                                for x in 0..rect.width as usize {
+                                    let index = (data[y * stride + x / 8] >> (7 - x % 8)) & 1;
+                                    let start = index as usize * bpp;
+                                    pixels.extend_from_slice(&palette[start..start + bpp])
+                                }
+                            }
+                        } else {
+                            let data = try!(self.read_data(reader, stream_id, pixel_count));
+                            for &index in &data {
+                                if index as usize >= palette_size {
+                                    return Err(Error::Unexpected("Tight palette index"))
+                                }
+                                let start = index as usize * bpp;
+                                pixels.extend_from_slice(&palette[start..start + bpp])
+                            }
+                        }
+                        Ok(Rectangle::Pixels(pixels))
+                    },
+                    GRADIENT_FILTER => {
+                        let data = try!(self.read_data(reader, stream_id,
+                                                       pixel_count * tpixel_size));
+                        Ok(Rectangle::Pixels(undo_gradient(&format, rect.width as usize, &data)))
+                    },
+                    _ => Err(Error::Unexpected("Tight filter"))
+                }
+            },
+            _ => Err(Error::Unexpected("Tight subencoding"))
+        }
+    }
+
+    fn read_data<R: Read>(&mut self, reader: &mut R, stream_id: usize,
+                          length: usize) -> Result<Vec<u8>> {
+        let mut output = vec![0; length];
+        if length < MIN_TO_COMPRESS {
+            try!(reader.read_exact(&mut output));
+            return Ok(output)
+        }
+
+        let compressed_length = try!(read_compact_length(reader));
+        let mut compressed = vec![0; compressed_length];
+        try!(reader.read_exact(&mut compressed));
+
+        // The server flushes its stream after every rectangle, so the compressed data
+        // always ends at a block boundary; the trailing empty block of a sync flush
+        // is consumed into `overflow` and must not produce any bytes.
+        let decompressor = &mut self.decompressors[stream_id];
+        let mut input = &compressed[..];
+        let mut produced = 0;
+        let mut overflow = [0; 1];
+        while input.len() > 0 {
+            let in_before  = decompressor.total_in();
+            let out_before = decompressor.total_out();
+            let result =
+                if produced < length {
+                    decompressor.decompress(input, &mut output[produced..], flate2::Flush::Sync)
+                } else {
+                    decompressor.decompress(input, &mut overflow, flate2::Flush::Sync)
+                };
+            let consumed = (decompressor.total_in()  - in_before) as usize;
+            let written  = (decompressor.total_out() - out_before) as usize;
+
+            if produced + written > length {
+                return Err(Error::Unexpected("leftover Tight byte data"))
+            }
+            produced += written;
+            input = &input[consumed..];
+
+            match result {
+                Ok(flate2::Status::Ok) | Ok(flate2::Status::BufError) => {
+                    if consumed == 0 && written == 0 { break }
+                },
+                Ok(flate2::Status::StreamEnd) | Err(_) =>
+                    return Err(Error::Unexpected("Tight zlib data"))
+            }
+        }
+
+        if produced != length {
+            return Err(Error::Unexpected("truncated Tight byte data"))
+        }
+        Ok(output)
+    }
+}
+
+fn read_compact_length<R: Read>(reader: &mut R) -> Result<usize> {
+    let mut length = 0;
+    for i in 0..3 {
+        let byte = try!(reader.read_u8());
+        if i == 2 {
+            length |= (byte as usize) << 14;
+        } else {
+            length |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 { break }
+        }
+    }
+    Ok(length)
+}
+
+/// Returns `true` if pixels are sent as 3-byte RGB triples ("TPIXEL"s) in this format.
+fn is_tpixel(format: &protocol::PixelFormat) -> bool {
+    format.true_colour && format.bits_per_pixel == 32 && format.depth == 24 &&
+        format.red_max == 255 && format.green_max == 255 && format.blue_max == 255
+}
+
+fn read_pixel(format: &protocol::PixelFormat, bytes: &[u8]) -> u32 {
+    let mut value = 0u32;
+    for i in 0..bytes.len() {
+        let byte = if format.big_endian { bytes[i] } else { bytes[bytes.len() - 1 - i] };
+        value = (value << 8) | byte as u32;
+    }
+    value
+}
+
+fn write_pixel(format: &protocol::PixelFormat, value: u32, pixels: &mut Vec<u8>) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    for i in 0..bpp {
+        let shift = if format.big_endian { (bpp - 1 - i) * 8 } else { i * 8 };
+        pixels.push((value >> shift) as u8)
+    }
+}
+
+fn write_rgb(format: &protocol::PixelFormat, red: u8, green: u8, blue: u8,
+             pixels: &mut Vec<u8>) {
+    fn scale(component: u8, max: u16) -> u32 {
+        (component as u32 * max as u32 + 127) / 255
+    }
+
+    let value =
+        scale(red,   format.red_max)   << format.red_shift   |
+        scale(green, format.green_max) << format.green_shift |
+        scale(blue,  format.blue_max)  << format.blue_shift;
+    write_pixel(format, value, pixels)
+}
+
+fn from_tpixels(format: &protocol::PixelFormat, data: &[u8]) -> Vec<u8> {
+    if !is_tpixel(format) {
+        return data.to_vec()
+    }
+
+    let mut pixels = Vec::with_capacity(data.len() / 3 * 4);
+    for rgb in data.chunks(3) {
+        write_rgb(format, rgb[0], rgb[1], rgb[2], &mut pixels)
+    }
+    pixels
+}
+
+fn decode_jpeg(format: &protocol::PixelFormat, rect: protocol::Rect,
+               data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let image = try!(decoder.decode().map_err(|_| Error::Unexpected("Tight JPEG data")));
+    let info = decoder.info().unwrap();
+    if info.width != rect.width || info.height != rect.height {
+        return Err(Error::Unexpected("Tight JPEG image size"))
+    }
+
+    let bpp = format.bits_per_pixel as usize / 8;
+    let mut pixels = Vec::with_capacity(rect.width as usize * rect.height as usize * bpp);
+    match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => {
+            for rgb in image.chunks(3) {
+                write_rgb(format, rgb[0], rgb[1], rgb[2], &mut pixels)
+            }
+        },
+        jpeg_decoder::PixelFormat::L8 => {
+            for &luma in &image {
+                write_rgb(format, luma, luma, luma, &mut pixels)
+            }
+        },
+        _ => return Err(Error::Unexpected("Tight JPEG pixel format"))
+    }
+    Ok(pixels)
+}
+
+/// Reverses the gradient filter: every component was sent as the difference from
+/// the prediction `left + above - above_left`, clamped to the component range.
+fn undo_gradient(format: &protocol::PixelFormat, width: usize, data: &[u8]) -> Vec<u8> {
+    let tpixel = is_tpixel(format);
+    let size = if tpixel { 3 } else { format.bits_per_pixel as usize / 8 };
+    let max = [format.red_max as i32, format.green_max as i32, format.blue_max as i32];
+    let shift = [format.red_shift, format.green_shift, format.blue_shift];
+
+    let mut pixels   = Vec::with_capacity(data.len() / size * (format.bits_per_pixel as usize / 8));
+    let mut previous = vec![[0i32; 3]; width];
+    let mut current  = vec![[0i32; 3]; width];
+    for row in data.chunks(width * size) {
+        for (x, bytes) in row.chunks(size).enumerate() {
+            let difference =
+                if tpixel {
+                    [bytes[0] as i32, bytes[1] as i32, bytes[2] as i32]
+                } else {
+                    let value = read_pixel(format, bytes);
+                    [((value >> shift[0]) as i32) & max[0],
+                     ((value >> shift[1]) as i32) & max[1],
+                     ((value >> shift[2]) as i32) & max[2]]
+                };
+
+            for c in 0..3 {
+                let (left, above_left) =
+                    if x > 0 { (current[x - 1][c], previous[x - 1][c]) } else { (0, 0) };
+                let mut prediction = left + previous[x][c] - above_left;
+                if prediction < 0 { prediction = 0 }
+                if prediction > max[c] { prediction = max[c] }
+                current[x][c] = (prediction + difference[c]) & max[c];
+            }
+
+            if tpixel {
+                write_rgb(format, current[x][0] as u8, current[x][1] as u8,
+                          current[x][2] as u8, &mut pixels)
+            } else {
+                let value =
+                    (current[x][0] as u32) << shift[0] |
+                    (current[x][1] as u32) << shift[1] |
+                    (current[x][2] as u32) << shift[2];
+                write_pixel(format, value, &mut pixels)
+            }
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2;
+    use super::{Decoder, Rectangle};
+    use ::protocol;
+
+    fn compress(compressor: &mut flate2::Compress, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() * 2 + 64);
+        compressor.compress_vec(data, &mut output, flate2::Flush::Sync);
+        output
+    }
+
+    /// Checks if consecutive rectangles on one persistent zlib stream decode correctly.
+    #[test]
+    fn check_if_basic_compression_reuses_zlib_stream() {
+        let format = ::pixel_format::RGB8888;
+        let rect = protocol::Rect::new(0, 0, 4, 4);
+        let rgb: Vec<u8> = (0..48).collect();
+
+        let mut compressor = flate2::Compress::new(flate2::Compression::Default, true);
+        let mut decoder = Decoder::new();
+        for _ in 0..2 {
+            let compressed = compress(&mut compressor, &rgb);
+            let mut input = vec![0x00, compressed.len() as u8];
+            input.extend_from_slice(&compressed);
+
+            match decoder.decode(&mut &input[..], format, rect).unwrap() {
+                Rectangle::Pixels(pixels) => {
+                    assert_eq!(pixels.len(), 64);
+                    assert_eq!(&pixels[0..8], &[0, 2, 1, 0, 0, 5, 4, 3]);
+                },
+                Rectangle::Fill(_) => panic!("expected pixels")
+            }
+        }
+    }
+
+    /// Checks if a two-colour palette rectangle expands its bitmap correctly.
+    #[test]
+    fn check_if_mono_palette_is_expanded() {
+        let format = ::pixel_format::RGB8888;
+        let rect = protocol::Rect::new(0, 0, 3, 2);
+        let input = [0x40, 0x01, 0x01, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff,
+                     0b1010_0000, 0b0100_0000];
+
+        match Decoder::new().decode(&mut &input[..], format, rect).unwrap() {
+            Rectangle::Pixels(pixels) => {
+                assert_eq!(pixels, vec![0, 255, 0, 0, 0, 0, 0, 255, 0, 255, 0, 0,
+                                        0, 0, 0, 255, 0, 255, 0, 0, 0, 0, 0, 255]);
+            },
+            Rectangle::Fill(_) => panic!("expected pixels")
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, Shutdown};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+/// A bidirectional byte stream that a connection to a peer can be established over.
+///
+/// The client and the proxy read and write from different threads at once, so a transport
+/// has to be able to hand out further handles to itself, and to stop the other threads
+/// by shutting down.
+pub trait Transport: Read + Write + Send + fmt::Debug {
+    /// Returns another handle to the same transport.
+    fn try_clone(&self) -> io::Result<Box<Transport>>;
+
+    /// Shuts down reading, writing or both halves of the transport, for all handles to it.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Ok(Box::new(try!(TcpStream::try_clone(self))))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Ok(Box::new(try!(UnixStream::try_clone(self))))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+/// A transport made of separate reading and writing halves, such as the standard input
+/// and output of a server started by inetd, or a pair of pipes.
+///
+/// Unlike sockets, the reading half cannot be interrupted: shutting down writing drops
+/// the writing half, which usually makes the peer close its own end in turn, but a thread
+/// blocked reading only returns once that happens.
+pub struct Split<R, W> {
+    reader: Arc<Mutex<R>>,
+    writer: Arc<Mutex<Option<W>>>,
+}
+
+impl<R: Read, W: Write> Split<R, W> {
+    pub fn new(reader: R, writer: W) -> Split<R, W> {
+        Split {
+            reader: Arc::new(Mutex::new(reader)),
+            writer: Arc::new(Mutex::new(Some(writer))),
+        }
+    }
+}
+
+impl<R, W> fmt::Debug for Split<R, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Split")
+         .field("shut_down", &self.writer.lock().unwrap().is_none())
+         .finish()
+    }
+}
+
+impl<R: Read, W> Read for Split<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.lock().unwrap().read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Split<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => writer.write(buf),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "transport is shut down"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+impl<R, W> Transport for Split<R, W>
+        where R: Read + Send + 'static, W: Write + Send + 'static {
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Ok(Box::new(Split {
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+        }))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how == Shutdown::Read { return Ok(()) }
+
+        let writer = self.writer.lock().unwrap().take();
+        match writer {
+            Some(mut writer) => writer.flush(),
+            None => Ok(())
+        }
+    }
+}
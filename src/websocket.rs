@@ -0,0 +1,533 @@
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rsa::rand_core::{OsRng, RngCore};
+use sha1::{Sha1, Digest};
+use ::{Error, Result};
+use transport::Transport;
+
+// Appended to the key sent by the client before hashing it; see RFC 6455, section 1.3.
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Longest HTTP request or response head accepted during the upgrade.
+const MAX_HEAD: usize = 8192;
+
+// Longest payload sent in a single frame.
+const MAX_FRAME: usize = 65536;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY:       u8 = 0x2;
+const OPCODE_CLOSE:        u8 = 0x8;
+const OPCODE_PING:         u8 = 0x9;
+const OPCODE_PONG:         u8 = 0xa;
+
+// Status code of a close frame sent when shutting down.
+const CLOSE_NORMAL: u16 = 1000;
+
+/// RFB carried in binary WebSocket messages, as spoken by noVNC and websockify.
+///
+/// Data is read regardless of how it is split into messages and frames; pings are answered
+/// as they are received, and a close frame ends the stream. Every write is sent as a single
+/// binary message, masked when sent by the client.
+///
+/// Like other transports, it can be cloned to read and write from different threads at once.
+pub struct WebSocket {
+    socket: Box<Transport>,
+    reader: Arc<Mutex<FrameReader>>,
+    writer: Arc<Mutex<FrameWriter>>,
+}
+
+struct FrameReader {
+    // Whether frames received from the peer have to be masked, i.e. whether this is
+    // the server side.
+    masked:    bool,
+    remaining: u64,
+    mask:      Option<[u8; 4]>,
+    offset:    usize,
+    closed:    bool,
+}
+
+struct FrameWriter {
+    socket: Box<Transport>,
+    masked: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    /// Performs the server side of the HTTP upgrade over `transport`, for example
+    /// on a connection from noVNC, and returns the transport to pass to `Server`.
+    ///
+    /// Clients offering subprotocols are required to offer `binary`; the `base64`
+    /// subprotocol used by old versions of websockify is not supported. Requests that cannot
+    /// be upgraded are answered with `400 Bad Request`.
+    pub fn accept<T: Transport + 'static>(transport: T) -> Result<WebSocket> {
+        let mut socket: Box<Transport> = Box::new(transport);
+        let head = try!(read_head(&mut *socket));
+        debug!("<- WebSocket upgrade {:?}", head[0]);
+
+        let result = (|| {
+            if !head[0].starts_with("GET ") {
+                return Err(Error::Unexpected("HTTP method"))
+            }
+            if !header_tokens(&head, "upgrade").contains(&String::from("websocket")) ||
+               !header_tokens(&head, "connection").contains(&String::from("upgrade")) {
+                return Err(Error::Unexpected("HTTP request without WebSocket upgrade"))
+            }
+            if header_tokens(&head, "sec-websocket-version") != [String::from("13")] {
+                return Err(Error::Unexpected("WebSocket version"))
+            }
+            let protocols = header_tokens(&head, "sec-websocket-protocol");
+            if protocols.len() > 0 && !protocols.contains(&String::from("binary")) {
+                return Err(Error::Unexpected("WebSocket subprotocol"))
+            }
+            match header(&head, "sec-websocket-key") {
+                Some(key) => Ok((accept_key(key), protocols.len() > 0)),
+                None => Err(Error::Unexpected("HTTP request without WebSocket key"))
+            }
+        })();
+
+        let response = match result {
+            Ok((ref accept, binary)) => {
+                let mut response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                            Upgrade: websocket\r\n\
+                                            Connection: Upgrade\r\n\
+                                            Sec-WebSocket-Accept: {}\r\n", accept);
+                if binary {
+                    response.push_str("Sec-WebSocket-Protocol: binary\r\n");
+                }
+                response
+            }
+            Err(_) => String::from("HTTP/1.1 400 Bad Request\r\n\
+                                    Sec-WebSocket-Version: 13\r\n\
+                                    Connection: close\r\n")
+        };
+        try!(socket.write_all(response.as_bytes()));
+        try!(socket.write_all(b"\r\n"));
+        try!(socket.flush());
+        try!(result);
+
+        WebSocket::new(socket, false)
+    }
+
+    /// Performs the client side of the HTTP upgrade over `transport` to the endpoint
+    /// at `path` on `host`, for example a websockify instance, and returns the transport
+    /// to pass to `Client`.
+    pub fn connect<T: Transport + 'static>(transport: T, host: &str, path: &str)
+            -> Result<WebSocket> {
+        let mut socket: Box<Transport> = Box::new(transport);
+        let mut key = [0; 16];
+        OsRng.fill_bytes(&mut key);
+        let key = base64(&key);
+
+        let request = format!("GET {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\
+                               Sec-WebSocket-Protocol: binary\r\n\
+                               \r\n", path, host, key);
+        try!(socket.write_all(request.as_bytes()));
+        try!(socket.flush());
+
+        let head = try!(read_head(&mut *socket));
+        debug!("<- WebSocket upgrade {:?}", head[0]);
+        if head[0].split_whitespace().nth(1) != Some("101") {
+            return Err(Error::Server(format!("WebSocket upgrade refused: {}", head[0])))
+        }
+        if header(&head, "sec-websocket-accept") != Some(&accept_key(&key)[..]) {
+            return Err(Error::Unexpected("WebSocket accept key"))
+        }
+        match header(&head, "sec-websocket-protocol") {
+            None | Some("binary") => (),
+            Some(_) => return Err(Error::Unexpected("WebSocket subprotocol"))
+        }
+
+        WebSocket::new(socket, true)
+    }
+
+    fn new(socket: Box<Transport>, client: bool) -> Result<WebSocket> {
+        let writer_socket = try!(socket.try_clone());
+        Ok(WebSocket {
+            socket: socket,
+            reader: Arc::new(Mutex::new(FrameReader {
+                masked:    !client,
+                remaining: 0,
+                mask:      None,
+                offset:    0,
+                closed:    false,
+            })),
+            writer: Arc::new(Mutex::new(FrameWriter {
+                socket: writer_socket,
+                masked: client,
+                closed: false,
+            })),
+        })
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket").field("socket", &self.socket).finish()
+    }
+}
+
+// Reads an HTTP request or response head up to the empty line ending it, without reading
+// any further, and returns its lines.
+fn read_head(socket: &mut Transport) -> Result<Vec<String>> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HEAD {
+            return Err(Error::Unexpected("HTTP head length"))
+        }
+        let mut byte = [0; 1];
+        if try!(socket.read(&mut byte)) == 0 {
+            return Err(Error::Disconnected)
+        }
+        head.push(byte[0]);
+    }
+
+    let head = try!(String::from_utf8(head).map_err(|_| Error::Unexpected("HTTP head")));
+    Ok(head.split("\r\n").take_while(|line| line.len() > 0).map(String::from).collect())
+}
+
+// Returns the value of the first header called `name`, which must be lowercase.
+fn header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head[1..].iter().filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().to_lowercase() == name => Some(value.trim()),
+            _ => None
+        }
+    }).next()
+}
+
+// Returns the comma-separated, lowercased values of all headers called `name`.
+fn header_tokens(head: &[String], name: &str) -> Vec<String> {
+    head[1..].iter().filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().to_lowercase() == name => Some(value),
+            _ => None
+        }
+    }).flat_map(|value| value.split(',').map(|token| token.trim().to_lowercase()))
+      .filter(|token| token.len() > 0)
+      .collect()
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    base64(&hasher.finalize())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &'static [u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate()
+                        .fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn apply_mask(mask: &[u8; 4], offset: usize, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[(offset + i) % 4];
+    }
+}
+
+fn invalid_frame(description: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+struct FrameHeader {
+    fin:    bool,
+    opcode: u8,
+    mask:   Option<[u8; 4]>,
+    length: u64,
+}
+
+impl FrameHeader {
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<FrameHeader> {
+        let first = try!(reader.read_u8());
+        let second = try!(reader.read_u8());
+        if first & 0x70 != 0 {
+            return Err(invalid_frame("WebSocket frame with reserved bits set"))
+        }
+        let length = match second & 0x7f {
+            126 => try!(reader.read_u16::<BigEndian>()) as u64,
+            127 => try!(reader.read_u64::<BigEndian>()),
+            length => length as u64
+        };
+        let mask = if second & 0x80 != 0 {
+            let mut mask = [0; 4];
+            try!(reader.read_exact(&mut mask));
+            Some(mask)
+        } else {
+            None
+        };
+        Ok(FrameHeader {
+            fin:    first & 0x80 != 0,
+            opcode: first & 0x0f,
+            mask:   mask,
+            length: length,
+        })
+    }
+}
+
+impl FrameWriter {
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket is closed"))
+        }
+        if opcode == OPCODE_CLOSE {
+            self.closed = true;
+        }
+
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        try!(frame.write_u8(0x80 | opcode));
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            try!(frame.write_u8(mask_bit | payload.len() as u8));
+        } else if payload.len() <= 0xffff {
+            try!(frame.write_u8(mask_bit | 126));
+            try!(frame.write_u16::<BigEndian>(payload.len() as u16));
+        } else {
+            try!(frame.write_u8(mask_bit | 127));
+            try!(frame.write_u64::<BigEndian>(payload.len() as u64));
+        }
+        let start = frame.len();
+        if self.masked {
+            let mut mask = [0; 4];
+            OsRng.fill_bytes(&mut mask);
+            frame.extend_from_slice(&mask);
+            frame.extend_from_slice(payload);
+            apply_mask(&mask, 0, &mut frame[start + 4..]);
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.socket.write_all(&frame)
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() == 0 { return Ok(0) }
+
+        let mut reader = self.reader.lock().unwrap();
+        while reader.remaining == 0 {
+            if reader.closed { return Ok(0) }
+
+            let header = match FrameHeader::read_from(&mut self.socket) {
+                Ok(header) => header,
+                Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(error) => return Err(error)
+            };
+            if header.mask.is_some() != reader.masked {
+                return Err(invalid_frame("WebSocket frame masked incorrectly"))
+            }
+
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_BINARY => {
+                    reader.remaining = header.length;
+                    reader.mask = header.mask;
+                    reader.offset = 0;
+                }
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                    if !header.fin || header.length > 125 {
+                        return Err(invalid_frame("WebSocket control frame too long"))
+                    }
+                    let mut payload = vec![0; header.length as usize];
+                    try!(self.socket.read_exact(&mut payload));
+                    if let Some(ref mask) = header.mask {
+                        apply_mask(mask, 0, &mut payload);
+                    }
+
+                    let mut writer = self.writer.lock().unwrap();
+                    match header.opcode {
+                        OPCODE_PING => {
+                            debug!("<- WebSocket ping");
+                            try!(writer.send(OPCODE_PONG, &payload));
+                        }
+                        OPCODE_CLOSE => {
+                            debug!("<- WebSocket close");
+                            reader.closed = true;
+                            if !writer.closed {
+                                // Echo the status code, if any. The peer may well be gone
+                                // already.
+                                let length = cmp::min(payload.len(), 2);
+                                let _ = writer.send(OPCODE_CLOSE, &payload[..length]);
+                            }
+                        }
+                        _ => ()
+                    }
+                }
+                _ => return Err(invalid_frame("WebSocket frame is not binary"))
+            }
+        }
+
+        let length = cmp::min(buf.len() as u64, reader.remaining) as usize;
+        let length = try!(self.socket.read(&mut buf[..length]));
+        if length == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "WebSocket frame truncated"))
+        }
+        if let Some(mask) = reader.mask {
+            apply_mask(&mask, reader.offset, &mut buf[..length]);
+        }
+        reader.remaining -= length as u64;
+        reader.offset = (reader.offset + length) % 4;
+        Ok(length)
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() == 0 { return Ok(0) }
+
+        let length = cmp::min(buf.len(), MAX_FRAME);
+        try!(self.writer.lock().unwrap().send(OPCODE_BINARY, &buf[..length]));
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().socket.flush()
+    }
+}
+
+impl Transport for WebSocket {
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Ok(Box::new(WebSocket {
+            socket: try!(self.socket.try_clone()),
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+        }))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut writer = self.writer.lock().unwrap();
+            if !writer.closed {
+                let mut payload = Vec::new();
+                try!(payload.write_u16::<BigEndian>(CLOSE_NORMAL));
+                // The peer may well be gone already.
+                let _ = writer.send(OPCODE_CLOSE, &payload);
+            }
+        }
+        self.socket.shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use ::{client, pixel_format, server, Error, Server};
+    use super::{WebSocket, accept_key};
+
+    /// Checks if the accept key matches the example in RFC 6455, and if pings are answered
+    /// while data split into several frames is being read.
+    #[test]
+    fn check_if_websocket_handshake_and_framing_conform() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket = WebSocket::accept(stream).unwrap();
+            let mut data = [0; 5];
+            websocket.read_exact(&mut data).unwrap();
+            data
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /websockify HTTP/1.1\r\n\
+                           Host: localhost\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: keep-alive, Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\
+                           Sec-WebSocket-Protocol: binary\r\n\
+                           \r\n").unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: binary\r\n"));
+
+        let mask = [1, 2, 3, 4];
+        // A fragmented binary message with a ping in between, all masked with `mask`.
+        stream.write_all(&[0x02, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'e' ^ 2]).unwrap();
+        stream.write_all(&[0x89, 0x81, 1, 2, 3, 4, b'!' ^ 1]).unwrap();
+        let mut frame = vec![0x80, 0x83];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"llo".iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask));
+        stream.write_all(&frame).unwrap();
+
+        let mut pong = [0; 3];
+        stream.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8a, 0x01, b'!']);
+        assert_eq!(&server.join().unwrap(), b"hello");
+    }
+
+    /// Checks if a `Client` and a `Server` can talk to each other over WebSocket, and if
+    /// the server notices the client closing it.
+    #[test]
+    fn check_if_session_is_established_over_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let websocket = WebSocket::accept(stream).unwrap();
+            let (mut server, _) = Server::from_transport(
+                websocket, 64, 64, pixel_format::RGB8888, String::from("test")).unwrap();
+            let event = server.read_event().unwrap();
+            server.send_cut_text("hello").unwrap();
+            match server.read_event() {
+                Err(Error::Disconnected) => event,
+                result => panic!("unexpected result {:?}", result)
+            }
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let websocket = WebSocket::connect(stream, "localhost", "/websockify").unwrap();
+        let mut client = client::Client::from_transport(websocket, true, |_| {
+            Some(client::AuthChoice::None)
+        }).unwrap();
+        client.send_key_event(true, 0x61).unwrap();
+        loop {
+            match client.poll_event() {
+                Some(client::Event::Clipboard(ref text)) => {
+                    assert_eq!(text, "hello");
+                    break
+                }
+                Some(client::Event::Disconnected(error)) => panic!("disconnected: {:?}", error),
+                _ => thread::yield_now()
+            }
+        }
+        client.disconnect().unwrap();
+        match server.join().unwrap() {
+            server::Event::KeyEvent { down: true, key: 0x61 } => (),
+            event => panic!("unexpected event {:?}", event)
+        }
+    }
+}
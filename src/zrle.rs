@@ -1,4 +1,5 @@
 use std;
+use std::collections::HashMap;
 use std::io::Read;
 use flate2;
 use byteorder::ReadBytesExt;
@@ -102,6 +103,27 @@ impl<T: Read> Read for BitReader<T> {
     }
 }
 
+/// Returns the size of a compressed pixel ("CPIXEL") in `format`, and whether the unused
+/// byte of a 32-bit pixel comes first in memory and so has to be skipped.
+fn cpixel_layout(format: &protocol::PixelFormat) -> (usize, bool) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    let pixel_mask =
+        (format.red_max   as u32) << format.red_shift   |
+        (format.green_max as u32) << format.green_shift |
+        (format.blue_max  as u32) << format.blue_shift;
+    if format.bits_per_pixel == 32 && format.true_colour == true && format.depth <= 24 {
+        if pixel_mask & 0x000000ff == 0 {
+            (3, !format.big_endian)
+        } else if pixel_mask & 0xff000000 == 0 {
+            (3, format.big_endian)
+        } else {
+            (4, false)
+        }
+    } else {
+        (bpp, false)
+    }
+}
+
 pub struct Decoder {
     decompressor: Option<flate2::Decompress>
 }
@@ -113,7 +135,7 @@ impl Decoder {
 
     pub fn decode<F>(&mut self, format: protocol::PixelFormat, rect: protocol::Rect,
                  input: &[u8], mut callback: F) -> Result<bool>
-            where F: FnMut(protocol::Rect, Vec<u8>) -> Result<bool> {
+            where F: FnMut(protocol::Rect, &[u8]) -> Result<bool> {
         fn read_run_length(reader: &mut Read) -> Result<usize> {
             let mut run_length_part = try!(reader.read_u8());
             let mut run_length = 1 + run_length_part as usize;
@@ -138,24 +160,12 @@ impl Decoder {
         }
 
         let bpp = format.bits_per_pixel as usize / 8;
-        let pixel_mask =
-            (format.red_max   as u32) << format.red_shift   |
-            (format.green_max as u32) << format.green_shift |
-            (format.blue_max  as u32) << format.blue_shift;
-        let (compressed_bpp, pad_pixel) =
-            if format.bits_per_pixel == 32 && format.true_colour == true && format.depth <= 24 {
-                if pixel_mask & 0x000000ff == 0 {
-                    (3, !format.big_endian)
-                } else if pixel_mask & 0xff000000 == 0 {
-                    (3, format.big_endian)
-                } else {
-                    (4, false)
-                }
-            } else {
-                (bpp, false)
-            };
+        let (compressed_bpp, pad_pixel) = cpixel_layout(&format);
 
         let mut palette = Vec::with_capacity(128 * bpp);
+        // Reused from tile to tile, as there are thousands of them in a large update.
+        let mut pixels  = Vec::with_capacity(64 * 64 * bpp);
+        let mut pixel   = Vec::with_capacity(bpp);
         let mut reader  = BitReader::new(ZlibReader::new(self.decompressor.take().unwrap(), input));
 
         let mut y = 0;
@@ -175,7 +185,7 @@ impl Decoder {
                                          pad_pixel, compressed_bpp, bpp))
                 }
 
-                let mut pixels = Vec::with_capacity(pixel_count * bpp);
+                pixels.truncate(0);
                 match (is_rle, palette_size) {
                     (false, 0) => { // True Color pixels
                         for _ in 0..pixel_count {
@@ -203,7 +213,6 @@ impl Decoder {
                     },
                     (true, 0) => { // True Color RLE
                         let mut count = 0;
-                        let mut pixel = Vec::new();
                         while count < pixel_count {
                             pixel.truncate(0);
                             try!(copy_true_color(&mut reader, &mut pixel,
@@ -235,8 +244,8 @@ impl Decoder {
                     _ => return Err(Error::Unexpected("ZRLE subencoding"))
                 }
 
-                let tile = protocol::Rect::new(rect.top + y, rect.left + x, width, height);
-                if let false = try!(callback(tile, pixels)) {
+                let tile = protocol::Rect::new(rect.left + x, rect.top + y, width, height);
+                if let false = try!(callback(tile, &pixels)) {
                     return Ok(false)
                 }
 
@@ -249,3 +258,314 @@ impl Decoder {
         Ok(true)
     }
 }
+
+pub struct Encoder {
+    compressor: flate2::Compress
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            compressor: flate2::Compress::new(flate2::Compression::Default, /*zlib_header*/true)
+        }
+    }
+
+    /// Encodes `pixels` in `format` covering `rect`, and returns zlib data to be sent
+    /// as a ZRLE rectangle.
+    ///
+    /// Every 64x64 tile is sent using whichever subencoding is the most compact.
+    /// The zlib stream persists across calls, so the rectangles must be sent
+    /// in the same order as they were encoded.
+    pub fn encode(&mut self, format: protocol::PixelFormat, rect: protocol::Rect,
+                  pixels: &[u8]) -> Vec<u8> {
+        let bpp = format.bits_per_pixel as usize / 8;
+        let (compressed_bpp, pad_pixel) = cpixel_layout(&format);
+        let cpixel = CPixel { bpp: bpp, compressed_bpp: compressed_bpp, pad: pad_pixel };
+
+        let mut data = Vec::new();
+        let mut tile = Vec::with_capacity(64 * 64);
+        let mut y = 0;
+        while y < rect.height {
+            let height = if y + 64 > rect.height { rect.height - y } else { 64 };
+            let mut x = 0;
+            while x < rect.width {
+                let width = if x + 64 > rect.width { rect.width - x } else { 64 };
+
+                tile.truncate(0);
+                for row in y..y + height {
+                    let start = (row as usize * rect.width as usize + x as usize) * bpp;
+                    let end   = start + width as usize * bpp;
+                    tile.extend(pixels[start..end].chunks(bpp).map(|pixel| cpixel.key(pixel)))
+                }
+                encode_tile(&mut data, &tile, width as usize, &cpixel);
+
+                x += width;
+            }
+            y += height;
+        }
+
+        let mut output = Vec::with_capacity(data.len() + data.len() / 1000 + 64);
+        let mut input  = &data[..];
+        loop {
+            let in_before = self.compressor.total_in();
+            self.compressor.compress_vec(input, &mut output, flate2::Flush::Sync);
+            input = &input[(self.compressor.total_in() - in_before) as usize..];
+            if input.len() == 0 && output.len() < output.capacity() { break }
+            let capacity = output.capacity();
+            output.reserve(capacity);
+        }
+        output
+    }
+}
+
+struct CPixel {
+    bpp:            usize,
+    compressed_bpp: usize,
+    pad:            bool,
+}
+
+impl CPixel {
+    /// Packs pixel bytes into an integer, for use as a palette key.
+    fn key(&self, pixel: &[u8]) -> u32 {
+        pixel.iter().rev().fold(0, |key, &byte| key << 8 | byte as u32)
+    }
+
+    fn write(&self, output: &mut Vec<u8>, key: u32) {
+        let bytes = [key as u8, (key >> 8) as u8, (key >> 16) as u8, (key >> 24) as u8];
+        let start = self.pad as usize;
+        output.extend_from_slice(&bytes[..self.bpp][start..start + self.compressed_bpp])
+    }
+}
+
+fn encode_tile(output: &mut Vec<u8>, tile: &[u32], width: usize, cpixel: &CPixel) {
+    fn write_run_length(output: &mut Vec<u8>, run_length: usize) {
+        let mut remaining = run_length - 1;
+        while remaining >= 255 {
+            output.push(255);
+            remaining -= 255;
+        }
+        output.push(remaining as u8)
+    }
+
+    fn run_length_size(run_length: usize) -> usize {
+        (run_length - 1) / 255 + 1
+    }
+
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+    for &pixel in tile {
+        if !indices.contains_key(&pixel) {
+            if palette.len() == 127 {
+                palette.truncate(0);
+                break
+            }
+            indices.insert(pixel, palette.len() as u8);
+            palette.push(pixel);
+        }
+    }
+
+    if palette.len() == 1 { // Color fill
+        output.push(1);
+        cpixel.write(output, palette[0]);
+        return
+    }
+
+    let mut runs = Vec::new();
+    for &pixel in tile {
+        match runs.last_mut() {
+            Some(&mut (last, ref mut run_length)) if last == pixel => {
+                *run_length += 1;
+                continue
+            },
+            _ => ()
+        }
+        runs.push((pixel, 1))
+    }
+
+    let height = tile.len() / width;
+    let bits_per_index =
+        match palette.len() {
+            2 => 1, 3...4 => 2, 5...16 => 4, _ => 0
+        };
+
+    let raw_size = tile.len() * cpixel.compressed_bpp;
+    let rle_size = runs.iter()
+        .map(|&(_, run_length)| cpixel.compressed_bpp + run_length_size(run_length))
+        .fold(0, |sum, size| sum + size);
+    let packed_size =
+        if bits_per_index > 0 {
+            palette.len() * cpixel.compressed_bpp +
+                height * ((width * bits_per_index + 7) / 8)
+        } else {
+            usize::max_value()
+        };
+    let palette_rle_size =
+        if palette.len() > 0 {
+            palette.len() * cpixel.compressed_bpp +
+                runs.iter()
+                    .map(|&(_, run_length)|
+                        if run_length == 1 { 1 } else { 1 + run_length_size(run_length) })
+                    .fold(0, |sum, size| sum + size)
+        } else {
+            usize::max_value()
+        };
+
+    if packed_size <= raw_size && packed_size <= rle_size && packed_size <= palette_rle_size {
+        // Indexed pixels
+        output.push(palette.len() as u8);
+        for &colour in &palette { cpixel.write(output, colour) }
+        for row in tile.chunks(width) {
+            let (mut byte, mut bits) = (0u8, 0);
+            for pixel in row {
+                byte = byte << bits_per_index | indices[pixel];
+                bits += bits_per_index;
+                if bits == 8 {
+                    output.push(byte);
+                    byte = 0;
+                    bits = 0;
+                }
+            }
+            if bits > 0 { output.push(byte << (8 - bits)) }
+        }
+    } else if palette_rle_size <= raw_size && palette_rle_size <= rle_size {
+        // Indexed RLE
+        output.push(128 | palette.len() as u8);
+        for &colour in &palette { cpixel.write(output, colour) }
+        for &(pixel, run_length) in &runs {
+            if run_length == 1 {
+                output.push(indices[&pixel])
+            } else {
+                output.push(128 | indices[&pixel]);
+                write_run_length(output, run_length)
+            }
+        }
+    } else if rle_size <= raw_size {
+        // True Color RLE
+        output.push(128);
+        for &(pixel, run_length) in &runs {
+            cpixel.write(output, pixel);
+            write_run_length(output, run_length)
+        }
+    } else {
+        // True Color pixels
+        output.push(0);
+        for &pixel in tile { cpixel.write(output, pixel) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2;
+    use super::{Decoder, Encoder};
+    use ::protocol;
+
+    /// Encodes `pixels` and checks that decoding them yields the same image.
+    fn check_roundtrip(encoder: &mut Encoder, decoder: &mut Decoder,
+                       format: protocol::PixelFormat, rect: protocol::Rect, pixels: &[u8]) {
+        let bpp = format.bits_per_pixel as usize / 8;
+        let data = encoder.encode(format, rect, pixels);
+
+        let mut decoded = vec![0; pixels.len()];
+        assert!(decoder.decode(format, rect, &data, |tile, tile_pixels| {
+            for row in 0..tile.height as usize {
+                let src = row * tile.width as usize * bpp;
+                let dst = ((tile.top - rect.top) as usize + row) * rect.width as usize +
+                          (tile.left - rect.left) as usize;
+                let length = tile.width as usize * bpp;
+                decoded[dst * bpp..dst * bpp + length]
+                    .copy_from_slice(&tile_pixels[src..src + length]);
+            }
+            Ok(true)
+        }).unwrap());
+        assert_eq!(&decoded[..], pixels);
+    }
+
+    /// Checks if every ZRLE subencoding chosen by the encoder is decoded back correctly.
+    #[test]
+    fn check_if_encoded_tiles_decode_to_same_pixels() {
+        let rect = protocol::Rect::new(10, 20, 100, 70);
+        let count = rect.width as usize * rect.height as usize;
+        let patterns: Vec<Box<Fn(usize) -> u32>> = vec![
+            Box::new(|_| 0x123456),                                  // solid
+            Box::new(|i| if (i / 3) % 2 == 0 { 0 } else { 0xffffff }),  // two colours
+            Box::new(|i| (i % 7) as u32 * 0x010203),                 // small palette
+            Box::new(|i| ((i / 4) % 20) as u32 * 0x050505),          // palette runs
+            Box::new(|i| ((i / 40) as u32).wrapping_mul(0x0a0b0c)),  // long runs
+            Box::new(|i| ((i / 5) as u32).wrapping_mul(0x9e3779)),   // many short runs
+            Box::new(|i| (i as u32).wrapping_mul(0x9e3779b9)),       // noise
+        ];
+
+        for &format in &[::pixel_format::RGB8888, ::pixel_format::BGR8888] {
+            let mut encoder = Encoder::new();
+            let mut decoder = Decoder::new();
+            for pattern in &patterns {
+                let mut pixels = Vec::with_capacity(count * 4);
+                for i in 0..count {
+                    let value = pattern(i) & 0xffffff;
+                    pixels.extend_from_slice(&[(value >> 16) as u8, (value >> 8) as u8,
+                                               value as u8, 0]);
+                    if format.big_endian { let n = pixels.len(); pixels[n - 4..].reverse() }
+                }
+                check_roundtrip(&mut encoder, &mut decoder, format, rect, &pixels);
+            }
+        }
+
+        let mut format = ::pixel_format::RGB8888;
+        format.bits_per_pixel = 16;
+        format.depth = 16;
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new();
+        for pattern in &patterns {
+            let mut pixels = Vec::with_capacity(count * 2);
+            for i in 0..count {
+                let value = pattern(i);
+                pixels.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+            }
+            check_roundtrip(&mut encoder, &mut decoder, format, rect, &pixels);
+        }
+    }
+
+    /// Checks if 32-bit pixels are sent as 3-byte CPIXELs that leave out the unused byte,
+    /// wherever the colour channels and the byte order place it.
+    #[test]
+    fn check_if_cpixels_leave_out_unused_byte() {
+        let rect = protocol::Rect::new(0, 0, 4, 4);
+        let mut pixels = Vec::new();
+        for i in 0..16 {
+            pixels.extend_from_slice(&[i as u8, 0x10 + i as u8, 0x20 + i as u8, 0x30 + i as u8]);
+        }
+        // (big endian, colour shifts, index of the unused byte)
+        let cases = [(false, [0, 8, 16], 3), (true, [0, 8, 16], 0),
+                     (false, [8, 16, 24], 0), (true, [8, 16, 24], 3)];
+        for &(big_endian, shifts, unused) in &cases {
+            let format = protocol::PixelFormat {
+                bits_per_pixel: 32,
+                depth: 24,
+                big_endian: big_endian,
+                true_colour: true,
+                red_max: 255,
+                green_max: 255,
+                blue_max: 255,
+                red_shift: shifts[0],
+                green_shift: shifts[1],
+                blue_shift: shifts[2],
+            };
+            let mut pixels = pixels.clone();
+            for pixel in pixels.chunks_mut(4) {
+                pixel[unused] = 0;
+            }
+
+            let data = Encoder::new().encode(format, rect, &pixels);
+            let mut tile = Vec::with_capacity(256);
+            flate2::Decompress::new(/*zlib_header*/true)
+                .decompress_vec(&data, &mut tile, flate2::Flush::Sync).unwrap();
+            let expected: Vec<u8> = ::std::iter::once(0).chain(
+                pixels.chunks(4).flat_map(|pixel|
+                    (0..4).filter(|&index| index != unused).map(move |index| pixel[index])))
+                .collect();
+            assert_eq!(tile, expected, "{:?}", format);
+
+            check_roundtrip(&mut Encoder::new(), &mut Decoder::new(), format, rect, &pixels);
+        }
+    }
+}